@@ -0,0 +1,21 @@
+#version 450
+#extension GL_EXT_nonuniform_qualifier : require
+
+layout (location = 0) in vec3 position;
+layout (location = 1) in vec4 color;
+
+layout (location = 0) out vec4 frag_color;
+
+layout(std140, set = 0, binding = 0) readonly buffer Some{
+	mat4 view_projection_matrix;
+} Matrices[];
+
+layout(push_constant) uniform PushConstants
+{
+    uint view_projection_matrix_index;
+} push_constants;
+
+void main() {
+    gl_Position = Matrices[push_constants.view_projection_matrix_index].view_projection_matrix * vec4(position, 1.0);
+    frag_color = color;
+}
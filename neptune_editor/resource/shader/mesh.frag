@@ -34,6 +34,7 @@ layout(push_constant) uniform PushConstants
 {
     uint view_projection_matrix_index;
     uint model_matrices_index;
+    uint instance_indices_index;
     SamplerBinding image_sampler;
     SampledImageBinding albedo_texture;
 } push_constants;
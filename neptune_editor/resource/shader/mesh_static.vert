@@ -20,14 +20,20 @@ layout(std140, set = 0, binding = 0) readonly buffer Some1{
 	mat4 model_matrices[];
 } ModelMatrices[];
 
+layout(std430, set = 0, binding = 0) readonly buffer Some2{
+	uint instance_indices[];
+} InstanceIndices[];
+
 layout(push_constant) uniform PushConstants
 {
     uint view_projection_matrix_index;
     uint model_matrices_index;
+    uint instance_indices_index;
 } push_constants;
 
 void main() {
-    mat4 model_matrix = ModelMatrices[push_constants.model_matrices_index].model_matrices[gl_InstanceIndex];
+    uint model_index = InstanceIndices[push_constants.instance_indices_index].instance_indices[gl_InstanceIndex];
+    mat4 model_matrix = ModelMatrices[push_constants.model_matrices_index].model_matrices[model_index];
     mat4 mvp_matrix = Matrices[push_constants.view_projection_matrix_index].view_projection_matrix * model_matrix;
     gl_Position = mvp_matrix * vec4(position, 1.0);
 
@@ -0,0 +1,102 @@
+use neptune_vulkan::vk;
+
+/// Picks between a low latency present mode and FIFO based on the measured frame time.
+/// FIFO caps the frame time at the refresh interval, so a frame time at the interval counts as keeping up
+pub struct AdaptivePresentMode {
+    low_latency_mode: vk::PresentModeKHR,
+    refresh_interval: f32,
+    current_mode: vk::PresentModeKHR,
+    average_frame_time: f32,
+}
+
+impl AdaptivePresentMode {
+    /// Weight of the newest frame in the frame time average
+    const AVERAGE_WEIGHT: f32 = 0.1;
+
+    /// Switch to the low latency mode once the average is within this fraction above the refresh interval
+    const KEEPING_UP_THRESHOLD: f32 = 0.05;
+
+    /// Fall back to FIFO once the average is this fraction above the refresh interval
+    const FALLING_BEHIND_THRESHOLD: f32 = 0.2;
+
+    /// Returns None if the surface supports neither MAILBOX nor IMMEDIATE
+    pub fn new(supported_modes: &[vk::PresentModeKHR], refresh_rate: f32) -> Option<Self> {
+        let low_latency_mode = [vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
+            .into_iter()
+            .find(|mode| supported_modes.contains(mode))?;
+        let refresh_interval = 1.0 / refresh_rate;
+        Some(Self {
+            low_latency_mode,
+            refresh_interval,
+            current_mode: vk::PresentModeKHR::FIFO,
+            average_frame_time: refresh_interval,
+        })
+    }
+
+    /// Returns the new present mode when the swapchain needs to switch to it
+    pub fn update(&mut self, frame_time: f32) -> Option<vk::PresentModeKHR> {
+        self.average_frame_time += (frame_time - self.average_frame_time) * Self::AVERAGE_WEIGHT;
+
+        let new_mode = if self.current_mode == vk::PresentModeKHR::FIFO {
+            (self.average_frame_time <= self.refresh_interval * (1.0 + Self::KEEPING_UP_THRESHOLD))
+                .then_some(self.low_latency_mode)
+        } else {
+            (self.average_frame_time
+                > self.refresh_interval * (1.0 + Self::FALLING_BEHIND_THRESHOLD))
+                .then_some(vk::PresentModeKHR::FIFO)
+        }?;
+
+        self.current_mode = new_mode;
+        Some(new_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFRESH_RATE: f32 = 60.0;
+
+    /// Feeds `count` frames of `interval_fraction` times the refresh interval, returning every switch made
+    fn run(
+        adaptive: &mut AdaptivePresentMode,
+        interval_fraction: f32,
+        count: usize,
+    ) -> Vec<vk::PresentModeKHR> {
+        (0..count)
+            .filter_map(|_| adaptive.update(interval_fraction / REFRESH_RATE))
+            .collect()
+    }
+
+    #[test]
+    fn switches_only_when_crossing_thresholds() {
+        let mut adaptive = AdaptivePresentMode::new(
+            &[vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX],
+            REFRESH_RATE,
+        )
+        .unwrap();
+
+        assert_eq!(run(&mut adaptive, 1.0, 10), [vk::PresentModeKHR::MAILBOX]);
+        assert!(
+            run(&mut adaptive, 1.1, 100).is_empty(),
+            "slightly slow frames stay within the hysteresis band"
+        );
+        assert_eq!(run(&mut adaptive, 1.5, 100), [vk::PresentModeKHR::FIFO]);
+        assert!(
+            run(&mut adaptive, 1.1, 100).is_empty(),
+            "FIFO is kept until frames are back near the refresh interval"
+        );
+        assert_eq!(run(&mut adaptive, 1.0, 100), [vk::PresentModeKHR::MAILBOX]);
+    }
+
+    #[test]
+    fn requires_a_low_latency_mode() {
+        assert!(AdaptivePresentMode::new(&[vk::PresentModeKHR::FIFO], REFRESH_RATE).is_none());
+        let adaptive = AdaptivePresentMode::new(
+            &[vk::PresentModeKHR::FIFO, vk::PresentModeKHR::IMMEDIATE],
+            REFRESH_RATE,
+        )
+        .unwrap();
+        assert_eq!(adaptive.low_latency_mode, vk::PresentModeKHR::IMMEDIATE);
+    }
+}
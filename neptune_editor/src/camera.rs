@@ -1,4 +1,4 @@
-use glam::Mat4;
+use glam::{Mat4, Vec3, Vec4};
 
 #[derive(Debug, Clone, Copy)]
 pub enum FieldOfView {
@@ -55,3 +55,61 @@ impl Camera {
         matrix
     }
 }
+
+/// Clip planes of a view projection matrix with 0..1 depth, used to cull bounding boxes on the cpu.
+/// The default frustum has no planes that reject anything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn from_view_projection(view_projection_matrix: &Mat4) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|index| view_projection_matrix.row(index));
+        Self {
+            planes: [w + x, w - x, w + y, w - y, z, w - z],
+        }
+    }
+
+    /// False only if the box is entirely outside one of the planes, so some boxes near corners pass
+    pub fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            let furthest_point = Vec3::select(normal.cmpge(Vec3::ZERO), max, min);
+            normal.dot(furthest_point) + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frustum_culls_boxes_outside_the_view() {
+        let camera = Camera::new(FieldOfView::Y(90.0), 0.1, Some(100.0));
+        let view_matrix = Mat4::look_to_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+        let frustum = Frustum::from_view_projection(&(camera.projection_matrix(1.0) * view_matrix));
+
+        let unit_box_at = |center: Vec3| frustum.intersects_aabb(center - 0.5, center + 0.5);
+        assert!(unit_box_at(Vec3::new(0.0, 0.0, -10.0)));
+        assert!(
+            unit_box_at(Vec3::new(10.0, 0.0, -10.0)),
+            "box straddling the side plane"
+        );
+        assert!(!unit_box_at(Vec3::new(0.0, 0.0, 10.0)), "behind the camera");
+        assert!(
+            !unit_box_at(Vec3::new(20.0, 0.0, -10.0)),
+            "outside the side plane"
+        );
+        assert!(
+            !unit_box_at(Vec3::new(0.0, 0.0, -200.0)),
+            "beyond the far plane"
+        );
+    }
+
+    #[test]
+    fn default_frustum_contains_everything() {
+        assert!(Frustum::default().intersects_aabb(Vec3::splat(1.0e6), Vec3::splat(1.0e6 + 1.0)));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::adaptive_present::AdaptivePresentMode;
 use crate::camera::{Camera, FieldOfView};
 use crate::game::entity::StaticEntity;
 use crate::game::player::Player;
@@ -6,14 +7,17 @@ use crate::game::world::{World, WorldData};
 use crate::gltf_loader::{load_gltf_resources, load_gltf_scene};
 use crate::input::{ButtonState, InputEventReceiver, StaticString};
 use crate::physics::physics_world::{Collider, PhysicsWorld};
+use crate::physics::vec3_na_to_glam;
 use crate::platform::WindowEventReceiver;
+use crate::render_settings::{Anisotropy, RenderSettings, SamplerQuality};
+use crate::scene::debug_draw::{DebugDepthMode, DebugDraw};
 use crate::scene::scene_renderer::{Model, ModelPrimitive, Scene, SceneCamera, SceneRenderer};
 use crate::transform::Transform;
 use anyhow::Context;
-use glam::Vec3;
+use glam::{Vec3, Vec4};
 use neptune_vulkan::gpu_allocator::MemoryLocation;
 use neptune_vulkan::render_graph_builder::{
-    BufferOffset, BufferReadCallback, BufferWriteCallback, RenderGraphBuilderTrait,
+    BufferOffset, BufferReadCallback, BufferWriteCallback, ClearValue, RenderGraphBuilderTrait,
 };
 use neptune_vulkan::{vk, BufferUsage, DeviceSettings};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
@@ -24,15 +28,40 @@ use std::sync::Arc;
 pub struct EditorConfig {
     #[arg(short, long)]
     pub fullscreen: bool,
+
+    /// Switch between a low latency present mode and FIFO depending on frame time
+    #[arg(long)]
+    pub adaptive_vsync: bool,
+
+    /// Textures larger than this many pixels on a side are downscaled when loaded
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_texture_size: Option<u32>,
+
+    /// Maximum anisotropic filtering samples of texture samplers
+    #[arg(long, value_enum, default_value = "1")]
+    pub anisotropy: Anisotropy,
+
+    /// Mip level bias of texture samplers, positive values trade sharpness for performance
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub mip_bias: f32,
+
+    /// Let the compositor rotate presented images instead of compensating in the camera projection
+    #[arg(long)]
+    pub compositor_pre_transform: bool,
 }
 
 pub struct Editor {
     instance: neptune_vulkan::Instance,
     surface_handle: neptune_vulkan::SurfaceHandle,
     surface_size: [u32; 2],
+    surface_format: vk::SurfaceFormatKHR,
+    present_mode: vk::PresentModeKHR,
+    adaptive_present_mode: Option<AdaptivePresentMode>,
+    pre_transform: Option<vk::SurfaceTransformFlagsKHR>,
 
     device: neptune_vulkan::Device,
     scene_renderer: SceneRenderer,
+    debug_draw: DebugDraw,
 
     camera: Camera,
     camera_transform: Transform,
@@ -45,10 +74,24 @@ pub struct Editor {
 
     camera_rotate_speed: Vec3,
     camera_rotate_input: Vec3,
+
+    pass_timings_enabled: bool,
+    last_logged_timings_frame: u64,
+    debug_bounds_enabled: bool,
 }
 
 impl Editor {
     const DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+    const SURFACE_FORMATS: &'static [vk::SurfaceFormatKHR] = &[
+        vk::SurfaceFormatKHR {
+            format: vk::Format::B8G8R8A8_UNORM,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        },
+        vk::SurfaceFormatKHR {
+            format: vk::Format::R8G8B8A8_UNORM,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        },
+    ];
 
     pub fn new<W: HasRawDisplayHandle + HasRawWindowHandle>(
         window: &W,
@@ -114,27 +157,38 @@ impl Editor {
         let mut device = physical_device
             .create_device(DeviceSettings {
                 frames_in_flight: FRAME_IN_FLIGHT_COUNT,
+                features: Default::default(),
+                descriptor_count: None,
+                staging_memory: Default::default(),
+                command_buffer_capacity: Default::default(),
+                frame_arena: true,
             })
             .context("Failed to initialize vulkan device")?;
 
         let surface_size = window_size;
+        let surface_format = device.pick_surface_format(surface_handle, Self::SURFACE_FORMATS)?;
+        info!("Surface Format: {:?}", surface_format);
 
+        let pre_transform = config
+            .compositor_pre_transform
+            .then_some(vk::SurfaceTransformFlagsKHR::IDENTITY);
         device.configure_surface(
             surface_handle,
             &neptune_vulkan::SurfaceSettings {
                 image_count: FRAME_IN_FLIGHT_COUNT,
-                format: vk::SurfaceFormatKHR {
-                    format: vk::Format::B8G8R8A8_UNORM,
-                    color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-                },
+                format: surface_format,
                 size: surface_size,
                 usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST,
                 present_mode: vk::PresentModeKHR::FIFO,
+                acquire_timeout_ns: u64::MAX,
+                pre_transform,
             },
         )?;
         clear_surfaces(&mut device, [0.0; 3], &[surface_handle])?;
 
-        let scene_renderer = SceneRenderer::new(&mut device, Self::DEPTH_FORMAT)?;
+        let scene_renderer =
+            SceneRenderer::new(&mut device, surface_format.format, Self::DEPTH_FORMAT)?;
+        let debug_draw = DebugDraw::new(&mut device, surface_format.format, Self::DEPTH_FORMAT)?;
 
         // let gltf_scene_path = if let Some(path) = &config.gltf_scene_path {
         //     path.clone()
@@ -146,10 +200,23 @@ impl Editor {
         //         .expect("Failed to pick a gltf file")
         // };
 
-        let scene_camera = SceneCamera::new(&mut device)?;
+        let mut scene_camera = SceneCamera::new(&mut device)?;
+        scene_camera.set_pre_transform(
+            device
+                .get_surface_pre_transform(surface_handle)
+                .unwrap_or(vk::SurfaceTransformFlagsKHR::IDENTITY),
+        );
 
         //let world = load_world(&mut device, gltf_scene_path)?;
-        let world = create_test_world(&mut device)?;
+        let render_settings = RenderSettings {
+            sampler_quality: SamplerQuality {
+                anisotropy: config.anisotropy,
+                mip_bias: config.mip_bias,
+                max_lod: None,
+            },
+            ..Default::default()
+        };
+        let world = create_test_world(&mut device, config.max_texture_size, &render_settings)?;
 
         let new_world = crate::universe::world::init_test_world();
         drop(new_world);
@@ -158,8 +225,13 @@ impl Editor {
             instance,
             surface_handle,
             surface_size,
+            surface_format,
+            present_mode: vk::PresentModeKHR::FIFO,
+            adaptive_present_mode: None,
+            pre_transform,
             device,
             scene_renderer,
+            debug_draw,
             camera: Camera::new(FieldOfView::X(90.0), 0.1, None),
             camera_transform: Transform::with_position(Vec3::NEG_Z),
             scene_camera,
@@ -168,25 +240,59 @@ impl Editor {
             camera_move_input: Vec3::ZERO,
             camera_rotate_speed: Vec3::new(0.0, 60.0f32.to_radians(), 0.0),
             camera_rotate_input: Vec3::ZERO,
+            pass_timings_enabled: false,
+            last_logged_timings_frame: 0,
+            debug_bounds_enabled: false,
         })
     }
 
     pub fn window_resize(&mut self, new_size: [u32; 2]) -> anyhow::Result<()> {
         info!("Swapchain Resize: {:?}", new_size);
         self.surface_size = new_size;
+        self.configure_surface()
+    }
+
+    fn configure_surface(&mut self) -> anyhow::Result<()> {
         self.device.configure_surface(
             self.surface_handle,
             &neptune_vulkan::SurfaceSettings {
                 image_count: 3,
-                format: vk::SurfaceFormatKHR {
-                    format: vk::Format::B8G8R8A8_UNORM,
-                    color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-                },
-                size: new_size,
+                format: self.surface_format,
+                size: self.surface_size,
                 usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST,
-                present_mode: vk::PresentModeKHR::FIFO,
+                present_mode: self.present_mode,
+                acquire_timeout_ns: u64::MAX,
+                pre_transform: self.pre_transform,
             },
         )?;
+        self.scene_camera.set_pre_transform(
+            self.device
+                .get_surface_pre_transform(self.surface_handle)
+                .unwrap_or(vk::SurfaceTransformFlagsKHR::IDENTITY),
+        );
+        Ok(())
+    }
+
+    pub fn enable_adaptive_present_mode(&mut self, refresh_rate: f32) -> anyhow::Result<()> {
+        let supported_modes = self.device.get_surface_present_modes(self.surface_handle)?;
+        self.adaptive_present_mode = AdaptivePresentMode::new(&supported_modes, refresh_rate);
+        if self.adaptive_present_mode.is_none() {
+            warn!("Surface only supports FIFO, adaptive vsync disabled");
+        }
+        Ok(())
+    }
+
+    /// Only rebuilds the swapchain when the adaptive present mode actually changes
+    pub fn update_present_mode(&mut self, frame_time: f32) -> anyhow::Result<()> {
+        if let Some(present_mode) = self
+            .adaptive_present_mode
+            .as_mut()
+            .and_then(|adaptive_present_mode| adaptive_present_mode.update(frame_time))
+        {
+            info!("Switching present mode to {:?}", present_mode);
+            self.present_mode = present_mode;
+            self.configure_surface()?;
+        }
         Ok(())
     }
 
@@ -206,74 +312,167 @@ impl Editor {
             Some(player) => player.get_camera_transform(),
         };
 
+        let [width, height] = neptune_vulkan::pre_transform_extent(
+            self.device
+                .get_surface_pre_transform(self.surface_handle)
+                .unwrap_or(vk::SurfaceTransformFlagsKHR::IDENTITY),
+            self.surface_size,
+        );
         self.scene_camera.update(
             &self.camera,
             &camera_transform,
-            (self.surface_size[0] as f32) / (self.surface_size[1] as f32),
+            (width as f32) / (height as f32),
         );
 
         self.world.update(delta_time);
     }
 
     pub fn render(&mut self) -> anyhow::Result<()> {
-        let mut render_graph_builder =
-            neptune_vulkan::basic_render_graph_builder::BasicRenderGraphBuilder::default();
-
-        let swapchain_image = render_graph_builder.acquire_swapchain_image(self.surface_handle);
-
-        self.scene_camera
-            .write_render_passes(&mut render_graph_builder);
-        self.world
-            .data
-            .scene
-            .write_render_passes(&mut render_graph_builder);
-        self.scene_renderer.write_render_passes(
-            swapchain_image,
-            &self.scene_camera,
-            &self.world.data.scene,
-            &mut render_graph_builder,
+        if self.debug_bounds_enabled {
+            self.draw_debug_bounds();
+        }
+
+        let frame_result = self.device.render_frame(
+            &[self.surface_handle],
+            |render_graph_builder, swapchain_images| -> anyhow::Result<()> {
+                self.scene_camera.write_render_passes(render_graph_builder);
+                self.world
+                    .data
+                    .scene
+                    .write_render_passes(render_graph_builder);
+                let depth_image = self.scene_renderer.write_render_passes(
+                    swapchain_images[0],
+                    &self.scene_camera,
+                    &self.world.data.scene,
+                    render_graph_builder,
+                )?;
+                self.debug_draw.write_render_passes(
+                    swapchain_images[0],
+                    depth_image,
+                    &self.scene_camera,
+                    render_graph_builder,
+                )?;
+
+                //Round-trip Upload/Download Test
+                {
+                    let test_data = &[127u8; 16];
+                    let test_buffer = render_graph_builder.create_transient_buffer(
+                        test_data.len(),
+                        BufferUsage::TRANSFER,
+                        MemoryLocation::GpuOnly,
+                    );
+
+                    //Upload data to gpu
+                    render_graph_builder.add_buffer_write(
+                        BufferOffset {
+                            buffer: test_buffer,
+                            offset: 0,
+                        },
+                        test_data.len(),
+                        BufferWriteCallback::new(move |slice| {
+                            slice.copy_from_slice(test_data);
+                        }),
+                    );
+
+                    //Download from gpu
+                    render_graph_builder.add_buffer_read(
+                        BufferOffset {
+                            buffer: test_buffer,
+                            offset: 0,
+                        },
+                        test_data.len(),
+                        BufferReadCallback::new(move |slice| {
+                            assert_eq!(
+                                slice, test_data,
+                                "Downloaded data doesn't match uploaded data"
+                            );
+                        }),
+                    )?;
+                }
+                Ok(())
+            },
         );
+        // Also cleared when the frame failed before the debug shapes were drawn
+        self.debug_draw.clear();
+        frame_result?;
 
-        //Round-trip Upload/Download Test
+        self.log_pass_timings();
+        Ok(())
+    }
+
+    fn toggle_pass_timings(&mut self) {
+        self.pass_timings_enabled = !self.pass_timings_enabled;
+        if !self
+            .device
+            .set_pass_timings_enabled(self.pass_timings_enabled)
         {
-            let test_data = &[127u8; 16];
-            let test_buffer = render_graph_builder.create_transient_buffer(
-                test_data.len(),
-                BufferUsage::TRANSFER,
-                MemoryLocation::GpuOnly,
-            );
+            warn!("Device doesn't support gpu timestamps, pass timings are unavailable");
+            self.pass_timings_enabled = false;
+        }
+        info!(
+            "Pass timings {}",
+            if self.pass_timings_enabled {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
 
-            //Upload data to gpu
-            render_graph_builder.add_buffer_write(
-                BufferOffset {
-                    buffer: test_buffer,
-                    offset: 0,
-                },
-                test_data.len(),
-                BufferWriteCallback::new(move |slice| {
-                    slice.copy_from_slice(test_data);
-                }),
-            );
+    fn draw_debug_bounds(&mut self) {
+        self.debug_draw.set_depth_mode(DebugDepthMode::Tested);
+        for bounds in self.world.data.scene.primitive_bounds() {
+            self.debug_draw
+                .aabb(bounds.min, bounds.max, Vec4::new(1.0, 1.0, 0.0, 1.0));
+        }
 
-            //Download from gpu
-            render_graph_builder.add_buffer_read(
-                BufferOffset {
-                    buffer: test_buffer,
-                    offset: 0,
-                },
-                test_data.len(),
-                BufferReadCallback::new(move |slice| {
-                    assert_eq!(
-                        slice, test_data,
-                        "Downloaded data doesn't match uploaded data"
+        let collider_color = Vec4::new(0.0, 1.0, 1.0, 1.0);
+        for (_, collider) in self.world.data.physics.collider_set.iter() {
+            match collider.shape().as_ball() {
+                Some(ball) => self.debug_draw.sphere(
+                    vec3_na_to_glam(collider.translation()),
+                    ball.radius,
+                    collider_color,
+                ),
+                None => {
+                    let aabb = collider.compute_aabb();
+                    self.debug_draw.aabb(
+                        vec3_na_to_glam(&aabb.mins.coords),
+                        vec3_na_to_glam(&aabb.maxs.coords),
+                        collider_color,
                     );
-                }),
-            );
+                }
+            }
         }
 
-        let render_graph = render_graph_builder.build();
-        self.device.submit_graph(&render_graph)?;
-        Ok(())
+        self.debug_draw.set_depth_mode(DebugDepthMode::Ignored);
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.debug_draw.line(Vec3::ZERO, axis, axis.extend(1.0));
+        }
+    }
+
+    fn log_pass_timings(&mut self) {
+        const LOG_INTERVAL_FRAMES: u64 = 120;
+
+        if let Some(frame_timings) = self.device.last_frame_timings() {
+            if frame_timings.frame_id < self.last_logged_timings_frame + LOG_INTERVAL_FRAMES {
+                return;
+            }
+            self.last_logged_timings_frame = frame_timings.frame_id;
+
+            info!(
+                "Frame {} gpu time: {:.3}ms",
+                frame_timings.frame_id,
+                frame_timings.total_gpu_time().as_secs_f64() * 1000.0
+            );
+            for pass_timing in frame_timings.passes.iter() {
+                info!(
+                    "    {}: {:.3}ms",
+                    pass_timing.name,
+                    pass_timing.gpu_time.as_secs_f64() * 1000.0
+                );
+            }
+        }
     }
 }
 
@@ -296,6 +495,20 @@ impl InputEventReceiver for Editor {
     }
 
     fn on_button_event(&mut self, button_name: StaticString, state: ButtonState) -> bool {
+        if button_name == "debug_toggle_pass_timings" {
+            if state.is_down() {
+                self.toggle_pass_timings();
+            }
+            return true;
+        }
+
+        if button_name == "debug_toggle_bounds" {
+            if state.is_down() {
+                self.debug_bounds_enabled = !self.debug_bounds_enabled;
+            }
+            return true;
+        }
+
         if let Some(player) = &mut self.world.entities.player {
             return player.on_button_event(button_name, state);
         }
@@ -339,11 +552,21 @@ impl InputEventReceiver for Editor {
     }
 }
 
-fn create_test_world(device: &mut neptune_vulkan::Device) -> anyhow::Result<World> {
-    let gltf_data = load_gltf_resources(device, "neptune_editor/resource/NeptuneResources.glb")?;
+fn create_test_world(
+    device: &mut neptune_vulkan::Device,
+    max_texture_size: Option<u32>,
+    render_settings: &RenderSettings,
+) -> anyhow::Result<World> {
+    let gltf_data = load_gltf_resources(
+        device,
+        "neptune_editor/resource/NeptuneResources.glb",
+        max_texture_size,
+        render_settings,
+    )?;
 
     info!("Available Meshes: {:?}", gltf_data.meshes.keys());
     info!("Available Materials: {:?}", gltf_data.materials.keys());
+    info!("Available Models: {:?}", gltf_data.models.keys());
 
     let mut world = World {
         data: WorldData {
@@ -431,19 +654,34 @@ fn clear_surfaces(
     color: [f32; 3],
     surface_handles: &[neptune_vulkan::SurfaceHandle],
 ) -> anyhow::Result<()> {
-    let mut render_graph_builder =
-        neptune_vulkan::basic_render_graph_builder::BasicRenderGraphBuilder::default();
+    let mut render_graph_builder = device.create_render_graph_builder();
 
     for handle in surface_handles {
         let swapchain_image = render_graph_builder.acquire_swapchain_image(*handle);
         let mut raster_pass_builder =
             neptune_vulkan::render_graph_builder::RasterPassBuilder::new("Swapchain Pass");
-        raster_pass_builder
-            .add_color_attachment(swapchain_image, Some([color[0], color[1], color[2], 1.0]));
+        raster_pass_builder.add_color_attachment(
+            swapchain_image,
+            Some(ClearValue::Float([color[0], color[1], color[2], 1.0])),
+        );
         raster_pass_builder.build(&mut render_graph_builder);
     }
 
-    let render_graph = render_graph_builder.build();
+    let render_graph = render_graph_builder.build()?;
     device.submit_graph(&render_graph)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn zero_max_texture_size_is_rejected() {
+        assert!(EditorConfig::try_parse_from(["neptune", "--max-texture-size", "0"]).is_err());
+        let config =
+            EditorConfig::try_parse_from(["neptune", "--max-texture-size", "512"]).unwrap();
+        assert_eq!(config.max_texture_size, Some(512));
+    }
+}
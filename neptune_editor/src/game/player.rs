@@ -1,7 +1,7 @@
 use crate::game::entity::Entity;
 use crate::game::world::WorldData;
 use crate::input::{ButtonState, InputEventReceiver, StaticString};
-use crate::physics::character::CharacterController;
+use crate::physics::character::{CharacterController, CharacterControllerSettings};
 use crate::transform::Transform;
 use glam::{Quat, Vec2, Vec3};
 
@@ -37,7 +37,10 @@ impl Player {
             camera_pitch: 0.0,
             camera_offset: Vec3::Y * 0.5,
 
-            character: CharacterController::new(),
+            character: CharacterController::new(CharacterControllerSettings {
+                max_step_height: 0.35,
+                ..Default::default()
+            }),
             gravity_acceleration: 9.8,
             gravity_velocity: 0.0,
 
@@ -1,13 +1,16 @@
 use crate::material::{Material, MaterialTexture};
 use crate::mesh::{
-    BoundingBox, IndexBuffer, Mesh, Primitive, VertexAttributes, VertexSkinningAttributes,
+    compute_normals, compute_tangents, simplify_indices, BoundingBox, IndexBuffer, Mesh, Primitive,
+    PrimitiveLod, VertexAttributes, VertexSkinningAttributes,
 };
+use crate::render_settings::RenderSettings;
+use crate::scene::scene_renderer::{Model, ModelPrimitive};
 use anyhow::anyhow;
 use glam::{Mat4, Vec2, Vec3, Vec4};
 use gltf::image::Format;
 use gltf::texture::{MagFilter, MinFilter, WrappingMode};
 use neptune_vulkan::gpu_allocator::MemoryLocation;
-use neptune_vulkan::{vk, AddressMode, FilterMode, ImageHandle, SamplerHandle};
+use neptune_vulkan::{vk, AddressMode, AllocationScheme, FilterMode, ImageHandle, SamplerHandle};
 use std::collections::HashMap;
 use std::sync::Arc;
 
@@ -22,27 +25,52 @@ fn neptune_address_mode(mode: WrappingMode) -> AddressMode {
 pub struct GltfSamplers {
     pub default: SamplerHandle,
     pub samplers: Vec<SamplerHandle>,
+    /// Samplers of textures with a quality override, keyed by texture index
+    pub texture_samplers: HashMap<usize, SamplerHandle>,
 }
 
+fn gltf_sampler_description(
+    gltf_sampler: &gltf::texture::Sampler,
+) -> neptune_vulkan::SamplerDescription {
+    let mag_filter = match gltf_sampler.mag_filter().unwrap_or(MagFilter::Linear) {
+        MagFilter::Nearest => FilterMode::Nearest,
+        MagFilter::Linear => FilterMode::Linear,
+    };
+
+    let (min_filter, mip_filter) = match gltf_sampler.min_filter().unwrap_or(MinFilter::Linear) {
+        MinFilter::Nearest | MinFilter::NearestMipmapNearest => {
+            (FilterMode::Nearest, FilterMode::Nearest)
+        }
+        MinFilter::Linear | MinFilter::LinearMipmapLinear => {
+            (FilterMode::Linear, FilterMode::Linear)
+        }
+        MinFilter::NearestMipmapLinear => (FilterMode::Nearest, FilterMode::Linear),
+        MinFilter::LinearMipmapNearest => (FilterMode::Linear, FilterMode::Nearest),
+    };
+
+    neptune_vulkan::SamplerDescription {
+        address_mode_u: neptune_address_mode(gltf_sampler.wrap_s()),
+        address_mode_v: neptune_address_mode(gltf_sampler.wrap_t()),
+        address_mode_w: AddressMode::Repeat,
+        mag_filter,
+        min_filter,
+        mip_filter,
+        ..Default::default()
+    }
+}
+
+/// Samplers get render_settings' global quality, textures with an override get their own sampler
 pub fn load_samplers(
     device: &mut neptune_vulkan::Device,
     gltf_doc: &gltf::Document,
+    render_settings: &RenderSettings,
 ) -> anyhow::Result<GltfSamplers> {
-    let default_sampler = device.create_sampler(
-        "Gltf Default Sampler",
-        &neptune_vulkan::SamplerDescription {
-            address_mode_u: AddressMode::Repeat,
-            address_mode_v: AddressMode::Repeat,
-            address_mode_w: AddressMode::Repeat,
-            mag_filter: FilterMode::Linear,
-            min_filter: FilterMode::Linear,
-            mip_filter: FilterMode::Linear,
-            lod_clamp_range: None,
-            anisotropy_clamp: None,
-            border_color: Default::default(),
-            unnormalized_coordinates: false,
-        },
-    )?;
+    let max_anisotropy = device.max_sampler_anisotropy();
+    let mut default_description = neptune_vulkan::SamplerDescription::linear_repeat();
+    render_settings
+        .sampler_quality
+        .apply(&mut default_description, max_anisotropy);
+    let default_sampler = device.create_sampler("Gltf Default Sampler", &default_description)?;
 
     let mut samplers = Vec::with_capacity(gltf_doc.samplers().len());
     for gltf_sampler in gltf_doc.samplers() {
@@ -56,52 +84,44 @@ pub fn load_samplers(
                 )
             });
 
-        let address_mode_u = neptune_address_mode(gltf_sampler.wrap_s());
-        let address_mode_v = neptune_address_mode(gltf_sampler.wrap_t());
-
-        let mag_filter = match gltf_sampler.mag_filter().unwrap_or(MagFilter::Linear) {
-            MagFilter::Nearest => FilterMode::Nearest,
-            MagFilter::Linear => FilterMode::Linear,
-        };
-
-        let (min_filter, mip_filter) = match gltf_sampler.min_filter().unwrap_or(MinFilter::Linear)
-        {
-            MinFilter::Nearest | MinFilter::NearestMipmapNearest => {
-                (FilterMode::Nearest, FilterMode::Nearest)
-            }
-            MinFilter::Linear | MinFilter::LinearMipmapLinear => {
-                (FilterMode::Linear, FilterMode::Linear)
-            }
-            MinFilter::NearestMipmapLinear => (FilterMode::Nearest, FilterMode::Linear),
-            MinFilter::LinearMipmapNearest => (FilterMode::Linear, FilterMode::Nearest),
-        };
+        let mut description = gltf_sampler_description(&gltf_sampler);
+        render_settings
+            .sampler_quality
+            .apply(&mut description, max_anisotropy);
+        samplers.push(device.create_sampler(&name, &description)?);
+    }
 
-        let description = neptune_vulkan::SamplerDescription {
-            address_mode_u,
-            address_mode_v,
-            address_mode_w: AddressMode::Repeat,
-            mag_filter,
-            min_filter,
-            mip_filter,
-            lod_clamp_range: None,
-            anisotropy_clamp: None,
-            border_color: Default::default(),
-            unnormalized_coordinates: false,
+    let mut texture_samplers = HashMap::new();
+    for gltf_texture in gltf_doc.textures() {
+        let Some(quality) = gltf_texture
+            .name()
+            .and_then(|name| render_settings.texture_sampler_overrides.get(name))
+        else {
+            continue;
         };
 
-        samplers.push(device.create_sampler(&name, &description)?);
+        let mut description = gltf_sampler_description(&gltf_texture.sampler());
+        quality.apply(&mut description, max_anisotropy);
+        let name = format!("{} Sampler", gltf_texture.name().unwrap_or_default());
+        texture_samplers.insert(
+            gltf_texture.index(),
+            device.create_sampler(&name, &description)?,
+        );
     }
 
     Ok(GltfSamplers {
         default: default_sampler,
         samplers,
+        texture_samplers,
     })
 }
 
+/// Images larger than max_texture_size on either side are halved until they fit
 pub fn load_images(
     device: &mut neptune_vulkan::Device,
     gltf_doc: &gltf::Document,
     gltf_images: &[gltf::image::Data],
+    max_texture_size: Option<u32>,
 ) -> anyhow::Result<Vec<ImageHandle>> {
     let mut images = Vec::with_capacity(gltf_doc.images().len());
     for gltf_image in gltf_doc.images() {
@@ -147,12 +167,30 @@ pub fn load_images(
                 .collect();
             image_data_slice = &image_data_slice_new;
         }
+
+        let mut size = [gltf_image_data.width, gltf_image_data.height];
+        let downscaled_data: Vec<u8>;
+        if let Some(max_texture_size) = max_texture_size {
+            if let Some((data, downscaled_size)) =
+                downscale_image(image_data_slice, size, format, max_texture_size)
+            {
+                info!(
+                    "Downscaled image {} from {:?} to {:?}",
+                    name, size, downscaled_size
+                );
+                downscaled_data = data;
+                image_data_slice = &downscaled_data;
+                size = downscaled_size;
+            }
+        }
+
         let description = neptune_vulkan::ImageDescription2D {
-            size: [gltf_image_data.width, gltf_image_data.height],
+            size,
             format,
             usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
             mip_levels: 1,
             location: MemoryLocation::GpuOnly,
+            allocation_scheme: AllocationScheme::Managed,
         };
 
         images.push(device.create_image_init(&name, &description, image_data_slice)?);
@@ -161,6 +199,72 @@ pub fn load_images(
     Ok(images)
 }
 
+/// Halves the image with a 2x2 box filter until both sides are within max_size, like generating mip levels.
+/// Returns None if the image already fits, a max_size of 0 is treated as 1
+fn downscale_image(
+    pixels: &[u8],
+    size: [u32; 2],
+    format: vk::Format,
+    max_size: u32,
+) -> Option<(Vec<u8>, [u32; 2])> {
+    // Halving stops at 1x1, so a smaller limit would never be reached
+    let max_size = max_size.max(1);
+    if size[0] <= max_size && size[1] <= max_size {
+        return None;
+    }
+
+    let channel_count = neptune_vulkan::vk_format_channel_count(format)? as usize;
+    let component_size =
+        neptune_vulkan::vk_format_bytes_per_pixel(format)? as usize / channel_count;
+    let read_component = |bytes: &[u8]| -> f32 {
+        match component_size {
+            1 => bytes[0] as f32,
+            2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as f32,
+            _ => f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    };
+    let write_component = |value: f32, bytes: &mut [u8]| match component_size {
+        1 => bytes[0] = value.round() as u8,
+        2 => bytes.copy_from_slice(&(value.round() as u16).to_ne_bytes()),
+        _ => bytes.copy_from_slice(&value.to_ne_bytes()),
+    };
+
+    let pixel_size = channel_count * component_size;
+    let mut pixels = pixels.to_vec();
+    let mut size = size;
+    while size[0] > max_size || size[1] > max_size {
+        let [width, height] = size.map(|side| side as usize);
+        let half_size = size.map(|side| (side / 2).max(1));
+        let [half_width, half_height] = half_size.map(|side| side as usize);
+
+        let mut half_pixels = vec![0u8; half_width * half_height * pixel_size];
+        for y in 0..half_height {
+            for x in 0..half_width {
+                // Clamped so odd and single pixel sides reuse their last row or column
+                let samples = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| {
+                    ((2 * y + dy).min(height - 1) * width + (2 * x + dx).min(width - 1))
+                        * pixel_size
+                });
+                for channel in 0..channel_count {
+                    let offset = channel * component_size;
+                    let average = samples
+                        .iter()
+                        .map(|sample| read_component(&pixels[sample + offset..]))
+                        .sum::<f32>()
+                        / samples.len() as f32;
+                    let dst = (y * half_width + x) * pixel_size + offset;
+                    write_component(average, &mut half_pixels[dst..dst + component_size]);
+                }
+            }
+        }
+
+        pixels = half_pixels;
+        size = half_size;
+    }
+
+    Some((pixels, size))
+}
+
 pub fn load_meshes(
     device: &mut neptune_vulkan::Device,
     gltf_doc: &gltf::Document,
@@ -204,40 +308,67 @@ pub fn load_primitive(
         max: Vec3::from_array(gltf_primitive.bounding_box().max),
     };
 
-    let (position_buffer, vertex_count) = {
-        let positions: Vec<Vec3> = match reader.read_positions() {
-            None => return Err(anyhow!("Mesh contains no vertex positions")),
-            Some(positions) => positions,
-        }
-        .map(Vec3::from_array)
-        .collect();
-        (create_vertex_buffer(device, &positions)?, positions.len())
-    };
+    let positions: Vec<Vec3> = match reader.read_positions() {
+        None => return Err(anyhow!("Mesh contains no vertex positions")),
+        Some(positions) => positions,
+    }
+    .map(Vec3::from_array)
+    .collect();
+    let vertex_count = positions.len();
+    let position_buffer = create_vertex_buffer(device, &positions)?;
+
+    let indices: Option<Vec<u32>> = reader
+        .read_indices()
+        .map(|indices| indices.into_u32().collect());
 
     let attributes_buffer = {
-        let mut attributes: Vec<VertexAttributes> = if let Some(normals) = reader.read_normals() {
-            if let Some(tangents) = reader.read_tangents() {
-                if let Some(tex_coords) = reader.read_tex_coords(0) {
-                    normals
-                        .zip(tangents)
-                        .zip(tex_coords.into_f32())
-                        .map(|((normal, tangent), tex_coord)| VertexAttributes {
-                            normal: Vec3::from_array(normal),
-                            tangent: Vec4::from_array(tangent),
-                            tex_coords: Vec4::new(tex_coord[0], tex_coord[1], 0.0, 0.0),
-                            color: Vec4::splat(1.0),
-                        })
-                        .collect()
-                } else {
-                    return Err(anyhow!("Mesh primitive doesn't contain uv0"));
-                }
-            } else {
-                return Err(anyhow!("Mesh primitive doesn't contain tangents"));
+        let tex_coords: Vec<Vec2> = match reader.read_tex_coords(0) {
+            None => return Err(anyhow!("Mesh primitive doesn't contain uv0")),
+            Some(tex_coords) => tex_coords.into_f32().map(Vec2::from_array).collect(),
+        };
+
+        // Missing normals and tangents are generated, which only makes sense for triangle lists
+        let triangle_indices = || -> anyhow::Result<Vec<u32>> {
+            if gltf_primitive.mode() != gltf::mesh::Mode::Triangles {
+                return Err(anyhow!(
+                    "Can't generate normals or tangents for {:?} primitives",
+                    gltf_primitive.mode()
+                ));
             }
-        } else {
-            return Err(anyhow!("Mesh primitive doesn't contain normals"));
+            Ok(indices
+                .clone()
+                .unwrap_or_else(|| (0..vertex_count as u32).collect()))
+        };
+
+        let normals: Vec<Vec3> = match reader.read_normals() {
+            Some(normals) => normals.map(Vec3::from_array).collect(),
+            None => compute_normals(&positions, &triangle_indices()?),
+        };
+
+        let tangents: Vec<Vec4> = match reader.read_tangents() {
+            Some(tangents) => tangents.map(Vec4::from_array).collect(),
+            None if gltf_primitive.material().normal_texture().is_some() => {
+                compute_tangents(&positions, &normals, &tex_coords, &triangle_indices()?)
+            }
+            // Only normal mapping reads tangents, so any tangent perpendicular to the normal will do
+            None => normals
+                .iter()
+                .map(|normal| normal.any_orthonormal_vector().extend(1.0))
+                .collect(),
         };
 
+        let mut attributes: Vec<VertexAttributes> = normals
+            .into_iter()
+            .zip(tangents)
+            .zip(tex_coords)
+            .map(|((normal, tangent), tex_coord)| VertexAttributes {
+                normal,
+                tangent,
+                tex_coords: Vec4::new(tex_coord.x, tex_coord.y, 0.0, 0.0),
+                color: Vec4::splat(1.0),
+            })
+            .collect();
+
         //Uv1
         if let Some(tex_coords) = reader.read_tex_coords(1) {
             for (attribute, tex_coord) in attributes.iter_mut().zip(tex_coords.into_f32()) {
@@ -278,15 +409,34 @@ pub fn load_primitive(
         None
     };
 
-    let index_buffer = match reader.read_indices() {
-        None => None,
-        Some(indices) => {
-            let indices_vec: Vec<u32> = indices.into_u32().collect();
-            Some(IndexBuffer {
-                count: indices_vec.len() as u32,
-                buffer: create_index_buffer(device, &indices_vec)?,
-            })
+    let mut lods = Vec::new();
+    if let (Some(indices), gltf::mesh::Mode::Triangles) = (&indices, gltf_primitive.mode()) {
+        let diagonal = (bounding_box.max - bounding_box.min).length();
+        let mut previous_count = indices.len();
+        for (cell_fraction, screen_coverage) in GENERATED_LODS {
+            let lod_indices = simplify_indices(&positions, indices, diagonal * cell_fraction);
+            if lod_indices.is_empty()
+                || lod_indices.len() as f32 > previous_count as f32 * MIN_LOD_REDUCTION
+            {
+                continue;
+            }
+            previous_count = lod_indices.len();
+            lods.push(PrimitiveLod {
+                index_buffer: IndexBuffer {
+                    count: lod_indices.len() as u32,
+                    buffer: create_index_buffer(device, &lod_indices)?,
+                },
+                screen_coverage,
+            });
         }
+    }
+
+    let index_buffer = match indices {
+        None => None,
+        Some(indices_vec) => Some(IndexBuffer {
+            count: indices_vec.len() as u32,
+            buffer: create_index_buffer(device, &indices_vec)?,
+        }),
     };
 
     Ok(Primitive {
@@ -296,9 +446,16 @@ pub fn load_primitive(
         attributes_buffer,
         skinning_buffer,
         index_buffer,
+        lods,
     })
 }
 
+/// (Simplification cell size as a fraction of the bounding box diagonal, screen coverage below which the level is used)
+const GENERATED_LODS: [(f32, f32); 3] = [(0.02, 0.3), (0.05, 0.12), (0.1, 0.04)];
+
+/// Levels keeping more than this fraction of the previous level's indices aren't worth the extra buffer
+const MIN_LOD_REDUCTION: f32 = 0.8;
+
 fn create_vertex_buffer<T>(
     device: &mut neptune_vulkan::Device,
     data: &[T],
@@ -309,7 +466,7 @@ fn create_vertex_buffer<T>(
 
     Ok(device.create_buffer_init(
         "Vertex Buffer",
-        neptune_vulkan::BufferUsage::VERTEX | neptune_vulkan::BufferUsage::TRANSFER,
+        neptune_vulkan::BufferUsage::VERTEX,
         MemoryLocation::GpuOnly,
         data_bytes,
     )?)
@@ -325,7 +482,7 @@ fn create_index_buffer(
 
     Ok(device.create_buffer_init(
         "Index Buffer",
-        neptune_vulkan::BufferUsage::INDEX | neptune_vulkan::BufferUsage::TRANSFER,
+        neptune_vulkan::BufferUsage::INDEX,
         MemoryLocation::GpuOnly,
         data_bytes,
     )?)
@@ -402,7 +559,9 @@ fn load_material_texture(
     samplers: &GltfSamplers,
 ) -> MaterialTexture {
     let image = images[texture.source().index()];
-    let sampler = if let Some(sampler_index) = texture.sampler().index() {
+    let sampler = if let Some(sampler) = samplers.texture_samplers.get(&texture.index()) {
+        *sampler
+    } else if let Some(sampler_index) = texture.sampler().index() {
         samplers.samplers[sampler_index]
     } else {
         samplers.default
@@ -419,6 +578,8 @@ pub struct GltfScene {
     pub images: Vec<ImageHandle>,
     pub samplers: GltfSamplers,
     pub materials: Vec<Material>,
+    /// Material index of each primitive of each mesh, None for primitives using the default material
+    pub mesh_primitive_materials: Vec<Vec<Option<usize>>>,
 
     pub mesh_nodes: Vec<GltfNode>,
 }
@@ -432,6 +593,8 @@ pub struct GltfNode {
 pub fn load_gltf_scene<P: AsRef<std::path::Path>>(
     device: &mut neptune_vulkan::Device,
     path: P,
+    max_texture_size: Option<u32>,
+    render_settings: &RenderSettings,
 ) -> anyhow::Result<GltfScene> {
     let (gltf_doc, buffer_data, image_data) = {
         let now = std::time::Instant::now();
@@ -445,12 +608,21 @@ pub fn load_gltf_scene<P: AsRef<std::path::Path>>(
     info!("Mesh Convert/Upload: {}", now.elapsed().as_secs_f32());
 
     let now = std::time::Instant::now();
-    let images = load_images(device, &gltf_doc, &image_data)?;
+    let images = load_images(device, &gltf_doc, &image_data, max_texture_size)?;
     info!("Image Convert/Upload: {}", now.elapsed().as_secs_f32());
 
-    let samplers = load_samplers(device, &gltf_doc)?;
+    let samplers = load_samplers(device, &gltf_doc, render_settings)?;
 
     let materials = load_materials(&gltf_doc, &images, &samplers);
+    let mesh_primitive_materials = gltf_doc
+        .meshes()
+        .map(|gltf_mesh| {
+            gltf_mesh
+                .primitives()
+                .map(|gltf_primitive| gltf_primitive.material().index())
+                .collect()
+        })
+        .collect();
 
     let mut mesh_nodes = Vec::new();
 
@@ -465,6 +637,7 @@ pub fn load_gltf_scene<P: AsRef<std::path::Path>>(
         images,
         samplers,
         materials,
+        mesh_primitive_materials,
         mesh_nodes,
     })
 }
@@ -492,24 +665,146 @@ fn gltf_node(parent_transform: Mat4, mesh_nodes: &mut Vec<GltfNode>, node: &gltf
 pub struct GltfResources {
     pub meshes: HashMap<String, Mesh>,
     pub materials: HashMap<String, Material>,
+    /// Every mesh paired with its primitives' materials, keyed by mesh name
+    pub models: HashMap<String, Model>,
 }
 
 pub fn load_gltf_resources<P: AsRef<std::path::Path>>(
     device: &mut neptune_vulkan::Device,
     path: P,
+    max_texture_size: Option<u32>,
+    render_settings: &RenderSettings,
 ) -> anyhow::Result<GltfResources> {
-    let mut gltf_scene = load_gltf_scene(device, path)?;
+    let gltf_scene = load_gltf_scene(device, path, max_texture_size, render_settings)?;
+
+    // Shared so primitives using the same material are batched together by the scene renderer
+    let materials: Vec<Arc<Material>> = gltf_scene.materials.into_iter().map(Arc::new).collect();
+    let models = gltf_scene
+        .meshes
+        .iter()
+        .zip(gltf_scene.mesh_primitive_materials.iter())
+        .map(|(mesh, primitive_materials)| {
+            (
+                mesh.name.clone(),
+                mesh_model(mesh, primitive_materials, &materials),
+            )
+        })
+        .collect();
 
     Ok(GltfResources {
         meshes: gltf_scene
             .meshes
-            .drain(..)
+            .into_iter()
             .map(|mesh| (mesh.name.clone(), mesh))
             .collect(),
-        materials: gltf_scene
-            .materials
-            .drain(..)
-            .map(|material| (material.name.clone(), material))
+        materials: materials
+            .iter()
+            .map(|material| (material.name.clone(), Material::clone(material)))
             .collect(),
+        models,
     })
 }
+
+/// Pairs each primitive of the mesh with its material, primitive_materials holds an index into materials per primitive
+fn mesh_model(
+    mesh: &Mesh,
+    primitive_materials: &[Option<usize>],
+    materials: &[Arc<Material>],
+) -> Model {
+    Model {
+        name: mesh.name.clone(),
+        primitives: mesh
+            .primitives
+            .iter()
+            .zip(primitive_materials.iter())
+            .map(|(primitive, material_index)| ModelPrimitive {
+                primitive: primitive.clone(),
+                material: material_index.map(|index| materials[index].clone()),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_primitive() -> Arc<Primitive> {
+        Arc::new(Primitive {
+            bounding_box: BoundingBox::default(),
+            vertex_count: 3,
+            position_buffer: neptune_vulkan::BufferHandle::Transient(0),
+            attributes_buffer: neptune_vulkan::BufferHandle::Transient(1),
+            skinning_buffer: None,
+            index_buffer: None,
+            lods: Vec::new(),
+        })
+    }
+
+    fn test_material(name: &str) -> Arc<Material> {
+        Arc::new(Material {
+            name: name.to_string(),
+            alpha_blending: false,
+            base_color: Vec4::ONE,
+            metallic_roughness_factor: Vec2::ONE,
+            emissive_color: Vec3::ZERO,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        })
+    }
+
+    #[test]
+    fn each_primitive_keeps_its_own_material() {
+        let mesh = Mesh {
+            name: String::from("TwoMaterials"),
+            primitives: vec![test_primitive(), test_primitive(), test_primitive()],
+        };
+        let materials = [test_material("Metal"), test_material("Wood")];
+
+        let model = mesh_model(&mesh, &[Some(1), Some(0), None], &materials);
+        assert_eq!(model.name, "TwoMaterials");
+        let material_names: Vec<Option<&str>> = model
+            .primitives
+            .iter()
+            .map(|primitive| {
+                primitive
+                    .material
+                    .as_ref()
+                    .map(|material| material.name.as_str())
+            })
+            .collect();
+        assert_eq!(material_names, [Some("Wood"), Some("Metal"), None]);
+        assert!(Arc::ptr_eq(
+            model.primitives[0].material.as_ref().unwrap(),
+            &materials[1]
+        ));
+    }
+
+    #[test]
+    fn images_within_the_limit_are_not_downscaled() {
+        assert_eq!(
+            downscale_image(&[0; 16], [2, 2], vk::Format::R8G8B8A8_UNORM, 2),
+            None
+        );
+    }
+
+    #[test]
+    fn downscaling_averages_2x2_blocks() {
+        let pixels = [0, 100, 200, 255, 100, 100, 0, 255];
+        let (downscaled, size) =
+            downscale_image(&pixels, [2, 1], vk::Format::R8G8B8A8_UNORM, 1).unwrap();
+        assert_eq!(size, [1, 1]);
+        assert_eq!(downscaled, vec![50, 100, 100, 255]);
+    }
+
+    #[test]
+    fn zero_max_size_stops_at_one_pixel() {
+        let (downscaled, size) =
+            downscale_image(&[255; 64], [4, 4], vk::Format::R8G8B8A8_UNORM, 0).unwrap();
+        assert_eq!(size, [1, 1]);
+        assert_eq!(downscaled, vec![255; 4]);
+    }
+}
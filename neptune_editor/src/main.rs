@@ -1,3 +1,4 @@
+mod adaptive_present;
 mod camera;
 mod editor;
 mod game;
@@ -8,6 +9,7 @@ mod material;
 mod mesh;
 mod physics;
 mod platform;
+mod render_settings;
 mod scene;
 mod shader;
 mod transform;
@@ -41,6 +43,14 @@ fn main() -> anyhow::Result<()> {
     info!("window_size: {:?}", window_size);
     let mut editor = Editor::new(&platform.window, [window_size.0, window_size.1], &config)?;
 
+    if config.adaptive_vsync {
+        let refresh_rate = match platform.window.display_mode() {
+            Ok(display_mode) if display_mode.refresh_rate > 0 => display_mode.refresh_rate,
+            _ => 60,
+        };
+        editor.enable_adaptive_present_mode(refresh_rate as f32)?;
+    }
+
     let mut last_frame_start = Instant::now();
     let mut frame_count_time: (u32, f32) = (0, 0.0);
     while !platform.should_quit() {
@@ -50,6 +60,7 @@ fn main() -> anyhow::Result<()> {
         last_frame_start = Instant::now();
 
         editor.update(last_frame_time.as_secs_f32());
+        editor.update_present_mode(last_frame_time.as_secs_f32())?;
 
         editor.render().expect("Failed to render a frame");
 
@@ -99,12 +99,62 @@ pub struct BoundingBox {
     pub max: glam::Vec3,
 }
 
+impl BoundingBox {
+    /// World space box enclosing all 8 transformed corners
+    pub fn transformed(&self, model_matrix: &glam::Mat4) -> Self {
+        let mut min = glam::Vec3::splat(f32::INFINITY);
+        let mut max = glam::Vec3::splat(f32::NEG_INFINITY);
+        for corner in 0..8 {
+            let point = glam::Vec3::select(
+                glam::BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                self.max,
+                self.min,
+            );
+            let point = model_matrix.transform_point3(point);
+            min = min.min(point);
+            max = max.max(point);
+        }
+        Self { min, max }
+    }
+
+    /// Approximate fraction of the screen height covered by the box's bounding sphere,
+    /// projection_scale is the projection matrix's y scale, 1 / tan(fov_y / 2)
+    pub fn screen_coverage(
+        &self,
+        model_matrix: &glam::Mat4,
+        camera_position: glam::Vec3,
+        projection_scale: f32,
+    ) -> f32 {
+        let center = model_matrix.transform_point3((self.min + self.max) * 0.5);
+        let scale = model_matrix
+            .x_axis
+            .truncate()
+            .length()
+            .max(model_matrix.y_axis.truncate().length())
+            .max(model_matrix.z_axis.truncate().length());
+        let radius = (self.max - self.min).length() * 0.5 * scale;
+
+        let distance = center.distance(camera_position);
+        if distance <= radius {
+            return f32::INFINITY;
+        }
+        radius * projection_scale / distance
+    }
+}
+
 #[derive(Clone)]
 pub struct IndexBuffer {
     pub buffer: neptune_vulkan::BufferHandle,
     pub count: u32,
 }
 
+/// Lower detail indices into the primitive's vertices, used once the object covers less than screen_coverage
+#[derive(Clone)]
+pub struct PrimitiveLod {
+    pub index_buffer: IndexBuffer,
+    pub screen_coverage: f32,
+}
+
 #[derive(Clone)]
 pub struct Primitive {
     pub bounding_box: BoundingBox,
@@ -114,4 +164,263 @@ pub struct Primitive {
     pub attributes_buffer: neptune_vulkan::BufferHandle,
     pub skinning_buffer: Option<neptune_vulkan::BufferHandle>,
     pub index_buffer: Option<IndexBuffer>,
+
+    /// Ordered from most to least detailed, so screen_coverage decreases
+    pub lods: Vec<PrimitiveLod>,
+}
+
+impl Primitive {
+    /// 0 is the full detail primitive, i the level lods[i - 1]
+    pub fn select_lod(&self, screen_coverage: f32) -> usize {
+        self.lods
+            .iter()
+            .take_while(|lod| screen_coverage < lod.screen_coverage)
+            .count()
+    }
+
+    pub fn lod_index_buffer(&self, lod: usize) -> Option<&IndexBuffer> {
+        match lod {
+            0 => self.index_buffer.as_ref(),
+            _ => Some(&self.lods[lod - 1].index_buffer),
+        }
+    }
+}
+
+/// Vertex clustering simplification of a triangle list, vertices are snapped to a grid of cell_size and each cell
+/// is represented by its first vertex. Triangles collapsed by the snapping are dropped, the vertices are kept as is
+pub fn simplify_indices(positions: &[glam::Vec3], indices: &[u32], cell_size: f32) -> Vec<u32> {
+    let mut cell_vertices: std::collections::HashMap<glam::IVec3, u32> = Default::default();
+    let remap: Vec<u32> = positions
+        .iter()
+        .enumerate()
+        .map(|(index, position)| {
+            *cell_vertices
+                .entry((*position / cell_size).floor().as_ivec3())
+                .or_insert(index as u32)
+        })
+        .collect();
+
+    indices
+        .chunks_exact(3)
+        .filter_map(|triangle| {
+            let [a, b, c] =
+                [triangle[0], triangle[1], triangle[2]].map(|index| remap.get(index as usize));
+            let [a, b, c] = [*a?, *b?, *c?];
+            (a != b && b != c && a != c).then_some([a, b, c])
+        })
+        .flatten()
+        .collect()
+}
+
+/// Area weighted vertex normals for a triangle list, degenerate triangles contribute nothing
+pub fn compute_normals(positions: &[glam::Vec3], indices: &[u32]) -> Vec<glam::Vec3> {
+    let mut normals = vec![glam::Vec3::ZERO; positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|index| index as usize);
+        if a >= positions.len() || b >= positions.len() || c >= positions.len() {
+            continue;
+        }
+
+        // The cross product's length is twice the triangle's area, so larger faces weigh more
+        let face_normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        for index in [a, b, c] {
+            normals[index] += face_normal;
+        }
+    }
+
+    normals
+        .into_iter()
+        .map(|normal| normal.try_normalize().unwrap_or(glam::Vec3::Y))
+        .collect()
+}
+
+/// Per vertex tangents derived from the uv gradients of a triangle list, w holds the bitangent sign.
+/// Vertices without a usable uv gradient get an arbitrary tangent perpendicular to their normal
+pub fn compute_tangents(
+    positions: &[glam::Vec3],
+    normals: &[glam::Vec3],
+    tex_coords: &[glam::Vec2],
+    indices: &[u32],
+) -> Vec<glam::Vec4> {
+    let mut tangents = vec![glam::Vec3::ZERO; positions.len()];
+    let mut bitangents = vec![glam::Vec3::ZERO; positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|index| index as usize);
+        if a >= positions.len() || b >= positions.len() || c >= positions.len() {
+            continue;
+        }
+
+        let edge1 = positions[b] - positions[a];
+        let edge2 = positions[c] - positions[a];
+        let delta_uv1 = tex_coords[b] - tex_coords[a];
+        let delta_uv2 = tex_coords[c] - tex_coords[a];
+
+        let determinant = delta_uv1.perp_dot(delta_uv2);
+        if determinant.abs() <= f32::EPSILON {
+            continue;
+        }
+
+        let tangent = (edge1 * delta_uv2.y - edge2 * delta_uv1.y) / determinant;
+        let bitangent = (edge2 * delta_uv1.x - edge1 * delta_uv2.x) / determinant;
+        for index in [a, b, c] {
+            tangents[index] += tangent;
+            bitangents[index] += bitangent;
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tangents.iter().zip(bitangents.iter()))
+        .map(|(normal, (tangent, bitangent))| {
+            // Gram-Schmidt orthogonalize against the normal
+            let tangent = (*tangent - *normal * normal.dot(*tangent))
+                .try_normalize()
+                .unwrap_or_else(|| normal.any_orthonormal_vector());
+            let handedness = if normal.cross(tangent).dot(*bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            tangent.extend(handedness)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Vec2, Vec3, Vec4};
+
+    /// Unit quad in the xy plane facing +z, with uvs following x and y
+    fn quad() -> (Vec<Vec3>, Vec<Vec2>, Vec<u32>) {
+        (
+            vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            vec![
+                Vec2::new(0.0, 0.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(1.0, 1.0),
+                Vec2::new(0.0, 1.0),
+            ],
+            vec![0, 1, 2, 0, 2, 3],
+        )
+    }
+
+    #[test]
+    fn normals_of_a_flat_quad_face_out_of_it() {
+        let (positions, _, indices) = quad();
+        for normal in compute_normals(&positions, &indices) {
+            assert!(normal.abs_diff_eq(Vec3::Z, 1e-6));
+        }
+    }
+
+    #[test]
+    fn normals_are_area_weighted_and_unit_length() {
+        // A large triangle facing +z and a small one facing +x share vertex 0
+        let positions = vec![
+            Vec3::ZERO,
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 10.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        let normals = compute_normals(&positions, &[0, 1, 2, 0, 3, 4]);
+        for normal in normals.iter() {
+            assert!((normal.length() - 1.0).abs() < 1e-5);
+        }
+        assert!(normals[0].z > normals[0].x && normals[0].x > 0.0);
+    }
+
+    #[test]
+    fn degenerate_and_unused_vertices_get_a_fallback_normal() {
+        let positions = vec![Vec3::ZERO, Vec3::X, Vec3::X * 2.0, Vec3::Y];
+        // Collinear triangle and an out of range index are skipped
+        let normals = compute_normals(&positions, &[0, 1, 2, 0, 1, 9]);
+        assert_eq!(normals, vec![Vec3::Y; 4]);
+    }
+
+    #[test]
+    fn tangents_follow_the_u_direction() {
+        let (positions, tex_coords, indices) = quad();
+        let normals = compute_normals(&positions, &indices);
+        for tangent in compute_tangents(&positions, &normals, &tex_coords, &indices) {
+            assert!(tangent.abs_diff_eq(Vec4::new(1.0, 0.0, 0.0, 1.0), 1e-6));
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_bitangent_sign() {
+        let (positions, tex_coords, indices) = quad();
+        let tex_coords: Vec<Vec2> = tex_coords
+            .iter()
+            .map(|uv| Vec2::new(uv.x, 1.0 - uv.y))
+            .collect();
+        let normals = compute_normals(&positions, &indices);
+        for tangent in compute_tangents(&positions, &normals, &tex_coords, &indices) {
+            assert!(tangent.abs_diff_eq(Vec4::new(1.0, 0.0, 0.0, -1.0), 1e-6));
+        }
+    }
+
+    #[test]
+    fn degenerate_uvs_still_give_a_perpendicular_tangent() {
+        let (positions, _, indices) = quad();
+        let normals = compute_normals(&positions, &indices);
+        let tex_coords = vec![Vec2::ZERO; positions.len()];
+        for (normal, tangent) in normals.iter().zip(compute_tangents(
+            &positions,
+            &normals,
+            &tex_coords,
+            &indices,
+        )) {
+            assert!((tangent.truncate().length() - 1.0).abs() < 1e-5);
+            assert!(normal.dot(tangent.truncate()).abs() < 1e-5);
+        }
+    }
+
+    fn lod_primitive() -> Primitive {
+        let buffer = neptune_vulkan::BufferHandle::Transient(0);
+        let lod = |screen_coverage| PrimitiveLod {
+            index_buffer: IndexBuffer { buffer, count: 3 },
+            screen_coverage,
+        };
+        Primitive {
+            bounding_box: BoundingBox {
+                min: Vec3::splat(-1.0),
+                max: Vec3::splat(1.0),
+            },
+            vertex_count: 3,
+            position_buffer: buffer,
+            attributes_buffer: buffer,
+            skinning_buffer: None,
+            index_buffer: Some(IndexBuffer { buffer, count: 3 }),
+            lods: vec![lod(0.5), lod(0.1)],
+        }
+    }
+
+    #[test]
+    fn far_objects_select_a_lower_lod() {
+        let primitive = lod_primitive();
+        let projection_scale = 1.0 / (45f32.to_radians() / 2.0).tan();
+        let coverage_at = |distance: f32| {
+            primitive.bounding_box.screen_coverage(
+                &glam::Mat4::from_translation(Vec3::new(0.0, 0.0, -distance)),
+                Vec3::ZERO,
+                projection_scale,
+            )
+        };
+
+        let near = coverage_at(3.0);
+        let far = coverage_at(200.0);
+        assert!(near > far);
+        assert_eq!(primitive.select_lod(near), 0);
+        assert_eq!(primitive.select_lod(far), 2);
+        assert!(primitive.select_lod(coverage_at(20.0)) > primitive.select_lod(near));
+
+        // Inside the bounding sphere always uses full detail
+        assert_eq!(primitive.select_lod(coverage_at(0.5)), 0);
+    }
 }
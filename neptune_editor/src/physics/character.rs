@@ -6,6 +6,32 @@ use rapier3d::control::CharacterLength;
 use rapier3d::na::{Isometry3, Translation3, UnitQuaternion, UnitVector3, Vector3};
 use rapier3d::pipeline::QueryFilter;
 
+#[derive(Debug, Clone, Copy)]
+pub struct CharacterControllerSettings {
+    /// Tallest ledge the character will automatically climb, units: m
+    pub max_step_height: f32,
+    /// Minimum free space needed on top of a ledge to step onto it, units: m
+    pub min_step_width: f32,
+    /// Steepest walkable surface, anything steeper blocks movement, units: rad
+    pub max_slope_angle: f32,
+    /// Slopes steeper than this slide the character down, units: rad
+    pub min_slope_slide_angle: f32,
+    /// Distance the character will snap down to stay on the ground, units: m
+    pub snap_to_ground: Option<f32>,
+}
+
+impl Default for CharacterControllerSettings {
+    fn default() -> Self {
+        Self {
+            max_step_height: 0.2,
+            min_step_width: 0.2,
+            max_slope_angle: 45.0f32.to_radians(),
+            min_slope_slide_angle: 30.0f32.to_radians(),
+            snap_to_ground: Some(0.01),
+        }
+    }
+}
+
 // Goals of this struct is to abstract character movement behaviour
 // Zero-G will probably require a separate controller
 pub struct CharacterController {
@@ -17,18 +43,22 @@ pub struct CharacterController {
 }
 
 impl CharacterController {
-    pub fn new() -> Self {
+    pub fn new(settings: CharacterControllerSettings) -> Self {
+        // Rapier shape-casts the character along the movement, so velocity tangent to walkable slopes is kept,
+        // while the component pushing into slopes steeper than max_slope_angle is removed.
         let controller = rapier3d::control::KinematicCharacterController {
             up: UnitVector3::new_normalize(Vector3::new(0.0, 1.0, 0.0)),
             offset: CharacterLength::Absolute(0.01),
-            autostep: Some(rapier3d::control::CharacterAutostep {
-                max_height: CharacterLength::Absolute(0.2),
-                min_width: CharacterLength::Absolute(0.2),
-                include_dynamic_bodies: true,
-            }),
-            max_slope_climb_angle: 45.0f32.to_radians(),
-            min_slope_slide_angle: 30.0f32.to_radians(),
-            snap_to_ground: Some(CharacterLength::Absolute(0.01)),
+            autostep: (settings.max_step_height > 0.0).then_some(
+                rapier3d::control::CharacterAutostep {
+                    max_height: CharacterLength::Absolute(settings.max_step_height),
+                    min_width: CharacterLength::Absolute(settings.min_step_width),
+                    include_dynamic_bodies: true,
+                },
+            ),
+            max_slope_climb_angle: settings.max_slope_angle,
+            min_slope_slide_angle: settings.min_slope_slide_angle,
+            snap_to_ground: settings.snap_to_ground.map(CharacterLength::Absolute),
             ..Default::default()
         };
 
@@ -115,3 +145,62 @@ impl CharacterController {
         self.is_grounded
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::physics_world::Collider;
+
+    /// Walks the character into a step of step_height placed 1m ahead and returns its final position
+    fn walk_into_step(step_height: f32) -> Vec3 {
+        const DELTA_TIME: f32 = 1.0 / 60.0;
+
+        let mut world = PhysicsWorld::new();
+        world.add_collider(
+            None,
+            &Transform::with_position(Vec3::new(0.0, -0.5, 0.0)),
+            &Collider::Box(Vec3::new(10.0, 0.5, 10.0)),
+        );
+        world.add_collider(
+            None,
+            &Transform::with_position(Vec3::new(5.0, step_height * 0.5, 0.0)),
+            &Collider::Box(Vec3::new(4.0, step_height * 0.5, 10.0)),
+        );
+        world.step(DELTA_TIME);
+
+        let mut character = CharacterController::new(CharacterControllerSettings {
+            max_step_height: 0.35,
+            ..Default::default()
+        });
+        // The capsule is 4.2m tall, so its center sits 2.1m above its feet
+        let mut transform = Transform::with_position(Vec3::new(0.0, 2.11, 0.0));
+        character.add_to_world(&mut world, &transform);
+
+        for _ in 0..120 {
+            character.update(
+                &mut world,
+                &mut transform,
+                &(Vec3::X * 2.0 * DELTA_TIME),
+                DELTA_TIME,
+            );
+        }
+        transform.position
+    }
+
+    #[test]
+    fn climbs_step_below_max_height() {
+        let position = walk_into_step(0.25);
+        assert!(position.x > 1.5, "character stopped at {}", position);
+        assert!(position.y > 2.3, "character didn't climb, at {}", position);
+    }
+
+    #[test]
+    fn blocked_by_step_above_max_height() {
+        let position = walk_into_step(0.5);
+        assert!(
+            position.x < 1.0,
+            "character passed the step, at {}",
+            position
+        );
+    }
+}
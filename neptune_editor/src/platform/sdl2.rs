@@ -218,6 +218,11 @@ impl Sdl2Platform {
 
         key_bindings.insert(Keycode::Space, ButtonBinding::Button("player_jump"));
         key_bindings.insert(Keycode::LShift, ButtonBinding::Button("player_move_sprint"));
+        key_bindings.insert(
+            Keycode::F3,
+            ButtonBinding::Button("debug_toggle_pass_timings"),
+        );
+        key_bindings.insert(Keycode::F4, ButtonBinding::Button("debug_toggle_bounds"));
 
         let mouse_button_bindings = HashMap::new();
 
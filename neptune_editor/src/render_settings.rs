@@ -0,0 +1,95 @@
+use neptune_vulkan::SamplerDescription;
+use std::collections::HashMap;
+
+/// Maximum number of anisotropic filtering samples
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Anisotropy {
+    #[default]
+    #[value(name = "1")]
+    X1,
+    #[value(name = "4")]
+    X4,
+    #[value(name = "8")]
+    X8,
+    #[value(name = "16")]
+    X16,
+}
+
+impl Anisotropy {
+    /// None when anisotropic filtering is disabled
+    pub fn max_samples(&self) -> Option<f32> {
+        match self {
+            Anisotropy::X1 => None,
+            Anisotropy::X4 => Some(4.0),
+            Anisotropy::X8 => Some(8.0),
+            Anisotropy::X16 => Some(16.0),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct SamplerQuality {
+    pub anisotropy: Anisotropy,
+    pub mip_bias: f32,
+    /// Highest mip level that can be sampled, all mips when None
+    pub max_lod: Option<f32>,
+}
+
+impl SamplerQuality {
+    /// max_anisotropy is Device::max_sampler_anisotropy, anisotropy is clamped to it and disabled when None
+    pub fn apply(&self, description: &mut SamplerDescription, max_anisotropy: Option<f32>) {
+        description.anisotropy_clamp = self
+            .anisotropy
+            .max_samples()
+            .zip(max_anisotropy)
+            .map(|(samples, max)| samples.min(max));
+        description.mip_lod_bias = self.mip_bias;
+        description.lod_clamp_range = self.max_lod.map(|max_lod| 0.0..max_lod);
+    }
+}
+
+/// Quality settings applied to loaded assets
+#[derive(Debug, Default, Clone)]
+pub struct RenderSettings {
+    /// Used by every texture sampler without an override
+    pub sampler_quality: SamplerQuality,
+    /// Keyed by glTF texture name
+    pub texture_sampler_overrides: HashMap<String, SamplerQuality>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_anisotropy_applies_to_new_samplers() {
+        let mut settings = RenderSettings::default();
+        let new_sampler = |settings: &RenderSettings| {
+            let mut description = SamplerDescription::linear_repeat();
+            settings.sampler_quality.apply(&mut description, Some(16.0));
+            description.anisotropy_clamp
+        };
+        assert_eq!(new_sampler(&settings), None);
+
+        settings.sampler_quality.anisotropy = Anisotropy::X8;
+        assert_eq!(new_sampler(&settings), Some(8.0));
+
+        settings.sampler_quality.anisotropy = Anisotropy::X16;
+        assert_eq!(new_sampler(&settings), Some(16.0));
+    }
+
+    #[test]
+    fn anisotropy_is_limited_by_the_device() {
+        let quality = SamplerQuality {
+            anisotropy: Anisotropy::X16,
+            ..Default::default()
+        };
+        let mut description = SamplerDescription::linear_repeat();
+
+        quality.apply(&mut description, Some(4.0));
+        assert_eq!(description.anisotropy_clamp, Some(4.0));
+
+        quality.apply(&mut description, None);
+        assert_eq!(description.anisotropy_clamp, None);
+    }
+}
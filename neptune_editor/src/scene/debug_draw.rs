@@ -0,0 +1,281 @@
+use crate::scene::scene_renderer::{slice_to_bytes_unsafe, SceneCamera};
+use glam::{Vec3, Vec4};
+use memoffset::offset_of;
+use neptune_vulkan::gpu_allocator::MemoryLocation;
+use neptune_vulkan::render_graph_builder::{
+    BufferOffset, BufferWriteCallback, RasterDrawCommandBuilder, RasterPassBuilder,
+    RenderGraphBuilderTrait,
+};
+use neptune_vulkan::{vk, BufferUsage, Device, ImageHandle, RasterPipelineHandle};
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DebugVertex {
+    pub position: Vec3,
+    pub color: Vec4,
+}
+
+impl DebugVertex {
+    pub const VERTEX_BUFFER_LAYOUT: neptune_vulkan::VertexBufferLayout<'static> =
+        neptune_vulkan::VertexBufferLayout {
+            stride: std::mem::size_of::<Self>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+            attributes: &[
+                neptune_vulkan::VertexAttribute {
+                    shader_location: 0,
+                    format: vk::Format::R32G32B32_SFLOAT,
+                    offset: offset_of!(Self, position) as u32,
+                },
+                neptune_vulkan::VertexAttribute {
+                    shader_location: 1,
+                    format: vk::Format::R32G32B32A32_SFLOAT,
+                    offset: offset_of!(Self, color) as u32,
+                },
+            ],
+        };
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum DebugDepthMode {
+    /// Hidden behind scene geometry
+    #[default]
+    Tested,
+    /// Drawn on top of everything
+    Ignored,
+}
+
+/// Line list vertices of the shapes queued for a frame, per depth mode
+#[derive(Debug, Default)]
+struct DebugShapes {
+    depth_mode: DebugDepthMode,
+    depth_tested_vertices: Vec<DebugVertex>,
+    depth_ignored_vertices: Vec<DebugVertex>,
+}
+
+impl DebugShapes {
+    const SPHERE_SEGMENTS: usize = 32;
+
+    fn vertex_count(&self) -> usize {
+        self.depth_tested_vertices.len() + self.depth_ignored_vertices.len()
+    }
+
+    fn clear(&mut self) {
+        self.depth_tested_vertices.clear();
+        self.depth_ignored_vertices.clear();
+    }
+
+    fn line(&mut self, a: Vec3, b: Vec3, color: Vec4) {
+        let vertices = match self.depth_mode {
+            DebugDepthMode::Tested => &mut self.depth_tested_vertices,
+            DebugDepthMode::Ignored => &mut self.depth_ignored_vertices,
+        };
+        vertices.push(DebugVertex { position: a, color });
+        vertices.push(DebugVertex { position: b, color });
+    }
+
+    fn aabb(&mut self, min: Vec3, max: Vec3, color: Vec4) {
+        let corner = |x: bool, y: bool, z: bool| {
+            Vec3::new(
+                if x { max.x } else { min.x },
+                if y { max.y } else { min.y },
+                if z { max.z } else { min.z },
+            )
+        };
+        for a in [false, true] {
+            for b in [false, true] {
+                self.line(corner(false, a, b), corner(true, a, b), color);
+                self.line(corner(a, false, b), corner(a, true, b), color);
+                self.line(corner(a, b, false), corner(a, b, true), color);
+            }
+        }
+    }
+
+    fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        for (u, v) in [(Vec3::X, Vec3::Y), (Vec3::X, Vec3::Z), (Vec3::Y, Vec3::Z)] {
+            let point = |segment: usize| {
+                let angle = segment as f32 / Self::SPHERE_SEGMENTS as f32 * std::f32::consts::TAU;
+                center + (u * angle.cos() + v * angle.sin()) * radius
+            };
+            for segment in 0..Self::SPHERE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+}
+
+/// Immediate mode debug lines, shapes are queued during the frame and drawn over the scene at the end of it
+pub struct DebugDraw {
+    depth_tested_pipeline: RasterPipelineHandle,
+    depth_ignored_pipeline: RasterPipelineHandle,
+    shapes: DebugShapes,
+}
+
+impl DebugDraw {
+    pub fn new(
+        device: &mut Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+    ) -> anyhow::Result<Self> {
+        let mut create_pipeline = |depth_enabled: bool| {
+            device.create_raster_pipeline(&neptune_vulkan::RasterPipelineDescription {
+                vertex: neptune_vulkan::VertexState {
+                    shader: neptune_vulkan::ShaderStage {
+                        code: crate::shader::DEBUG_LINE_VERT,
+                        entry: "main",
+                    },
+                    layouts: &[DebugVertex::VERTEX_BUFFER_LAYOUT],
+                },
+                primitive: neptune_vulkan::PrimitiveState {
+                    front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+                    cull_mode: vk::CullModeFlags::NONE,
+                    topology: vk::PrimitiveTopology::LINE_LIST,
+                    primitive_restart: false,
+                    flip_viewport_y: false,
+                },
+                depth_state: Some(neptune_vulkan::DepthState {
+                    format: depth_format,
+                    depth_enabled,
+                    write_depth: false,
+                    depth_op: vk::CompareOp::LESS_OR_EQUAL,
+                }),
+                fragment: Some(neptune_vulkan::FragmentState {
+                    shader: neptune_vulkan::ShaderStage {
+                        code: crate::shader::DEBUG_LINE_FRAG,
+                        entry: "main",
+                    },
+                    targets: &[neptune_vulkan::ColorTargetState {
+                        format: color_format,
+                        blend: None,
+                        write_mask: vk::ColorComponentFlags::RGBA,
+                    }],
+                }),
+                view_mask: 0,
+                dynamic_states: &[],
+            })
+        };
+
+        Ok(Self {
+            depth_tested_pipeline: create_pipeline(true)?,
+            depth_ignored_pipeline: create_pipeline(false)?,
+            shapes: DebugShapes::default(),
+        })
+    }
+
+    /// Applies to shapes queued after this call
+    pub fn set_depth_mode(&mut self, depth_mode: DebugDepthMode) {
+        self.shapes.depth_mode = depth_mode;
+    }
+
+    /// Vertices queued for the current frame, two per line
+    pub fn vertex_count(&self) -> usize {
+        self.shapes.vertex_count()
+    }
+
+    /// Drops everything queued, call once per frame so shapes don't pile up when the frame isn't recorded
+    pub fn clear(&mut self) {
+        self.shapes.clear();
+    }
+
+    pub fn line(&mut self, a: Vec3, b: Vec3, color: Vec4) {
+        self.shapes.line(a, b, color);
+    }
+
+    pub fn aabb(&mut self, min: Vec3, max: Vec3, color: Vec4) {
+        self.shapes.aabb(min, max, color);
+    }
+
+    /// Drawn as a circle around each axis
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        self.shapes.sphere(center, radius, color);
+    }
+
+    /// Draws and clears everything queued this frame, loading the existing color and depth contents
+    pub fn write_render_passes<T: RenderGraphBuilderTrait>(
+        &mut self,
+        target_image: ImageHandle,
+        depth_image: ImageHandle,
+        camera: &SceneCamera,
+        render_graph_builder: &mut T,
+    ) -> anyhow::Result<()> {
+        if self.vertex_count() == 0 {
+            return Ok(());
+        }
+
+        let mut raster_pass_builder = RasterPassBuilder::new("Debug Draw Pass");
+        raster_pass_builder.add_color_attachment(target_image, None);
+        raster_pass_builder.add_depth_stencil_attachment(depth_image, None);
+
+        for (pipeline, vertices) in [
+            (
+                self.depth_tested_pipeline,
+                std::mem::take(&mut self.shapes.depth_tested_vertices),
+            ),
+            (
+                self.depth_ignored_pipeline,
+                std::mem::take(&mut self.shapes.depth_ignored_vertices),
+            ),
+        ] {
+            if vertices.is_empty() {
+                continue;
+            }
+
+            let vertex_count = vertices.len() as u32;
+            let vertex_buffer_size = std::mem::size_of_val(vertices.as_slice());
+            let vertex_buffer = render_graph_builder.create_transient_buffer(
+                vertex_buffer_size,
+                BufferUsage::VERTEX | BufferUsage::TRANSFER,
+                MemoryLocation::GpuOnly,
+            );
+            render_graph_builder.add_buffer_write(
+                BufferOffset {
+                    buffer: vertex_buffer,
+                    offset: 0,
+                },
+                vertex_buffer_size,
+                BufferWriteCallback::new(move |slice| {
+                    slice.copy_from_slice(unsafe { slice_to_bytes_unsafe(&vertices) });
+                }),
+            );
+
+            let mut draw_command_builder = RasterDrawCommandBuilder::new(pipeline);
+            draw_command_builder.add_vertex_buffer(BufferOffset {
+                buffer: vertex_buffer,
+                offset: 0,
+            });
+            draw_command_builder.read_buffer(camera.buffer());
+            draw_command_builder.draw(0..vertex_count, 0..1);
+            draw_command_builder.build(&mut raster_pass_builder)?;
+        }
+
+        raster_pass_builder.build(render_graph_builder);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_queue_two_vertices_per_line() {
+        let mut shapes = DebugShapes::default();
+        let color = Vec4::ONE;
+
+        shapes.line(Vec3::ZERO, Vec3::X, color);
+        assert_eq!(shapes.vertex_count(), 2);
+
+        shapes.aabb(Vec3::ZERO, Vec3::ONE, color);
+        assert_eq!(shapes.vertex_count(), 2 + 12 * 2);
+
+        shapes.depth_mode = DebugDepthMode::Ignored;
+        shapes.sphere(Vec3::ZERO, 1.0, color);
+        assert_eq!(
+            shapes.depth_ignored_vertices.len(),
+            3 * DebugShapes::SPHERE_SEGMENTS * 2
+        );
+        assert_eq!(shapes.depth_tested_vertices.len(), 26);
+
+        shapes.clear();
+        assert_eq!(shapes.vertex_count(), 0);
+    }
+}
@@ -1 +1,2 @@
+pub mod debug_draw;
 pub mod scene_renderer;
@@ -1,4 +1,4 @@
-use crate::camera::Camera;
+use crate::camera::{Camera, Frustum};
 use crate::material::{Material, MaterialTexture};
 use crate::mesh;
 use crate::mesh::Primitive;
@@ -8,14 +8,15 @@ use glam::{Mat4, Vec3};
 use neptune_core::id_pool::IdPool;
 use neptune_vulkan::gpu_allocator::MemoryLocation;
 use neptune_vulkan::render_graph_builder::{
-    BufferOffset, BufferWriteCallback, RenderGraphBuilderTrait,
+    BufferOffset, BufferWriteCallback, ClearValue, RenderGraphBuilderTrait,
 };
 use neptune_vulkan::{
-    vk, BufferUsage, Device, ImageDescription2D, ImageHandle, RasterPipelineHandle,
-    SamplerDescription, TransientImageDesc, TransientImageSize,
+    vk, AllocationScheme, BufferUsage, Device, ImageDescription2D, ImageHandle,
+    RasterPipelineHandle, SamplerDescription, TransientImageDesc, TransientImageSize,
 };
 use slotmap::SlotMap;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -30,7 +31,11 @@ pub struct SceneRenderer {
 }
 
 impl SceneRenderer {
-    pub fn new(device: &mut Device, depth_format: vk::Format) -> anyhow::Result<Self> {
+    pub fn new(
+        device: &mut Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+    ) -> anyhow::Result<Self> {
         let raster_pipeline = {
             let vertex_shader_code = crate::shader::MESH_STATIC_VERT;
             let fragment_shader_code = crate::shader::MESH_FRAG;
@@ -51,6 +56,9 @@ impl SceneRenderer {
                 primitive: neptune_vulkan::PrimitiveState {
                     front_face: vk::FrontFace::COUNTER_CLOCKWISE,
                     cull_mode: vk::CullModeFlags::BACK,
+                    topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                    primitive_restart: false,
+                    flip_viewport_y: false,
                 },
                 depth_state: Some(neptune_vulkan::DepthState {
                     format: depth_format,
@@ -64,11 +72,13 @@ impl SceneRenderer {
                         entry: "main",
                     },
                     targets: &[neptune_vulkan::ColorTargetState {
-                        format: vk::Format::B8G8R8A8_UNORM,
+                        format: color_format,
                         blend: None,
                         write_mask: vk::ColorComponentFlags::RGBA,
                     }],
                 }),
+                view_mask: 0,
+                dynamic_states: &[],
             })?
         };
 
@@ -81,6 +91,7 @@ impl SceneRenderer {
                     usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
                     mip_levels: 1,
                     location: MemoryLocation::GpuOnly,
+                    allocation_scheme: AllocationScheme::Managed,
                 },
                 &[255u8; 4],
             )?,
@@ -100,7 +111,7 @@ impl SceneRenderer {
         camera: &SceneCamera,
         scene: &Scene,
         render_graph_builder: &mut T,
-    ) {
+    ) -> anyhow::Result<ImageHandle> {
         let depth_image = render_graph_builder.create_transient_image(TransientImageDesc {
             size: TransientImageSize::Relative([1.0; 2], target_image),
             format: self.depth_format,
@@ -111,70 +122,160 @@ impl SceneRenderer {
 
         let mut raster_pass_builder =
             neptune_vulkan::render_graph_builder::RasterPassBuilder::new("Swapchain Pass");
-        raster_pass_builder.add_color_attachment(target_image, Some([0.0, 0.0, 0.0, 1.0]));
-        raster_pass_builder.add_depth_stencil_attachment(depth_image, Some((1.0, 0)));
-
-        for (_key, instance) in scene.instance_map.iter() {
-            for model_primitive in instance.model.primitives.iter() {
-                if model_primitive
-                    .material
-                    .as_ref()
-                    .map(|material| material.alpha_blending)
-                    .unwrap_or_default()
-                {
-                    continue;
-                }
-
-                let texture = model_primitive
-                    .material
-                    .as_ref()
-                    .and_then(|material| material.base_color_texture.clone())
-                    .unwrap_or_else(|| self.default_texture.clone());
+        raster_pass_builder
+            .add_color_attachment(target_image, Some(ClearValue::Float([0.0, 0.0, 0.0, 1.0])));
+        raster_pass_builder
+            .add_depth_stencil_attachment(depth_image, Some(ClearValue::DepthStencil(1.0, 0)));
+
+        let batches = batch_visible_instances(scene.instance_map.values(), camera);
+        if batches.is_empty() {
+            raster_pass_builder.build(render_graph_builder);
+            return Ok(depth_image);
+        }
 
-                let mut draw_command_builder =
-                    neptune_vulkan::render_graph_builder::RasterDrawCommandBuilder::new(
-                        self.raster_pipeline,
-                    );
+        let instance_indices: Vec<u32> = batches
+            .iter()
+            .flat_map(|batch| batch.instance_indices.iter().copied())
+            .collect();
+        let instance_indices_size = std::mem::size_of_val(instance_indices.as_slice());
+        let instance_indices_buffer = render_graph_builder.create_transient_buffer(
+            instance_indices_size,
+            BufferUsage::STORAGE | BufferUsage::TRANSFER,
+            MemoryLocation::GpuOnly,
+        );
+        render_graph_builder.add_buffer_write(
+            BufferOffset {
+                buffer: instance_indices_buffer,
+                offset: 0,
+            },
+            instance_indices_size,
+            BufferWriteCallback::new(move |slice| {
+                slice.copy_from_slice(unsafe { slice_to_bytes_unsafe(&instance_indices) });
+            }),
+        );
 
-                draw_command_builder.add_vertex_buffer(BufferOffset {
-                    buffer: model_primitive.primitive.position_buffer,
-                    offset: 0,
-                });
-                draw_command_builder.add_vertex_buffer(BufferOffset {
-                    buffer: model_primitive.primitive.attributes_buffer,
-                    offset: 0,
-                });
-                draw_command_builder.read_buffer(camera.camera_buffer);
-                draw_command_builder.read_buffer(scene.model_matrix_buffer);
-                draw_command_builder.read_sampler(texture.sampler);
-                draw_command_builder.read_sampled_image(texture.image);
-
-                let instance_range = (instance.index as u32)..(instance.index as u32 + 1);
-
-                if let Some(index_buffer_ref) = &model_primitive.primitive.index_buffer {
-                    draw_command_builder.draw_indexed(
-                        0,
-                        0..index_buffer_ref.count,
-                        instance_range,
-                        BufferOffset {
-                            buffer: index_buffer_ref.buffer,
-                            offset: 0,
-                        },
-                        neptune_vulkan::render_graph::IndexType::U32,
-                    );
-                } else {
-                    draw_command_builder.draw(
-                        0..model_primitive.primitive.vertex_count as u32,
-                        instance_range,
-                    );
-                }
-
-                draw_command_builder.build(&mut raster_pass_builder);
+        let mut first_instance = 0;
+        for batch in batches.iter() {
+            let model_primitive = &batch.model_primitive;
+            let instance_count = batch.instance_indices.len() as u32;
+            let instance_range = first_instance..(first_instance + instance_count);
+            first_instance += instance_count;
+
+            let texture = model_primitive
+                .material
+                .as_ref()
+                .and_then(|material| material.base_color_texture.clone())
+                .unwrap_or_else(|| self.default_texture.clone());
+
+            let mut draw_command_builder =
+                neptune_vulkan::render_graph_builder::RasterDrawCommandBuilder::new(
+                    self.raster_pipeline,
+                );
+
+            draw_command_builder.add_vertex_buffer(BufferOffset {
+                buffer: model_primitive.primitive.position_buffer,
+                offset: 0,
+            });
+            draw_command_builder.add_vertex_buffer(BufferOffset {
+                buffer: model_primitive.primitive.attributes_buffer,
+                offset: 0,
+            });
+            draw_command_builder.read_buffer(camera.camera_buffer);
+            draw_command_builder.read_buffer(scene.model_matrix_buffer);
+            draw_command_builder.read_buffer(instance_indices_buffer);
+            draw_command_builder.read_sampler(texture.sampler);
+            draw_command_builder.read_sampled_image(texture.image);
+
+            if let Some(index_buffer_ref) = model_primitive.primitive.lod_index_buffer(batch.lod) {
+                draw_command_builder.draw_indexed(
+                    0,
+                    0..index_buffer_ref.count,
+                    instance_range,
+                    BufferOffset {
+                        buffer: index_buffer_ref.buffer,
+                        offset: 0,
+                    },
+                    neptune_vulkan::render_graph::IndexType::U32,
+                );
+            } else {
+                draw_command_builder.draw(
+                    0..model_primitive.primitive.vertex_count as u32,
+                    instance_range,
+                );
             }
+
+            draw_command_builder.build(&mut raster_pass_builder)?;
         }
 
         raster_pass_builder.build(render_graph_builder);
+        Ok(depth_image)
+    }
+}
+
+struct InstanceBatch {
+    model_primitive: ModelPrimitive,
+    lod: usize,
+    instance_indices: Vec<u32>,
+}
+
+/// Batches every visible instance of the same primitive + material + lod together so they can be drawn with a single instanced draw,
+/// primitives outside the camera frustum are culled
+fn batch_visible_instances<'a>(
+    instances: impl Iterator<Item = &'a SceneInstance>,
+    camera: &SceneCamera,
+) -> Vec<InstanceBatch> {
+    let mut batch_map: HashMap<(*const Primitive, *const Material, usize), usize> = HashMap::new();
+    let mut batches: Vec<InstanceBatch> = Vec::new();
+
+    for instance in instances {
+        let model_matrix = instance.transform.model_matrix();
+        for model_primitive in instance.model.primitives.iter() {
+            if model_primitive
+                .material
+                .as_ref()
+                .map(|material| material.alpha_blending)
+                .unwrap_or_default()
+            {
+                continue;
+            }
+
+            let primitive = &model_primitive.primitive;
+            let bounds = primitive.bounding_box.transformed(&model_matrix);
+            if !camera.frustum.intersects_aabb(bounds.min, bounds.max) {
+                continue;
+            }
+
+            let lod = primitive.select_lod(primitive.bounding_box.screen_coverage(
+                &model_matrix,
+                camera.position,
+                camera.projection_scale,
+            ));
+
+            let batch_key = (
+                Arc::as_ptr(primitive),
+                model_primitive
+                    .material
+                    .as_ref()
+                    .map(Arc::as_ptr)
+                    .unwrap_or(std::ptr::null()),
+                lod,
+            );
+
+            let batch_index = *batch_map.entry(batch_key).or_insert_with(|| {
+                batches.push(InstanceBatch {
+                    model_primitive: model_primitive.clone(),
+                    lod,
+                    instance_indices: Vec::new(),
+                });
+                batches.len() - 1
+            });
+            batches[batch_index]
+                .instance_indices
+                .push(instance.index as u32);
+        }
     }
+
+    batches
 }
 
 #[derive(Clone)]
@@ -275,6 +376,23 @@ impl Scene {
         }
     }
 
+    /// World space bounds of every instance's primitives
+    pub fn primitive_bounds(&self) -> impl Iterator<Item = mesh::BoundingBox> + '_ {
+        self.instance_map.values().flat_map(|instance| {
+            let model_matrix = instance.transform.model_matrix();
+            instance
+                .model
+                .primitives
+                .iter()
+                .map(move |model_primitive| {
+                    model_primitive
+                        .primitive
+                        .bounding_box
+                        .transformed(&model_matrix)
+                })
+        })
+    }
+
     pub fn write_render_passes<T: RenderGraphBuilderTrait>(
         &mut self,
         render_graph_builder: &mut T,
@@ -316,6 +434,16 @@ impl SceneCameraData {
 pub struct SceneCamera {
     camera_buffer: neptune_vulkan::BufferHandle,
     camera_data: Rc<RefCell<SceneCameraData>>,
+
+    /// Counter rotates clip space for the swapchain's pre-transform
+    pre_transform: Mat4,
+
+    /// Used to cull instances on the cpu
+    frustum: Frustum,
+
+    /// Used to pick mesh lods on the cpu
+    position: Vec3,
+    projection_scale: f32,
 }
 
 impl SceneCamera {
@@ -332,12 +460,30 @@ impl SceneCamera {
         Ok(Self {
             camera_buffer,
             camera_data: Rc::new(RefCell::new(camera_data)),
+            pre_transform: Mat4::IDENTITY,
+            frustum: Frustum::default(),
+            position: Vec3::ZERO,
+            projection_scale: 1.0,
         })
     }
 
+    pub fn buffer(&self) -> neptune_vulkan::BufferHandle {
+        self.camera_buffer
+    }
+
+    /// Applied from the next update
+    pub fn set_pre_transform(&mut self, pre_transform: vk::SurfaceTransformFlagsKHR) {
+        self.pre_transform =
+            Mat4::from_cols_array_2d(&neptune_vulkan::pre_transform_matrix(pre_transform));
+    }
+
     pub fn update(&mut self, camera: &Camera, camera_transform: &Transform, aspect_ratio: f32) {
         let mut data_mut = self.camera_data.borrow_mut();
         *data_mut = SceneCameraData::new(camera, camera_transform, aspect_ratio);
+        data_mut.view_projection_matrix = self.pre_transform * data_mut.view_projection_matrix;
+        self.frustum = Frustum::from_view_projection(&data_mut.view_projection_matrix);
+        self.position = camera_transform.position;
+        self.projection_scale = camera.projection_matrix(aspect_ratio).y_axis.y.abs();
     }
 
     pub fn write_render_passes<T: RenderGraphBuilderTrait>(
@@ -358,3 +504,131 @@ impl SceneCamera {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::FieldOfView;
+    use glam::{Vec2, Vec4};
+
+    fn test_camera() -> SceneCamera {
+        let mut camera = SceneCamera {
+            camera_buffer: neptune_vulkan::BufferHandle::Transient(0),
+            camera_data: Default::default(),
+            pre_transform: Mat4::IDENTITY,
+            frustum: Frustum::default(),
+            position: Vec3::ZERO,
+            projection_scale: 1.0,
+        };
+        camera.update(
+            &Camera::new(FieldOfView::X(90.0), 0.1, Some(100.0)),
+            &Transform::default(),
+            1.0,
+        );
+        camera
+    }
+
+    fn test_model() -> Model {
+        Model {
+            name: String::from("Cube"),
+            primitives: vec![ModelPrimitive {
+                primitive: Arc::new(Primitive {
+                    bounding_box: mesh::BoundingBox {
+                        min: Vec3::splat(-0.5),
+                        max: Vec3::splat(0.5),
+                    },
+                    vertex_count: 36,
+                    position_buffer: neptune_vulkan::BufferHandle::Transient(0),
+                    attributes_buffer: neptune_vulkan::BufferHandle::Transient(1),
+                    skinning_buffer: None,
+                    index_buffer: None,
+                    lods: Vec::new(),
+                }),
+                material: None,
+            }],
+        }
+    }
+
+    fn test_instances(model: &Model, positions: impl Iterator<Item = Vec3>) -> Vec<SceneInstance> {
+        positions
+            .enumerate()
+            .map(|(index, position)| SceneInstance {
+                index,
+                transform: Transform::with_position(position),
+                model: model.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn instances_of_one_mesh_share_a_draw() {
+        let model = test_model();
+        let instances = test_instances(
+            &model,
+            (0..100)
+                .map(|index| Vec3::new((index % 10) as f32 - 4.5, 0.0, 20.0 + (index / 10) as f32)),
+        );
+
+        let batches = batch_visible_instances(instances.iter(), &test_camera());
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].instance_indices, (0..100).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn instances_outside_the_frustum_are_culled() {
+        let model = test_model();
+        let instances = test_instances(
+            &model,
+            [
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(0.0, 0.0, -5.0),
+                Vec3::new(50.0, 0.0, 5.0),
+            ]
+            .into_iter(),
+        );
+
+        let batches = batch_visible_instances(instances.iter(), &test_camera());
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].instance_indices, vec![0]);
+    }
+
+    #[test]
+    fn primitives_with_distinct_materials_get_a_draw_each() {
+        let material = |name: &str| {
+            Some(Arc::new(Material {
+                name: name.to_string(),
+                alpha_blending: false,
+                base_color: Vec4::ONE,
+                metallic_roughness_factor: Vec2::ONE,
+                emissive_color: Vec3::ZERO,
+                base_color_texture: None,
+                metallic_roughness_texture: None,
+                normal_texture: None,
+                occlusion_texture: None,
+                emissive_texture: None,
+            }))
+        };
+
+        let mut model = test_model();
+        let mut second_primitive = model.primitives[0].clone();
+        model.primitives[0].material = material("Metal");
+        second_primitive.material = material("Wood");
+        model.primitives.push(second_primitive);
+        let instances = test_instances(&model, [Vec3::new(0.0, 0.0, 5.0)].into_iter());
+
+        let batches = batch_visible_instances(instances.iter(), &test_camera());
+        let material_names: Vec<&str> = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .model_primitive
+                    .material
+                    .as_ref()
+                    .unwrap()
+                    .name
+                    .as_str()
+            })
+            .collect();
+        assert_eq!(material_names, ["Metal", "Wood"]);
+    }
+}
@@ -1,31 +1,79 @@
+use crate::frame_arena::{FrameArena, ScratchAllocator, ScratchVec};
+use crate::image::{vk_format_get_aspect_flags, FormatSupport};
 use crate::render_graph::{
-    BufferBarrier, BufferGraphResource, BufferIndex, BufferRead, BufferResourceDescription,
-    BufferWrite, CommandBuffer, CommandBufferDependency, CompiledRenderGraph, Framebuffer,
-    ImageBarrier, ImageBarrierSource, ImageGraphResource, ImageIndex, ImageResourceDescription,
-    QueueType, RenderPassCommand,
+    BufferBarrier, BufferBarrierSource, BufferGraphResource, BufferIndex, BufferRead,
+    BufferResourceDescription, BufferWrite, CommandBuffer, CommandBufferDependency,
+    CompiledRenderGraph, ExternalSemaphore, Framebuffer, ImageBarrier, ImageBarrierSource,
+    ImageGraphResource, ImageIndex, ImageResourceDescription, QueueType, RenderPassCommand,
 };
 use crate::render_graph_builder::{
-    BufferOffset, ColorAttachment, ComputeDispatch, DepthStencilAttachment, DrawCommandDispatch,
-    ImageCopyBuffer, ImageCopyImage, RasterDrawCommand, RenderGraphBuilderTrait,
+    validate_depth_range, validate_dynamic_buffers, validate_push_constant_size,
+    validate_sampled_resources, view_mask_layer_count, AttachmentSubresource, BufferOffset,
+    ColorAttachment, ComputeDispatch, DepthStencilAttachment, DrawCommandDispatch,
+    DynamicBufferLimits, ImageCopyBuffer, ImageCopyImage, PassHandle, RasterDrawCommand,
+    RenderGraphBuilderTrait, RenderGraphError, MAX_INLINE_BUFFER_UPDATE_SIZE,
+    MIN_MAX_PUSH_CONSTANTS_SIZE,
 };
-use crate::render_graph_builder::{BufferReadCallback, BufferWriteCallback, ShaderResourceUsage};
-use crate::resource_managers::{BufferResourceAccess, ImageResourceAccess};
+use crate::render_graph_builder::{
+    BufferReadCallback, BufferWriteCallback, CustomCommand, ShaderResourceUsage,
+};
+use crate::resource_managers::{BufferResourceAccess, ImageResourceAccess, LiveResources};
 use crate::{
-    BufferHandle, BufferUsage, ComputePipelineHandle, ImageHandle, SurfaceHandle,
-    TransientImageDesc,
+    BufferHandle, BufferUsage, BufferWriteError, ComputePipelineHandle, ImageHandle, SurfaceHandle,
+    TransientImageDesc, TransientImageSize,
 };
 use ash::vk;
-use std::collections::HashMap;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 
 #[derive(Debug)]
 pub struct BasicRenderGraphBuilder {
     render_graph: CompiledRenderGraph,
     buffer_index_map: HashMap<BufferHandle, BufferIndex>,
     image_index_map: HashMap<ImageHandle, ImageIndex>,
+
+    /// Every usage a transient buffer is subjected to, checked against its declared usage in build()
+    transient_buffer_usages: HashMap<BufferIndex, BufferUsage>,
+
+    /// Every usage a transient image is subjected to, checked against its format's features in build()
+    transient_image_usages: HashMap<ImageIndex, vk::ImageUsageFlags>,
+    format_support: Option<FormatSupport>,
+
+    /// Pass that last wrote each transient resource, cleared once a later pass reads it
+    unread_buffer_writes: HashMap<BufferIndex, String>,
+    unread_image_writes: HashMap<ImageIndex, String>,
+    strict_write_ordering: bool,
+
+    /// Transient resources written by a pass or a buffer write so far, reading anything else is an error
+    initialized_buffers: HashSet<BufferIndex>,
+    initialized_images: HashSet<ImageIndex>,
+
+    /// Shader stages of the pass that set each buffer's last_access, so barriers only wait on those stages
+    buffer_shader_stages: HashMap<BufferIndex, vk::PipelineStageFlags2>,
+
+    /// Persistent handles are checked against this when set, catching handles used after being destroyed
+    live_resources: Option<LiveResources>,
+
+    /// Binding indices and push constants of a pass must fit in this many bytes
+    max_push_constants_size: u32,
+
+    /// Dynamic buffer offsets and ranges are checked against these
+    dynamic_buffer_limits: DynamicBufferLimits,
+
+    /// Timers begun but not yet ended, with the index of the first pass they cover
+    open_timers: Vec<(String, usize)>,
+
+    /// First validation error hit while adding passes, returned from build()
+    validation_error: Option<RenderGraphError>,
+
+    /// Scratch collections of each added pass are allocated from this when it's enabled
+    arena: FrameArena,
 }
 
-impl Default for BasicRenderGraphBuilder {
-    fn default() -> Self {
+impl BasicRenderGraphBuilder {
+    /// Skips the transient format checks, only for use without a device. Use [`crate::Device::create_render_graph_builder`] instead
+    pub(crate) fn new() -> Self {
         let render_graph = CompiledRenderGraph {
             command_buffers: vec![CommandBuffer::default()],
             ..Default::default()
@@ -34,6 +82,21 @@ impl Default for BasicRenderGraphBuilder {
             render_graph,
             buffer_index_map: Default::default(),
             image_index_map: Default::default(),
+            transient_buffer_usages: Default::default(),
+            transient_image_usages: Default::default(),
+            format_support: None,
+            unread_buffer_writes: Default::default(),
+            unread_image_writes: Default::default(),
+            strict_write_ordering: false,
+            initialized_buffers: Default::default(),
+            initialized_images: Default::default(),
+            buffer_shader_stages: Default::default(),
+            live_resources: None,
+            max_push_constants_size: MIN_MAX_PUSH_CONSTANTS_SIZE,
+            dynamic_buffer_limits: DynamicBufferLimits::default(),
+            open_timers: Vec::new(),
+            validation_error: None,
+            arena: FrameArena::default(),
         }
     }
 }
@@ -46,6 +109,7 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
         callback: BufferWriteCallback,
     ) {
         let buffer_offset = self.get_buffer_offset(buffer_offset);
+        self.initialized_buffers.insert(buffer_offset.buffer);
         self.render_graph.buffer_writes.push(BufferWrite {
             buffer_offset,
             write_size,
@@ -58,13 +122,28 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
         buffer_offset: BufferOffset,
         read_size: usize,
         callback: BufferReadCallback,
-    ) {
+    ) -> Result<(), BufferWriteError> {
         let buffer_offset = self.get_buffer_offset(buffer_offset);
+        if let BufferResourceDescription::Transient {
+            size: buffer_size, ..
+        } = self.render_graph.buffer_resources[buffer_offset.buffer].description
+        {
+            let offset = buffer_offset.offset as usize;
+            if offset + read_size > buffer_size {
+                return Err(BufferWriteError::ReadOutOfBounds {
+                    offset,
+                    size: read_size,
+                    buffer_size,
+                });
+            }
+        }
+
         self.render_graph.buffer_reads.push(BufferRead {
             buffer_offset,
             read_size,
             callback,
         });
+        Ok(())
     }
 
     fn create_transient_buffer(
@@ -95,6 +174,8 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
             description: ImageResourceDescription::Transient(desc),
             first_access: None,
             last_access: None,
+            attachment_views: Vec::new(),
+            release_access: None,
         });
         let handle = ImageHandle::Transient(index);
         self.image_index_map.insert(handle, index);
@@ -111,24 +192,37 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
             description: ImageResourceDescription::Swapchain(swapchain_index),
             first_access: None,
             last_access: None,
+            attachment_views: Vec::new(),
+            release_access: None,
         });
         let handle = ImageHandle::Transient(index);
         self.image_index_map.insert(handle, index);
         handle
     }
 
+    fn add_external_wait_semaphore(&mut self, semaphore: ExternalSemaphore) {
+        self.render_graph.external_wait_semaphores.push(semaphore);
+    }
+
+    fn add_external_signal_semaphore(&mut self, semaphore: ExternalSemaphore) {
+        self.render_graph.external_signal_semaphores.push(semaphore);
+    }
+
     fn add_transfer_pass(
         &mut self,
         name: String,
         color: [f32; 4],
         queue: QueueType,
         transfers: &[crate::render_graph_builder::Transfer],
-    ) {
+    ) -> PassHandle {
         //TODO: queue
         let _ = queue;
 
-        let mut buffer_usages = Vec::new();
-        let mut image_usages = Vec::new();
+        let arena = self.arena.clone();
+        let arena_guard = arena.lock();
+        let arena = arena_guard.allocator();
+        let mut buffer_usages = ScratchVec::new_in(arena);
+        let mut image_usages = ScratchVec::new_in(arena);
 
         let transfers: Vec<crate::render_graph::Transfer> = transfers
             .iter()
@@ -193,19 +287,148 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
                         copy_size: *copy_size,
                     }
                 }
+                crate::render_graph_builder::Transfer::FillBuffer { dst, size, data } => {
+                    let dst = self.get_buffer_offset(*dst);
+                    buffer_usages.push((dst.buffer, BufferResourceAccess::TransferWrite));
+                    crate::render_graph::Transfer::FillBuffer {
+                        dst,
+                        size: *size,
+                        data: *data,
+                    }
+                }
             })
             .collect();
 
         self.add_render_pass(
+            arena,
             name,
             color,
             &buffer_usages,
             &image_usages,
             Some(RenderPassCommand::Transfer { transfers }),
-        );
+        )
+    }
+
+    fn add_fill_buffer_pass(
+        &mut self,
+        buffer_offset: BufferOffset,
+        size: usize,
+        value: u32,
+    ) -> Result<PassHandle, RenderGraphError> {
+        if !size.is_multiple_of(4) || !buffer_offset.offset.is_multiple_of(4) {
+            return Err(RenderGraphError::UnalignedFill {
+                offset: buffer_offset.offset,
+                size,
+            });
+        }
+
+        // Persistent buffer sizes aren't known here, those are bounds checked when recorded
+        let buffer_index = self.get_buffer_index(buffer_offset.buffer);
+        if let BufferResourceDescription::Transient {
+            size: buffer_size, ..
+        } = self.render_graph.buffer_resources[buffer_index].description
+        {
+            if buffer_offset.offset + size > buffer_size {
+                return Err(RenderGraphError::FillOutOfBounds {
+                    buffer: buffer_offset.buffer,
+                    offset: buffer_offset.offset,
+                    size,
+                    buffer_size,
+                });
+            }
+        }
+
+        Ok(self.add_transfer_pass(
+            "Fill Buffer".to_string(),
+            [1.0, 0.0, 0.0, 1.0],
+            QueueType::Graphics,
+            &[crate::render_graph_builder::Transfer::FillBuffer {
+                dst: buffer_offset,
+                size: size as u64,
+                data: value,
+            }],
+        ))
+    }
+
+    fn add_update_buffer_pass(
+        &mut self,
+        buffer_offset: BufferOffset,
+        data: &[u8],
+    ) -> Result<PassHandle, RenderGraphError> {
+        if data.is_empty() {
+            return Err(RenderGraphError::EmptyUpdate {
+                buffer: buffer_offset.buffer,
+            });
+        }
+
+        // Persistent buffer sizes aren't known here, those are bounds checked when recorded
+        let buffer_index = self.get_buffer_index(buffer_offset.buffer);
+        if let BufferResourceDescription::Transient {
+            size: buffer_size, ..
+        } = self.render_graph.buffer_resources[buffer_index].description
+        {
+            if buffer_offset.offset + data.len() > buffer_size {
+                return Err(RenderGraphError::UpdateOutOfBounds {
+                    buffer: buffer_offset.buffer,
+                    offset: buffer_offset.offset,
+                    size: data.len(),
+                    buffer_size,
+                });
+            }
+        }
+
+        let can_inline = data.len() <= MAX_INLINE_BUFFER_UPDATE_SIZE
+            && data.len().is_multiple_of(4)
+            && buffer_offset.offset.is_multiple_of(4);
+
+        if can_inline {
+            let dst = self.get_buffer_offset(buffer_offset);
+            let arena = self.arena.clone();
+            let arena_guard = arena.lock();
+            Ok(self.add_render_pass(
+                arena_guard.allocator(),
+                "Update Buffer".to_string(),
+                [1.0, 0.0, 0.0, 1.0],
+                &[(dst.buffer, BufferResourceAccess::TransferWrite)],
+                &[],
+                Some(RenderPassCommand::Transfer {
+                    transfers: vec![crate::render_graph::Transfer::UpdateBuffer {
+                        dst,
+                        data: data.to_vec(),
+                    }],
+                }),
+            ))
+        } else {
+            // Only the staging buffer is written at frame start, the copy into the target runs in pass order
+            let staging = BufferOffset {
+                buffer: self.create_transient_buffer(
+                    data.len(),
+                    BufferUsage::TRANSFER,
+                    gpu_allocator::MemoryLocation::CpuToGpu,
+                ),
+                offset: 0,
+            };
+            let copy_size = data.len();
+            let data = data.to_vec();
+            self.add_buffer_write(
+                staging,
+                copy_size,
+                BufferWriteCallback::new(move |slice| slice.copy_from_slice(&data)),
+            );
+            Ok(self.add_transfer_pass(
+                "Update Buffer".to_string(),
+                [1.0, 0.0, 0.0, 1.0],
+                QueueType::Graphics,
+                &[crate::render_graph_builder::Transfer::CopyBufferToBuffer {
+                    src: staging,
+                    dst: buffer_offset,
+                    copy_size: copy_size as u64,
+                }],
+            ))
+        }
     }
 
-    fn add_compute_pass(
+    fn add_compute_pass_with_push_constants(
         &mut self,
         name: String,
         color: [f32; 4],
@@ -213,15 +436,30 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
         pipeline: ComputePipelineHandle,
         dispatch: ComputeDispatch,
         resources: &[ShaderResourceUsage],
-    ) {
+        push_constants: &[u8],
+    ) -> PassHandle {
         //TODO: queue
         let _ = queue;
 
-        let mut buffer_usages = Vec::new();
-        let mut image_usages = Vec::new();
+        self.validate(validate_sampled_resources(&name, resources));
+        self.validate(validate_dynamic_buffers(&name, resources));
+        self.validate_dynamic_buffer_ranges(&name, resources);
+        self.validate(validate_push_constant_size(
+            &name,
+            resources,
+            push_constants,
+            self.max_push_constants_size,
+        ));
+
+        let arena = self.arena.clone();
+        let arena_guard = arena.lock();
+        let arena = arena_guard.allocator();
+        let mut buffer_usages = ScratchVec::new_in(arena);
+        let mut image_usages = ScratchVec::new_in(arena);
 
         let resources =
             self.get_shader_resource_access(&mut buffer_usages, &mut image_usages, resources);
+        self.validate_bindable_resources(&name, &buffer_usages, &image_usages);
 
         let dispatch = match dispatch {
             ComputeDispatch::Size(size) => crate::render_graph::ComputeDispatch::Size(size),
@@ -233,6 +471,7 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
         };
 
         self.add_render_pass(
+            arena,
             name,
             color,
             &buffer_usages,
@@ -241,8 +480,46 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
                 pipeline,
                 resources,
                 dispatch,
+                push_constants: push_constants.to_vec(),
             }),
+        )
+    }
+
+    fn add_custom_pass(
+        &mut self,
+        name: String,
+        color: [f32; 4],
+        buffer_usages: &[(BufferHandle, BufferResourceAccess)],
+        image_usages: &[(ImageHandle, ImageResourceAccess)],
+        command: CustomCommand,
+    ) -> PassHandle {
+        let arena = self.arena.clone();
+        let arena_guard = arena.lock();
+        let arena = arena_guard.allocator();
+        let buffer_usages = arena.collect(
+            buffer_usages
+                .iter()
+                .map(|(buffer, access)| (self.get_buffer_index(*buffer), *access)),
+        );
+        let image_usages = arena.collect(
+            image_usages
+                .iter()
+                .map(|(image, access)| (self.get_image_index(*image), *access)),
         );
+
+        let custom_command = RenderPassCommand::Custom {
+            buffers: buffer_usages.iter().map(|(buffer, _)| *buffer).collect(),
+            images: image_usages.iter().map(|(image, _)| *image).collect(),
+            command,
+        };
+        self.add_render_pass(
+            arena,
+            name,
+            color,
+            &buffer_usages,
+            &image_usages,
+            Some(custom_command),
+        )
     }
 
     fn add_raster_pass(
@@ -251,10 +528,40 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
         color: [f32; 4],
         color_attachments: &[ColorAttachment],
         depth_stencil_attachment: Option<DepthStencilAttachment>,
+        view_mask: u32,
+        cacheable: bool,
         raster_draw_commands: &[RasterDrawCommand],
-    ) {
-        let mut buffer_usages = Vec::new();
-        let mut image_usages = Vec::new();
+    ) -> PassHandle {
+        // Depth only passes are fine, but the render area comes from the attachments
+        if color_attachments.is_empty() && depth_stencil_attachment.is_none() {
+            self.validate(Err(RenderGraphError::NoAttachments { pass: name.clone() }));
+        }
+
+        for (draw_index, raster_draw_command) in raster_draw_commands.iter().enumerate() {
+            self.validate(validate_sampled_resources(
+                &name,
+                &raster_draw_command.resources,
+            ));
+            self.validate(validate_dynamic_buffers(
+                &name,
+                &raster_draw_command.resources,
+            ));
+            self.validate_dynamic_buffer_ranges(&name, &raster_draw_command.resources);
+            self.validate(validate_depth_range(
+                &name,
+                draw_index,
+                raster_draw_command.depth_range,
+            ));
+        }
+
+        let arena = self.arena.clone();
+        let arena_guard = arena.lock();
+        let arena = arena_guard.allocator();
+        let mut buffer_usages = ScratchVec::new_in(arena);
+        let mut image_usages = ScratchVec::new_in(arena);
+
+        // Multiview passes render every layer in the mask through a single array view
+        let layer_count = view_mask_layer_count(view_mask).max(1);
 
         let raster_command = RenderPassCommand::Raster {
             framebuffer: Framebuffer {
@@ -262,33 +569,173 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
                     .iter()
                     .map(|attachment| {
                         let image_index = self.get_image_index(attachment.image);
+                        let subresource = AttachmentSubresource {
+                            layer_count,
+                            ..attachment.subresource
+                        };
                         image_usages.push((image_index, ImageResourceAccess::AttachmentWrite));
+                        self.add_attachment_view(&name, image_index, subresource);
+                        if attachment.clear.is_none() {
+                            self.unread_image_writes.remove(&image_index);
+                        }
                         crate::render_graph::ColorAttachment {
                             image: image_index,
                             clear: attachment.clear,
+                            subresource,
                         }
                     })
                     .collect(),
                 depth_stencil_attachment: depth_stencil_attachment.map(|attachment| {
                     let image_index = self.get_image_index(attachment.image);
+                    let subresource = AttachmentSubresource {
+                        layer_count,
+                        ..attachment.subresource
+                    };
                     image_usages.push((image_index, ImageResourceAccess::AttachmentWrite));
+                    self.add_attachment_view(&name, image_index, subresource);
+                    if attachment.clear.is_none() {
+                        self.unread_image_writes.remove(&image_index);
+                    }
                     crate::render_graph::DepthStencilAttachment {
                         image: image_index,
                         clear: attachment.clear,
+                        subresource,
                     }
                 }),
+                view_mask,
             },
+            cacheable,
             draw_commands: self.get_raster_draw_commands(
                 &mut buffer_usages,
                 &mut image_usages,
                 raster_draw_commands,
             ),
         };
+        self.validate_bindable_resources(&name, &buffer_usages, &image_usages);
+
+        // Sampling an image while it's an attachment would need it in two layouts at once
+        if let Some((image_index, _)) = image_usages.iter().find(|(image_index, access)| {
+            *access == ImageResourceAccess::SampledRead
+                && image_usages.contains(&(*image_index, ImageResourceAccess::AttachmentWrite))
+        }) {
+            self.validate(Err(RenderGraphError::SampledAttachment {
+                pass: name.clone(),
+                image: self.get_image_handle(*image_index),
+            }));
+        }
+
+        self.add_render_pass(
+            arena,
+            name,
+            color,
+            &buffer_usages,
+            &image_usages,
+            Some(raster_command),
+        )
+    }
+
+    fn set_image_release_access(&mut self, image: ImageHandle, access: ImageResourceAccess) {
+        if !matches!(image, ImageHandle::Persistent(_)) {
+            self.validate(Err(RenderGraphError::NonPersistentRelease { image }));
+            return;
+        }
+
+        let image_index = self.get_image_index(image);
+        self.render_graph.image_resources[image_index].release_access = Some(access);
+    }
+
+    fn add_execution_dependency(&mut self, before: PassHandle, after: PassHandle) {
+        if before.0 >= after.0 || after.0 >= self.pass_count() {
+            self.validate(Err(RenderGraphError::InvalidExecutionDependency {
+                before,
+                after,
+            }));
+            return;
+        }
+
+        // Passes execute in the order they are added and each has its own set until build() merges them,
+        // so a full barrier in front of the later pass both orders them and keeps its set from being merged
+        let render_pass_set = &mut self.render_graph.command_buffers[0].render_pass_sets[after.0];
+        if render_pass_set.memory_barriers.is_empty() {
+            render_pass_set.memory_barriers.push(
+                vk::MemoryBarrier2::builder()
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+                    .build(),
+            );
+        }
+    }
+
+    fn begin_timer(&mut self, name: &str) {
+        if self.open_timers.iter().any(|(open, _)| open == name) {
+            self.validate(Err(RenderGraphError::UnbalancedTimer {
+                name: name.to_string(),
+            }));
+            return;
+        }
+        let first_pass = self.pass_count();
+        self.open_timers.push((name.to_string(), first_pass));
+    }
 
-        self.add_render_pass(name, color, &[], &image_usages, Some(raster_command));
+    fn end_timer(&mut self, name: &str) {
+        let Some(index) = self.open_timers.iter().position(|(open, _)| open == name) else {
+            self.validate(Err(RenderGraphError::UnbalancedTimer {
+                name: name.to_string(),
+            }));
+            return;
+        };
+        let (name, first_pass) = self.open_timers.remove(index);
+        let passes = first_pass..self.pass_count();
+        // Timers around no passes have nothing to measure
+        if !passes.is_empty() {
+            self.render_graph
+                .timer_scopes
+                .push(crate::render_graph::TimerScope { name, passes });
+        }
     }
 
-    fn build(mut self) -> CompiledRenderGraph {
+    fn build(mut self) -> Result<CompiledRenderGraph, RenderGraphError> {
+        if let Some(error) = self.validation_error.take() {
+            return Err(error);
+        }
+        if let Some((name, _)) = self.open_timers.first() {
+            return Err(RenderGraphError::UnbalancedTimer { name: name.clone() });
+        }
+
+        for (buffer_index, used_usage) in self.transient_buffer_usages.iter() {
+            if let BufferResourceDescription::Transient { usage, .. } =
+                &self.render_graph.buffer_resources[*buffer_index].description
+            {
+                let missing = used_usage.difference(*usage);
+                if !missing.is_empty() {
+                    return Err(RenderGraphError::MissingBufferUsage {
+                        buffer: BufferHandle::Transient(*buffer_index),
+                        missing,
+                    });
+                }
+            }
+        }
+
+        if let Some(format_support) = &self.format_support {
+            for (image_index, used_usage) in self.transient_image_usages.iter() {
+                if let ImageResourceDescription::Transient(description) =
+                    &self.render_graph.image_resources[*image_index].description
+                {
+                    let unsupported =
+                        format_support.unsupported_usage(description.format, *used_usage);
+                    if !unsupported.is_empty() {
+                        return Err(RenderGraphError::UnsupportedTransientFormat {
+                            image: ImageHandle::Transient(*image_index),
+                            format: description.format,
+                            unsupported,
+                        });
+                    }
+                }
+            }
+        }
+
         if let Some(command_buffer) = self.render_graph.command_buffers.get_mut(0) {
             for (swapchain_index, (_, image_index)) in
                 self.render_graph.swapchain_images.iter().enumerate()
@@ -312,47 +759,448 @@ impl RenderGraphBuilderTrait for BasicRenderGraphBuilder {
             }
         }
 
-        self.render_graph
+        for command_buffer in self.render_graph.command_buffers.iter_mut() {
+            command_buffer.merge_render_pass_sets();
+        }
+
+        Ok(self.render_graph)
     }
 }
 
 impl BasicRenderGraphBuilder {
+    /// Creates a builder that checks transient image formats against the device's format features
+    pub fn with_format_support(format_support: FormatSupport) -> Self {
+        Self {
+            format_support: Some(format_support),
+            ..Self::new()
+        }
+    }
+
+    /// Makes build() fail on unordered transient writes instead of only logging a warning
+    pub fn set_strict_write_ordering(&mut self, strict: bool) {
+        self.strict_write_ordering = strict;
+    }
+
+    /// Makes build() fail with [`RenderGraphError::StaleHandle`] if a persistent handle missing from live_resources is used,
+    /// and checks persistent resources are bound as shader resources only with the usage they were created with
+    pub fn set_live_resources(&mut self, live_resources: LiveResources) {
+        self.live_resources = Some(live_resources);
+    }
+
+    /// Raises the push constant limit checked by compute passes from the spec minimum to the device's
+    pub fn set_max_push_constants_size(&mut self, max_push_constants_size: u32) {
+        self.max_push_constants_size = max_push_constants_size;
+    }
+
+    /// Replaces the spec guaranteed dynamic buffer limits with the device's
+    pub fn set_dynamic_buffer_limits(&mut self, dynamic_buffer_limits: DynamicBufferLimits) {
+        self.dynamic_buffer_limits = dynamic_buffer_limits;
+    }
+
+    pub(crate) fn set_frame_arena(&mut self, arena: FrameArena) {
+        self.arena = arena;
+    }
+
+    /// Passes added so far, which is also the index of the next pass in recording order
+    fn pass_count(&self) -> usize {
+        self.render_graph.command_buffers[0]
+            .render_pass_sets
+            .iter()
+            .map(|render_pass_set| render_pass_set.render_passes.len())
+            .sum()
+    }
+
+    fn add_attachment_view(
+        &mut self,
+        pass: &str,
+        image_index: ImageIndex,
+        subresource: AttachmentSubresource,
+    ) {
+        // Persistent images are only checked when live resources are set
+        let counts = match &self.render_graph.image_resources[image_index].description {
+            ImageResourceDescription::Persistent(key) => self
+                .live_resources
+                .as_ref()
+                .and_then(|live_resources| live_resources.image_subresource_counts(*key)),
+            ImageResourceDescription::Transient(description) => Some((description.mip_levels, 1)),
+            ImageResourceDescription::Swapchain(_) => Some((1, 1)),
+        };
+        if let Some((mip_levels, array_layers)) = counts {
+            if subresource.mip_level >= mip_levels
+                || subresource.base_layer as u64 + subresource.layer_count as u64
+                    > array_layers as u64
+            {
+                let image = self.get_image_handle(image_index);
+                self.validate(Err(RenderGraphError::AttachmentSubresourceOutOfRange {
+                    pass: pass.to_string(),
+                    image,
+                    subresource,
+                    mip_levels,
+                    array_layers,
+                }));
+                return;
+            }
+        }
+
+        let attachment_views = &mut self.render_graph.image_resources[image_index].attachment_views;
+        if !subresource.is_default() && !attachment_views.contains(&subresource) {
+            attachment_views.push(subresource);
+        }
+    }
+
+    /// Dynamic offsets must be aligned and the bound range must fit in both the buffer and the device's range limit.
+    /// Persistent buffer sizes are only known when live resources are set
+    fn validate_dynamic_buffer_ranges(&mut self, pass: &str, resources: &[ShaderResourceUsage]) {
+        for resource in resources.iter() {
+            let (buffer, offset, range, alignment, max_range) = match *resource {
+                ShaderResourceUsage::DynamicBuffer {
+                    buffer,
+                    offset,
+                    range,
+                } => (
+                    buffer,
+                    offset,
+                    range,
+                    self.dynamic_buffer_limits
+                        .min_uniform_buffer_offset_alignment,
+                    self.dynamic_buffer_limits.max_uniform_buffer_range,
+                ),
+                ShaderResourceUsage::DynamicStorageBuffer {
+                    buffer,
+                    offset,
+                    range,
+                    ..
+                } => (
+                    buffer,
+                    offset,
+                    range,
+                    self.dynamic_buffer_limits
+                        .min_storage_buffer_offset_alignment,
+                    self.dynamic_buffer_limits.max_storage_buffer_range,
+                ),
+                _ => continue,
+            };
+
+            if !(offset as u64).is_multiple_of(alignment.max(1)) {
+                self.validate(Err(RenderGraphError::UnalignedDynamicBuffer {
+                    pass: pass.to_string(),
+                    buffer,
+                    offset,
+                    alignment,
+                }));
+            }
+
+            if range > max_range {
+                self.validate(Err(RenderGraphError::DynamicBufferRangeTooLarge {
+                    pass: pass.to_string(),
+                    buffer,
+                    range,
+                    max: max_range,
+                }));
+            }
+
+            let buffer_index = self.get_buffer_index(buffer);
+            let buffer_size = match &self.render_graph.buffer_resources[buffer_index].description {
+                BufferResourceDescription::Persistent(key) => self
+                    .live_resources
+                    .as_ref()
+                    .and_then(|live_resources| live_resources.buffer_size(*key)),
+                BufferResourceDescription::Transient { size, .. } => Some(*size as u64),
+            };
+            if let Some(buffer_size) = buffer_size {
+                if offset as u64 + range > buffer_size {
+                    self.validate(Err(RenderGraphError::DynamicBufferOutOfBounds {
+                        pass: pass.to_string(),
+                        buffer,
+                        offset,
+                        range,
+                        buffer_size,
+                    }));
+                }
+            }
+        }
+    }
+
+    fn validate(&mut self, result: Result<(), RenderGraphError>) {
+        if let Err(error) = result {
+            let _ = self.validation_error.get_or_insert(error);
+        }
+    }
+
+    /// Shaders reach resources through bindless indices, which only exist for resources created with the matching usage.
+    /// Persistent resources are only checked when live resources are set, transient buffers are checked in build()
+    fn validate_bindable_resources(
+        &mut self,
+        pass: &str,
+        buffer_usages: &[(BufferIndex, BufferResourceAccess)],
+        image_usages: &[(ImageIndex, ImageResourceAccess)],
+    ) {
+        for (buffer_index, access) in buffer_usages.iter() {
+            let required_usage = match access {
+                BufferResourceAccess::StorageRead | BufferResourceAccess::StorageWrite => {
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                }
+                BufferResourceAccess::UniformRead => vk::BufferUsageFlags::UNIFORM_BUFFER,
+                _ => continue,
+            };
+
+            let Some(key) = self.render_graph.buffer_resources[*buffer_index]
+                .description
+                .as_persistent()
+            else {
+                continue;
+            };
+            let usage = self
+                .live_resources
+                .as_ref()
+                .and_then(|live_resources| live_resources.buffer_usage(key));
+            if usage.is_some_and(|usage| !usage.contains(required_usage)) {
+                let pass = pass.to_string();
+                let buffer = BufferHandle::Persistent(key);
+                self.validate(Err(
+                    if required_usage == vk::BufferUsageFlags::UNIFORM_BUFFER {
+                        RenderGraphError::BufferNotUniformCapable { pass, buffer }
+                    } else {
+                        RenderGraphError::BufferNotStorageCapable { pass, buffer }
+                    },
+                ));
+            }
+        }
+
+        for (image_index, access) in image_usages.iter() {
+            let required_usage = match access {
+                ImageResourceAccess::SampledRead => vk::ImageUsageFlags::SAMPLED,
+                ImageResourceAccess::StorageRead | ImageResourceAccess::StorageWrite => {
+                    vk::ImageUsageFlags::STORAGE
+                }
+                _ => continue,
+            };
+
+            let usage = match &self.render_graph.image_resources[*image_index].description {
+                ImageResourceDescription::Persistent(key) => self
+                    .live_resources
+                    .as_ref()
+                    .and_then(|live_resources| live_resources.image_usage(*key)),
+                ImageResourceDescription::Transient(description) => Some(description.usage),
+                ImageResourceDescription::Swapchain(_) => None,
+            };
+            if usage.is_some_and(|usage| !usage.contains(required_usage)) {
+                let pass = pass.to_string();
+                let image = self.get_image_handle(*image_index);
+                self.validate(Err(if required_usage == vk::ImageUsageFlags::SAMPLED {
+                    RenderGraphError::ImageNotSampledCapable { pass, image }
+                } else {
+                    RenderGraphError::ImageNotStorageCapable { pass, image }
+                }));
+            }
+        }
+    }
+
+    /// Scratch collections are allocated from arena, the callers' usages are copied into the pass
     fn add_render_pass(
         &mut self,
+        arena: ScratchAllocator,
         label_name: String,
         label_color: [f32; 4],
         buffer_usages: &[(BufferIndex, BufferResourceAccess)],
         image_usages: &[(ImageIndex, ImageResourceAccess)],
         command: Option<RenderPassCommand>,
-    ) {
-        let buffer_barriers = self.create_buffer_barriers(buffer_usages);
+    ) -> PassHandle {
+        for (buffer_index, access) in buffer_usages.iter() {
+            if !self.render_graph.buffer_resources[*buffer_index]
+                .description
+                .is_persistent()
+            {
+                *self
+                    .transient_buffer_usages
+                    .entry(*buffer_index)
+                    .or_insert(BufferUsage::empty()) |= access.required_usage();
+            }
+        }
+
+        for (image_index, access) in image_usages.iter() {
+            if let ImageResourceDescription::Transient(description) =
+                &self.render_graph.image_resources[*image_index].description
+            {
+                let is_color =
+                    vk_format_get_aspect_flags(description.format) == vk::ImageAspectFlags::COLOR;
+                *self
+                    .transient_image_usages
+                    .entry(*image_index)
+                    .or_insert(vk::ImageUsageFlags::empty()) |= access.required_usage(is_color);
+            }
+        }
+
+        self.check_unordered_writes(arena, &label_name, buffer_usages, image_usages);
+        self.check_uninitialized_reads(arena, &label_name, buffer_usages, image_usages);
+
+        let shader_stages = match &command {
+            Some(RenderPassCommand::Compute { .. }) => vk::PipelineStageFlags2::COMPUTE_SHADER,
+            Some(RenderPassCommand::Raster { .. }) => {
+                vk::PipelineStageFlags2::VERTEX_SHADER | vk::PipelineStageFlags2::FRAGMENT_SHADER
+            }
+            _ => BufferResourceAccess::ALL_SHADER_STAGES,
+        };
+        let buffer_barriers = self.create_buffer_barriers(buffer_usages, shader_stages);
         let image_barriers = self.create_image_barriers(image_usages);
+        let pass = PassHandle(self.pass_count());
         self.render_graph.command_buffers[0].render_pass_sets.push(
             crate::render_graph::RenderPassSet {
-                memory_barriers: vec![vk::MemoryBarrier2::builder()
-                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
-                    .src_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
-                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
-                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
-                    .build()],
+                memory_barriers: Vec::new(),
                 buffer_barriers,
                 image_barriers,
                 render_passes: vec![crate::render_graph::RenderPass {
                     label_name,
                     label_color,
+                    buffer_usages: buffer_usages.to_vec(),
+                    image_usages: image_usages.to_vec(),
                     command,
                 }],
             },
         );
+        pass
+    }
+
+    /// Persistent resources may be read outside the graph, so only transient resources are checked
+    fn check_unordered_writes(
+        &mut self,
+        arena: ScratchAllocator,
+        pass: &str,
+        buffer_usages: &[(BufferIndex, BufferResourceAccess)],
+        image_usages: &[(ImageIndex, ImageResourceAccess)],
+    ) {
+        let buffer_resources = &self.render_graph.buffer_resources;
+        let unordered_buffers = track_unread_writes(
+            arena,
+            &mut self.unread_buffer_writes,
+            pass,
+            buffer_usages
+                .iter()
+                .filter(|(index, _)| !buffer_resources[*index].description.is_persistent())
+                .map(|(index, access)| (*index, access.is_write())),
+        )
+        .into_iter()
+        .map(|(index, first_pass)| (format!("{:?}", BufferHandle::Transient(index)), first_pass));
+
+        let image_resources = &self.render_graph.image_resources;
+        let unordered_images = track_unread_writes(
+            arena,
+            &mut self.unread_image_writes,
+            pass,
+            image_usages
+                .iter()
+                .filter(|(index, _)| {
+                    matches!(
+                        image_resources[*index].description,
+                        ImageResourceDescription::Transient(_)
+                    )
+                })
+                .map(|(index, access)| (*index, access.is_write())),
+        )
+        .into_iter()
+        .map(|(index, first_pass)| (format!("{:?}", ImageHandle::Transient(index)), first_pass));
+
+        for (resource, first_pass) in arena.collect(unordered_buffers.chain(unordered_images)) {
+            let error = RenderGraphError::UnorderedWrites {
+                resource,
+                first_pass,
+                second_pass: pass.to_string(),
+            };
+            if self.strict_write_ordering {
+                self.validate(Err(error));
+            } else {
+                warn!("{}", error);
+            }
+        }
+    }
+
+    /// Persistent and swapchain images keep their contents from outside the graph, so only transient resources are checked.
+    /// A pass that both reads and writes a resource is assumed to initialize it
+    fn check_uninitialized_reads(
+        &mut self,
+        arena: ScratchAllocator,
+        pass: &str,
+        buffer_usages: &[(BufferIndex, BufferResourceAccess)],
+        image_usages: &[(ImageIndex, ImageResourceAccess)],
+    ) {
+        let buffer_resources = &self.render_graph.buffer_resources;
+        let uninitialized_buffers = track_initialized(
+            arena,
+            &mut self.initialized_buffers,
+            buffer_usages
+                .iter()
+                .filter(|(index, _)| !buffer_resources[*index].description.is_persistent())
+                .map(|(index, access)| (*index, access.is_write())),
+        )
+        .into_iter()
+        .map(|index| format!("{:?}", BufferHandle::Transient(index)));
+
+        let image_resources = &self.render_graph.image_resources;
+        let uninitialized_images = track_initialized(
+            arena,
+            &mut self.initialized_images,
+            image_usages
+                .iter()
+                .filter(|(index, _)| {
+                    matches!(
+                        image_resources[*index].description,
+                        ImageResourceDescription::Transient(_)
+                    )
+                })
+                .map(|(index, access)| (*index, access.is_write())),
+        )
+        .into_iter()
+        .map(|index| format!("{:?}", ImageHandle::Transient(index)));
+
+        for resource in arena.collect(uninitialized_buffers.chain(uninitialized_images)) {
+            self.validate(Err(RenderGraphError::UninitializedRead {
+                pass: pass.to_string(),
+                resource,
+            }));
+        }
     }
 
     fn create_buffer_barriers(
         &mut self,
         buffer_usages: &[(BufferIndex, BufferResourceAccess)],
+        shader_stages: vk::PipelineStageFlags2,
     ) -> Vec<BufferBarrier> {
-        //Unused since this uses global barriers
-        let _ = buffer_usages;
-        Vec::new()
+        // Every usage within the pass waits on the access before the pass
+        let barriers: Vec<BufferBarrier> = buffer_usages
+            .iter()
+            .map(|(buffer_index, dst_access)| {
+                let src = match self.render_graph.buffer_resources[*buffer_index].last_access {
+                    BufferResourceAccess::None => BufferBarrierSource::FirstUsage,
+                    access => BufferBarrierSource::Precalculated(access),
+                };
+                BufferBarrier {
+                    index: *buffer_index,
+                    src,
+                    dst: *dst_access,
+                    src_shader_stages: self
+                        .buffer_shader_stages
+                        .get(buffer_index)
+                        .copied()
+                        .unwrap_or(BufferResourceAccess::ALL_SHADER_STAGES),
+                    dst_shader_stages: shader_stages,
+                }
+            })
+            .collect();
+
+        // A write has to be waited on by later passes even if this pass also reads the buffer
+        for (buffer_index, access) in buffer_usages.iter() {
+            let last_access = &mut self.render_graph.buffer_resources[*buffer_index].last_access;
+            let pass_wrote = buffer_usages
+                .iter()
+                .any(|(index, access)| index == buffer_index && access.is_write());
+            if access.is_write() || !pass_wrote {
+                *last_access = *access;
+            }
+            self.buffer_shader_stages
+                .insert(*buffer_index, shader_stages);
+        }
+
+        barriers
     }
 
     fn create_image_barriers(
@@ -384,8 +1232,8 @@ impl BasicRenderGraphBuilder {
 
     fn get_raster_draw_commands(
         &mut self,
-        buffer_usages: &mut Vec<(BufferIndex, BufferResourceAccess)>,
-        image_usages: &mut Vec<(ImageIndex, ImageResourceAccess)>,
+        buffer_usages: &mut ScratchVec<(BufferIndex, BufferResourceAccess)>,
+        image_usages: &mut ScratchVec<(ImageIndex, ImageResourceAccess)>,
         raster_draw_commands: &[RasterDrawCommand],
     ) -> Vec<crate::render_graph::RasterDrawCommand> {
         raster_draw_commands
@@ -470,6 +1318,9 @@ impl BasicRenderGraphBuilder {
                             }
                         }
                     },
+                    depth_range: raster_draw_command.depth_range,
+                    scissor: raster_draw_command.scissor,
+                    dynamic_state: raster_draw_command.dynamic_state,
                 },
             )
             .collect()
@@ -477,8 +1328,8 @@ impl BasicRenderGraphBuilder {
 
     fn get_shader_resource_access(
         &mut self,
-        buffer_usages: &mut Vec<(BufferIndex, BufferResourceAccess)>,
-        image_usages: &mut Vec<(ImageIndex, ImageResourceAccess)>,
+        buffer_usages: &mut ScratchVec<(BufferIndex, BufferResourceAccess)>,
+        image_usages: &mut ScratchVec<(ImageIndex, ImageResourceAccess)>,
         resources: &[ShaderResourceUsage],
     ) -> Vec<crate::render_graph::ShaderResourceUsage> {
         resources
@@ -522,6 +1373,41 @@ impl BasicRenderGraphBuilder {
                 ShaderResourceUsage::Sampler(sampler) => {
                     crate::render_graph::ShaderResourceUsage::Sampler(*sampler)
                 }
+                ShaderResourceUsage::DynamicBuffer {
+                    buffer,
+                    offset,
+                    range,
+                } => {
+                    let buffer = self.get_buffer_index(*buffer);
+                    buffer_usages.push((buffer, BufferResourceAccess::UniformRead));
+                    crate::render_graph::ShaderResourceUsage::DynamicBuffer {
+                        buffer,
+                        offset: *offset,
+                        range: *range,
+                    }
+                }
+                ShaderResourceUsage::DynamicStorageBuffer {
+                    buffer,
+                    offset,
+                    range,
+                    write,
+                } => {
+                    let buffer = self.get_buffer_index(*buffer);
+                    buffer_usages.push((
+                        buffer,
+                        if *write {
+                            BufferResourceAccess::StorageWrite
+                        } else {
+                            BufferResourceAccess::StorageRead
+                        },
+                    ));
+                    crate::render_graph::ShaderResourceUsage::DynamicStorageBuffer {
+                        buffer,
+                        offset: *offset,
+                        range: *range,
+                        write: *write,
+                    }
+                }
             })
             .collect()
     }
@@ -530,24 +1416,44 @@ impl BasicRenderGraphBuilder {
         match self.buffer_index_map.get(&buffer_handle) {
             Some(index) => *index,
             None => {
-                if let BufferHandle::Persistent(buffer_key) = buffer_handle {
-                    let index = self.render_graph.buffer_resources.len() as BufferIndex;
-                    self.render_graph
-                        .buffer_resources
-                        .push(BufferGraphResource {
-                            description: BufferResourceDescription::Persistent(buffer_key),
-                            last_access: BufferResourceAccess::None,
-                        });
-                    self.buffer_index_map.insert(buffer_handle, index);
-                    index
-                } else {
-                    panic!("Invalid Transient Buffer Handle: {:?}", buffer_handle)
-                }
-            }
-        }
-    }
-
-    fn get_buffer_offset(
+                let description = match buffer_handle {
+                    BufferHandle::Persistent(buffer_key) => {
+                        if let Some(live_resources) = &self.live_resources {
+                            if !live_resources.contains_buffer(buffer_key) {
+                                self.validate(Err(RenderGraphError::StaleHandle {
+                                    resource: format!("{:?}", buffer_handle),
+                                }));
+                            }
+                        }
+                        BufferResourceDescription::Persistent(buffer_key)
+                    }
+                    BufferHandle::Transient(_) => {
+                        self.validate(Err(RenderGraphError::InvalidBufferHandle {
+                            buffer: buffer_handle,
+                        }));
+                        // Placeholder so passes can still be added, build() returns the error before it's allocated
+                        BufferResourceDescription::Transient {
+                            size: 0,
+                            usage: BufferUsage::empty(),
+                            location: gpu_allocator::MemoryLocation::Unknown,
+                        }
+                    }
+                };
+
+                let index = self.render_graph.buffer_resources.len() as BufferIndex;
+                self.render_graph
+                    .buffer_resources
+                    .push(BufferGraphResource {
+                        description,
+                        last_access: BufferResourceAccess::None,
+                    });
+                self.buffer_index_map.insert(buffer_handle, index);
+                index
+            }
+        }
+    }
+
+    fn get_buffer_offset(
         &mut self,
         buffer_offset: BufferOffset,
     ) -> crate::render_graph::BufferOffset {
@@ -569,22 +1475,56 @@ impl BasicRenderGraphBuilder {
         }
     }
 
+    fn get_image_handle(&self, image_index: ImageIndex) -> ImageHandle {
+        match self.render_graph.image_resources[image_index].description {
+            ImageResourceDescription::Persistent(image_key) => ImageHandle::Persistent(image_key),
+            _ => ImageHandle::Transient(image_index),
+        }
+    }
+
     pub fn get_image_index(&mut self, image_handle: ImageHandle) -> ImageIndex {
         match self.image_index_map.get(&image_handle) {
             Some(index) => *index,
             None => {
-                if let ImageHandle::Persistent(image_key) = image_handle {
-                    let index = self.render_graph.image_resources.len() as ImageIndex;
-                    self.render_graph.image_resources.push(ImageGraphResource {
-                        description: ImageResourceDescription::Persistent(image_key),
-                        first_access: None,
-                        last_access: None,
-                    });
-                    self.image_index_map.insert(image_handle, index);
-                    index
-                } else {
-                    panic!("Invalid Transient Image Handle: {:?}", image_handle)
-                }
+                let description = match image_handle {
+                    ImageHandle::Persistent(image_key) => {
+                        if let Some(live_resources) = &self.live_resources {
+                            if !live_resources.contains_image(image_key) {
+                                self.validate(Err(RenderGraphError::StaleHandle {
+                                    resource: format!("{:?}", image_handle),
+                                }));
+                            }
+                        }
+                        ImageResourceDescription::Persistent(image_key)
+                    }
+                    ImageHandle::Transient(_) => {
+                        self.validate(Err(RenderGraphError::InvalidImageHandle {
+                            image: image_handle,
+                        }));
+                        // Placeholder so passes can still be added, build() returns the error before it's allocated
+                        ImageResourceDescription::Transient(TransientImageDesc {
+                            size: TransientImageSize::Exact(vk::Extent2D {
+                                width: 1,
+                                height: 1,
+                            }),
+                            format: vk::Format::UNDEFINED,
+                            usage: vk::ImageUsageFlags::empty(),
+                            mip_levels: 1,
+                            memory_location: gpu_allocator::MemoryLocation::Unknown,
+                        })
+                    }
+                };
+
+                let index = self.render_graph.image_resources.len() as ImageIndex;
+                self.render_graph.image_resources.push(ImageGraphResource {
+                    description,
+                    first_access: None,
+                    last_access: None,
+                    attachment_views: Vec::new(),
+                    release_access: None,
+                });
+                self.image_index_map.insert(image_handle, index);
+                index
             }
         }
     }
@@ -596,6 +1536,1955 @@ impl BasicRenderGraphBuilder {
         crate::render_graph::ImageCopyImage {
             image: self.get_image_index(image.image),
             offset: image.offset,
+            mip_level: image.mip_level,
+            base_array_layer: image.base_array_layer,
+        }
+    }
+}
+
+/// Marks the resources a pass writes as initialized, returning the resources it only reads that nothing has written yet
+fn track_initialized<'a, K: Copy + Eq + Hash>(
+    arena: ScratchAllocator<'a>,
+    initialized: &mut HashSet<K>,
+    usages: impl Iterator<Item = (K, bool)>,
+) -> ScratchVec<'a, K> {
+    let usages = arena.collect(usages);
+    let mut uninitialized = ScratchVec::new_in(arena);
+    for &(key, _) in usages.iter() {
+        let written = usages
+            .iter()
+            .any(|&(other, is_write)| other == key && is_write);
+        if !written && !initialized.contains(&key) && !uninitialized.contains(&key) {
+            uninitialized.push(key);
+        }
+    }
+    initialized.extend(
+        usages
+            .into_iter()
+            .filter(|&(_, is_write)| is_write)
+            .map(|(key, _)| key),
+    );
+    uninitialized
+}
+
+/// Updates the last unread writer of each resource used by a pass, returning the resources whose previous write was never read
+fn track_unread_writes<'a, K: Copy + Eq + Hash>(
+    arena: ScratchAllocator<'a>,
+    unread_writes: &mut HashMap<K, String>,
+    pass: &str,
+    usages: impl Iterator<Item = (K, bool)>,
+) -> ScratchVec<'a, (K, String)> {
+    // A pass that both reads and writes a resource consumes the previous write
+    let mut pass_usages: ScratchVec<(K, bool, bool)> = ScratchVec::new_in(arena);
+    for (key, is_write) in usages {
+        match pass_usages.iter_mut().find(|(other, _, _)| *other == key) {
+            Some((_, reads, writes)) => {
+                *reads |= !is_write;
+                *writes |= is_write;
+            }
+            None => pass_usages.push((key, !is_write, is_write)),
+        }
+    }
+
+    let mut unordered = ScratchVec::new_in(arena);
+    for (key, reads, writes) in pass_usages {
+        if writes {
+            if let Some(first_pass) = unread_writes.insert(key, pass.to_string()) {
+                if !reads {
+                    unordered.push((key, first_pass));
+                }
+            }
+        } else {
+            unread_writes.remove(&key);
+        }
+    }
+    unordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_graph::GraphStats;
+    use crate::render_graph_builder::{
+        ClearValue, ComputePassBuilder, HistoryImage, RasterDrawCommandBuilder, RasterPassBuilder,
+        TransferPassBuilder,
+    };
+    use crate::{ComputePipelineHandle, RasterPipelineHandle};
+
+    fn color_image(builder: &mut BasicRenderGraphBuilder, mip_levels: u32) -> ImageHandle {
+        builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            mip_levels,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        })
+    }
+
+    fn render_to_subresource(subresource: AttachmentSubresource) -> Result<(), RenderGraphError> {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = color_image(&mut builder, 4);
+        let mut raster_pass = RasterPassBuilder::new("mip_pass");
+        raster_pass.add_color_attachment_subresource(
+            image,
+            Some(ClearValue::Float([0.0; 4])),
+            subresource,
+        );
+        raster_pass.build(&mut builder);
+        builder.build().map(|_| ())
+    }
+
+    #[test]
+    fn attachment_subresource_in_range_builds() {
+        assert_eq!(
+            render_to_subresource(AttachmentSubresource {
+                mip_level: 3,
+                ..Default::default()
+            }),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn attachment_subresource_out_of_range_is_rejected() {
+        for subresource in [
+            AttachmentSubresource {
+                mip_level: 4,
+                ..Default::default()
+            },
+            AttachmentSubresource {
+                base_layer: 1,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                render_to_subresource(subresource),
+                Err(RenderGraphError::AttachmentSubresourceOutOfRange {
+                    mip_levels: 4,
+                    array_layers: 1,
+                    ..
+                })
+            ));
         }
     }
+
+    fn empty_compute_pass(builder: &mut BasicRenderGraphBuilder, name: &str) -> PassHandle {
+        builder.add_compute_pass(
+            name.to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[],
+        )
+    }
+
+    fn pass_set_names(render_graph: &CompiledRenderGraph) -> Vec<Vec<&str>> {
+        render_graph.command_buffers[0]
+            .render_pass_sets
+            .iter()
+            .map(|render_pass_set| {
+                render_pass_set
+                    .render_passes
+                    .iter()
+                    .map(|render_pass| render_pass.label_name.as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn independent_passes_share_a_barrier_batch() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        empty_compute_pass(&mut builder, "reset");
+        empty_compute_pass(&mut builder, "query");
+        let render_graph = builder.build().unwrap();
+        assert_eq!(pass_set_names(&render_graph), vec![vec!["reset", "query"]]);
+    }
+
+    #[test]
+    fn execution_dependency_keeps_pass_order_behind_a_barrier() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let reset = empty_compute_pass(&mut builder, "reset");
+        let query = empty_compute_pass(&mut builder, "query");
+        builder.add_execution_dependency(reset, query);
+        let render_graph = builder.build().unwrap();
+
+        assert_eq!(
+            pass_set_names(&render_graph),
+            vec![vec!["reset"], vec!["query"]]
+        );
+        let memory_barriers = &render_graph.command_buffers[0].render_pass_sets[1].memory_barriers;
+        assert_eq!(memory_barriers.len(), 1);
+        assert_eq!(
+            memory_barriers[0].src_stage_mask,
+            vk::PipelineStageFlags2::ALL_COMMANDS
+        );
+        assert_eq!(
+            memory_barriers[0].dst_stage_mask,
+            vk::PipelineStageFlags2::ALL_COMMANDS
+        );
+    }
+
+    #[test]
+    fn execution_dependency_on_a_later_pass_is_rejected() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let reset = empty_compute_pass(&mut builder, "reset");
+        let query = empty_compute_pass(&mut builder, "query");
+        builder.add_execution_dependency(query, reset);
+        assert_eq!(
+            builder.build().err(),
+            Some(RenderGraphError::InvalidExecutionDependency {
+                before: query,
+                after: reset,
+            })
+        );
+    }
+
+    #[test]
+    fn sampling_an_image_without_sampled_usage_is_rejected() {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image(key, vk::ImageUsageFlags::STORAGE);
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+        let image = ImageHandle::Persistent(key);
+        builder.add_compute_pass(
+            "sample".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(image),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+            ],
+        );
+
+        let error = builder.build().unwrap_err();
+        assert_eq!(
+            error,
+            RenderGraphError::ImageNotSampledCapable {
+                pass: "sample".to_string(),
+                image,
+            }
+        );
+        assert!(error.to_string().contains("SAMPLED usage"));
+    }
+
+    #[test]
+    fn double_buffered_images_swap_and_read_last_frame() {
+        let mut keys = slotmap::SlotMap::<crate::ImageKey, ()>::with_key();
+        let (first, second) = (
+            ImageHandle::Persistent(keys.insert(())),
+            ImageHandle::Persistent(keys.insert(())),
+        );
+        let mut history = HistoryImage::new(first, second);
+        assert_eq!(history.images(), [first, second]);
+
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            let mut builder = BasicRenderGraphBuilder::new();
+            let (current, previous) = builder.double_buffered(&mut history);
+            frames.push((current, previous, history.has_history()));
+
+            builder.add_compute_pass(
+                "temporal".to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &[
+                    ShaderResourceUsage::StorageImage {
+                        image: current,
+                        write: true,
+                    },
+                    ShaderResourceUsage::SampledImage(previous),
+                    ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+                ],
+            );
+            let previous_index = builder.get_image_index(previous);
+            let render_graph = builder.build().unwrap();
+
+            // Last frame's write is only known at execution, so the read barrier is resolved from the tracked last access
+            let barrier = render_graph.command_buffers[0].render_pass_sets[0]
+                .image_barriers
+                .iter()
+                .find(|barrier| barrier.index == previous_index)
+                .unwrap();
+            assert!(matches!(barrier.src, ImageBarrierSource::FirstUsage));
+            assert_eq!(barrier.dst, ImageResourceAccess::SampledRead);
+        }
+
+        assert_eq!(
+            frames,
+            vec![
+                (first, second, false),
+                (second, first, true),
+                (first, second, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn history_image_reads_the_image_written_last_frame() {
+        let mut keys = slotmap::SlotMap::<crate::ImageKey, ()>::with_key();
+        let mut history = HistoryImage::new(
+            ImageHandle::Persistent(keys.insert(())),
+            ImageHandle::Persistent(keys.insert(())),
+        );
+
+        let mut written = Vec::new();
+        for frame in 0..2 {
+            let mut builder = BasicRenderGraphBuilder::new();
+            let (current, previous) = builder.history_image(&mut history);
+            assert_eq!(history.has_history(), frame > 0);
+            if let Some(&last_written) = written.last() {
+                assert_eq!(previous, last_written);
+            }
+            written.push(current);
+
+            builder.add_compute_pass(
+                "taa".to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &[
+                    ShaderResourceUsage::StorageImage {
+                        image: current,
+                        write: true,
+                    },
+                    ShaderResourceUsage::SampledImage(previous),
+                    ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+                ],
+            );
+            let previous_index = builder.get_image_index(previous);
+            let render_graph = builder.build().unwrap();
+
+            let barrier = render_graph.command_buffers[0].render_pass_sets[0]
+                .image_barriers
+                .iter()
+                .find(|barrier| barrier.index == previous_index)
+                .unwrap();
+            assert!(matches!(barrier.src, ImageBarrierSource::FirstUsage));
+            assert_eq!(barrier.dst, ImageResourceAccess::SampledRead);
+        }
+        assert_ne!(written[0], written[1]);
+    }
+
+    #[test]
+    fn timer_scopes_cover_the_wrapped_passes() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.begin_timer("shadows");
+        empty_compute_pass(&mut builder, "cascade_0");
+        empty_compute_pass(&mut builder, "cascade_1");
+        builder.end_timer("shadows");
+        empty_compute_pass(&mut builder, "untimed");
+
+        // Scopes around no passes are dropped
+        builder.begin_timer("empty");
+        builder.end_timer("empty");
+
+        let render_graph = builder.build().unwrap();
+        assert_eq!(render_graph.timer_scopes.len(), 1);
+        assert_eq!(render_graph.timer_scopes[0].name, "shadows");
+        assert_eq!(render_graph.timer_scopes[0].passes, 0..2);
+    }
+
+    #[test]
+    fn unbalanced_timers_are_rejected() {
+        let unbalanced = |name: &str| RenderGraphError::UnbalancedTimer {
+            name: name.to_string(),
+        };
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.begin_timer("never_ended");
+        empty_compute_pass(&mut builder, "pass");
+        assert_eq!(builder.build().unwrap_err(), unbalanced("never_ended"));
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        empty_compute_pass(&mut builder, "pass");
+        builder.end_timer("never_begun");
+        assert_eq!(builder.build().unwrap_err(), unbalanced("never_begun"));
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.begin_timer("twice");
+        builder.begin_timer("twice");
+        empty_compute_pass(&mut builder, "pass");
+        builder.end_timer("twice");
+        assert_eq!(builder.build().unwrap_err(), unbalanced("twice"));
+    }
+
+    fn add_typed_push_pass<P: bytemuck::Pod>(builder: &mut BasicRenderGraphBuilder, push: P) {
+        builder.add_compute_pass_typed(
+            "typed_push".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[ShaderResourceUsage::Sampler(crate::SamplerHandle(
+                Default::default(),
+            ))],
+            push,
+        );
+    }
+
+    #[test]
+    fn push_constants_over_the_limit_are_rejected() {
+        // 128 bytes fits on its own, but not after the sampler's binding index
+        let mut builder = BasicRenderGraphBuilder::new();
+        add_typed_push_pass(&mut builder, [0u32; 32]);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::PushConstantsTooLarge {
+                pass: "typed_push".to_string(),
+                size: 132,
+                max: 128,
+            }
+        );
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_max_push_constants_size(256);
+        add_typed_push_pass(&mut builder, [0u32; 32]);
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn typed_push_constants_round_trip() {
+        let push = [1.5f32, -2.0, 3.25, 42.0];
+        let mut builder = BasicRenderGraphBuilder::new();
+        add_typed_push_pass(&mut builder, push);
+        let render_graph = builder.build().unwrap();
+
+        let Some(RenderPassCommand::Compute { push_constants, .. }) =
+            &render_graph.command_buffers[0].render_pass_sets[0].render_passes[0].command
+        else {
+            panic!("Expected a compute pass");
+        };
+        assert_eq!(push_constants.len(), 16);
+        assert_eq!(
+            bytemuck::pod_read_unaligned::<[f32; 4]>(push_constants),
+            push
+        );
+    }
+
+    /// Compute writes indirect draw args that a raster pass then draws with, optionally with an explicit dependency as well
+    fn indirect_args_graph(with_dependency: bool) -> (CompiledRenderGraph, BufferIndex) {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = color_image(&mut builder, 1);
+        let args = builder.create_transient_buffer(
+            64,
+            BufferUsage::STORAGE | BufferUsage::INDIRECT,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        );
+        let compute = builder.add_compute_pass(
+            "write_args".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[ShaderResourceUsage::StorageBuffer {
+                buffer: args,
+                write: true,
+            }],
+        );
+
+        let mut raster_pass = RasterPassBuilder::new("draw_args");
+        raster_pass.add_color_attachment(image, Some(ClearValue::Float([0.0; 4])));
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.draw_indirect(
+            BufferOffset {
+                buffer: args,
+                offset: 0,
+            },
+            1,
+            16,
+        );
+        draw.build(&mut raster_pass).unwrap();
+        let raster = raster_pass.build(&mut builder);
+        if with_dependency {
+            builder.add_execution_dependency(compute, raster);
+        }
+
+        let args = builder.get_buffer_index(args);
+        (builder.build().unwrap(), args)
+    }
+
+    fn draw_args_set(render_graph: &CompiledRenderGraph) -> &crate::render_graph::RenderPassSet {
+        render_graph.command_buffers[0]
+            .render_pass_sets
+            .iter()
+            .find(|render_pass_set| {
+                render_pass_set
+                    .render_passes
+                    .iter()
+                    .any(|render_pass| render_pass.label_name == "draw_args")
+            })
+            .unwrap()
+    }
+
+    fn assert_compute_to_indirect_barrier(
+        render_pass_set: &crate::render_graph::RenderPassSet,
+        args: BufferIndex,
+    ) {
+        let barrier = render_pass_set
+            .buffer_barriers
+            .iter()
+            .find(|barrier| barrier.index == args)
+            .expect("Missing barrier for the indirect args");
+        let BufferBarrierSource::Precalculated(src_access) = barrier.src else {
+            panic!("Expected the compute write as the barrier source");
+        };
+
+        let src = src_access.get_stage_barrier_flags(barrier.src_shader_stages);
+        let dst = barrier
+            .dst
+            .get_stage_barrier_flags(barrier.dst_shader_stages);
+        assert_eq!(src.stage_mask, vk::PipelineStageFlags2::COMPUTE_SHADER);
+        assert!(src.access_mask.contains(vk::AccessFlags2::SHADER_WRITE));
+        assert_eq!(dst.stage_mask, vk::PipelineStageFlags2::DRAW_INDIRECT);
+        assert_eq!(dst.access_mask, vk::AccessFlags2::INDIRECT_COMMAND_READ);
+    }
+
+    #[test]
+    fn indirect_args_get_a_compute_to_draw_indirect_barrier() {
+        let (render_graph, args) = indirect_args_graph(false);
+        let render_pass_set = draw_args_set(&render_graph);
+        assert_compute_to_indirect_barrier(render_pass_set, args);
+        assert!(render_pass_set.memory_barriers.is_empty());
+    }
+
+    #[test]
+    fn execution_dependency_keeps_its_memory_barrier_next_to_precise_barriers() {
+        let (render_graph, args) = indirect_args_graph(true);
+        let render_pass_set = draw_args_set(&render_graph);
+        assert_compute_to_indirect_barrier(render_pass_set, args);
+        assert_eq!(render_pass_set.memory_barriers.len(), 1);
+        assert_eq!(
+            render_pass_set.memory_barriers[0].src_stage_mask,
+            vk::PipelineStageFlags2::ALL_COMMANDS
+        );
+    }
+
+    #[test]
+    fn stats_count_passes_barriers_and_transients() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = builder.create_transient_buffer(
+            256,
+            BufferUsage::STORAGE | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        );
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 256, 0)
+            .unwrap();
+        // Two passes reading the same buffer the same way need no barrier between them
+        for name in ["read_a", "read_b"] {
+            builder.add_compute_pass(
+                name.to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &[ShaderResourceUsage::StorageBuffer {
+                    buffer,
+                    write: false,
+                }],
+            );
+        }
+
+        let unmerged = builder.render_graph.stats();
+        assert_eq!(unmerged.pass_count, 3);
+        assert_eq!(unmerged.render_pass_set_count, 3);
+
+        let stats = builder.build().unwrap().stats();
+        assert_eq!(
+            stats,
+            GraphStats {
+                pass_count: 3,
+                render_pass_set_count: 2,
+                buffer_barrier_count: unmerged.buffer_barrier_count - 1,
+                image_barrier_count: 0,
+                transient_buffer_count: 1,
+                transient_image_count: 0,
+                staging_bytes: 0,
+            }
+        );
+    }
+
+    fn draw_dynamic_buffer(offset: u32, range: u64) -> Result<(), RenderGraphError> {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = color_image(&mut builder, 1);
+        let buffer = builder.create_transient_buffer(
+            1024,
+            BufferUsage::UNIFORM | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 1024, 0)
+            .unwrap();
+        let mut raster_pass = RasterPassBuilder::new("dynamic_pass");
+        raster_pass.add_color_attachment(image, None);
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.read_dynamic_buffer(buffer, offset, range);
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass)?;
+        raster_pass.build(&mut builder);
+        builder.build().map(|_| ())
+    }
+
+    #[test]
+    fn dynamic_buffer_in_range_builds() {
+        assert_eq!(draw_dynamic_buffer(768, 256), Ok(()));
+    }
+
+    #[test]
+    fn dynamic_buffer_unaligned_offset_is_rejected() {
+        assert!(matches!(
+            draw_dynamic_buffer(64, 64),
+            Err(RenderGraphError::UnalignedDynamicBuffer {
+                offset: 64,
+                alignment: 256,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn dynamic_buffer_out_of_bounds_is_rejected() {
+        assert!(matches!(
+            draw_dynamic_buffer(768, 512),
+            Err(RenderGraphError::DynamicBufferOutOfBounds {
+                buffer_size: 1024,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn dynamic_buffer_range_over_device_limit_is_rejected() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_dynamic_buffer_limits(DynamicBufferLimits {
+            max_uniform_buffer_range: 128,
+            ..Default::default()
+        });
+        let image = color_image(&mut builder, 1);
+        let buffer = builder.create_transient_buffer(
+            1024,
+            BufferUsage::UNIFORM | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 1024, 0)
+            .unwrap();
+        let mut raster_pass = RasterPassBuilder::new("dynamic_pass");
+        raster_pass.add_color_attachment(image, None);
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.read_dynamic_buffer(buffer, 0, 256);
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+        assert!(matches!(
+            builder.build(),
+            Err(RenderGraphError::DynamicBufferRangeTooLarge {
+                range: 256,
+                max: 128,
+                ..
+            })
+        ));
+    }
+
+    fn storage_buffer(builder: &mut BasicRenderGraphBuilder, size: usize) -> BufferHandle {
+        builder.create_transient_buffer(
+            size,
+            BufferUsage::STORAGE | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        )
+    }
+
+    fn only_transfers(render_graph: &CompiledRenderGraph) -> &[crate::render_graph::Transfer] {
+        let render_passes = &render_graph.command_buffers[0].render_pass_sets[0].render_passes;
+        assert_eq!(render_passes.len(), 1);
+        let Some(RenderPassCommand::Transfer { transfers }) = &render_passes[0].command else {
+            panic!("Expected a transfer pass");
+        };
+        transfers
+    }
+
+    #[test]
+    fn fill_records_a_zero_fill_of_the_range() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 256);
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 64 }, 128, 0)
+            .unwrap();
+        let buffer = builder.get_buffer_index(buffer);
+        let render_graph = builder.build().unwrap();
+
+        assert_eq!(
+            only_transfers(&render_graph),
+            [crate::render_graph::Transfer::FillBuffer {
+                dst: crate::render_graph::BufferOffset { buffer, offset: 64 },
+                size: 128,
+                data: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn unaligned_or_out_of_bounds_fills_are_rejected() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 256);
+        assert_eq!(
+            builder.add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 6, 0),
+            Err(RenderGraphError::UnalignedFill { offset: 0, size: 6 })
+        );
+        assert_eq!(
+            builder.add_fill_buffer_pass(BufferOffset { buffer, offset: 2 }, 4, 0),
+            Err(RenderGraphError::UnalignedFill { offset: 2, size: 4 })
+        );
+        assert_eq!(
+            builder.add_fill_buffer_pass(
+                BufferOffset {
+                    buffer,
+                    offset: 128
+                },
+                256,
+                0
+            ),
+            Err(RenderGraphError::FillOutOfBounds {
+                buffer,
+                offset: 128,
+                size: 256,
+                buffer_size: 256,
+            })
+        );
+    }
+
+    #[test]
+    fn small_updates_are_inline_and_large_ones_are_staged() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 128 * 1024);
+        builder
+            .add_update_buffer_pass(BufferOffset { buffer, offset: 0 }, &[7; 64])
+            .unwrap();
+        let buffer_index = builder.get_buffer_index(buffer);
+        let render_graph = builder.build().unwrap();
+        assert!(render_graph.buffer_writes.buffer_writes.is_empty());
+        assert_eq!(
+            only_transfers(&render_graph),
+            [crate::render_graph::Transfer::UpdateBuffer {
+                dst: crate::render_graph::BufferOffset {
+                    buffer: buffer_index,
+                    offset: 0,
+                },
+                data: vec![7; 64],
+            }]
+        );
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 128 * 1024);
+        builder
+            .add_update_buffer_pass(BufferOffset { buffer, offset: 0 }, &[7; 128 * 1024])
+            .unwrap();
+        let buffer_index = builder.get_buffer_index(buffer);
+        let render_graph = builder.build().unwrap();
+        assert_eq!(render_graph.buffer_writes.buffer_writes.len(), 1);
+        assert_eq!(render_graph.buffer_writes.total_write_size, 128 * 1024);
+        let staging_index = render_graph.buffer_writes.buffer_writes[0]
+            .buffer_offset
+            .buffer;
+        assert_ne!(staging_index, buffer_index);
+        assert_eq!(
+            only_transfers(&render_graph),
+            [crate::render_graph::Transfer::BufferToBuffer {
+                src: crate::render_graph::BufferOffset {
+                    buffer: staging_index,
+                    offset: 0,
+                },
+                dst: crate::render_graph::BufferOffset {
+                    buffer: buffer_index,
+                    offset: 0,
+                },
+                copy_size: 128 * 1024,
+            }]
+        );
+    }
+
+    #[test]
+    fn staged_updates_run_in_pass_order() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 128 * 1024);
+        builder.add_compute_pass(
+            "before_update".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[ShaderResourceUsage::StorageBuffer {
+                buffer,
+                write: true,
+            }],
+        );
+        let query_reset = empty_compute_pass(&mut builder, "query_reset");
+        let update = builder
+            .add_update_buffer_pass(BufferOffset { buffer, offset: 0 }, &[7; 128 * 1024])
+            .unwrap();
+        builder.add_execution_dependency(query_reset, update);
+        let render_graph = builder.build().unwrap();
+
+        // The staged copy is recorded after the earlier write and the explicit dependency instead of at frame start
+        let names: Vec<&str> = pass_set_names(&render_graph).concat();
+        assert_eq!(names, ["before_update", "query_reset", "Update Buffer"]);
+    }
+
+    #[test]
+    fn updates_outside_transient_buffers_are_rejected() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 256);
+        assert_eq!(
+            builder.add_update_buffer_pass(
+                BufferOffset {
+                    buffer,
+                    offset: 192
+                },
+                &[7; 128]
+            ),
+            Err(RenderGraphError::UpdateOutOfBounds {
+                buffer,
+                offset: 192,
+                size: 128,
+                buffer_size: 256,
+            })
+        );
+        assert_eq!(
+            builder.add_update_buffer_pass(BufferOffset { buffer, offset: 0 }, &[]),
+            Err(RenderGraphError::EmptyUpdate { buffer })
+        );
+    }
+
+    #[test]
+    fn usage_report_lists_each_resource_with_its_access() {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image(key, vk::ImageUsageFlags::SAMPLED);
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+        let image = ImageHandle::Persistent(key);
+        let buffer = storage_buffer(&mut builder, 256);
+        builder.add_compute_pass(
+            "reduce".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(image),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+                ShaderResourceUsage::StorageBuffer {
+                    buffer,
+                    write: true,
+                },
+            ],
+        );
+        let (image, buffer) = (
+            builder.get_image_index(image),
+            builder.get_buffer_index(buffer),
+        );
+        let report = builder.build().unwrap().usage_report();
+
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].name, "reduce");
+        assert_eq!(report[0].images.len(), 1);
+        assert_eq!(report[0].images[0].image, image);
+        assert_eq!(report[0].images[0].access, ImageResourceAccess::SampledRead);
+        assert_eq!(report[0].buffers.len(), 1);
+        assert_eq!(report[0].buffers[0].buffer, buffer);
+        assert_eq!(
+            report[0].buffers[0].access,
+            BufferResourceAccess::StorageWrite
+        );
+    }
+
+    fn sampled_image_builder() -> (BasicRenderGraphBuilder, ImageHandle) {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image(key, vk::ImageUsageFlags::SAMPLED);
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+        (builder, ImageHandle::Persistent(key))
+    }
+
+    #[test]
+    fn separate_samplers_each_get_a_binding() {
+        let mut sampler_keys = slotmap::SlotMap::<crate::SamplerKey, ()>::with_key();
+        let (linear, nearest) = (
+            crate::SamplerHandle(sampler_keys.insert(())),
+            crate::SamplerHandle(sampler_keys.insert(())),
+        );
+
+        let (mut builder, image) = sampled_image_builder();
+        let mut compute_pass = ComputePassBuilder::new(
+            "two_samplers",
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+        );
+        compute_pass.sample_image(image, linear);
+        compute_pass.read_sampler(nearest);
+        compute_pass.build(&mut builder);
+        let image = builder.get_image_index(image);
+        let render_graph = builder.build().unwrap();
+
+        let Some(RenderPassCommand::Compute { resources, .. }) =
+            &render_graph.command_buffers[0].render_pass_sets[0].render_passes[0].command
+        else {
+            panic!("Expected a compute pass");
+        };
+        use crate::render_graph::ShaderResourceUsage::{SampledImage, Sampler};
+        assert!(matches!(
+            resources.as_slice(),
+            [SampledImage(sampled), Sampler(first), Sampler(second)]
+                if *sampled == image && first.0 == linear.0 && second.0 == nearest.0
+        ));
+    }
+
+    #[test]
+    fn sampled_image_without_a_sampler_is_rejected() {
+        let (mut builder, image) = sampled_image_builder();
+        builder.add_compute_pass(
+            "no_sampler".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[ShaderResourceUsage::SampledImage(image)],
+        );
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::MissingSampler {
+                pass: "no_sampler".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn storage_only_buffer_used_as_vertices_names_the_missing_usage() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = color_image(&mut builder, 1);
+        let vertices = storage_buffer(&mut builder, 256);
+        builder
+            .add_fill_buffer_pass(
+                BufferOffset {
+                    buffer: vertices,
+                    offset: 0,
+                },
+                256,
+                0,
+            )
+            .unwrap();
+
+        let mut raster_pass = RasterPassBuilder::new("draw_vertices");
+        raster_pass.add_color_attachment(image, Some(ClearValue::Float([0.0; 4])));
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.add_vertex_buffer(BufferOffset {
+            buffer: vertices,
+            offset: 0,
+        });
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+
+        let error = builder.build().unwrap_err();
+        assert_eq!(
+            error,
+            RenderGraphError::MissingBufferUsage {
+                buffer: vertices,
+                missing: BufferUsage::VERTEX,
+            }
+        );
+        assert!(error.to_string().contains("VERTEX"));
+    }
+
+    #[test]
+    fn storage_write_then_sample_transitions_between_the_two_bindings() {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image(
+            key,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        );
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+        let image = ImageHandle::Persistent(key);
+        let target = color_image(&mut builder, 1);
+
+        builder.add_compute_pass(
+            "generate".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[ShaderResourceUsage::StorageImage { image, write: true }],
+        );
+        let mut raster_pass = RasterPassBuilder::new("present_generated");
+        raster_pass.add_color_attachment(target, Some(ClearValue::Float([0.0; 4])));
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.read_sampled_image(image);
+        draw.read_sampler(crate::SamplerHandle(Default::default()));
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+
+        let image = builder.get_image_index(image);
+        let report = builder.build().unwrap().usage_report();
+        let usage = |pass: &str| {
+            report
+                .iter()
+                .find(|usage| usage.name == pass)
+                .and_then(|usage| usage.images.iter().find(|usage| usage.image == image))
+                .cloned()
+                .unwrap()
+        };
+
+        assert_eq!(usage("generate").access, ImageResourceAccess::StorageWrite);
+        let sampled = usage("present_generated");
+        assert_eq!(sampled.access, ImageResourceAccess::SampledRead);
+        assert_eq!(
+            sampled.barrier,
+            Some(crate::render_graph::ResolvedBarrier {
+                src: Some(ImageResourceAccess::StorageWrite),
+                dst: ImageResourceAccess::SampledRead,
+            })
+        );
+        assert_eq!(
+            ImageResourceAccess::StorageWrite
+                .get_barrier_flags(true)
+                .layout,
+            vk::ImageLayout::GENERAL
+        );
+        assert_eq!(
+            ImageResourceAccess::SampledRead
+                .get_barrier_flags(true)
+                .layout,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        );
+    }
+
+    fn compiled_raster_pass<'a>(
+        render_graph: &'a CompiledRenderGraph,
+        name: &str,
+    ) -> (
+        &'a crate::render_graph::Framebuffer,
+        &'a [crate::render_graph::RasterDrawCommand],
+    ) {
+        render_graph
+            .command_buffers
+            .iter()
+            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .find(|render_pass| render_pass.label_name == name)
+            .and_then(|render_pass| match &render_pass.command {
+                Some(RenderPassCommand::Raster {
+                    framebuffer,
+                    draw_commands,
+                    ..
+                }) => Some((framebuffer, draw_commands.as_slice())),
+                _ => None,
+            })
+            .expect("Expected a raster pass")
+    }
+
+    #[test]
+    fn fullscreen_draw_has_three_vertices_and_no_vertex_buffers() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = color_image(&mut builder, 1);
+        let mut raster_pass = RasterPassBuilder::new("fullscreen");
+        raster_pass.add_color_attachment(image, Some(ClearValue::Float([0.0; 4])));
+        raster_pass.add_draw_command(crate::render_graph_builder::RasterDrawCommand::fullscreen(
+            RasterPipelineHandle(Default::default()),
+            Vec::new(),
+        ));
+        raster_pass.build(&mut builder);
+        let render_graph = builder.build().unwrap();
+
+        let (_, draw_commands) = compiled_raster_pass(&render_graph, "fullscreen");
+        assert_eq!(draw_commands.len(), 1);
+        assert!(draw_commands[0].vertex_buffers.is_empty());
+        assert_eq!(
+            draw_commands[0].dispatch,
+            crate::render_graph::DrawCommandDispatch::Draw {
+                vertices: 0..3,
+                instances: 0..1,
+            }
+        );
+    }
+
+    fn render_stereo(array_layers: u32) -> Result<CompiledRenderGraph, RenderGraphError> {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image_array(key, vk::ImageUsageFlags::COLOR_ATTACHMENT, array_layers);
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+
+        let mut raster_pass = RasterPassBuilder::new("stereo");
+        raster_pass.add_color_attachment(
+            ImageHandle::Persistent(key),
+            Some(ClearValue::Float([0.0; 4])),
+        );
+        raster_pass.set_view_mask(0b11);
+        raster_pass.build(&mut builder);
+        builder.build()
+    }
+
+    #[test]
+    fn multiview_pass_records_its_view_mask_over_every_layer() {
+        let render_graph = render_stereo(2).unwrap();
+        let (framebuffer, _) = compiled_raster_pass(&render_graph, "stereo");
+        assert_eq!(framebuffer.view_mask, 0b11);
+        assert_eq!(framebuffer.color_attachments[0].subresource.layer_count, 2);
+    }
+
+    #[test]
+    fn multiview_pass_needs_a_layer_per_view() {
+        assert!(matches!(
+            render_stereo(1),
+            Err(RenderGraphError::AttachmentSubresourceOutOfRange {
+                array_layers: 1,
+                ..
+            })
+        ));
+    }
+
+    fn storage_image_writes(read_between: bool) -> Result<CompiledRenderGraph, RenderGraphError> {
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_strict_write_ordering(true);
+        let image = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::STORAGE,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let mut storage_pass = |name: &str, write: bool| {
+            builder.add_compute_pass(
+                name.to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &[ShaderResourceUsage::StorageImage { image, write }],
+            );
+        };
+        storage_pass("first_write", true);
+        if read_between {
+            storage_pass("read", false);
+        }
+        storage_pass("second_write", true);
+        builder.build()
+    }
+
+    #[test]
+    fn overwriting_an_unread_transient_is_reported() {
+        assert_eq!(
+            storage_image_writes(false).unwrap_err(),
+            RenderGraphError::UnorderedWrites {
+                resource: "Transient(0)".to_string(),
+                first_pass: "first_write".to_string(),
+                second_pass: "second_write".to_string(),
+            }
+        );
+        assert!(storage_image_writes(true).is_ok());
+    }
+
+    #[test]
+    fn buffer_reads_are_bounds_checked() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 256);
+        let read = |builder: &mut BasicRenderGraphBuilder, offset: usize, size: usize| {
+            builder.add_buffer_read(
+                BufferOffset { buffer, offset },
+                size,
+                BufferReadCallback::new(|_| {}),
+            )
+        };
+
+        assert!(matches!(
+            read(&mut builder, 192, 128),
+            Err(crate::BufferWriteError::ReadOutOfBounds {
+                offset: 192,
+                size: 128,
+                buffer_size: 256,
+            })
+        ));
+        assert!(read(&mut builder, 128, 128).is_ok());
+        assert_eq!(builder.render_graph.buffer_reads.buffer_reads.len(), 1);
+        assert_eq!(builder.render_graph.buffer_reads.total_read_size, 128);
+    }
+
+    #[test]
+    fn external_signal_semaphores_reach_the_submit_info() {
+        let semaphore = crate::render_graph::ExternalSemaphore {
+            semaphore: <vk::Semaphore as vk::Handle>::from_raw(7),
+            value: Some(3),
+            stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+        };
+        let mut builder = BasicRenderGraphBuilder::new();
+        empty_compute_pass(&mut builder, "pass");
+        builder.add_external_signal_semaphore(semaphore);
+        let render_graph = builder.build().unwrap();
+
+        assert_eq!(render_graph.external_signal_semaphores, [semaphore]);
+        assert!(render_graph.external_wait_semaphores.is_empty());
+        let submit_info = render_graph.external_signal_semaphores[0].submit_info();
+        assert_eq!(submit_info.semaphore, semaphore.semaphore);
+        assert_eq!(submit_info.value, 3);
+        assert_eq!(
+            submit_info.stage_mask,
+            vk::PipelineStageFlags2::ALL_COMMANDS
+        );
+    }
+
+    fn depth_image(builder: &mut BasicRenderGraphBuilder) -> ImageHandle {
+        builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::D32_SFLOAT,
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        })
+    }
+
+    #[test]
+    fn rendered_depth_moves_to_a_read_only_layout_for_sampling() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let depth = depth_image(&mut builder);
+        let mut raster_pass = RasterPassBuilder::new("depth_prepass");
+        raster_pass.add_depth_stencil_attachment(depth, Some(ClearValue::DepthStencil(1.0, 0)));
+        raster_pass.build(&mut builder);
+        builder.add_compute_pass(
+            "read_depth".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(depth),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+            ],
+        );
+        let depth = builder.get_image_index(depth);
+        let report = builder.build().unwrap().usage_report();
+
+        let sampled = report
+            .iter()
+            .find(|usage| usage.name == "read_depth")
+            .and_then(|usage| usage.images.iter().find(|usage| usage.image == depth))
+            .unwrap();
+        assert_eq!(
+            sampled.barrier,
+            Some(crate::render_graph::ResolvedBarrier {
+                src: Some(ImageResourceAccess::AttachmentWrite),
+                dst: ImageResourceAccess::SampledRead,
+            })
+        );
+        assert_eq!(
+            ImageResourceAccess::AttachmentWrite
+                .get_barrier_flags(false)
+                .layout,
+            vk::ImageLayout::ATTACHMENT_OPTIMAL
+        );
+        assert_eq!(
+            ImageResourceAccess::SampledRead
+                .get_barrier_flags(false)
+                .layout,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        );
+    }
+
+    #[test]
+    fn sampling_the_depth_attachment_being_rendered_is_rejected() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let depth = depth_image(&mut builder);
+        let mut raster_pass = RasterPassBuilder::new("feedback");
+        raster_pass.add_depth_stencil_attachment(depth, Some(ClearValue::DepthStencil(1.0, 0)));
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.read_sampled_image(depth);
+        draw.read_sampler(crate::SamplerHandle(Default::default()));
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::SampledAttachment {
+                pass: "feedback".to_string(),
+                image: depth,
+            }
+        );
+    }
+
+    #[test]
+    fn acceleration_structure_builds_read_their_inputs_and_write_their_storage() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let vertices = builder.create_transient_buffer(
+            1024,
+            BufferUsage::ACCELERATION_STRUCTURE_INPUT | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        );
+        builder
+            .add_fill_buffer_pass(
+                BufferOffset {
+                    buffer: vertices,
+                    offset: 0,
+                },
+                1024,
+                0,
+            )
+            .unwrap();
+        let storage = builder.create_transient_buffer(
+            4096,
+            BufferUsage::ACCELERATION_STRUCTURE_STORAGE,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        );
+        builder.add_custom_pass(
+            "build_blas".to_string(),
+            [0.0; 4],
+            &[
+                (vertices, BufferResourceAccess::AccelStructBuildRead),
+                (storage, BufferResourceAccess::AccelStructBuildWrite),
+            ],
+            &[],
+            CustomCommand::new(|_, _, _| {}),
+        );
+        let (vertices, storage) = (
+            builder.get_buffer_index(vertices),
+            builder.get_buffer_index(storage),
+        );
+        assert!(BufferUsage::ACCELERATION_STRUCTURE_INPUT
+            .to_vk()
+            .contains(vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR));
+
+        let report = builder.build().unwrap().usage_report();
+        assert_eq!(report[1].name, "build_blas");
+        assert_eq!(report[1].buffers.len(), 2);
+        for buffer in &report[1].buffers {
+            let expected = if buffer.buffer == vertices {
+                BufferResourceAccess::AccelStructBuildRead
+            } else {
+                assert_eq!(buffer.buffer, storage);
+                BufferResourceAccess::AccelStructBuildWrite
+            };
+            assert_eq!(buffer.access, expected);
+            assert_eq!(
+                buffer.access.get_barrier_flags().stage_mask,
+                vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR
+            );
+        }
+    }
+
+    #[test]
+    fn acceleration_structure_inputs_need_the_input_usage() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let vertices = storage_buffer(&mut builder, 1024);
+        builder
+            .add_fill_buffer_pass(
+                BufferOffset {
+                    buffer: vertices,
+                    offset: 0,
+                },
+                1024,
+                0,
+            )
+            .unwrap();
+        builder.add_custom_pass(
+            "build_blas".to_string(),
+            [0.0; 4],
+            &[(vertices, BufferResourceAccess::AccelStructBuildRead)],
+            &[],
+            CustomCommand::new(|_, _, _| {}),
+        );
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::MissingBufferUsage {
+                buffer: vertices,
+                missing: BufferUsage::ACCELERATION_STRUCTURE_INPUT,
+            }
+        );
+    }
+
+    #[test]
+    fn image_copies_target_the_requested_mip_and_layer() {
+        let key = slotmap::SlotMap::<crate::ImageKey, ()>::with_key().insert(());
+        let mut live_resources = LiveResources::default();
+        live_resources.insert_image(key, vk::ImageUsageFlags::TRANSFER_SRC);
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(live_resources);
+        let mips = ImageHandle::Persistent(key);
+        let target = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 32,
+                height: 32,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::TRANSFER_DST,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let mut copy = TransferPassBuilder::new("copy_mip", QueueType::Graphics);
+        copy.copy_image_to_image(
+            crate::render_graph_builder::ImageCopyImage {
+                image: mips,
+                offset: [0; 3],
+                mip_level: 1,
+                base_array_layer: 0,
+            },
+            crate::render_graph_builder::ImageCopyImage {
+                image: target,
+                offset: [0; 3],
+                mip_level: 0,
+                base_array_layer: 0,
+            },
+            [32, 32, 1],
+        );
+        copy.build(&mut builder);
+        let render_graph = builder.build().unwrap();
+
+        let crate::render_graph::Transfer::ImageToImage { src, dst, .. } =
+            &only_transfers(&render_graph)[0]
+        else {
+            panic!("Expected an image to image copy");
+        };
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let src_layers = src.subresource_layers(format);
+        assert_eq!(src_layers.aspect_mask, vk::ImageAspectFlags::COLOR);
+        assert_eq!(src_layers.mip_level, 1);
+        assert_eq!(src_layers.base_array_layer, 0);
+        assert_eq!(src_layers.layer_count, 1);
+        assert_eq!(dst.subresource_layers(format).mip_level, 0);
+    }
+
+    #[test]
+    fn render_to_texture_output_can_be_sampled_by_a_later_pass() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let texture = builder.render_to_texture(
+            "offscreen",
+            TransientImageSize::Exact(vk::Extent2D {
+                width: 128,
+                height: 128,
+            }),
+            vk::Format::R16G16B16A16_SFLOAT,
+            Some(vk::Format::D32_SFLOAT),
+            |_| {},
+        );
+        builder.add_compute_pass(
+            "sample".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(texture),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+            ],
+        );
+        let texture = builder.get_image_index(texture);
+        let render_graph = builder.build().unwrap();
+
+        let ImageResourceDescription::Transient(description) =
+            &render_graph.image_resources[texture].description
+        else {
+            panic!("Expected a transient image");
+        };
+        assert_eq!(description.format, vk::Format::R16G16B16A16_SFLOAT);
+        assert!(description.usage.contains(vk::ImageUsageFlags::SAMPLED));
+
+        let report = render_graph.usage_report();
+        assert_eq!(report[0].name, "offscreen");
+        assert_eq!(report[0].images.len(), 2);
+        assert_eq!(report[1].name, "sample");
+        let sampled = &report[1].images[0];
+        assert_eq!(sampled.image, texture);
+        let barrier = sampled.barrier.as_ref().unwrap();
+        assert_eq!(barrier.src, Some(ImageResourceAccess::AttachmentWrite));
+        assert_eq!(barrier.dst, ImageResourceAccess::SampledRead);
+    }
+
+    #[test]
+    fn passes_on_distinct_resources_share_a_barrier_batch_until_a_dependency() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffers: Vec<BufferHandle> =
+            (0..3).map(|_| storage_buffer(&mut builder, 256)).collect();
+        let mut storage_pass = |name: &str, buffer, write| {
+            builder.add_compute_pass(
+                name.to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &[ShaderResourceUsage::StorageBuffer { buffer, write }],
+            );
+        };
+        storage_pass("write_a", buffers[0], true);
+        storage_pass("write_b", buffers[1], true);
+        storage_pass("write_c", buffers[2], true);
+        storage_pass("read_a", buffers[0], false);
+        let read_buffer = builder.get_buffer_index(buffers[0]);
+        let render_graph = builder.build().unwrap();
+
+        assert_eq!(
+            pass_set_names(&render_graph),
+            vec![vec!["write_a", "write_b", "write_c"], vec!["read_a"]]
+        );
+        let buffer_barriers = &render_graph.command_buffers[0].render_pass_sets[1].buffer_barriers;
+        assert_eq!(buffer_barriers.len(), 1);
+        assert_eq!(buffer_barriers[0].index, read_buffer);
+        assert_eq!(buffer_barriers[0].dst, BufferResourceAccess::StorageRead);
+    }
+
+    #[test]
+    fn volume_uploads_keep_their_depth_and_can_be_sampled() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let lut = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Volume([16, 16, 16]),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let texels = builder.create_transient_buffer(
+            16 * 16 * 16 * 4,
+            BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        builder
+            .add_fill_buffer_pass(
+                BufferOffset {
+                    buffer: texels,
+                    offset: 0,
+                },
+                16 * 16 * 16 * 4,
+                0,
+            )
+            .unwrap();
+        let mut upload = TransferPassBuilder::new("upload_lut", QueueType::Graphics);
+        upload.copy_buffer_to_image(
+            crate::render_graph_builder::ImageCopyBuffer {
+                buffer: texels,
+                offset: 0,
+                row_length: None,
+                row_height: None,
+            },
+            crate::render_graph_builder::ImageCopyImage {
+                image: lut,
+                offset: [0; 3],
+                mip_level: 0,
+                base_array_layer: 0,
+            },
+            [16, 16, 16],
+        );
+        upload.build(&mut builder);
+        builder.add_compute_pass(
+            "grade".to_string(),
+            [0.0; 4],
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(lut),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+            ],
+        );
+        let lut = builder.get_image_index(lut);
+        let render_graph = builder.build().unwrap();
+
+        let upload = render_graph.command_buffers[0]
+            .render_pass_sets
+            .iter()
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .find(|render_pass| render_pass.label_name == "upload_lut")
+            .unwrap();
+        let Some(RenderPassCommand::Transfer { transfers }) = &upload.command else {
+            panic!("Expected a transfer pass");
+        };
+        assert!(matches!(
+            transfers[0],
+            crate::render_graph::Transfer::BufferToImage {
+                copy_size: [16, 16, 16],
+                ..
+            }
+        ));
+
+        let report = render_graph.usage_report();
+        let grade = report.iter().find(|pass| pass.name == "grade").unwrap();
+        assert_eq!(grade.images[0].image, lut);
+        let barrier = grade.images[0].barrier.as_ref().unwrap();
+        assert_eq!(barrier.src, Some(ImageResourceAccess::TransferWrite));
+        assert_eq!(barrier.dst, ImageResourceAccess::SampledRead);
+    }
+
+    #[test]
+    fn only_transients_with_disjoint_lifetimes_are_reported_as_aliasable() {
+        use crate::render_graph::TransientResource;
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        let [a, b, c, d] = [(); 4].map(|_| storage_buffer(&mut builder, 256));
+        let mut storage_pass = |name: &str, usages: &[(BufferHandle, bool)]| {
+            let usages: Vec<ShaderResourceUsage> = usages
+                .iter()
+                .map(|&(buffer, write)| ShaderResourceUsage::StorageBuffer { buffer, write })
+                .collect();
+            builder.add_compute_pass(
+                name.to_string(),
+                [0.0; 4],
+                QueueType::Graphics,
+                ComputePipelineHandle(Default::default()),
+                ComputeDispatch::Size([1, 1, 1]),
+                &usages,
+            );
+        };
+        // a and b are used in passes 0-1 and 2-3, c and d overlap in passes 0-3 and 1-2
+        storage_pass("first", &[(a, true), (c, true)]);
+        storage_pass("second", &[(a, false), (d, true)]);
+        storage_pass("third", &[(b, true), (d, false)]);
+        storage_pass("fourth", &[(b, false), (c, false)]);
+        let [a, b] =
+            [a, b].map(|buffer| TransientResource::Buffer(builder.get_buffer_index(buffer)));
+
+        let report = builder.build().unwrap().aliasing_report();
+        assert_eq!(report.intervals.len(), 4);
+        assert_eq!(report.aliasing_groups, vec![vec![a, b]]);
+    }
+
+    #[test]
+    fn passes_using_a_destroyed_buffer_fail_to_build() {
+        let mut buffer_keys = slotmap::SlotMap::<crate::BufferKey, ()>::with_key();
+        let buffer_key = buffer_keys.insert(());
+        buffer_keys.remove(buffer_key);
+        let buffer = BufferHandle::Persistent(buffer_key);
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        builder.set_live_resources(LiveResources::default());
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 16, 0)
+            .unwrap();
+
+        let error = builder.build().unwrap_err();
+        assert_eq!(
+            error,
+            RenderGraphError::StaleHandle {
+                resource: format!("{:?}", buffer),
+            }
+        );
+        assert!(error.to_string().contains("Persistent"));
+    }
+
+    #[test]
+    fn only_persistent_images_can_be_released() {
+        let (mut builder, image) = sampled_image_builder();
+        let mut compute_pass = ComputePassBuilder::new(
+            "sample",
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+        );
+        compute_pass.sample_image(image, crate::SamplerHandle(Default::default()));
+        compute_pass.build(&mut builder);
+        builder.set_image_release_access(image, ImageResourceAccess::AttachmentWrite);
+        let image_index = builder.get_image_index(image);
+
+        let render_graph = builder.build().unwrap();
+        assert_eq!(
+            render_graph.image_resources[image_index].release_access,
+            Some(ImageResourceAccess::AttachmentWrite)
+        );
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        let transient = color_image(&mut builder, 1);
+        builder.set_image_release_access(transient, ImageResourceAccess::AttachmentWrite);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::NonPersistentRelease { image: transient }
+        );
+    }
+
+    #[test]
+    fn former_panics_return_errors() {
+        let foreign_buffer = BufferHandle::Transient(5);
+        let mut builder = BasicRenderGraphBuilder::new();
+        let mut compute_pass = ComputePassBuilder::new(
+            "foreign_buffer",
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+        );
+        compute_pass.write_buffer(foreign_buffer);
+        compute_pass.build(&mut builder);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::InvalidBufferHandle {
+                buffer: foreign_buffer
+            }
+        );
+
+        let foreign_image = ImageHandle::Transient(3);
+        let mut builder = BasicRenderGraphBuilder::new();
+        let mut raster_pass = RasterPassBuilder::new("foreign_target");
+        raster_pass.add_color_attachment(foreign_image, Some(ClearValue::Float([0.0; 4])));
+        raster_pass.build(&mut builder);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::InvalidImageHandle {
+                image: foreign_image
+            }
+        );
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        let buffer = storage_buffer(&mut builder, 64);
+        let mut compute_pass = ComputePassBuilder::new(
+            "read_before_write",
+            QueueType::Graphics,
+            ComputePipelineHandle(Default::default()),
+        );
+        compute_pass.read_buffer(buffer);
+        compute_pass.build(&mut builder);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::UninitializedRead {
+                pass: "read_before_write".to_string(),
+                resource: format!("{:?}", buffer),
+            }
+        );
+
+        let mut raster_pass = RasterPassBuilder::new("no_dispatch");
+        assert_eq!(
+            RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()))
+                .build(&mut raster_pass),
+            Err(RenderGraphError::MissingDrawDispatch {
+                pass: "no_dispatch".to_string(),
+                draw_index: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn depth_only_passes_render_into_a_d32_target() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let shadow_map = builder.render_depth_to_texture(
+            "shadow_map",
+            TransientImageSize::Exact(vk::Extent2D {
+                width: 1024,
+                height: 1024,
+            }),
+            vk::Format::D32_SFLOAT,
+            |raster_pass| {
+                let mut draw =
+                    RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+                draw.draw(0..3, 0..1);
+                draw.build(raster_pass).unwrap();
+            },
+        );
+        let shadow_map = builder.get_image_index(shadow_map);
+
+        let render_graph = builder.build().unwrap();
+        let render_pass = &render_graph.command_buffers[0].render_pass_sets[0].render_passes[0];
+        let Some(RenderPassCommand::Raster { framebuffer, .. }) = &render_pass.command else {
+            panic!("Expected a raster pass");
+        };
+        assert!(framebuffer.color_attachments.is_empty());
+        assert_eq!(
+            framebuffer.depth_stencil_attachment.unwrap().image,
+            shadow_map
+        );
+        let ImageResourceDescription::Transient(description) =
+            &render_graph.image_resources[shadow_map].description
+        else {
+            panic!("Expected a transient image");
+        };
+        assert_eq!(description.format, vk::Format::D32_SFLOAT);
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        RasterPassBuilder::new("no_attachments").build(&mut builder);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            RenderGraphError::NoAttachments {
+                pass: "no_attachments".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn swapchain_readback_copies_the_presented_image_last() {
+        let mut surface_keys = slotmap::SlotMap::<crate::SurfaceKey, ()>::with_key();
+        let mut builder = BasicRenderGraphBuilder::new();
+        let swapchain_image =
+            builder.acquire_swapchain_image(SurfaceHandle(surface_keys.insert(())));
+        let mut raster_pass = RasterPassBuilder::new("clear_pass");
+        raster_pass.add_color_attachment(swapchain_image, Some(ClearValue::Float([0.0; 4])));
+        raster_pass.build(&mut builder);
+        let readback = builder.create_transient_buffer(
+            64 * 32 * 4,
+            BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::GpuToCpu,
+        );
+        builder.add_swapchain_readback(
+            swapchain_image,
+            [64, 32],
+            BufferOffset {
+                buffer: readback,
+                offset: 0,
+            },
+        );
+        let render_graph = builder.build().unwrap();
+
+        let command_buffer = &render_graph.command_buffers[0];
+        let last_pass = command_buffer
+            .render_pass_sets
+            .iter()
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .last()
+            .unwrap();
+        assert_eq!(last_pass.label_name, "Swapchain Readback");
+        let Some(RenderPassCommand::Transfer { transfers }) = &last_pass.command else {
+            panic!("Expected a transfer pass");
+        };
+        let [crate::render_graph::Transfer::ImageToBuffer {
+            src,
+            dst,
+            copy_size,
+        }] = transfers.as_slice()
+        else {
+            panic!("Expected a single image to buffer copy");
+        };
+        assert_eq!(src.image, render_graph.swapchain_images[0].1);
+        assert_eq!(dst.buffer, 0);
+        assert_eq!(*copy_size, [64, 32, 1]);
+
+        // The present transition starts from the readback's access rather than the clear's
+        assert!(command_buffer
+            .command_buffer_signal_dependencies
+            .iter()
+            .any(|dependency| matches!(
+                dependency,
+                CommandBufferDependency::Swapchain {
+                    index: 0,
+                    access: ImageResourceAccess::TransferRead,
+                }
+            )));
+    }
+
+    /// Counts this thread's heap allocations, so tests running in parallel don't affect each other
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count_allocation() {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            count_allocation();
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+            count_allocation();
+            std::alloc::System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            count_allocation();
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Heap allocations made while building each frame's graph, the arena is reset between frames like the executor does
+    fn allocations_per_frame(arena: &FrameArena, frames: usize) -> Vec<usize> {
+        (0..frames)
+            .map(|_| {
+                arena.reset();
+                let before = ALLOCATIONS.with(|allocations| allocations.get());
+
+                let mut builder = BasicRenderGraphBuilder::new();
+                builder.set_frame_arena(arena.clone());
+                let buffer = storage_buffer(&mut builder, 256);
+                builder
+                    .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 256, 0)
+                    .unwrap();
+                for name in ["simulate", "cull", "sort"] {
+                    builder.add_compute_pass(
+                        name.to_string(),
+                        [0.0; 4],
+                        QueueType::Graphics,
+                        ComputePipelineHandle(Default::default()),
+                        ComputeDispatch::Size([1, 1, 1]),
+                        &[ShaderResourceUsage::StorageBuffer {
+                            buffer,
+                            write: true,
+                        }],
+                    );
+                }
+                let image = color_image(&mut builder, 1);
+                let mut raster_pass = RasterPassBuilder::new("draw");
+                raster_pass.add_color_attachment(image, Some(ClearValue::Float([0.0; 4])));
+                for _ in 0..4 {
+                    let mut draw =
+                        RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+                    draw.read_buffer(buffer);
+                    draw.draw(0..3, 0..1);
+                    draw.build(&mut raster_pass).unwrap();
+                }
+                raster_pass.build(&mut builder);
+                drop(builder.build().unwrap());
+
+                ALLOCATIONS.with(|allocations| allocations.get()) - before
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_arena_bounds_allocations_per_frame() {
+        const WARM_UP_FRAMES: usize = 3;
+        let with_arena = allocations_per_frame(&FrameArena::new(true), 10);
+        let without_arena = allocations_per_frame(&FrameArena::new(false), 10);
+
+        // Once the arena has grown to fit a frame, later frames allocate no more than the first warm frame
+        let warm_allocations = with_arena[WARM_UP_FRAMES];
+        assert!(
+            with_arena[WARM_UP_FRAMES..]
+                .iter()
+                .all(|&allocations| allocations <= warm_allocations),
+            "{:?}",
+            with_arena
+        );
+        assert!(
+            warm_allocations < without_arena[WARM_UP_FRAMES],
+            "with arena {:?}, without {:?}",
+            with_arena,
+            without_arena
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use crate::descriptor_set::{DescriptorBinding, GpuBindingIndex};
 use crate::device::AshDevice;
-use crate::{BufferWriteError, VulkanError};
+use crate::resource_managers::MappedSlice;
+use crate::{AllocationScheme, BufferWriteError, VulkanError};
 use ash::vk;
 use bitflags::bitflags;
 use std::sync::Arc;
@@ -16,10 +17,21 @@ bitflags! {
         const STORAGE = 1 << 3;
         const INDIRECT  = 1 << 4;
         const TRANSFER = 1 << 5;
+        /// Geometry and instance inputs of an acceleration structure build
+        const ACCELERATION_STRUCTURE_INPUT = 1 << 6;
+        /// Acceleration structure storage and build scratch memory
+        const ACCELERATION_STRUCTURE_STORAGE = 1 << 7;
     }
 }
 
 impl BufferUsage {
+    /// Flag names joined with " | ", used in error messages
+    pub fn names(&self) -> String {
+        let mut names = String::new();
+        let _ = bitflags::parser::to_writer(self, &mut names);
+        names
+    }
+
     pub(crate) fn to_vk(&self) -> vk::BufferUsageFlags {
         //Needed to keep clippy from complaining about contains function
         #[allow(unused)]
@@ -51,6 +63,17 @@ impl BufferUsage {
             vk_usage |= vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
         }
 
+        if self.contains(BufferUsage::ACCELERATION_STRUCTURE_INPUT) {
+            vk_usage |= vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+
+        if self.contains(BufferUsage::ACCELERATION_STRUCTURE_STORAGE) {
+            vk_usage |= vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+
         vk_usage
     }
 }
@@ -75,13 +98,25 @@ pub struct BufferDescription2 {
     pub per_frame_memory_mapped: bool,
 }
 
+/// Size and placement of a buffer's memory, the allocator may round the requested size up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferAllocationInfo {
+    pub size: vk::DeviceSize,
+    pub allocated_size: vk::DeviceSize,
+    pub alignment: vk::DeviceSize,
+}
+
 pub struct Buffer {
     pub device: Arc<AshDevice>,
+    #[cfg(feature = "track-resources")]
+    pub name: String,
     pub handle: vk::Buffer,
     pub allocation: gpu_allocator::vulkan::Allocation,
+    pub alignment: vk::DeviceSize,
     pub size: vk::DeviceSize,
     pub usage: vk::BufferUsageFlags,
     pub location: gpu_allocator::MemoryLocation,
+    pub allocation_scheme: AllocationScheme,
     pub storage_binding: Option<DescriptorBinding>,
 }
 
@@ -92,6 +127,20 @@ impl Buffer {
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
         location: gpu_allocator::MemoryLocation,
+        allocation_scheme: AllocationScheme,
+    ) -> Result<Self, VulkanError> {
+        Self::new_preferring_memory_types(device, name, size, usage, location, 0, allocation_scheme)
+    }
+
+    /// Allocates from preferred_memory_types when the buffer can use any of them, otherwise from location
+    pub fn new_preferring_memory_types(
+        device: Arc<AshDevice>,
+        name: &str,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: gpu_allocator::MemoryLocation,
+        preferred_memory_types: u32,
+        allocation_scheme: AllocationScheme,
     ) -> Result<Self, VulkanError> {
         let handle = unsafe {
             device.core.create_buffer(
@@ -108,14 +157,16 @@ impl Buffer {
         }
 
         let requirements = unsafe { device.core.get_buffer_memory_requirements(handle) };
+        let (allocation_requirements, allocation_location) =
+            memory_preference(requirements, location, preferred_memory_types);
 
         let allocation = match device.allocator.lock().unwrap().allocate(
             &gpu_allocator::vulkan::AllocationCreateDesc {
                 name,
-                requirements,
-                location,
+                requirements: allocation_requirements,
+                location: allocation_location,
                 linear: true,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                allocation_scheme: allocation_scheme.for_buffer(handle),
             },
         ) {
             Ok(allocation) => allocation,
@@ -139,11 +190,15 @@ impl Buffer {
 
         Ok(Self {
             device,
+            #[cfg(feature = "track-resources")]
+            name: name.to_string(),
             handle,
             allocation,
+            alignment: requirements.alignment,
             size,
             usage,
             location,
+            allocation_scheme,
             storage_binding: None,
         })
     }
@@ -152,6 +207,26 @@ impl Buffer {
         self.allocation.mapped_slice().is_some()
     }
 
+    /// Must be called after writing to mapped memory, makes the writes visible if the memory isn't coherent
+    pub fn flush_mapped_range(&self, range: std::ops::Range<usize>) -> Result<(), VulkanError> {
+        if let Some(mapped_slice) = MappedSlice::new(&self.allocation) {
+            mapped_slice.flush(&self.device, range)?;
+        }
+        Ok(())
+    }
+
+    pub fn allocated_size(&self) -> vk::DeviceSize {
+        self.allocation.size()
+    }
+
+    pub fn alignment(&self) -> vk::DeviceSize {
+        self.alignment
+    }
+
+    pub fn allocation_info(&self) -> BufferAllocationInfo {
+        allocation_info(self.size, self.allocated_size(), self.alignment())
+    }
+
     pub fn get_copy(&self) -> AshBuffer {
         AshBuffer {
             handle: self.handle,
@@ -163,6 +238,39 @@ impl Buffer {
     }
 }
 
+/// Requested size next to the size the allocator actually reserved for it
+fn allocation_info(
+    size: vk::DeviceSize,
+    allocated_size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+) -> BufferAllocationInfo {
+    BufferAllocationInfo {
+        size,
+        allocated_size,
+        alignment,
+    }
+}
+
+/// Restricts the requirements to the preferred memory types the buffer supports,
+/// allocating from them with the Unknown location so gpu_allocator doesn't require other property flags
+pub(crate) fn memory_preference(
+    requirements: vk::MemoryRequirements,
+    location: gpu_allocator::MemoryLocation,
+    preferred_memory_types: u32,
+) -> (vk::MemoryRequirements, gpu_allocator::MemoryLocation) {
+    let memory_type_bits = requirements.memory_type_bits & preferred_memory_types;
+    if memory_type_bits == 0 {
+        return (requirements, location);
+    }
+    (
+        vk::MemoryRequirements {
+            memory_type_bits,
+            ..requirements
+        },
+        gpu_allocator::MemoryLocation::Unknown,
+    )
+}
+
 impl Drop for Buffer {
     fn drop(&mut self) {
         unsafe {
@@ -185,3 +293,21 @@ pub struct AshBuffer {
     pub location: gpu_allocator::MemoryLocation,
     pub storage_binding: Option<GpuBindingIndex>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation_info_reports_the_requested_and_allocated_sizes() {
+        // The allocator rounds 1000 bytes up to the 256 byte alignment
+        assert_eq!(
+            allocation_info(1000, 1024, 256),
+            BufferAllocationInfo {
+                size: 1000,
+                allocated_size: 1024,
+                alignment: 256,
+            }
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::uniform_ring::align_up;
+use crate::BufferHandle;
+use std::ops::Range;
+
+/// Range of a [`BufferSuballocator`]'s buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferSubAllocation {
+    pub buffer: BufferHandle,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Hands out aligned ranges of a single large GpuOnly buffer, so many meshes can share one buffer object.
+/// Freed ranges are merged with their neighbours and reused first fit.
+pub struct BufferSuballocator {
+    pub(crate) buffer: BufferHandle,
+    pub(crate) size: u64,
+    pub(crate) alignment: u64,
+
+    /// Sorted by offset, never adjacent to each other
+    free_ranges: Vec<Range<u64>>,
+}
+
+impl BufferSuballocator {
+    pub(crate) fn new(buffer: BufferHandle, size: u64, alignment: u64) -> Self {
+        Self {
+            buffer,
+            size,
+            alignment,
+            free_ranges: std::iter::once(0..size).collect(),
+        }
+    }
+
+    pub fn buffer(&self) -> BufferHandle {
+        self.buffer
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// Returns None if no free range is large enough
+    pub fn allocate(&mut self, size: u64) -> Option<BufferSubAllocation> {
+        let size = align_up(size.max(1), self.alignment);
+        let index = self
+            .free_ranges
+            .iter()
+            .position(|range| range.end - range.start >= size)?;
+
+        let range = &mut self.free_ranges[index];
+        let offset = range.start;
+        range.start += size;
+        if range.is_empty() {
+            self.free_ranges.remove(index);
+        }
+
+        Some(BufferSubAllocation {
+            buffer: self.buffer,
+            offset,
+            size,
+        })
+    }
+
+    /// The range must no longer be in use by the gpu, i.e. the frames that used it have finished
+    pub fn free(&mut self, allocation: BufferSubAllocation) {
+        debug_assert_eq!(allocation.buffer, self.buffer);
+        let freed = allocation.offset..allocation.offset + allocation.size;
+        let index = self
+            .free_ranges
+            .partition_point(|range| range.start < freed.start);
+
+        let merges_next = self
+            .free_ranges
+            .get(index)
+            .is_some_and(|next| next.start == freed.end);
+        let merges_previous = index > 0 && self.free_ranges[index - 1].end == freed.start;
+
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                let next_end = self.free_ranges.remove(index).end;
+                self.free_ranges[index - 1].end = next_end;
+            }
+            (true, false) => self.free_ranges[index - 1].end = freed.end,
+            (false, true) => self.free_ranges[index].start = freed.start,
+            (false, false) => self.free_ranges.insert(index, freed),
+        }
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    pub fn largest_free_range(&self) -> u64 {
+        self.free_ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// 0.0 when all free space is contiguous, approaching 1.0 as it gets split into small ranges
+    pub fn fragmentation(&self) -> f32 {
+        let free_bytes = self.free_bytes();
+        if free_bytes == 0 {
+            0.0
+        } else {
+            1.0 - (self.largest_free_range() as f64 / free_bytes as f64) as f32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_ranges_are_reused_and_stay_aligned() {
+        let mut suballocator = BufferSuballocator::new(BufferHandle::Transient(0), 1024, 256);
+
+        let allocations: Vec<BufferSubAllocation> = [100, 256, 300]
+            .into_iter()
+            .map(|size| suballocator.allocate(size).expect("should fit"))
+            .collect();
+        let offsets: Vec<u64> = allocations
+            .iter()
+            .map(|allocation| allocation.offset)
+            .collect();
+        assert_eq!(offsets, [0, 256, 512]);
+        assert_eq!(allocations[2].size, 512);
+        assert_eq!(suballocator.allocate(1), None, "buffer should be full");
+
+        suballocator.free(allocations[0]);
+        suballocator.free(allocations[2]);
+        assert_eq!(suballocator.free_bytes(), 768);
+        assert_eq!(suballocator.largest_free_range(), 512);
+        assert!((suballocator.fragmentation() - 1.0 / 3.0).abs() < 1e-6);
+
+        let reused = suballocator
+            .allocate(10)
+            .expect("should reuse the first range");
+        assert_eq!(reused.offset, 0);
+
+        suballocator.free(reused);
+        suballocator.free(allocations[1]);
+        assert_eq!(suballocator.free_bytes(), 1024);
+        assert_eq!(suballocator.fragmentation(), 0.0);
+        assert_eq!(
+            suballocator
+                .allocate(1024)
+                .map(|allocation| allocation.offset),
+            Some(0)
+        );
+    }
+}
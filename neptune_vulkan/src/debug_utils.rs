@@ -1,36 +1,148 @@
 use ash::vk;
 use ash::vk::DebugUtilsObjectNameInfoEXT;
 use std::ffi::{CStr, CString};
+use std::sync::Arc;
 
 use log::{error, info, trace, warn};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ValidationSeverity {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+impl ValidationSeverity {
+    fn from_vk(severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            Self::Error
+        } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+            Self::Warning
+        } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+            Self::Info
+        } else {
+            Self::Verbose
+        }
+    }
+
+    /// All vulkan severity flags at or above this severity
+    fn to_vk_filter(self) -> vk::DebugUtilsMessageSeverityFlagsEXT {
+        [
+            (
+                Self::Verbose,
+                vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+            ),
+            (Self::Info, vk::DebugUtilsMessageSeverityFlagsEXT::INFO),
+            (
+                Self::Warning,
+                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+            ),
+            (Self::Error, vk::DebugUtilsMessageSeverityFlagsEXT::ERROR),
+        ]
+        .iter()
+        .filter(|(severity, _)| *severity >= self)
+        .fold(
+            vk::DebugUtilsMessageSeverityFlagsEXT::empty(),
+            |flags, (_, flag)| flags | *flag,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValidationMessageType {
+    General,
+    Validation,
+    Performance,
+}
+
+impl ValidationMessageType {
+    fn from_vk(message_type: vk::DebugUtilsMessageTypeFlagsEXT) -> Self {
+        if message_type.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) {
+            Self::Validation
+        } else if message_type.contains(vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE) {
+            Self::Performance
+        } else {
+            Self::General
+        }
+    }
+}
+
+pub type ValidationCallback =
+    Arc<dyn Fn(ValidationSeverity, ValidationMessageType, &str) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ValidationSettings {
+    pub enabled: bool,
+    /// Messages below this severity are dropped
+    pub min_severity: ValidationSeverity,
+    /// Receives every message that passes the severity filter, messages go to `log` when None
+    pub callback: Option<ValidationCallback>,
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_severity: ValidationSeverity::Verbose,
+            callback: None,
+        }
+    }
+}
+
+impl ValidationSettings {
+    /// Returns true if the message passed the severity filter
+    pub fn dispatch(
+        &self,
+        severity: ValidationSeverity,
+        message_type: ValidationMessageType,
+        message: &str,
+    ) -> bool {
+        if severity < self.min_severity {
+            return false;
+        }
+
+        match &self.callback {
+            Some(callback) => callback(severity, message_type, message),
+            None => match severity {
+                ValidationSeverity::Verbose => trace!("{:?}", message),
+                ValidationSeverity::Info => info!("{:?}", message),
+                ValidationSeverity::Warning => warn!("{:?}", message),
+                ValidationSeverity::Error => error!("{:?}", message),
+            },
+        }
+        true
+    }
+}
+
 #[allow(dead_code)]
 pub struct DebugUtils {
     debug_utils: ash::extensions::ext::DebugUtils,
     debug_call_back: vk::DebugUtilsMessengerEXT,
+
+    // Boxed so the pointer passed to the messenger as user data stays valid
+    settings: Box<ValidationSettings>,
 }
 
 impl DebugUtils {
     pub(crate) fn new(
         entry: &ash::Entry,
         instance: &ash::Instance,
+        settings: ValidationSettings,
     ) -> ash::prelude::VkResult<Self> {
+        let settings = Box::new(settings);
         let debug_utils_loader = ash::extensions::ext::DebugUtils::new(entry, instance);
         let debug_call_back = unsafe {
             debug_utils_loader.create_debug_utils_messenger(
                 &vk::DebugUtilsMessengerCreateInfoEXT::builder()
-                    .message_severity(
-                        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-                            | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
-                            | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
-                            | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
-                    )
+                    .message_severity(settings.min_severity.to_vk_filter())
                     .message_type(
                         vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
                             | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
                             | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
                     )
-                    .pfn_user_callback(Some(vulkan_debug_callback)),
+                    .pfn_user_callback(Some(vulkan_debug_callback))
+                    .user_data(settings.as_ref() as *const ValidationSettings as *mut _),
                 None,
             )?
         };
@@ -38,6 +150,7 @@ impl DebugUtils {
         Ok(Self {
             debug_utils: debug_utils_loader,
             debug_call_back,
+            settings,
         })
     }
 
@@ -85,6 +198,30 @@ impl DebugUtils {
             self.debug_utils.cmd_end_debug_utils_label(command_buffer);
         }
     }
+
+    pub(crate) fn queue_begin_label(
+        &self,
+        queue: vk::Queue,
+        label_name: &str,
+        label_color: [f32; 4],
+    ) {
+        let label_name = CString::new(label_name).expect("Failed to create CString");
+
+        unsafe {
+            self.debug_utils.queue_begin_debug_utils_label(
+                queue,
+                &vk::DebugUtilsLabelEXT::builder()
+                    .label_name(label_name.as_c_str())
+                    .color(label_color),
+            );
+        }
+    }
+
+    pub(crate) fn queue_end_label(&self, queue: vk::Queue) {
+        unsafe {
+            self.debug_utils.queue_end_debug_utils_label(queue);
+        }
+    }
 }
 
 impl Drop for DebugUtils {
@@ -98,9 +235,9 @@ impl Drop for DebugUtils {
 
 unsafe extern "system" fn vulkan_debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    _message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _user_data: *mut std::os::raw::c_void,
+    user_data: *mut std::os::raw::c_void,
 ) -> vk::Bool32 {
     use std::borrow::Cow;
     let callback_data = *p_callback_data;
@@ -110,13 +247,101 @@ unsafe extern "system" fn vulkan_debug_callback(
         CStr::from_ptr(callback_data.p_message).to_string_lossy()
     };
 
-    match message_severity {
-        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => trace!("{:?}", message),
-        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => info!("{:?}", message),
-        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => warn!("{:?}", message),
-        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => error!("{:?}", message),
-        _ => info!("Unknown Severity {:?}: {:?}", message_severity, message),
+    let settings = &*(user_data as *const ValidationSettings);
+
+    // Unwinding out of an extern "system" fn isn't allowed, so a panicking user callback is caught here
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        settings.dispatch(
+            ValidationSeverity::from_vk(message_severity),
+            ValidationMessageType::from_vk(message_type),
+            &message,
+        )
+    }))
+    .is_err()
+    {
+        error!("Validation callback panicked on message: {:?}", message);
     }
 
     vk::FALSE
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn recording_settings(
+        min_severity: ValidationSeverity,
+    ) -> (ValidationSettings, Arc<Mutex<Vec<String>>>) {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let callback_messages = messages.clone();
+        let settings = ValidationSettings {
+            enabled: true,
+            min_severity,
+            callback: Some(Arc::new(move |_, _, message: &str| {
+                callback_messages.lock().unwrap().push(message.to_string())
+            })),
+        };
+        (settings, messages)
+    }
+
+    #[test]
+    fn dispatch_filters_messages_below_min_severity() {
+        let (settings, messages) = recording_settings(ValidationSeverity::Warning);
+
+        assert!(!settings.dispatch(
+            ValidationSeverity::Info,
+            ValidationMessageType::General,
+            "info"
+        ));
+        assert!(settings.dispatch(
+            ValidationSeverity::Warning,
+            ValidationMessageType::Validation,
+            "warning"
+        ));
+        assert!(settings.dispatch(
+            ValidationSeverity::Error,
+            ValidationMessageType::Performance,
+            "error"
+        ));
+
+        assert_eq!(*messages.lock().unwrap(), vec!["warning", "error"]);
+    }
+
+    #[test]
+    fn vk_filter_includes_higher_severities() {
+        type Flags = vk::DebugUtilsMessageSeverityFlagsEXT;
+        assert_eq!(
+            ValidationSeverity::Warning.to_vk_filter(),
+            Flags::WARNING | Flags::ERROR
+        );
+        assert_eq!(ValidationSeverity::Error.to_vk_filter(), Flags::ERROR);
+        assert_eq!(
+            ValidationSeverity::from_vk(Flags::WARNING),
+            ValidationSeverity::Warning
+        );
+    }
+
+    #[test]
+    fn panicking_callback_doesnt_unwind_through_vulkan() {
+        let settings = ValidationSettings {
+            callback: Some(Arc::new(|_, _, _: &str| panic!("callback panic"))),
+            ..Default::default()
+        };
+        let message = CString::new("message").unwrap();
+        let callback_data = vk::DebugUtilsMessengerCallbackDataEXT {
+            p_message: message.as_ptr(),
+            ..Default::default()
+        };
+
+        let result = unsafe {
+            vulkan_debug_callback(
+                vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+                vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+                &callback_data,
+                &settings as *const ValidationSettings as *mut _,
+            )
+        };
+        assert_eq!(result, vk::FALSE);
+    }
+}
@@ -1,8 +1,9 @@
 use crate::buffer::Buffer;
 use crate::device::AshDevice;
 use crate::image::Image;
-use crate::{Sampler, VulkanError};
+use crate::{Sampler, UpdateAfterBindSupport, VulkanError};
 use ash::vk;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 #[derive(Default, Debug, Clone)]
@@ -14,6 +15,44 @@ pub struct DescriptorCount {
     pub acceleration_structures: u16,
 }
 
+impl DescriptorCount {
+    const MAX_STORAGE_BUFFERS: u32 = 1024;
+    const MAX_STORAGE_IMAGES: u32 = 1024;
+    const MAX_SAMPLED_IMAGES: u32 = 1024;
+    const MAX_SAMPLERS: u32 = 128;
+
+    /// Uses half of each update-after-bind limit, capped so large limits don't waste pool memory
+    pub fn from_limits(limits: &vk::PhysicalDeviceDescriptorIndexingProperties) -> Self {
+        let count = |set_limit: u32, stage_limit: u32, cap: u32| {
+            (set_limit.min(stage_limit) / 2).min(cap) as u16
+        };
+
+        Self {
+            storage_buffers: count(
+                limits.max_descriptor_set_update_after_bind_storage_buffers,
+                limits.max_per_stage_descriptor_update_after_bind_storage_buffers,
+                Self::MAX_STORAGE_BUFFERS,
+            ),
+            storage_images: count(
+                limits.max_descriptor_set_update_after_bind_storage_images,
+                limits.max_per_stage_descriptor_update_after_bind_storage_images,
+                Self::MAX_STORAGE_IMAGES,
+            ),
+            sampled_images: count(
+                limits.max_descriptor_set_update_after_bind_sampled_images,
+                limits.max_per_stage_descriptor_update_after_bind_sampled_images,
+                Self::MAX_SAMPLED_IMAGES,
+            ),
+            samplers: count(
+                limits.max_descriptor_set_update_after_bind_samplers,
+                limits.max_per_stage_descriptor_update_after_bind_samplers,
+                Self::MAX_SAMPLERS,
+            ),
+            acceleration_structures: 0,
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Default, Debug, Clone, Copy)]
 pub struct GpuBindingIndex(u32);
@@ -22,6 +61,10 @@ impl GpuBindingIndex {
     pub(crate) fn to_bytes(self) -> [u8; 4] {
         self.0.to_ne_bytes()
     }
+
+    pub(crate) fn value(self) -> u32 {
+        self.0
+    }
 }
 
 pub struct DescriptorBinding {
@@ -50,8 +93,12 @@ pub struct DescriptorSet {
 }
 
 impl DescriptorSet {
-    pub fn new(device: Arc<AshDevice>, count: DescriptorCount) -> Result<Self, VulkanError> {
-        let inner = DescriptorSetInner::new(device, count)?;
+    pub fn new(
+        device: Arc<AshDevice>,
+        count: DescriptorCount,
+        update_after_bind: UpdateAfterBindSupport,
+    ) -> Result<Self, VulkanError> {
+        let inner = DescriptorSetInner::new(device, count, update_after_bind)?;
         let layout = inner.layout;
         let set = inner.set;
         let inner = Arc::new(Mutex::new(inner));
@@ -86,11 +133,24 @@ impl DescriptorSet {
     pub fn bind_sampled_image(&self, image: &Image) -> DescriptorBinding {
         DescriptorBinding {
             binding: DescriptorSetInner::SAMPLED_IMAGE_BINDING,
-            index: self.inner.lock().unwrap().bind_sampled_image(image),
+            index: self
+                .inner
+                .lock()
+                .unwrap()
+                .bind_sampled_image(image.sampled_view()),
             set: self.inner.clone(),
         }
     }
 
+    /// Binds the stencil view of a sampled combined depth stencil image
+    pub fn bind_sampled_stencil_image(&self, image: &Image) -> Option<DescriptorBinding> {
+        image.stencil_view.map(|view| DescriptorBinding {
+            binding: DescriptorSetInner::SAMPLED_IMAGE_BINDING,
+            index: self.inner.lock().unwrap().bind_sampled_image(view),
+            set: self.inner.clone(),
+        })
+    }
+
     pub fn bind_sampler(&self, sampler: &Sampler) -> DescriptorBinding {
         DescriptorBinding {
             binding: DescriptorSetInner::SAMPLER_BINDING,
@@ -127,15 +187,17 @@ pub struct DescriptorSetInner {
 }
 
 impl DescriptorSetInner {
-    const STORAGE_BUFFER_BINDING: u16 = 0;
-    const STORAGE_IMAGE_BINDING: u16 = 1;
-    const SAMPLED_IMAGE_BINDING: u16 = 2;
-    const SAMPLER_BINDING: u16 = 3;
-
-    #[allow(unused)]
-    const ACCELERATION_STRUCTURE_BINDING: u16 = 4;
-
-    fn new(device: Arc<AshDevice>, count: DescriptorCount) -> Result<Self, VulkanError> {
+    pub(crate) const STORAGE_BUFFER_BINDING: u16 = 0;
+    pub(crate) const STORAGE_IMAGE_BINDING: u16 = 1;
+    pub(crate) const SAMPLED_IMAGE_BINDING: u16 = 2;
+    pub(crate) const SAMPLER_BINDING: u16 = 3;
+    pub(crate) const ACCELERATION_STRUCTURE_BINDING: u16 = 4;
+
+    fn new(
+        device: Arc<AshDevice>,
+        count: DescriptorCount,
+        update_after_bind: UpdateAfterBindSupport,
+    ) -> Result<Self, VulkanError> {
         let mut bindings = Vec::new();
         let mut pool_sizes = Vec::new();
 
@@ -210,12 +272,21 @@ impl DescriptorSetInner {
         //     });
         // }
 
-        let binding_flags = vec![
-            vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
-                | vk::DescriptorBindingFlags::PARTIALLY_BOUND
-                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING;
-            bindings.len()
-        ];
+        let binding_flags = Self::binding_flags(&bindings, update_after_bind);
+        let (layout_flags, pool_flags) = if binding_flags
+            .iter()
+            .any(|flags| flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND))
+        {
+            (
+                vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
+                vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND,
+            )
+        } else {
+            (
+                vk::DescriptorSetLayoutCreateFlags::empty(),
+                vk::DescriptorPoolCreateFlags::empty(),
+            )
+        };
         let mut binding_flag_create_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
             .binding_flags(&binding_flags)
             .build();
@@ -224,7 +295,7 @@ impl DescriptorSetInner {
             device.core.create_descriptor_set_layout(
                 &vk::DescriptorSetLayoutCreateInfo::builder()
                     .bindings(&bindings)
-                    .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+                    .flags(layout_flags)
                     .push_next(&mut binding_flag_create_info),
                 None,
             )
@@ -235,7 +306,7 @@ impl DescriptorSetInner {
                 &vk::DescriptorPoolCreateInfo::builder()
                     .max_sets(1)
                     .pool_sizes(&pool_sizes)
-                    .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND),
+                    .flags(pool_flags),
                 None,
             )
         }?;
@@ -286,6 +357,25 @@ impl DescriptorSetInner {
         Ok(new_self)
     }
 
+    /// Only bindings whose descriptor type supports it get UPDATE_AFTER_BIND
+    fn binding_flags(
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        update_after_bind: UpdateAfterBindSupport,
+    ) -> Vec<vk::DescriptorBindingFlags> {
+        bindings
+            .iter()
+            .map(|binding| {
+                let flags = vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                    | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING;
+                if update_after_bind.supports(binding.descriptor_type) {
+                    flags | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                } else {
+                    flags
+                }
+            })
+            .collect()
+    }
+
     fn unbind(&mut self, binding: u16, index: u16) {
         match binding {
             Self::STORAGE_BUFFER_BINDING => self.unbind_storage_buffer(index),
@@ -350,7 +440,7 @@ impl DescriptorSetInner {
         );
     }
 
-    fn bind_sampled_image(&mut self, image: &Image) -> u16 {
+    fn bind_sampled_image(&mut self, image_view: vk::ImageView) -> u16 {
         let index = self
             .sampled_image_pool
             .get()
@@ -362,7 +452,7 @@ impl DescriptorSetInner {
             index,
             &[vk::DescriptorImageInfo {
                 sampler: vk::Sampler::null(),
-                image_view: image.view,
+                image_view,
                 image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             }],
         );
@@ -482,6 +572,179 @@ impl Drop for DescriptorSetInner {
     }
 }
 
+/// A set of the pipeline layout with a single UNIFORM_BUFFER_DYNAMIC or STORAGE_BUFFER_DYNAMIC binding.
+/// Dynamic descriptors can't live in the update-after-bind set, so each (buffer, range) pair gets its own small set
+pub(crate) struct DynamicBufferSets {
+    device: Arc<AshDevice>,
+    descriptor_type: vk::DescriptorType,
+    set_index: u32,
+    layout: vk::DescriptorSetLayout,
+    inner: Mutex<DynamicBufferSetsInner>,
+}
+
+#[derive(Default)]
+struct DynamicBufferSetsInner {
+    pools: Vec<vk::DescriptorPool>,
+    sets: HashMap<(vk::Buffer, vk::DeviceSize), (vk::DescriptorSet, vk::DescriptorPool)>,
+}
+
+impl DynamicBufferSets {
+    pub(crate) const UNIFORM_SET_INDEX: u32 = 1;
+    pub(crate) const STORAGE_SET_INDEX: u32 = 2;
+    const SETS_PER_POOL: u32 = 64;
+
+    pub(crate) fn new_uniform(device: Arc<AshDevice>) -> Result<Self, VulkanError> {
+        Self::new(
+            device,
+            vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            Self::UNIFORM_SET_INDEX,
+        )
+    }
+
+    pub(crate) fn new_storage(device: Arc<AshDevice>) -> Result<Self, VulkanError> {
+        Self::new(
+            device,
+            vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
+            Self::STORAGE_SET_INDEX,
+        )
+    }
+
+    fn new(
+        device: Arc<AshDevice>,
+        descriptor_type: vk::DescriptorType,
+        set_index: u32,
+    ) -> Result<Self, VulkanError> {
+        let layout = unsafe {
+            device.core.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
+                    vk::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        descriptor_type,
+                        descriptor_count: 1,
+                        stage_flags: vk::ShaderStageFlags::ALL,
+                        p_immutable_samplers: std::ptr::null(),
+                    },
+                ]),
+                None,
+            )
+        }?;
+
+        Ok(Self {
+            device,
+            descriptor_type,
+            set_index,
+            layout,
+            inner: Mutex::new(DynamicBufferSetsInner::default()),
+        })
+    }
+
+    pub(crate) fn get_layout(&self) -> vk::DescriptorSetLayout {
+        self.layout
+    }
+
+    pub(crate) fn set_index(&self) -> u32 {
+        self.set_index
+    }
+
+    /// Returns the set binding range bytes of the buffer, creating it on first use
+    pub(crate) fn get_or_create(
+        &self,
+        buffer: vk::Buffer,
+        range: vk::DeviceSize,
+    ) -> ash::prelude::VkResult<vk::DescriptorSet> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((set, _)) = inner.sets.get(&(buffer, range)) {
+            return Ok(*set);
+        }
+
+        let (set, pool) = match inner
+            .pools
+            .last()
+            .map(|&pool| (self.allocate_set(pool), pool))
+        {
+            Some((Ok(set), pool)) => (set, pool),
+            Some((
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL),
+                _,
+            ))
+            | None => {
+                let pool = unsafe {
+                    self.device.core.create_descriptor_pool(
+                        &vk::DescriptorPoolCreateInfo::builder()
+                            .max_sets(Self::SETS_PER_POOL)
+                            .pool_sizes(&[vk::DescriptorPoolSize {
+                                ty: self.descriptor_type,
+                                descriptor_count: Self::SETS_PER_POOL,
+                            }])
+                            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET),
+                        None,
+                    )
+                }?;
+                inner.pools.push(pool);
+                (self.allocate_set(pool)?, pool)
+            }
+            Some((Err(error), _)) => return Err(error),
+        };
+
+        unsafe {
+            self.device.core.update_descriptor_sets(
+                &[vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(self.descriptor_type)
+                    .buffer_info(&[vk::DescriptorBufferInfo {
+                        buffer,
+                        offset: 0,
+                        range,
+                    }])
+                    .build()],
+                &[],
+            );
+        }
+
+        inner.sets.insert((buffer, range), (set, pool));
+        Ok(set)
+    }
+
+    /// Frees every set that references the buffer, must be called when the buffer is destroyed
+    pub(crate) fn free_buffer(&self, buffer: vk::Buffer) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sets.retain(|&(set_buffer, _), &mut (set, pool)| {
+            if set_buffer != buffer {
+                return true;
+            }
+            unsafe {
+                let _ = self.device.core.free_descriptor_sets(pool, &[set]);
+            }
+            false
+        });
+    }
+
+    fn allocate_set(&self, pool: vk::DescriptorPool) -> ash::prelude::VkResult<vk::DescriptorSet> {
+        unsafe {
+            self.device.core.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(&[self.layout]),
+            )
+        }
+        .map(|sets| sets[0])
+    }
+}
+
+impl Drop for DynamicBufferSets {
+    fn drop(&mut self) {
+        unsafe {
+            for pool in self.inner.get_mut().unwrap().pools.drain(..) {
+                self.device.core.destroy_descriptor_pool(pool, None);
+            }
+            self.device
+                .core
+                .destroy_descriptor_set_layout(self.layout, None);
+        }
+    }
+}
+
 struct IndexPool {
     range: std::ops::Range<u16>,
     freed_indices: Vec<u16>,
@@ -507,3 +770,89 @@ impl IndexPool {
         self.freed_indices.push(index);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(descriptor_type: vk::DescriptorType) -> vk::DescriptorSetLayoutBinding {
+        vk::DescriptorSetLayoutBinding {
+            descriptor_type,
+            descriptor_count: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn missing_storage_image_update_after_bind_is_reported() {
+        let support = UpdateAfterBindSupport {
+            storage_buffers: true,
+            storage_images: false,
+            sampled_images: true,
+        };
+        let count = DescriptorCount {
+            storage_buffers: 8,
+            storage_images: 8,
+            sampled_images: 8,
+            samplers: 8,
+            acceleration_structures: 0,
+        };
+        assert_eq!(
+            support.missing_features(&count),
+            vec!["descriptorBindingStorageImageUpdateAfterBind"]
+        );
+
+        let count = DescriptorCount {
+            storage_images: 0,
+            ..count
+        };
+        assert!(support.missing_features(&count).is_empty());
+    }
+
+    #[test]
+    fn update_after_bind_is_only_set_on_supported_bindings() {
+        let support = UpdateAfterBindSupport {
+            storage_buffers: true,
+            storage_images: false,
+            sampled_images: true,
+        };
+        let flags = DescriptorSetInner::binding_flags(
+            &[
+                binding(vk::DescriptorType::STORAGE_BUFFER),
+                binding(vk::DescriptorType::STORAGE_IMAGE),
+                binding(vk::DescriptorType::SAMPLER),
+            ],
+            support,
+        );
+
+        let update_after_bind: Vec<bool> = flags
+            .iter()
+            .map(|flags| flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND))
+            .collect();
+        assert_eq!(update_after_bind, vec![true, false, true]);
+        assert!(flags
+            .iter()
+            .all(|flags| flags.contains(vk::DescriptorBindingFlags::PARTIALLY_BOUND)));
+    }
+
+    #[test]
+    fn descriptor_counts_stay_below_small_device_limits() {
+        let limits = vk::PhysicalDeviceDescriptorIndexingProperties {
+            max_descriptor_set_update_after_bind_storage_buffers: 96,
+            max_per_stage_descriptor_update_after_bind_storage_buffers: 64,
+            max_descriptor_set_update_after_bind_storage_images: 64,
+            max_per_stage_descriptor_update_after_bind_storage_images: 64,
+            max_descriptor_set_update_after_bind_sampled_images: 500_000,
+            max_per_stage_descriptor_update_after_bind_sampled_images: 500_000,
+            max_descriptor_set_update_after_bind_samplers: 16,
+            max_per_stage_descriptor_update_after_bind_samplers: 16,
+            ..Default::default()
+        };
+        let count = DescriptorCount::from_limits(&limits);
+        assert_eq!(count.storage_buffers, 32);
+        assert_eq!(count.storage_images, 32);
+        assert_eq!(count.sampled_images, 1024);
+        assert_eq!(count.samplers, 8);
+        assert_eq!(count.acceleration_structures, 0);
+    }
+}
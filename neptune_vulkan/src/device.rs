@@ -1,17 +1,28 @@
-use crate::buffer::{Buffer, BufferDescription, BufferUsage};
-use crate::image::{Image, ImageDescription2D};
+use crate::basic_render_graph_builder::BasicRenderGraphBuilder;
+use crate::buffer::{Buffer, BufferAllocationInfo, BufferDescription, BufferUsage};
+use crate::descriptor_set::DescriptorCount;
+use crate::frame_arena::FrameArena;
+use crate::image::{FormatSupport, Image, ImageBindings, ImageDescription2D};
 use crate::instance::AshInstance;
 use crate::pipeline::{ComputePipeline, Pipelines, RasterPipeline, RasterPipelineDescription};
 use crate::render_graph::CompiledRenderGraph;
-use crate::render_graph_builder::{BufferOffset, ImageCopyBuffer, ImageCopyImage};
-use crate::render_graph_executor::RenderGraphExecutor;
-use crate::resource_managers::ResourceManager;
+use crate::render_graph_builder::{
+    BufferOffset, DoubleBuffered, HistoryImage, ImageCopyBuffer, ImageCopyImage,
+};
+use crate::render_graph_builder::{DynamicBufferLimits, RenderGraphBuilderTrait, RenderGraphError};
+use crate::render_graph_executor::{
+    DescriptorBindFrequency, FrameResult, FrameTimings, RenderGraphExecutor,
+};
+use crate::resource_managers::{
+    BlockCompaction, ImageResourceAccess, ResourceCounts, ResourceManager,
+};
 use crate::sampler::{Sampler, SamplerDescription};
 use crate::swapchain::{SurfaceSettings, Swapchain, SwapchainManager};
 use crate::upload_queue::UploadQueue;
 use crate::{
-    BufferHandle, ComputePipelineHandle, ImageHandle, PhysicalDevice, RasterPipelineHandle,
-    SamplerHandle, ShaderStage, SurfaceHandle, VulkanError,
+    AllocationScheme, BufferHandle, BufferKey, BufferSuballocator, BufferWriteError,
+    ComputePipelineHandle, DeviceFeatures, DynamicUniformRing, ImageHandle, PhysicalDevice,
+    RasterPipelineHandle, SamplerHandle, ShaderStage, SurfaceHandle, UniformBatch, VulkanError,
 };
 use ash::vk;
 use log::error;
@@ -36,10 +47,17 @@ pub struct AshDevice {
     pub graphics_queue: Option<AshQueue>,
     pub compute_queue: Option<AshQueue>,
     pub transfer_queue: Option<AshQueue>,
+    /// Only set when the graphics queue family can't present to the device's surfaces
+    pub present_queue: Option<AshQueue>,
     pub core: ash::Device,
     pub swapchain: ash::extensions::khr::Swapchain,
     pub mesh_shader: Option<ash::extensions::ext::MeshShader>,
     pub raytracing: Option<AshRaytracing>,
+    pub multiview_support: bool,
+    /// Alignment of flushed and invalidated ranges of non-coherent mapped memory
+    pub non_coherent_atom_size: vk::DeviceSize,
+    /// Bitmask of the HOST_VISIBLE and HOST_CACHED memory types
+    pub host_cached_memory_types: u32,
     pub allocator: ManuallyDrop<Mutex<gpu_allocator::vulkan::Allocator>>,
 }
 
@@ -47,7 +65,10 @@ impl AshDevice {
     pub fn new(
         instance: Arc<AshInstance>,
         physical_device: &PhysicalDevice,
+        features: &DeviceFeatures,
     ) -> Result<Self, VulkanError> {
+        physical_device.extension.validate_features(features)?;
+
         let mut queue_create_infos: Vec<vk::DeviceQueueCreateInfo> = Vec::with_capacity(3);
 
         if let Some(queue_family_index) = physical_device.queue.graphics_queue_family_index {
@@ -75,9 +96,34 @@ impl AshDevice {
             );
         }
 
+        // Surfaces must be created before the device for a separate present family to be found
+        let surfaces = instance.surface_list.surfaces();
+        let present_queue_family_index = if surfaces.is_empty() {
+            None
+        } else {
+            physical_device
+                .present_queue_family_index_for(&surfaces)
+                .filter(|&family_index| {
+                    Some(family_index) != physical_device.queue.graphics_queue_family_index
+                })
+        };
+        if let Some(queue_family_index) = present_queue_family_index {
+            if !queue_create_infos
+                .iter()
+                .any(|create_info| create_info.queue_family_index == queue_family_index)
+            {
+                queue_create_infos.push(
+                    vk::DeviceQueueCreateInfo::builder()
+                        .queue_family_index(queue_family_index)
+                        .queue_priorities(&[1.0])
+                        .build(),
+                );
+            }
+        }
+
         let mut device_extension_names_raw = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
 
-        if physical_device.extension.raytracing_support {
+        if features.raytracing {
             device_extension_names_raw
                 .push(ash::extensions::khr::AccelerationStructure::name().as_ptr());
             device_extension_names_raw
@@ -87,7 +133,7 @@ impl AshDevice {
                 .push(ash::extensions::khr::DeferredHostOperations::name().as_ptr());
         }
 
-        if physical_device.extension.mesh_shader_support {
+        if features.mesh_shading {
             device_extension_names_raw.push(ash::extensions::ext::MeshShader::name().as_ptr());
         }
 
@@ -95,13 +141,22 @@ impl AshDevice {
             device_extension_names_raw.push(vk::KhrShaderNonSemanticInfoFn::name().as_ptr());
         }
 
+        let mut vulkan_1_1_features =
+            vk::PhysicalDeviceVulkan11Features::builder().multiview(features.multiview);
+
         let mut vulkan_1_2_features = vk::PhysicalDeviceVulkan12Features::builder()
             .buffer_device_address(true)
             .descriptor_indexing(true)
             .descriptor_binding_partially_bound(true)
-            .descriptor_binding_storage_buffer_update_after_bind(true)
-            .descriptor_binding_storage_image_update_after_bind(true)
-            .descriptor_binding_sampled_image_update_after_bind(true)
+            .descriptor_binding_storage_buffer_update_after_bind(
+                physical_device.update_after_bind.storage_buffers,
+            )
+            .descriptor_binding_storage_image_update_after_bind(
+                physical_device.update_after_bind.storage_images,
+            )
+            .descriptor_binding_sampled_image_update_after_bind(
+                physical_device.update_after_bind.sampled_images,
+            )
             .descriptor_binding_update_unused_while_pending(true)
             .runtime_descriptor_array(true);
 
@@ -112,12 +167,23 @@ impl AshDevice {
         let mut physical_device_robustness2_features =
             vk::PhysicalDeviceRobustness2FeaturesEXT::builder().null_descriptor(true);
 
+        // Enabled whenever available so anisotropic samplers can be created
+        let supported_features = unsafe {
+            instance
+                .core
+                .get_physical_device_features(physical_device.handle)
+        };
+        let enabled_features = vk::PhysicalDeviceFeatures::builder()
+            .sampler_anisotropy(supported_features.sampler_anisotropy == vk::TRUE);
+
         let core = unsafe {
             instance.core.create_device(
                 physical_device.handle,
                 &vk::DeviceCreateInfo::builder()
                     .queue_create_infos(&queue_create_infos)
                     .enabled_extension_names(&device_extension_names_raw)
+                    .enabled_features(&enabled_features)
+                    .push_next(&mut vulkan_1_1_features)
                     .push_next(&mut vulkan_1_2_features)
                     .push_next(&mut vulkan_1_3_features)
                     .push_next(&mut physical_device_robustness2_features)
@@ -163,6 +229,12 @@ impl AshDevice {
                     flags: queue_family_properties[family_index as usize].queue_flags,
                 });
 
+        let present_queue = present_queue_family_index.map(|family_index| AshQueue {
+            family_index,
+            handle: unsafe { core.get_device_queue(family_index, 0) },
+            flags: queue_family_properties[family_index as usize].queue_flags,
+        });
+
         let allocator = ManuallyDrop::new(Mutex::new(gpu_allocator::vulkan::Allocator::new(
             &gpu_allocator::vulkan::AllocatorCreateDesc {
                 instance: instance.core.clone(),
@@ -174,24 +246,34 @@ impl AshDevice {
             },
         )?));
 
-        let mesh_shader = physical_device
-            .extension
-            .mesh_shader_support
+        let mesh_shader = features
+            .mesh_shading
             .then(|| ash::extensions::ext::MeshShader::new(&instance.core, &core));
 
-        let raytracing = physical_device
-            .extension
-            .raytracing_support
-            .then(|| AshRaytracing {
-                acceleration_structure: ash::extensions::khr::AccelerationStructure::new(
-                    &instance.core,
-                    &core,
-                ),
-                raytracing_pipeline: ash::extensions::khr::RayTracingPipeline::new(
-                    &instance.core,
-                    &core,
-                ),
-            });
+        let raytracing = features.raytracing.then(|| AshRaytracing {
+            acceleration_structure: ash::extensions::khr::AccelerationStructure::new(
+                &instance.core,
+                &core,
+            ),
+            raytracing_pipeline: ash::extensions::khr::RayTracingPipeline::new(
+                &instance.core,
+                &core,
+            ),
+        });
+
+        let non_coherent_atom_size = unsafe {
+            instance
+                .core
+                .get_physical_device_properties(physical_device.handle)
+        }
+        .limits
+        .non_coherent_atom_size;
+
+        let host_cached_memory_types = host_cached_memory_types(unsafe {
+            &instance
+                .core
+                .get_physical_device_memory_properties(physical_device.handle)
+        });
 
         Ok(Self {
             instance,
@@ -199,10 +281,14 @@ impl AshDevice {
             graphics_queue,
             compute_queue,
             transfer_queue,
+            present_queue,
             core,
             swapchain,
             mesh_shader,
             raytracing,
+            multiview_support: features.multiview,
+            non_coherent_atom_size,
+            host_cached_memory_types,
             allocator,
         })
     }
@@ -219,16 +305,98 @@ impl Drop for AshDevice {
 
 pub struct DeviceSettings {
     pub frames_in_flight: u32,
+    pub features: DeviceFeatures,
+    /// Bindless descriptor counts, derived from the device's descriptor limits when None
+    pub descriptor_count: Option<DescriptorCount>,
+    pub staging_memory: StagingMemoryLocations,
+    /// Command buffers preallocated per frame in flight, pools only grow past this when a frame needs more
+    pub command_buffer_capacity: CommandBufferCounts,
+    /// Allocates per-frame scratch collections of the render graph builders and executor from a bump arena reset each frame,
+    /// instead of the heap
+    pub frame_arena: bool,
+}
+
+/// Per queue command buffer counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandBufferCounts {
+    pub graphics: u32,
+    pub async_compute: u32,
+    pub async_transfer: u32,
+}
+
+impl Default for CommandBufferCounts {
+    fn default() -> Self {
+        Self {
+            graphics: 8,
+            async_compute: 4,
+            async_transfer: 4,
+        }
+    }
+}
+
+/// Memory locations used for staging buffers
+#[derive(Debug, Clone, Copy)]
+pub struct StagingMemoryLocations {
+    /// Used for cpu to gpu uploads
+    pub write: gpu_allocator::MemoryLocation,
+    /// Used for gpu to cpu read-back, GpuToCpu uses host cached memory when the device has any
+    pub read: gpu_allocator::MemoryLocation,
+}
+
+impl StagingMemoryLocations {
+    /// Staging buffers are accessed through a mapping, so both locations must be host visible
+    pub fn validate(&self) -> Result<(), VulkanError> {
+        for (usage, location) in [("write", self.write), ("read", self.read)] {
+            if !matches!(
+                location,
+                gpu_allocator::MemoryLocation::CpuToGpu | gpu_allocator::MemoryLocation::GpuToCpu
+            ) {
+                return Err(VulkanError::StagingMemoryNotHostVisible { usage, location });
+            }
+        }
+        Ok(())
+    }
+
+    /// Memory types read-back staging buffers prefer, gpu_allocator's GpuToCpu only picks host cached memory that is also coherent
+    pub(crate) fn read_memory_types(&self, host_cached_memory_types: u32) -> u32 {
+        match self.read {
+            gpu_allocator::MemoryLocation::GpuToCpu => host_cached_memory_types,
+            _ => 0,
+        }
+    }
+}
+
+/// Bitmask of the HOST_VISIBLE and HOST_CACHED memory types
+fn host_cached_memory_types(properties: &vk::PhysicalDeviceMemoryProperties) -> u32 {
+    let host_cached = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_CACHED;
+    properties.memory_types[..properties.memory_type_count as usize]
+        .iter()
+        .enumerate()
+        .filter(|(_, memory_type)| memory_type.property_flags.contains(host_cached))
+        .fold(0, |bits, (index, _)| bits | (1 << index))
+}
+
+impl Default for StagingMemoryLocations {
+    fn default() -> Self {
+        Self {
+            write: gpu_allocator::MemoryLocation::CpuToGpu,
+            read: gpu_allocator::MemoryLocation::GpuToCpu,
+        }
+    }
 }
 
 pub struct Device {
     settings: DeviceSettings,
     device: Arc<AshDevice>,
+    limits: vk::PhysicalDeviceLimits,
+    sampler_anisotropy: bool,
+    frame_count: u64,
     pipelines: Pipelines,
     resource_manager: ResourceManager,
     swapchain_manager: SwapchainManager,
 
     upload_queue: UploadQueue,
+    frame_arena: FrameArena,
     graph_executor: RenderGraphExecutor,
 }
 
@@ -238,22 +406,63 @@ impl Device {
         physical_device: PhysicalDevice,
         settings: DeviceSettings,
     ) -> Result<Device, VulkanError> {
-        let push_constant_size = unsafe {
+        let limits = unsafe {
             instance
                 .core
                 .get_physical_device_properties(physical_device.handle)
         }
-        .limits
-        .max_push_constants_size;
+        .limits;
+        let push_constant_size = limits.max_push_constants_size;
+        // AshDevice enables samplerAnisotropy whenever it's supported
+        let sampler_anisotropy = unsafe {
+            instance
+                .core
+                .get_physical_device_features(physical_device.handle)
+        }
+        .sampler_anisotropy
+            == vk::TRUE;
+
+        let descriptor_count = settings.descriptor_count.clone().unwrap_or_else(|| {
+            let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+            let mut properties2 =
+                vk::PhysicalDeviceProperties2::builder().push_next(&mut indexing_properties);
+            unsafe {
+                instance
+                    .core
+                    .get_physical_device_properties2(physical_device.handle, &mut properties2);
+            }
+            DescriptorCount::from_limits(&indexing_properties)
+        });
+
+        settings.staging_memory.validate()?;
 
-        let device = AshDevice::new(instance, &physical_device).map(Arc::new)?;
-        let resource_manager = ResourceManager::new(device.clone(), settings.frames_in_flight);
+        // Checked before creating the device so the error names the missing feature
+        let missing_features = physical_device
+            .update_after_bind
+            .missing_features(&descriptor_count);
+        if !missing_features.is_empty() {
+            return Err(VulkanError::UnsupportedFeature(missing_features.join(", ")));
+        }
+
+        let device =
+            AshDevice::new(instance, &physical_device, &settings.features).map(Arc::new)?;
+        let resource_manager = ResourceManager::new(
+            device.clone(),
+            settings.frames_in_flight,
+            descriptor_count,
+            physical_device.update_after_bind,
+            settings.staging_memory,
+        );
         let swapchain_manager = SwapchainManager::new(device.instance.clone());
 
         let pipelines = Pipelines::new(device.clone(), unsafe {
             device.core.create_pipeline_layout(
                 &vk::PipelineLayoutCreateInfo::builder()
-                    .set_layouts(&[resource_manager.descriptor_set.get_layout()])
+                    .set_layouts(&[
+                        resource_manager.descriptor_set.get_layout(),
+                        resource_manager.dynamic_buffer_sets.get_layout(),
+                        resource_manager.dynamic_storage_buffer_sets.get_layout(),
+                    ])
                     .push_constant_ranges(&[vk::PushConstantRange {
                         stage_flags: vk::ShaderStageFlags::ALL,
                         offset: 0,
@@ -264,15 +473,33 @@ impl Device {
         });
 
         let upload_queue = UploadQueue::default();
-        let graph_executor = RenderGraphExecutor::new(device.clone(), settings.frames_in_flight)?;
+        let frame_arena = FrameArena::new(settings.frame_arena);
+        let graph_executor = RenderGraphExecutor::new(
+            device.clone(),
+            settings.frames_in_flight,
+            settings.command_buffer_capacity,
+            frame_arena.clone(),
+        )?;
+
+        // Resources are only destroyed once every frame that could still be using them has finished
+        if resource_manager.deletion_delay() != graph_executor.frames_in_flight() {
+            return Err(VulkanError::DeletionDelayMismatch {
+                deletion_delay: resource_manager.deletion_delay(),
+                frames_in_flight: graph_executor.frames_in_flight(),
+            });
+        }
 
         Ok(Device {
             settings,
             device,
+            limits,
+            sampler_anisotropy,
+            frame_count: 0,
             pipelines,
             resource_manager,
             swapchain_manager,
             upload_queue,
+            frame_arena,
             graph_executor,
         })
     }
@@ -283,13 +510,17 @@ impl Device {
         size: usize,
         usage: BufferUsage,
         location: gpu_allocator::MemoryLocation,
+        allocation_scheme: AllocationScheme,
     ) -> Result<BufferHandle, VulkanError> {
         Ok(BufferHandle::Persistent(
             self.resource_manager
-                .create_buffer(name, size, usage, location)?,
+                .create_buffer(name, size, usage, location, allocation_scheme)?,
         ))
     }
 
+    /// Creates a buffer holding data, written directly when the buffer is host visible,
+    /// otherwise staged and uploaded before the next submitted graph runs.
+    /// TRANSFER usage is always added so the staged copy is valid
     pub fn create_buffer_init(
         &mut self,
         name: &str,
@@ -297,22 +528,28 @@ impl Device {
         location: gpu_allocator::MemoryLocation,
         data: &[u8],
     ) -> Result<BufferHandle, VulkanError> {
-        let buffer_key = self
-            .resource_manager
-            .create_buffer(name, data.len(), usage, location)?;
+        let buffer_key = self.resource_manager.create_buffer(
+            name,
+            data.len(),
+            usage | BufferUsage::TRANSFER,
+            location,
+            AllocationScheme::Managed,
+        )?;
         let buffer_handle = BufferHandle::Persistent(buffer_key);
 
         let buffer = self.resource_manager.buffers.get_mut(buffer_key).unwrap();
 
         if let Some(mapped_slice) = buffer.buffer.allocation.mapped_slice_mut() {
             mapped_slice[0..data.len()].copy_from_slice(data);
+            buffer.buffer.flush_mapped_range(0..data.len())?;
         } else {
             let mut staging_buffer = Buffer::new(
                 self.device.clone(),
                 "Stating Buffer",
                 data.len() as vk::DeviceSize,
                 vk::BufferUsageFlags::TRANSFER_SRC,
-                gpu_allocator::MemoryLocation::CpuToGpu,
+                self.settings.staging_memory.write,
+                AllocationScheme::Managed,
             )?;
 
             let mut_slice = match staging_buffer.allocation.mapped_slice_mut() {
@@ -320,31 +557,175 @@ impl Device {
                 Some(mut_slice) => mut_slice,
             };
             mut_slice[0..data.len()].copy_from_slice(data);
+            staging_buffer.flush_mapped_range(0..data.len())?;
 
             let staging_handle =
                 BufferHandle::Persistent(self.resource_manager.add_buffer(staging_buffer));
 
+            queue_staged_init(
+                &mut self.upload_queue,
+                staging_handle,
+                buffer_handle,
+                data.len(),
+            );
+
+            //Destroy stating buffer once frame is done
+            self.destroy_buffer(staging_handle);
+        }
+
+        Ok(buffer_handle)
+    }
+
+    /// Reallocates a persistent buffer with a new size, the handle stays valid and refers to the new buffer.
+    /// With preserve_contents the overlapping range is copied over, directly when the buffer is host visible,
+    /// otherwise by a transfer before the next submitted graph runs, which requires TRANSFER usage.
+    /// Transient and destroyed handles return [`VulkanError::InvalidBufferHandle`]
+    pub fn resize_buffer(
+        &mut self,
+        buffer_handle: BufferHandle,
+        new_size: usize,
+        preserve_contents: bool,
+    ) -> Result<(), VulkanError> {
+        let BufferHandle::Persistent(key) = buffer_handle else {
+            return Err(VulkanError::InvalidBufferHandle(buffer_handle));
+        };
+        let Some(resource) = self.resource_manager.buffers.get(key) else {
+            return Err(VulkanError::InvalidBufferHandle(buffer_handle));
+        };
+        let is_mapped = resource.buffer.is_mapped();
+        let copy_size = preserved_size(
+            resource.buffer.size as usize,
+            new_size,
+            preserve_contents,
+            is_mapped
+                || resource
+                    .buffer
+                    .usage
+                    .contains(BufferUsage::TRANSFER.to_vk()),
+        )?;
+
+        let Some(old_key) = self.resource_manager.resize_buffer(key, new_size)? else {
+            return Err(VulkanError::InvalidBufferHandle(buffer_handle));
+        };
+
+        if copy_size != 0 {
+            self.copy_replaced_buffer(key, old_key, copy_size, is_mapped)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the start of the buffer replaced by resource_manager.resize_buffer into its replacement
+    fn copy_replaced_buffer(
+        &mut self,
+        key: BufferKey,
+        old_key: BufferKey,
+        copy_size: usize,
+        is_mapped: bool,
+    ) -> Result<(), VulkanError> {
+        if is_mapped {
+            let [new_resource, old_resource] = self
+                .resource_manager
+                .buffers
+                .get_disjoint_mut([key, old_key])
+                .unwrap();
+            if let (Some(dst), Some(src)) = (
+                new_resource.buffer.allocation.mapped_slice_mut(),
+                old_resource.buffer.allocation.mapped_slice(),
+            ) {
+                dst[0..copy_size].copy_from_slice(&src[0..copy_size]);
+                new_resource.buffer.flush_mapped_range(0..copy_size)?;
+            }
+        } else {
             self.upload_queue.add_buffer_upload(
                 BufferOffset {
-                    buffer: staging_handle,
+                    buffer: BufferHandle::Persistent(old_key),
                     offset: 0,
                 },
                 BufferOffset {
-                    buffer: buffer_handle,
+                    buffer: BufferHandle::Persistent(key),
                     offset: 0,
                 },
-                data.len(),
+                copy_size,
             );
+        }
+        Ok(())
+    }
 
-            //Destroy stating buffer once frame is done
-            self.destroy_buffer(staging_handle);
+    /// Compacts memory left fragmented by long sessions of allocation churn. Waits for the device to idle, then
+    /// moves persistent buffers with a managed allocation out of the sparsest memory blocks so they empty out
+    /// and get released, see BlockCompaction. Each buffer is copied and its old allocation freed before the next
+    /// one moves, so memory usage only grows by one buffer at a time. Handles stay valid and storage bindings are rebound.
+    /// Mapped buffers are copied on the CPU, others need TRANSFER usage and are copied on the GPU right away.
+    /// If a move fails the buffer is restored to its original allocation and the error returned,
+    /// buffers moved before it stay moved. Images aren't moved.
+    /// Heavy, call it explicitly at a quiet moment rather than every frame. Returns the number of buffers moved
+    pub fn defragment(&mut self) -> Result<usize, VulkanError> {
+        unsafe { self.device.core.device_wait_idle()? };
+
+        let transfer_usage = BufferUsage::TRANSFER.to_vk();
+        let (mut compaction, order) =
+            BlockCompaction::new(&self.resource_manager.movable_buffers());
+        let mut moved_count = 0;
+        for key in order {
+            let buffer = &self.resource_manager.buffers[key].buffer;
+            let size = buffer.size;
+            if !buffer.is_mapped() && !buffer.usage.contains(transfer_usage) {
+                continue;
+            }
+
+            let Some(old_resource) = self.resource_manager.replace_buffer(key, size as usize)?
+            else {
+                continue;
+            };
+            let old_memory = unsafe { old_resource.buffer.allocation.memory() };
+            let new_buffer = &mut self.resource_manager.buffers[key].buffer;
+            let new_memory = unsafe { new_buffer.allocation.memory() };
+            if !compaction.accept_move(old_memory, new_memory, size) {
+                self.resource_manager.restore_buffer(key, old_resource);
+                continue;
+            }
+
+            if let Err(err) = copy_idle_buffer(&self.device, &old_resource.buffer, new_buffer) {
+                self.resource_manager.restore_buffer(key, old_resource);
+                return Err(err);
+            }
+            self.resource_manager.destroy_idle_buffer(old_resource);
+            moved_count += 1;
         }
 
-        //let buffer = self.create_buffer(name, data.len(), usage, location)?;
-        //self.update_data_to_buffer(buffer, 0, data)?;
-        Ok(BufferHandle::Persistent(buffer_key))
+        Ok(moved_count)
     }
 
+    /// Creates the pair of persistent buffers backing a double buffered buffer
+    pub fn create_double_buffered_buffer(
+        &mut self,
+        name: &str,
+        size: usize,
+        usage: BufferUsage,
+        location: gpu_allocator::MemoryLocation,
+    ) -> Result<DoubleBuffered<BufferHandle>, VulkanError> {
+        let current = self.create_buffer(
+            &format!("{} 0", name),
+            size,
+            usage,
+            location,
+            AllocationScheme::Managed,
+        )?;
+        let previous = self.create_buffer(
+            &format!("{} 1", name),
+            size,
+            usage,
+            location,
+            AllocationScheme::Managed,
+        )?;
+        Ok(DoubleBuffered::new(current, previous))
+    }
+    pub fn destroy_double_buffered_buffer(&mut self, buffers: DoubleBuffered<BufferHandle>) {
+        for buffer in buffers.resources() {
+            self.destroy_buffer(buffer);
+        }
+    }
     pub fn destroy_buffer(&mut self, buffer_handle: BufferHandle) {
         match buffer_handle {
             BufferHandle::Persistent(key) => self.resource_manager.remove_buffer(key),
@@ -354,6 +735,140 @@ impl Device {
         }
     }
 
+    /// Counts live persistent resources, resources pending deletion aren't included
+    pub fn resource_counts(&self) -> ResourceCounts {
+        self.resource_manager.resource_counts()
+    }
+
+    #[cfg(feature = "track-resources")]
+    pub fn debug_dump_resources(&self) {
+        self.resource_manager.debug_dump_resources();
+    }
+
+    /// Returns the allocated size and alignment of a persistent buffer, None for transient or destroyed buffers
+    pub fn get_buffer_allocation_info(
+        &self,
+        buffer_handle: BufferHandle,
+    ) -> Option<BufferAllocationInfo> {
+        match buffer_handle {
+            BufferHandle::Persistent(key) => self.resource_manager.buffer_allocation_info(key),
+            BufferHandle::Transient(_) => None,
+        }
+    }
+
+    fn dynamic_offset_alignment(&self) -> u64 {
+        self.limits
+            .min_uniform_buffer_offset_alignment
+            .max(self.limits.min_storage_buffer_offset_alignment)
+    }
+
+    pub fn create_dynamic_uniform_ring(
+        &mut self,
+        name: &str,
+        frame_size: usize,
+    ) -> Result<DynamicUniformRing, VulkanError> {
+        let alignment = self.dynamic_offset_alignment();
+        let region_count = self.settings.frames_in_flight as u64 + 1;
+        let buffer_size =
+            DynamicUniformRing::buffer_size(alignment, frame_size as u64, region_count);
+
+        let buffer = self.create_buffer(
+            name,
+            buffer_size as usize,
+            BufferUsage::UNIFORM | BufferUsage::STORAGE,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+            AllocationScheme::Managed,
+        )?;
+
+        Ok(DynamicUniformRing::new(
+            buffer,
+            alignment,
+            buffer_size / region_count,
+            region_count,
+        ))
+    }
+
+    /// Creates one GpuOnly buffer of the given size to sub-allocate vertex, index or storage ranges from
+    pub fn create_buffer_suballocator(
+        &mut self,
+        name: &str,
+        size: usize,
+        usage: BufferUsage,
+    ) -> Result<BufferSuballocator, VulkanError> {
+        // Index buffers need 4 byte alignment for u32 indices, storage ranges need the device's offset alignment
+        let alignment = if usage.contains(BufferUsage::STORAGE) {
+            self.limits.min_storage_buffer_offset_alignment.max(4)
+        } else {
+            4
+        };
+
+        let buffer = self.create_buffer(
+            name,
+            size,
+            usage | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::GpuOnly,
+            AllocationScheme::Managed,
+        )?;
+        Ok(BufferSuballocator::new(buffer, size as u64, alignment))
+    }
+
+    pub fn destroy_buffer_suballocator(&mut self, suballocator: BufferSuballocator) {
+        self.destroy_buffer(suballocator.buffer);
+    }
+
+    /// Copies data into the current frame's region of the ring, returning where it was written
+    pub fn write_dynamic_uniform_ring(
+        &mut self,
+        ring: &mut DynamicUniformRing,
+        data: &[u8],
+    ) -> Result<BufferOffset, VulkanError> {
+        let buffer = self
+            .resource_manager
+            .buffers
+            .get_mut(ring.buffer.as_key())
+            .ok_or(BufferWriteError::BufferDestroyed)?;
+        let offset = ring
+            .allocate(self.frame_count, data.len() as u64)
+            .ok_or(BufferWriteError::UniformRingFull)?;
+
+        let mapped_slice = buffer
+            .buffer
+            .allocation
+            .mapped_slice_mut()
+            .ok_or(BufferWriteError::BufferNotMapped)?;
+        let write_range = offset as usize..offset as usize + data.len();
+        mapped_slice[write_range.clone()].copy_from_slice(data);
+        buffer.buffer.flush_mapped_range(write_range)?;
+
+        Ok(BufferOffset {
+            buffer: ring.buffer,
+            offset: offset as usize,
+        })
+    }
+
+    pub fn destroy_dynamic_uniform_ring(&mut self, ring: DynamicUniformRing) {
+        self.destroy_buffer(ring.buffer);
+    }
+
+    /// Empty batch using the device's dynamic offset alignment
+    pub fn create_uniform_batch(&self) -> UniformBatch {
+        UniformBatch::new(self.dynamic_offset_alignment())
+    }
+
+    /// Uploads the packed blocks of a non-empty batch into a single CpuToGpu uniform buffer
+    pub fn create_uniform_batch_buffer(
+        &mut self,
+        name: &str,
+        batch: &UniformBatch,
+    ) -> Result<BufferHandle, VulkanError> {
+        self.create_buffer_init(
+            name,
+            BufferUsage::UNIFORM,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+            batch.data(),
+        )
+    }
+
     pub fn create_image(
         &mut self,
         name: &str,
@@ -365,6 +880,58 @@ impl Device {
             self.resource_manager.add_image(image),
         ))
     }
+    pub fn create_cube_image(
+        &mut self,
+        name: &str,
+        description: &ImageDescription2D,
+    ) -> Result<ImageHandle, VulkanError> {
+        let image = Image::new_cube(self.device.clone(), name, description)?;
+
+        Ok(ImageHandle::Persistent(
+            self.resource_manager.add_image(image),
+        ))
+    }
+    /// Creates a 2D array image, used as the attachment of multiview passes
+    pub fn create_image_array(
+        &mut self,
+        name: &str,
+        description: &ImageDescription2D,
+        array_layers: u32,
+    ) -> Result<ImageHandle, VulkanError> {
+        let image = Image::new_2d_array(self.device.clone(), name, description, array_layers)?;
+
+        Ok(ImageHandle::Persistent(
+            self.resource_manager.add_image(image),
+        ))
+    }
+    /// Creates a 3D image, sampled and storage bindings use a 3D view
+    pub fn create_image_3d(
+        &mut self,
+        name: &str,
+        description: &ImageDescription2D,
+        depth: u32,
+    ) -> Result<ImageHandle, VulkanError> {
+        let image = Image::new_3d(self.device.clone(), name, description, depth)?;
+
+        Ok(ImageHandle::Persistent(
+            self.resource_manager.add_image(image),
+        ))
+    }
+    /// Creates the pair of persistent images backing a history image
+    pub fn create_history_image(
+        &mut self,
+        name: &str,
+        description: &ImageDescription2D,
+    ) -> Result<HistoryImage, VulkanError> {
+        let current = self.create_image(&format!("{} 0", name), description)?;
+        let previous = self.create_image(&format!("{} 1", name), description)?;
+        Ok(HistoryImage::new(current, previous))
+    }
+    pub fn destroy_history_image(&mut self, history_image: HistoryImage) {
+        for image in history_image.images() {
+            self.destroy_image(image);
+        }
+    }
     pub fn destroy_image(&mut self, image_handle: ImageHandle) {
         match image_handle {
             ImageHandle::Persistent(key) => self.resource_manager.remove_image(key),
@@ -373,18 +940,57 @@ impl Device {
             }
         }
     }
+    /// Sets the access a persistent image is left in at the end of each frame it is used in,
+    /// so its layout at frame boundaries doesn't depend on the last pass that touched it.
+    /// Defaults to [`ImageResourceAccess::steady_access`] of the image's usage
+    pub fn set_image_rest_access(
+        &mut self,
+        image_handle: ImageHandle,
+        access: Option<ImageResourceAccess>,
+    ) {
+        match image_handle {
+            ImageHandle::Persistent(key) => {
+                self.resource_manager.set_image_rest_access(key, access)
+            }
+            ImageHandle::Transient(index) => {
+                error!("Transient image {index} cannot have a rest access")
+            }
+        }
+    }
+    /// Returns the bindless indices of a persistent image, None for transient or destroyed images
+    pub fn get_image_bindings(&self, image_handle: ImageHandle) -> Option<ImageBindings> {
+        match image_handle {
+            ImageHandle::Persistent(key) => self
+                .resource_manager
+                .get_image(key)
+                .map(|image| image.get_bindings()),
+            ImageHandle::Transient(_) => None,
+        }
+    }
     pub fn update_data_to_image(
         &mut self,
         image_handle: ImageHandle,
         image_size: [u32; 2],
         data: &[u8],
+    ) -> Result<(), VulkanError> {
+        self.upload_image_region(image_handle, [0, 0], image_size, data)
+    }
+
+    /// Uploads tightly packed texel data into a sub-rect of an image.
+    pub fn upload_image_region(
+        &mut self,
+        image_handle: ImageHandle,
+        offset: [u32; 2],
+        region_size: [u32; 2],
+        data: &[u8],
     ) -> Result<(), VulkanError> {
         let mut staging_buffer = Buffer::new(
             self.device.clone(),
             "Stating Buffer",
             data.len() as vk::DeviceSize,
             vk::BufferUsageFlags::TRANSFER_SRC,
-            gpu_allocator::MemoryLocation::CpuToGpu,
+            self.settings.staging_memory.write,
+            AllocationScheme::Managed,
         )?;
 
         let mut_slice = match staging_buffer.allocation.mapped_slice_mut() {
@@ -392,6 +998,7 @@ impl Device {
             Some(mut_slice) => mut_slice,
         };
         mut_slice[0..data.len()].copy_from_slice(data);
+        staging_buffer.flush_mapped_range(0..data.len())?;
 
         let staging_handle =
             BufferHandle::Persistent(self.resource_manager.add_buffer(staging_buffer));
@@ -405,9 +1012,11 @@ impl Device {
             },
             ImageCopyImage {
                 image: image_handle,
-                offset: [0, 0],
+                offset: [offset[0], offset[1], 0],
+                mip_level: 0,
+                base_array_layer: 0,
             },
-            image_size,
+            [region_size[0], region_size[1], 1],
         );
 
         //Destroy stating buffer once frame is done
@@ -426,6 +1035,12 @@ impl Device {
         Ok(image)
     }
 
+    /// Highest SamplerDescription::anisotropy_clamp the device accepts, None when anisotropic filtering is unsupported
+    pub fn max_sampler_anisotropy(&self) -> Option<f32> {
+        self.sampler_anisotropy
+            .then_some(self.limits.max_sampler_anisotropy)
+    }
+
     pub fn create_sampler(
         &mut self,
         name: &str,
@@ -481,7 +1096,13 @@ impl Device {
         shader: &ShaderStage,
     ) -> Result<ComputePipelineHandle, VulkanError> {
         Ok(ComputePipelineHandle(self.pipelines.compute.insert(
-            ComputePipeline::new(self.device.clone(), self.pipelines.layout, shader)?,
+            ComputePipeline::new(
+                self.device.clone(),
+                self.pipelines.layout,
+                &mut self.pipelines.shader_modules,
+                &self.limits,
+                shader,
+            )?,
         )))
     }
     pub fn destroy_compute_pipeline(&mut self, compute_pipeline_handle: ComputePipelineHandle) {
@@ -495,18 +1116,90 @@ impl Device {
         description: &RasterPipelineDescription,
     ) -> Result<RasterPipelineHandle, VulkanError> {
         Ok(RasterPipelineHandle(self.pipelines.raster.insert(
-            RasterPipeline::new(self.device.clone(), self.pipelines.layout, description)?,
+            RasterPipeline::new(
+                self.device.clone(),
+                self.pipelines.layout,
+                &mut self.pipelines.shader_modules,
+                description,
+            )?,
         )))
     }
     pub fn destroy_raster_pipeline(&mut self, raster_pipeline_handle: RasterPipelineHandle) {
         drop(self.pipelines.raster.remove(raster_pipeline_handle.0))
     }
 
+    /// Number of shader modules currently shared between live pipelines
+    pub fn shader_module_count(&self) -> usize {
+        self.pipelines.shader_modules.len()
+    }
+
+    /// Creates a render graph builder that validates transient image formats against this device,
+    /// and persistent handles and their usage against the live resources
+    pub fn create_render_graph_builder(&self) -> BasicRenderGraphBuilder {
+        let mut render_graph_builder =
+            BasicRenderGraphBuilder::with_format_support(FormatSupport::new(self.device.clone()));
+        render_graph_builder.set_max_push_constants_size(self.limits.max_push_constants_size);
+        render_graph_builder
+            .set_dynamic_buffer_limits(DynamicBufferLimits::from_device_limits(&self.limits));
+        render_graph_builder.set_live_resources(self.resource_manager.live_resources());
+        render_graph_builder.set_frame_arena(self.frame_arena.clone());
+        render_graph_builder
+    }
+
+    /// Image usage flags that can be requested in [`SurfaceSettings::usage`] for this surface
+    pub fn get_surface_supported_usage(
+        &self,
+        surface_handle: SurfaceHandle,
+    ) -> Result<vk::ImageUsageFlags, VulkanError> {
+        Ok(crate::swapchain::get_supported_usage(
+            &self.device,
+            surface_handle,
+        )?)
+    }
+
+    /// Present modes that can be requested in [`SurfaceSettings::present_mode`] for this surface
+    pub fn get_surface_present_modes(
+        &self,
+        surface_handle: SurfaceHandle,
+    ) -> Result<Vec<vk::PresentModeKHR>, VulkanError> {
+        Ok(crate::swapchain::get_supported_present_modes(
+            &self.device,
+            surface_handle,
+        )?)
+    }
+
+    /// Formats that can be requested in [`SurfaceSettings::format`] for this surface
+    pub fn get_surface_formats(
+        &self,
+        surface_handle: SurfaceHandle,
+    ) -> Result<Vec<vk::SurfaceFormatKHR>, VulkanError> {
+        Ok(crate::swapchain::get_supported_formats(
+            &self.device,
+            surface_handle,
+        )?)
+    }
+
+    /// Picks the first of the preferred formats the surface supports, or any supported format if there are none
+    pub fn pick_surface_format(
+        &self,
+        surface_handle: SurfaceHandle,
+        preferred: &[vk::SurfaceFormatKHR],
+    ) -> Result<vk::SurfaceFormatKHR, VulkanError> {
+        let available = self.get_surface_formats(surface_handle)?;
+        crate::swapchain::pick_surface_format(&available, preferred)
+            .ok_or(VulkanError::Vk(vk::Result::ERROR_FORMAT_NOT_SUPPORTED))
+    }
+
     pub fn configure_surface(
         &mut self,
         surface_handle: SurfaceHandle,
         settings: &SurfaceSettings,
     ) -> Result<(), VulkanError> {
+        crate::swapchain::validate_usage(
+            settings.usage,
+            self.get_surface_supported_usage(surface_handle)?,
+        )?;
+
         if let Some(swapchain) = self.swapchain_manager.get(surface_handle) {
             swapchain.update_settings(settings)?;
         } else {
@@ -519,22 +1212,198 @@ impl Device {
 
         Ok(())
     }
+    /// Transform the surface's swapchain was created with, None if it isn't configured or has no swapchain yet
+    pub fn get_surface_pre_transform(
+        &mut self,
+        surface_handle: SurfaceHandle,
+    ) -> Option<vk::SurfaceTransformFlagsKHR> {
+        self.swapchain_manager
+            .get(surface_handle)
+            .and_then(|swapchain| swapchain.pre_transform())
+    }
+
     pub fn release_surface(&mut self, surface_handle: SurfaceHandle) {
         self.swapchain_manager.remove(surface_handle);
     }
 
-    pub fn submit_graph(&mut self, render_graph: &CompiledRenderGraph) -> Result<(), VulkanError> {
-        self.graph_executor.submit_frame(
+    /// Submits the graph, acquiring and presenting any swapchain images it uses.
+    /// Nothing is submitted while any of those surfaces is minimized, see [`FrameResult::skipped`]
+    pub fn submit_graph(
+        &mut self,
+        render_graph: &CompiledRenderGraph,
+    ) -> Result<FrameResult, VulkanError> {
+        if !self.device.multiview_support && render_graph.uses_multiview() {
+            return Err(VulkanError::UnsupportedFeature("multiview".to_string()));
+        }
+
+        let swapchain_manager = &mut self.swapchain_manager;
+        if let Some(skipped_frame) = skipped_frame_result(render_graph, |surface_handle| {
+            match swapchain_manager.get(surface_handle) {
+                Some(swapchain) => Ok(swapchain.is_minimized()?),
+                None => Ok(false),
+            }
+        })? {
+            return Ok(skipped_frame);
+        }
+
+        let frame_result = self.graph_executor.submit_frame(
             &mut self.resource_manager,
             &mut self.swapchain_manager,
             &self.pipelines,
             self.upload_queue.get_pass(),
             render_graph,
         )?;
-        Ok(())
+        self.frame_count += 1;
+        Ok(frame_result)
+    }
+
+    /// Builds and submits a frame in one call. The closure receives a builder and one swapchain image per surface,
+    /// in the same order as `surfaces`, and the images are presented once the graph is submitted
+    pub fn render_frame<E>(
+        &mut self,
+        surfaces: &[SurfaceHandle],
+        record: impl FnOnce(&mut BasicRenderGraphBuilder, &[ImageHandle]) -> Result<(), E>,
+    ) -> Result<FrameResult, E>
+    where
+        E: From<VulkanError> + From<RenderGraphError>,
+    {
+        let render_graph = build_frame_graph(self.create_render_graph_builder(), surfaces, record)?;
+        Ok(self.submit_graph(&render_graph)?)
+    }
+
+    /// Id of the most recently submitted graph's frame, for use with [`Device::wait_for_frame`]
+    pub fn last_submitted_frame(&self) -> Option<u64> {
+        self.graph_executor.last_submitted_frame()
+    }
+
+    /// Enables writing gpu timestamps around every render pass, returns false if the device can't write timestamps
+    pub fn set_pass_timings_enabled(&mut self, enabled: bool) -> bool {
+        self.graph_executor.set_pass_timings_enabled(enabled)
+    }
+
+    /// Sets how often the bindless descriptor set is bound while recording, takes effect on the next submitted frame
+    pub fn set_descriptor_bind_frequency(&mut self, frequency: DescriptorBindFrequency) {
+        self.graph_executor.set_descriptor_bind_frequency(frequency)
+    }
+
+    /// Pass timings of the most recent frame the gpu has finished, None while pass timings are disabled
+    pub fn last_frame_timings(&self) -> Option<&FrameTimings> {
+        self.graph_executor.last_frame_timings()
+    }
+
+    /// Most command buffers any single frame has used per queue, for tuning `command_buffer_capacity`
+    pub fn command_buffer_high_water_mark(&self) -> CommandBufferCounts {
+        self.graph_executor.command_buffer_high_water_mark()
+    }
+
+    /// Blocks until the gpu has finished a previously submitted frame or the timeout elapses
+    pub fn wait_for_frame(&self, frame_id: u64, timeout_ns: u64) -> Result<(), VulkanError> {
+        self.graph_executor.wait_for_frame(frame_id, timeout_ns)
+    }
+
+    /// Ordered (pass label, access) list for a buffer index of the last submitted graph
+    #[cfg(feature = "track-access-history")]
+    pub fn buffer_access_history(
+        &self,
+        index: crate::render_graph::BufferIndex,
+    ) -> &[(String, crate::BufferResourceAccess)] {
+        self.graph_executor.buffer_access_history(index)
+    }
+
+    /// Ordered (pass label, access) list for an image index of the last submitted graph
+    #[cfg(feature = "track-access-history")]
+    pub fn image_access_history(
+        &self,
+        index: crate::render_graph::ImageIndex,
+    ) -> &[(String, ImageResourceAccess)] {
+        self.graph_executor.image_access_history(index)
     }
 }
 
+/// Copies the start of src into dst right away, the device must be idle.
+/// Mapped buffers are copied on the CPU, others with a one time command buffer
+fn copy_idle_buffer(device: &AshDevice, src: &Buffer, dst: &mut Buffer) -> Result<(), VulkanError> {
+    let size = src.size.min(dst.size);
+    if let (Some(src_slice), Some(dst_slice)) = (
+        src.allocation.mapped_slice(),
+        dst.allocation.mapped_slice_mut(),
+    ) {
+        dst_slice[0..size as usize].copy_from_slice(&src_slice[0..size as usize]);
+        return dst.flush_mapped_range(0..size as usize);
+    }
+
+    let Some(queue) = device
+        .graphics_queue
+        .as_ref()
+        .or(device.compute_queue.as_ref())
+        .or(device.transfer_queue.as_ref())
+    else {
+        return Err(VulkanError::UnsupportedFeature(String::from(
+            "a queue to copy buffers on",
+        )));
+    };
+
+    let pool = unsafe {
+        device.core.create_command_pool(
+            &vk::CommandPoolCreateInfo::builder()
+                .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                .queue_family_index(queue.family_index),
+            None,
+        )
+    }?;
+    let fence = match unsafe {
+        device
+            .core
+            .create_fence(&vk::FenceCreateInfo::default(), None)
+    } {
+        Ok(fence) => fence,
+        Err(err) => {
+            unsafe { device.core.destroy_command_pool(pool, None) };
+            return Err(err.into());
+        }
+    };
+
+    let result = unsafe {
+        (|| {
+            let command_buffer = device.core.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(pool)
+                    .command_buffer_count(1),
+            )?[0];
+            device.core.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            device.core.cmd_copy_buffer(
+                command_buffer,
+                src.handle,
+                dst.handle,
+                &[vk::BufferCopy {
+                    src_offset: 0,
+                    dst_offset: 0,
+                    size,
+                }],
+            );
+            device.core.end_command_buffer(command_buffer)?;
+            device.core.queue_submit(
+                queue.handle,
+                &[vk::SubmitInfo::builder()
+                    .command_buffers(&[command_buffer])
+                    .build()],
+                fence,
+            )?;
+            device.core.wait_for_fences(&[fence], true, u64::MAX)
+        })()
+    };
+
+    unsafe {
+        device.core.destroy_fence(fence, None);
+        device.core.destroy_command_pool(pool, None);
+    }
+    Ok(result?)
+}
+
 impl Drop for Device {
     fn drop(&mut self) {
         unsafe {
@@ -542,3 +1411,340 @@ impl Drop for Device {
         }
     }
 }
+
+/// Bytes a resize carries over into the new buffer, the overlap of both sizes or none without preserve_contents.
+/// Copying needs either mapped memory or TRANSFER usage
+fn preserved_size(
+    old_size: usize,
+    new_size: usize,
+    preserve_contents: bool,
+    copyable: bool,
+) -> Result<usize, VulkanError> {
+    if !preserve_contents {
+        return Ok(0);
+    }
+    if !copyable {
+        return Err(VulkanError::ResizeRequiresTransfer);
+    }
+    Ok(old_size.min(new_size))
+}
+
+/// Copies the whole staging buffer into the start of the new buffer before the next submitted graph
+fn queue_staged_init(
+    upload_queue: &mut UploadQueue,
+    staging_buffer: BufferHandle,
+    buffer: BufferHandle,
+    size: usize,
+) {
+    upload_queue.add_buffer_upload(
+        BufferOffset {
+            buffer: staging_buffer,
+            offset: 0,
+        },
+        BufferOffset { buffer, offset: 0 },
+        size,
+    );
+}
+
+/// Result for a frame that can't be submitted because some of its surfaces are minimized, None if all are presentable
+fn skipped_frame_result(
+    render_graph: &CompiledRenderGraph,
+    mut is_minimized: impl FnMut(SurfaceHandle) -> Result<bool, VulkanError>,
+) -> Result<Option<FrameResult>, VulkanError> {
+    let mut minimized_surfaces = Vec::new();
+    for (surface_handle, _) in render_graph.swapchain_images.iter() {
+        if is_minimized(*surface_handle)? {
+            minimized_surfaces.push(*surface_handle);
+        }
+    }
+
+    if minimized_surfaces.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(FrameResult {
+            minimized_surfaces,
+            ..Default::default()
+        }))
+    }
+}
+
+/// Acquires one swapchain image per surface, lets `record` write the frame's passes, then builds the graph
+fn build_frame_graph<E>(
+    mut render_graph_builder: BasicRenderGraphBuilder,
+    surfaces: &[SurfaceHandle],
+    record: impl FnOnce(&mut BasicRenderGraphBuilder, &[ImageHandle]) -> Result<(), E>,
+) -> Result<CompiledRenderGraph, E>
+where
+    E: From<RenderGraphError>,
+{
+    let swapchain_images: Vec<ImageHandle> = surfaces
+        .iter()
+        .map(|surface| render_graph_builder.acquire_swapchain_image(*surface))
+        .collect();
+
+    record(&mut render_graph_builder, &swapchain_images)?;
+
+    Ok(render_graph_builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_graph_builder::{ClearValue, RasterPassBuilder};
+    use crate::SurfaceKey;
+
+    #[test]
+    fn frame_closure_receives_a_builder_and_the_swapchain_images() {
+        let mut surface_keys = slotmap::SlotMap::<SurfaceKey, ()>::with_key();
+        let surfaces = [
+            SurfaceHandle(surface_keys.insert(())),
+            SurfaceHandle(surface_keys.insert(())),
+        ];
+
+        let mut calls = 0;
+        let render_graph = build_frame_graph(
+            BasicRenderGraphBuilder::new(),
+            &surfaces,
+            |render_graph_builder, swapchain_images| -> Result<(), RenderGraphError> {
+                calls += 1;
+                assert_eq!(swapchain_images.len(), surfaces.len());
+
+                let mut raster_pass = RasterPassBuilder::new("clear_swapchain");
+                raster_pass
+                    .add_color_attachment(swapchain_images[0], Some(ClearValue::Float([0.0; 4])));
+                raster_pass.build(render_graph_builder);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(calls, 1);
+        let acquired: Vec<SurfaceHandle> = render_graph
+            .swapchain_images
+            .iter()
+            .map(|(surface, _)| *surface)
+            .collect();
+        assert_eq!(acquired, surfaces);
+    }
+
+    #[test]
+    fn zero_extent_surfaces_skip_the_frame() {
+        let mut surface_keys = slotmap::SlotMap::<SurfaceKey, ()>::with_key();
+        let visible = SurfaceHandle(surface_keys.insert(()));
+        let minimized = SurfaceHandle(surface_keys.insert(()));
+
+        let render_graph = build_frame_graph(
+            BasicRenderGraphBuilder::new(),
+            &[visible, minimized],
+            |_, _| -> Result<(), RenderGraphError> { Ok(()) },
+        )
+        .unwrap();
+
+        let mut checks = 0;
+        let frame_result = skipped_frame_result(&render_graph, |surface| {
+            checks += 1;
+            Ok(surface == minimized)
+        })
+        .unwrap()
+        .expect("A minimized surface should skip the frame");
+        assert_eq!(checks, 2);
+        assert!(frame_result.skipped());
+        assert_eq!(frame_result.minimized_surfaces, [minimized]);
+
+        assert_eq!(
+            skipped_frame_result(&render_graph, |_| Ok(false)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn resizing_preserves_the_overlapping_contents() {
+        assert_eq!(preserved_size(256, 1024, true, true).unwrap(), 256);
+        assert_eq!(preserved_size(1024, 256, true, true).unwrap(), 256);
+        assert_eq!(preserved_size(256, 1024, false, false).unwrap(), 0);
+        assert!(matches!(
+            preserved_size(256, 1024, true, false),
+            Err(VulkanError::ResizeRequiresTransfer)
+        ));
+
+        // Device local contents are copied from the old buffer into the new one before the next graph
+        let mut buffer_keys = slotmap::SlotMap::<BufferKey, ()>::with_key();
+        let (key, old_key) = (buffer_keys.insert(()), buffer_keys.insert(()));
+        let mut upload_queue = UploadQueue::default();
+        upload_queue.add_buffer_upload(
+            BufferOffset {
+                buffer: BufferHandle::Persistent(old_key),
+                offset: 0,
+            },
+            BufferOffset {
+                buffer: BufferHandle::Persistent(key),
+                offset: 0,
+            },
+            256,
+        );
+        let upload_pass = upload_queue.get_pass().unwrap();
+        let render_pass = &upload_pass.command_buffer.render_pass_sets[0].render_passes[0];
+        let Some(crate::render_graph::RenderPassCommand::Transfer { transfers }) =
+            &render_pass.command
+        else {
+            panic!("The upload pass should be a transfer pass");
+        };
+        let crate::render_graph::Transfer::BufferToBuffer {
+            src,
+            dst,
+            copy_size,
+        } = &transfers[0]
+        else {
+            panic!("The copy should be buffer to buffer");
+        };
+        assert_eq!(*copy_size, 256);
+        let persistent_key = |index: usize| match upload_pass.buffer_resources[index].description {
+            crate::render_graph::BufferResourceDescription::Persistent(key) => key,
+            _ => panic!("Uploads only use persistent buffers"),
+        };
+        assert_eq!(persistent_key(src.buffer), old_key);
+        assert_eq!(persistent_key(dst.buffer), key);
+    }
+
+    #[test]
+    fn staging_memory_must_be_host_visible() {
+        assert!(StagingMemoryLocations::default().validate().is_ok());
+        for (locations, expected_usage, expected_location) in [
+            (
+                StagingMemoryLocations {
+                    write: gpu_allocator::MemoryLocation::GpuOnly,
+                    ..Default::default()
+                },
+                "write",
+                gpu_allocator::MemoryLocation::GpuOnly,
+            ),
+            (
+                StagingMemoryLocations {
+                    read: gpu_allocator::MemoryLocation::Unknown,
+                    ..Default::default()
+                },
+                "read",
+                gpu_allocator::MemoryLocation::Unknown,
+            ),
+        ] {
+            match locations.validate() {
+                Err(VulkanError::StagingMemoryNotHostVisible { usage, location }) => {
+                    assert_eq!(usage, expected_usage);
+                    assert_eq!(location, expected_location);
+                }
+                result => panic!("Expected StagingMemoryNotHostVisible, got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn configured_staging_locations_reach_the_staging_buffers() {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 3,
+            ..Default::default()
+        };
+        memory_properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        memory_properties.memory_types[1].property_flags =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        memory_properties.memory_types[2].property_flags =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_CACHED;
+        let host_cached = host_cached_memory_types(&memory_properties);
+        assert_eq!(host_cached, 0b100);
+
+        // Memory types and location the staging buffer is allocated from
+        let allocation = |memory_type_bits, location, preferred_memory_types| {
+            let requirements = vk::MemoryRequirements {
+                size: 256,
+                alignment: 16,
+                memory_type_bits,
+            };
+            let (requirements, location) =
+                crate::buffer::memory_preference(requirements, location, preferred_memory_types);
+            (requirements.memory_type_bits, location)
+        };
+
+        // Uploads and overridden read-back locations allocate from the configured location
+        let locations = StagingMemoryLocations {
+            write: gpu_allocator::MemoryLocation::GpuToCpu,
+            read: gpu_allocator::MemoryLocation::CpuToGpu,
+        };
+        assert_eq!(
+            allocation(0b111, locations.write, 0),
+            (0b111, gpu_allocator::MemoryLocation::GpuToCpu)
+        );
+        assert_eq!(
+            allocation(
+                0b111,
+                locations.read,
+                locations.read_memory_types(host_cached)
+            ),
+            (0b111, gpu_allocator::MemoryLocation::CpuToGpu)
+        );
+
+        // GpuToCpu read-back prefers the host cached memory type, even though it isn't coherent
+        let locations = StagingMemoryLocations::default();
+        assert_eq!(
+            allocation(
+                0b111,
+                locations.read,
+                locations.read_memory_types(host_cached)
+            ),
+            (0b100, gpu_allocator::MemoryLocation::Unknown)
+        );
+
+        // Buffers that can't use host cached memory fall back to the configured location
+        assert_eq!(
+            allocation(
+                0b011,
+                locations.read,
+                locations.read_memory_types(host_cached)
+            ),
+            (0b011, gpu_allocator::MemoryLocation::GpuToCpu)
+        );
+    }
+
+    #[test]
+    fn staged_init_copies_the_whole_staging_buffer_to_the_start_of_the_buffer() {
+        use crate::render_graph::{BufferResourceDescription, RenderPassCommand, Transfer};
+
+        let mut keys = slotmap::SlotMap::<BufferKey, ()>::with_key();
+        let (staging_key, buffer_key) = (keys.insert(()), keys.insert(()));
+        let mut upload_queue = UploadQueue::default();
+        queue_staged_init(
+            &mut upload_queue,
+            BufferHandle::Persistent(staging_key),
+            BufferHandle::Persistent(buffer_key),
+            300,
+        );
+
+        let upload_pass = upload_queue.get_pass().unwrap();
+        let keys: Vec<_> = upload_pass
+            .buffer_resources
+            .iter()
+            .map(|resource| match resource.description {
+                BufferResourceDescription::Persistent(key) => key,
+                _ => panic!("Staged init only uses persistent buffers"),
+            })
+            .collect();
+        assert_eq!(keys, [staging_key, buffer_key]);
+
+        let render_pass = &upload_pass.command_buffer.render_pass_sets[0].render_passes[0];
+        let Some(RenderPassCommand::Transfer { transfers }) = &render_pass.command else {
+            panic!("Upload pass should be a transfer pass");
+        };
+        assert_eq!(
+            transfers,
+            &[Transfer::BufferToBuffer {
+                src: crate::render_graph::BufferOffset {
+                    buffer: 0,
+                    offset: 0
+                },
+                dst: crate::render_graph::BufferOffset {
+                    buffer: 1,
+                    offset: 0
+                },
+                copy_size: 300,
+            }]
+        );
+    }
+}
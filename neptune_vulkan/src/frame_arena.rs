@@ -0,0 +1,99 @@
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+use bumpalo::Bump;
+use std::alloc::Layout;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Vec for scratch data that doesn't outlive the graph building or recording call that creates it
+pub(crate) type ScratchVec<'a, T> = allocator_api2::vec::Vec<T, ScratchAllocator<'a>>;
+
+/// Bump arena shared by the render graph builders and the executor, reset each frame.
+/// Disabled arenas leave scratch collections on the heap
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FrameArena(Option<Arc<Mutex<Bump>>>);
+
+impl FrameArena {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self(enabled.then(|| Arc::new(Mutex::new(Bump::new()))))
+    }
+
+    /// Scratch collections are allocated from the heap while the arena is already locked, e.g. by a builder on another thread
+    pub(crate) fn lock(&self) -> FrameArenaGuard<'_> {
+        FrameArenaGuard(self.0.as_ref().and_then(|arena| arena.try_lock().ok()))
+    }
+
+    /// Waits for any scratch collections still alive to be dropped, keeping the arena's largest chunk for the next frame
+    pub(crate) fn reset(&self) {
+        if let Some(arena) = &self.0 {
+            arena
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .reset();
+        }
+    }
+}
+
+pub(crate) struct FrameArenaGuard<'a>(Option<MutexGuard<'a, Bump>>);
+
+impl FrameArenaGuard<'_> {
+    pub(crate) fn allocator(&self) -> ScratchAllocator<'_> {
+        match &self.0 {
+            Some(arena) => ScratchAllocator::Arena(arena),
+            None => ScratchAllocator::Heap,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ScratchAllocator<'a> {
+    Heap,
+    Arena(&'a Bump),
+}
+
+impl<'a> ScratchAllocator<'a> {
+    pub(crate) fn collect<T>(self, iter: impl IntoIterator<Item = T>) -> ScratchVec<'a, T> {
+        let mut vec = ScratchVec::new_in(self);
+        vec.extend(iter);
+        vec
+    }
+}
+
+unsafe impl Allocator for ScratchAllocator<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self {
+            Self::Heap => Global.allocate(layout),
+            Self::Arena(arena) => arena.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self {
+            Self::Heap => Global.deallocate(ptr, layout),
+            Self::Arena(arena) => arena.deallocate(ptr, layout),
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match self {
+            Self::Heap => Global.grow(ptr, old_layout, new_layout),
+            Self::Arena(arena) => arena.grow(ptr, old_layout, new_layout),
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match self {
+            Self::Heap => Global.shrink(ptr, old_layout, new_layout),
+            Self::Arena(arena) => arena.shrink(ptr, old_layout, new_layout),
+        }
+    }
+}
@@ -1,26 +1,333 @@
 use crate::descriptor_set::{DescriptorBinding, GpuBindingIndex};
 use crate::device::AshDevice;
-use crate::{ImageHandle, VulkanError};
+use crate::render_graph_builder::{AttachmentSubresource, CUBEMAP_FACE_COUNT};
+use crate::{AllocationScheme, ImageHandle, VulkanError};
 use ash::vk;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 pub fn vk_format_get_aspect_flags(format: vk::Format) -> vk::ImageAspectFlags {
-    match format {
-        vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => {
-            vk::ImageAspectFlags::DEPTH
-        }
-        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
-        vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT => {
-            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    let mut aspect_flags = vk::ImageAspectFlags::empty();
+    if vk_format_is_depth(format) {
+        aspect_flags |= vk::ImageAspectFlags::DEPTH;
+    }
+    if vk_format_is_stencil(format) {
+        aspect_flags |= vk::ImageAspectFlags::STENCIL;
+    }
+    if aspect_flags.is_empty() {
+        vk::ImageAspectFlags::COLOR
+    } else {
+        aspect_flags
+    }
+}
+
+/// True if the format has a depth component, including combined depth-stencil formats
+pub fn vk_format_is_depth(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM
+            | vk::Format::D32_SFLOAT
+            | vk::Format::X8_D24_UNORM_PACK32
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+/// True if the format has a stencil component, including combined depth-stencil formats
+pub fn vk_format_is_stencil(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+/// True if the format stores sRGB encoded color, reads decode to linear and writes encode from linear
+pub fn vk_format_is_srgb(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_SRGB
+            | vk::Format::R8G8_SRGB
+            | vk::Format::R8G8B8_SRGB
+            | vk::Format::B8G8R8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+            | vk::Format::BC1_RGB_SRGB_BLOCK
+            | vk::Format::BC1_RGBA_SRGB_BLOCK
+            | vk::Format::BC2_SRGB_BLOCK
+            | vk::Format::BC3_SRGB_BLOCK
+            | vk::Format::BC7_SRGB_BLOCK
+    )
+}
+
+/// Number of components in the format, depth and stencil count as one each. None for unknown formats
+pub fn vk_format_channel_count(format: vk::Format) -> Option<u32> {
+    if vk_format_is_depth(format) || vk_format_is_stencil(format) {
+        return Some(vk_format_is_depth(format) as u32 + vk_format_is_stencil(format) as u32);
+    }
+
+    Some(match format {
+        vk::Format::R8_UNORM
+        | vk::Format::R8_SNORM
+        | vk::Format::R8_UINT
+        | vk::Format::R8_SINT
+        | vk::Format::R8_SRGB
+        | vk::Format::R16_UNORM
+        | vk::Format::R16_SNORM
+        | vk::Format::R16_UINT
+        | vk::Format::R16_SINT
+        | vk::Format::R16_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::R32_SINT
+        | vk::Format::R32_SFLOAT
+        | vk::Format::R64_UINT
+        | vk::Format::R64_SINT
+        | vk::Format::R64_SFLOAT => 1,
+        vk::Format::R8G8_UNORM
+        | vk::Format::R8G8_SNORM
+        | vk::Format::R8G8_UINT
+        | vk::Format::R8G8_SINT
+        | vk::Format::R8G8_SRGB
+        | vk::Format::R16G16_UNORM
+        | vk::Format::R16G16_SNORM
+        | vk::Format::R16G16_UINT
+        | vk::Format::R16G16_SINT
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R32G32_UINT
+        | vk::Format::R32G32_SINT
+        | vk::Format::R32G32_SFLOAT
+        | vk::Format::R64G64_UINT
+        | vk::Format::R64G64_SINT
+        | vk::Format::R64G64_SFLOAT => 2,
+        vk::Format::R8G8B8_UNORM
+        | vk::Format::R8G8B8_SNORM
+        | vk::Format::R8G8B8_UINT
+        | vk::Format::R8G8B8_SINT
+        | vk::Format::R8G8B8_SRGB
+        | vk::Format::B8G8R8_UNORM
+        | vk::Format::B8G8R8_SNORM
+        | vk::Format::B8G8R8_UINT
+        | vk::Format::B8G8R8_SINT
+        | vk::Format::B8G8R8_SRGB
+        | vk::Format::R16G16B16_UNORM
+        | vk::Format::R16G16B16_SNORM
+        | vk::Format::R16G16B16_UINT
+        | vk::Format::R16G16B16_SINT
+        | vk::Format::R16G16B16_SFLOAT
+        | vk::Format::R32G32B32_UINT
+        | vk::Format::R32G32B32_SINT
+        | vk::Format::R32G32B32_SFLOAT
+        | vk::Format::R64G64B64_UINT
+        | vk::Format::R64G64B64_SINT
+        | vk::Format::R64G64B64_SFLOAT
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::E5B9G9R9_UFLOAT_PACK32 => 3,
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SNORM
+        | vk::Format::R8G8B8A8_UINT
+        | vk::Format::R8G8B8A8_SINT
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SNORM
+        | vk::Format::B8G8R8A8_UINT
+        | vk::Format::B8G8R8A8_SINT
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A8B8G8R8_UNORM_PACK32
+        | vk::Format::A8B8G8R8_SNORM_PACK32
+        | vk::Format::A8B8G8R8_UINT_PACK32
+        | vk::Format::A8B8G8R8_SINT_PACK32
+        | vk::Format::A8B8G8R8_SRGB_PACK32
+        | vk::Format::A2R10G10B10_UNORM_PACK32
+        | vk::Format::A2R10G10B10_UINT_PACK32
+        | vk::Format::A2R10G10B10_SINT_PACK32
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2B10G10R10_UINT_PACK32
+        | vk::Format::A2B10G10R10_SINT_PACK32
+        | vk::Format::R16G16B16A16_UNORM
+        | vk::Format::R16G16B16A16_SNORM
+        | vk::Format::R16G16B16A16_UINT
+        | vk::Format::R16G16B16A16_SINT
+        | vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::R32G32B32A32_UINT
+        | vk::Format::R32G32B32A32_SINT
+        | vk::Format::R32G32B32A32_SFLOAT
+        | vk::Format::R64G64B64A64_UINT
+        | vk::Format::R64G64B64A64_SINT
+        | vk::Format::R64G64B64A64_SFLOAT => 4,
+        _ => return None,
+    })
+}
+
+/// Size of a single texel, None for block compressed and unknown formats.
+/// Buffer copies of depth-stencil formats copy one aspect at a time, see the Vulkan spec for their layouts
+pub fn vk_format_bytes_per_pixel(format: vk::Format) -> Option<u32> {
+    Some(match format {
+        vk::Format::S8_UINT => 1,
+        vk::Format::D16_UNORM => 2,
+        vk::Format::D16_UNORM_S8_UINT => 3,
+        vk::Format::D32_SFLOAT
+        | vk::Format::X8_D24_UNORM_PACK32
+        | vk::Format::D24_UNORM_S8_UINT => 4,
+        vk::Format::D32_SFLOAT_S8_UINT => 5,
+        vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::E5B9G9R9_UFLOAT_PACK32
+        | vk::Format::A2R10G10B10_UNORM_PACK32
+        | vk::Format::A2R10G10B10_UINT_PACK32
+        | vk::Format::A2R10G10B10_SINT_PACK32
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2B10G10R10_UINT_PACK32
+        | vk::Format::A2B10G10R10_SINT_PACK32 => 4,
+        _ => {
+            let channel_count = vk_format_channel_count(format)?;
+            // Core format values are laid out contiguously by component size
+            let raw = format.as_raw();
+            let in_range = |first: vk::Format, last: vk::Format| {
+                (first.as_raw()..=last.as_raw()).contains(&raw)
+            };
+            let channel_bytes = if in_range(vk::Format::R8_UNORM, vk::Format::A8B8G8R8_SRGB_PACK32)
+            {
+                1
+            } else if in_range(vk::Format::R16_UNORM, vk::Format::R16G16B16A16_SFLOAT) {
+                2
+            } else if in_range(vk::Format::R32_UINT, vk::Format::R32G32B32A32_SFLOAT) {
+                4
+            } else if in_range(vk::Format::R64_UINT, vk::Format::R64G64B64A64_SFLOAT) {
+                8
+            } else {
+                return None;
+            };
+            channel_count * channel_bytes
         }
-        _ => vk::ImageAspectFlags::COLOR,
+    })
+}
+
+/// How a format's color values are interpreted, integer formats must be cleared with matching integer values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FormatNumericType {
+    Float,
+    Sint,
+    Uint,
+}
+
+pub(crate) fn vk_format_numeric_type(format: vk::Format) -> FormatNumericType {
+    match format {
+        vk::Format::R8_UINT
+        | vk::Format::R8G8_UINT
+        | vk::Format::R8G8B8_UINT
+        | vk::Format::B8G8R8_UINT
+        | vk::Format::R8G8B8A8_UINT
+        | vk::Format::B8G8R8A8_UINT
+        | vk::Format::A8B8G8R8_UINT_PACK32
+        | vk::Format::A2R10G10B10_UINT_PACK32
+        | vk::Format::A2B10G10R10_UINT_PACK32
+        | vk::Format::R16_UINT
+        | vk::Format::R16G16_UINT
+        | vk::Format::R16G16B16_UINT
+        | vk::Format::R16G16B16A16_UINT
+        | vk::Format::R32_UINT
+        | vk::Format::R32G32_UINT
+        | vk::Format::R32G32B32_UINT
+        | vk::Format::R32G32B32A32_UINT
+        | vk::Format::R64_UINT
+        | vk::Format::R64G64_UINT
+        | vk::Format::R64G64B64_UINT
+        | vk::Format::R64G64B64A64_UINT => FormatNumericType::Uint,
+        vk::Format::R8_SINT
+        | vk::Format::R8G8_SINT
+        | vk::Format::R8G8B8_SINT
+        | vk::Format::B8G8R8_SINT
+        | vk::Format::R8G8B8A8_SINT
+        | vk::Format::B8G8R8A8_SINT
+        | vk::Format::A8B8G8R8_SINT_PACK32
+        | vk::Format::A2R10G10B10_SINT_PACK32
+        | vk::Format::A2B10G10R10_SINT_PACK32
+        | vk::Format::R16_SINT
+        | vk::Format::R16G16_SINT
+        | vk::Format::R16G16B16_SINT
+        | vk::Format::R16G16B16A16_SINT
+        | vk::Format::R32_SINT
+        | vk::Format::R32G32_SINT
+        | vk::Format::R32G32B32_SINT
+        | vk::Format::R32G32B32A32_SINT
+        | vk::Format::R64_SINT
+        | vk::Format::R64G64_SINT
+        | vk::Format::R64G64B64_SINT
+        | vk::Format::R64G64B64A64_SINT => FormatNumericType::Sint,
+        _ => FormatNumericType::Float,
+    }
+}
+
+/// Converts tightly packed pixels read back from a 4 channel 8 bit color image, e.g. a BGRA swapchain,
+/// into RGBA8 for encoding. color_space is the surface's for swapchain images and None otherwise.
+/// UNORM data is sRGB encoded when presented with SRGB_NONLINEAR and linear otherwise, with srgb_output
+/// the color channels are encoded or decoded to match, alpha is always copied as is. None for other formats
+pub fn vk_format_to_rgba8(
+    format: vk::Format,
+    color_space: Option<vk::ColorSpaceKHR>,
+    pixels: &[u8],
+    srgb_output: bool,
+) -> Option<Vec<u8>> {
+    let swap_red_blue = match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => false,
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+        _ => return None,
+    };
+
+    // The presentation engine reads UNORM swapchain texels as already encoded in the surface color space
+    let srgb_encoded =
+        vk_format_is_srgb(format) || color_space == Some(vk::ColorSpaceKHR::SRGB_NONLINEAR);
+    let transfer: Option<fn(f32) -> f32> = match (srgb_encoded, srgb_output) {
+        (true, false) => Some(|c| {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }),
+        (false, true) => Some(|c| {
+            if c <= 0.0031308 {
+                c * 12.92
+            } else {
+                1.055 * c.powf(1.0 / 2.4) - 0.055
+            }
+        }),
+        _ => None,
+    };
+    let mut table = [0u8; 256];
+    for (i, value) in table.iter_mut().enumerate() {
+        *value = match transfer {
+            Some(transfer) => (transfer(i as f32 / 255.0) * 255.0).round() as u8,
+            None => i as u8,
+        };
     }
+
+    let mut rgba = Vec::with_capacity(pixels.len());
+    for pixel in pixels.chunks_exact(4) {
+        let (r, b) = if swap_red_blue {
+            (pixel[2], pixel[0])
+        } else {
+            (pixel[0], pixel[2])
+        };
+        rgba.extend_from_slice(&[
+            table[r as usize],
+            table[pixel[1] as usize],
+            table[b as usize],
+            pixel[3],
+        ]);
+    }
+    Some(rgba)
 }
 
 #[derive(Debug, Clone)]
 pub enum TransientImageSize {
     Exact(vk::Extent2D),
     Relative([f32; 2], ImageHandle),
+    /// Width, height and depth of a 3D image
+    Volume([u32; 3]),
 }
 
 #[derive(Debug, Clone)]
@@ -40,7 +347,84 @@ impl TransientImageDesc {
             usage: self.usage,
             mip_levels: self.mip_levels,
             location: self.memory_location,
+            allocation_scheme: AllocationScheme::Managed,
+        }
+    }
+}
+
+/// Queries which image usages a format supports with optimal tiling on a device
+#[derive(Clone)]
+pub struct FormatSupport {
+    device: Arc<AshDevice>,
+}
+
+impl FormatSupport {
+    pub(crate) fn new(device: Arc<AshDevice>) -> Self {
+        Self { device }
+    }
+
+    /// Returns the subset of usage that the format doesn't support
+    pub fn unsupported_usage(
+        &self,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+    ) -> vk::ImageUsageFlags {
+        let features = unsafe {
+            self.device
+                .instance
+                .core
+                .get_physical_device_format_properties(self.device.physical, format)
         }
+        .optimal_tiling_features;
+
+        usage_missing_features(features, usage)
+    }
+}
+
+/// Subset of usage that isn't backed by the given format features
+fn usage_missing_features(
+    features: vk::FormatFeatureFlags,
+    usage: vk::ImageUsageFlags,
+) -> vk::ImageUsageFlags {
+    [
+        (
+            vk::ImageUsageFlags::TRANSFER_SRC,
+            vk::FormatFeatureFlags::TRANSFER_SRC,
+        ),
+        (
+            vk::ImageUsageFlags::TRANSFER_DST,
+            vk::FormatFeatureFlags::TRANSFER_DST,
+        ),
+        (
+            vk::ImageUsageFlags::SAMPLED,
+            vk::FormatFeatureFlags::SAMPLED_IMAGE,
+        ),
+        (
+            vk::ImageUsageFlags::STORAGE,
+            vk::FormatFeatureFlags::STORAGE_IMAGE,
+        ),
+        (
+            vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            vk::FormatFeatureFlags::COLOR_ATTACHMENT,
+        ),
+        (
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+        ),
+    ]
+    .iter()
+    .filter(|(image_usage, feature)| usage.contains(*image_usage) && !features.contains(*feature))
+    .fold(
+        vk::ImageUsageFlags::empty(),
+        |unsupported, (image_usage, _)| unsupported | *image_usage,
+    )
+}
+
+impl std::fmt::Debug for FormatSupport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FormatSupport")
+            .field("physical", &self.device.physical)
+            .finish()
     }
 }
 
@@ -51,10 +435,13 @@ pub struct ImageDescription2D {
     pub usage: vk::ImageUsageFlags,
     pub mip_levels: u32,
     pub location: gpu_allocator::MemoryLocation,
+    pub allocation_scheme: AllocationScheme,
 }
 
 pub struct Image {
     pub device: Arc<AshDevice>,
+    #[cfg(feature = "track-resources")]
+    pub name: String,
     pub handle: vk::Image,
     pub view: vk::ImageView,
     pub allocation: gpu_allocator::vulkan::Allocation,
@@ -62,8 +449,19 @@ pub struct Image {
     pub format: vk::Format,
     pub usage: vk::ImageUsageFlags,
     pub location: gpu_allocator::MemoryLocation,
+    pub mip_levels: u32,
+    pub array_layers: u32,
     pub storage_binding: Option<DescriptorBinding>,
     pub sampled_binding: Option<DescriptorBinding>,
+
+    /// Descriptors can only view a single aspect, so sampled depth stencil images get a view per aspect.
+    /// The default view keeps both aspects for attachment use
+    pub depth_view: Option<vk::ImageView>,
+    pub stencil_view: Option<vk::ImageView>,
+    pub stencil_sampled_binding: Option<DescriptorBinding>,
+
+    /// Single mip/layer views used when rendering to part of the image, created on demand
+    subresource_views: HashMap<AttachmentSubresource, vk::ImageView>,
 }
 
 impl Image {
@@ -72,20 +470,107 @@ impl Image {
         name: &str,
         description: &ImageDescription2D,
     ) -> Result<Self, VulkanError> {
+        Self::new_layered(
+            device,
+            name,
+            description,
+            1,
+            vk::ImageCreateFlags::empty(),
+            vk::ImageViewType::TYPE_2D,
+        )
+    }
+
+    /// Creates a 3D image, its default view is TYPE_3D so sampled and storage bindings see the whole volume
+    pub fn new_3d(
+        device: Arc<AshDevice>,
+        name: &str,
+        description: &ImageDescription2D,
+        depth: u32,
+    ) -> Result<Self, VulkanError> {
+        Self::new_with_depth(
+            device,
+            name,
+            description,
+            1,
+            depth,
+            vk::ImageCreateFlags::empty(),
+            vk::ImageViewType::TYPE_3D,
+        )
+    }
+
+    /// Creates a six layer cube compatible image, layers are faces in the order +X, -X, +Y, -Y, +Z, -Z
+    pub fn new_cube(
+        device: Arc<AshDevice>,
+        name: &str,
+        description: &ImageDescription2D,
+    ) -> Result<Self, VulkanError> {
+        Self::new_layered(
+            device,
+            name,
+            description,
+            CUBEMAP_FACE_COUNT,
+            vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            vk::ImageViewType::CUBE,
+        )
+    }
+
+    pub fn new_2d_array(
+        device: Arc<AshDevice>,
+        name: &str,
+        description: &ImageDescription2D,
+        array_layers: u32,
+    ) -> Result<Self, VulkanError> {
+        Self::new_layered(
+            device,
+            name,
+            description,
+            array_layers,
+            vk::ImageCreateFlags::empty(),
+            vk::ImageViewType::TYPE_2D_ARRAY,
+        )
+    }
+
+    fn new_layered(
+        device: Arc<AshDevice>,
+        name: &str,
+        description: &ImageDescription2D,
+        array_layers: u32,
+        flags: vk::ImageCreateFlags,
+        view_type: vk::ImageViewType,
+    ) -> Result<Self, VulkanError> {
+        Self::new_with_depth(device, name, description, array_layers, 1, flags, view_type)
+    }
+
+    fn new_with_depth(
+        device: Arc<AshDevice>,
+        name: &str,
+        description: &ImageDescription2D,
+        array_layers: u32,
+        depth: u32,
+        flags: vk::ImageCreateFlags,
+        view_type: vk::ImageViewType,
+    ) -> Result<Self, VulkanError> {
+        let image_type = if view_type == vk::ImageViewType::TYPE_3D {
+            vk::ImageType::TYPE_3D
+        } else {
+            vk::ImageType::TYPE_2D
+        };
+
         let handle = unsafe {
             device.core.create_image(
                 &vk::ImageCreateInfo::builder()
+                    .flags(flags)
                     .format(description.format)
                     .extent(vk::Extent3D {
                         width: description.size[0],
                         height: description.size[1],
-                        depth: 1,
+                        depth,
                     })
                     .usage(description.usage)
-                    .array_layers(1)
+                    .array_layers(array_layers)
                     .mip_levels(description.mip_levels)
                     .samples(vk::SampleCountFlags::TYPE_1)
-                    .image_type(vk::ImageType::TYPE_2D),
+                    .image_type(image_type),
                 None,
             )
         }?;
@@ -102,7 +587,7 @@ impl Image {
                 requirements,
                 location: description.location,
                 linear: true,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                allocation_scheme: description.allocation_scheme.for_image(handle),
             },
         ) {
             Ok(allocation) => allocation,
@@ -124,31 +609,43 @@ impl Image {
             return Err(VulkanError::from(err));
         }
 
-        let view_create_info = vk::ImageViewCreateInfo::builder()
-            .image(handle)
-            .format(description.format)
-            .subresource_range(vk::ImageSubresourceRange {
-                aspect_mask: vk_format_get_aspect_flags(description.format),
-                base_mip_level: 0,
-                level_count: description.mip_levels,
-                base_array_layer: 0,
-                layer_count: 1,
-            })
-            .view_type(vk::ImageViewType::TYPE_2D);
-
-        let view = match unsafe { device.core.create_image_view(&view_create_info, None) } {
-            Ok(view) => view,
-            Err(err) => {
-                unsafe {
-                    device.core.destroy_image(handle, None);
-                };
-                let _ = device.allocator.lock().unwrap().free(allocation);
-                return Err(VulkanError::from(err));
+        let view_aspects = view_aspects(description.format, description.usage);
+        let mut views = Vec::with_capacity(view_aspects.len());
+        for aspect_mask in view_aspects {
+            let view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(handle)
+                .format(description.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: description.mip_levels,
+                    base_array_layer: 0,
+                    layer_count: array_layers,
+                })
+                .view_type(view_type);
+
+            match unsafe { device.core.create_image_view(&view_create_info, None) } {
+                Ok(view) => views.push(view),
+                Err(err) => {
+                    unsafe {
+                        for view in views {
+                            device.core.destroy_image_view(view, None);
+                        }
+                        device.core.destroy_image(handle, None);
+                    };
+                    let _ = device.allocator.lock().unwrap().free(allocation);
+                    return Err(VulkanError::from(err));
+                }
             }
-        };
+        }
+        let view = views[0];
+        let depth_view = views.get(1).copied();
+        let stencil_view = views.get(2).copied();
 
         Ok(Self {
             device,
+            #[cfg(feature = "track-resources")]
+            name: name.to_string(),
             handle,
             view,
             allocation,
@@ -159,11 +656,66 @@ impl Image {
             format: description.format,
             usage: description.usage,
             location: description.location,
+            mip_levels: description.mip_levels,
+            array_layers,
             storage_binding: None,
             sampled_binding: None,
+            depth_view,
+            stencil_view,
+            stencil_sampled_binding: None,
+            subresource_views: HashMap::new(),
         })
     }
 
+    pub(crate) fn get_subresource_view(
+        &mut self,
+        subresource: AttachmentSubresource,
+    ) -> Result<vk::ImageView, VulkanError> {
+        if subresource.is_default() {
+            return Ok(self.view);
+        }
+
+        if let Some(view) = self.subresource_views.get(&subresource) {
+            return Ok(*view);
+        }
+
+        if subresource.base_layer + subresource.layer_count > self.array_layers {
+            return Err(VulkanError::AttachmentLayersOutOfRange {
+                base_layer: subresource.base_layer,
+                layer_count: subresource.layer_count,
+                array_layers: self.array_layers,
+            });
+        }
+
+        let view = unsafe {
+            self.device.core.create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .image(self.handle)
+                    .format(self.format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk_format_get_aspect_flags(self.format),
+                        base_mip_level: subresource.mip_level,
+                        level_count: 1,
+                        base_array_layer: subresource.base_layer,
+                        layer_count: subresource.layer_count,
+                    })
+                    .view_type(if subresource.layer_count > 1 {
+                        vk::ImageViewType::TYPE_2D_ARRAY
+                    } else {
+                        vk::ImageViewType::TYPE_2D
+                    }),
+                None,
+            )
+        }?;
+        self.subresource_views.insert(subresource, view);
+        Ok(view)
+    }
+
+    /// View bound to sampled image descriptors, depth only for combined depth stencil formats
+    pub fn sampled_view(&self) -> vk::ImageView {
+        self.depth_view.unwrap_or(self.view)
+    }
+
     pub fn get_copy(&self) -> AshImage {
         AshImage {
             handle: self.handle,
@@ -176,11 +728,34 @@ impl Image {
             sampled_binding: self.sampled_binding.as_ref().map(|binding| binding.index()),
         }
     }
+
+    pub fn get_bindings(&self) -> ImageBindings {
+        ImageBindings {
+            storage: self
+                .storage_binding
+                .as_ref()
+                .map(|binding| binding.index().value()),
+            sampled: self
+                .sampled_binding
+                .as_ref()
+                .map(|binding| binding.index().value()),
+            stencil_sampled: self
+                .stencil_sampled_binding
+                .as_ref()
+                .map(|binding| binding.index().value()),
+        }
+    }
 }
 
 impl Drop for Image {
     fn drop(&mut self) {
         unsafe {
+            for view in self.subresource_views.values() {
+                self.device.core.destroy_image_view(*view, None);
+            }
+            for view in self.depth_view.iter().chain(self.stencil_view.iter()) {
+                self.device.core.destroy_image_view(*view, None);
+            }
             self.device.core.destroy_image_view(self.view, None);
             self.device.core.destroy_image(self.handle, None);
         };
@@ -194,6 +769,17 @@ impl Drop for Image {
     }
 }
 
+/// Bindless indices of an image, storage and sampled images live in separate descriptor bindings
+/// so both may be present at once and stay fixed for the lifetime of the image
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageBindings {
+    pub storage: Option<u32>,
+    /// Depth aspect for combined depth stencil images
+    pub sampled: Option<u32>,
+    /// Stencil aspect of a sampled combined depth stencil image
+    pub stencil_sampled: Option<u32>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct AshImage {
     pub handle: vk::Image,
@@ -207,6 +793,14 @@ pub struct AshImage {
 }
 
 impl AshImage {
+    /// Extent of a mip level, never smaller than 1x1
+    pub fn mip_extent(&self, mip_level: u32) -> vk::Extent2D {
+        vk::Extent2D {
+            width: (self.size.width >> mip_level).max(1),
+            height: (self.size.height >> mip_level).max(1),
+        }
+    }
+
     pub fn is_color(&self) -> bool {
         vk_format_get_aspect_flags(self.format) == vk::ImageAspectFlags::COLOR
     }
@@ -215,3 +809,205 @@ impl AshImage {
         vk_format_get_aspect_flags(self.format)
     }
 }
+
+/// Aspects of the views an image needs: the default view first, then the depth and stencil views of a sampled depth stencil image
+fn view_aspects(format: vk::Format, usage: vk::ImageUsageFlags) -> Vec<vk::ImageAspectFlags> {
+    let aspect_mask = vk_format_get_aspect_flags(format);
+    if aspect_mask == (vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL)
+        && usage.contains(vk::ImageUsageFlags::SAMPLED)
+    {
+        vec![
+            aspect_mask,
+            vk::ImageAspectFlags::DEPTH,
+            vk::ImageAspectFlags::STENCIL,
+        ]
+    } else {
+        vec![aspect_mask]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampled_depth_stencil_images_get_a_depth_only_view() {
+        let depth_stencil = vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL;
+        assert_eq!(
+            view_aspects(
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+            ),
+            [
+                depth_stencil,
+                vk::ImageAspectFlags::DEPTH,
+                vk::ImageAspectFlags::STENCIL
+            ]
+        );
+        assert_eq!(
+            view_aspects(
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+            ),
+            [depth_stencil]
+        );
+        assert_eq!(
+            view_aspects(vk::Format::D32_SFLOAT, vk::ImageUsageFlags::SAMPLED),
+            [vk::ImageAspectFlags::DEPTH]
+        );
+    }
+
+    #[test]
+    fn attachment_usage_without_the_format_feature_is_unsupported() {
+        let depth_features = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE
+            | vk::FormatFeatureFlags::TRANSFER_SRC;
+        assert_eq!(
+            usage_missing_features(
+                depth_features,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+            ),
+            vk::ImageUsageFlags::COLOR_ATTACHMENT
+        );
+        assert_eq!(
+            usage_missing_features(
+                depth_features,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+            ),
+            vk::ImageUsageFlags::empty()
+        );
+    }
+
+    #[test]
+    fn srgb_formats() {
+        assert!(vk_format_is_srgb(vk::Format::R8G8B8A8_SRGB));
+        assert!(vk_format_is_srgb(vk::Format::B8G8R8A8_SRGB));
+        assert!(vk_format_is_srgb(vk::Format::BC7_SRGB_BLOCK));
+        assert!(!vk_format_is_srgb(vk::Format::R8G8B8A8_UNORM));
+        assert!(!vk_format_is_srgb(vk::Format::R16G16B16A16_SFLOAT));
+        assert!(!vk_format_is_srgb(vk::Format::D24_UNORM_S8_UINT));
+    }
+
+    #[test]
+    fn depth_stencil_formats() {
+        assert!(vk_format_is_depth(vk::Format::D32_SFLOAT));
+        assert!(!vk_format_is_stencil(vk::Format::D32_SFLOAT));
+        assert!(vk_format_is_stencil(vk::Format::S8_UINT));
+        assert!(!vk_format_is_depth(vk::Format::S8_UINT));
+        assert!(vk_format_is_depth(vk::Format::D24_UNORM_S8_UINT));
+        assert!(vk_format_is_stencil(vk::Format::D24_UNORM_S8_UINT));
+        assert!(!vk_format_is_depth(vk::Format::R32_SFLOAT));
+
+        assert_eq!(
+            vk_format_get_aspect_flags(vk::Format::D32_SFLOAT_S8_UINT),
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            vk_format_get_aspect_flags(vk::Format::R8G8B8A8_UNORM),
+            vk::ImageAspectFlags::COLOR
+        );
+    }
+
+    #[test]
+    fn channel_counts() {
+        assert_eq!(vk_format_channel_count(vk::Format::R8_UNORM), Some(1));
+        assert_eq!(vk_format_channel_count(vk::Format::R16G16_SFLOAT), Some(2));
+        assert_eq!(
+            vk_format_channel_count(vk::Format::B10G11R11_UFLOAT_PACK32),
+            Some(3)
+        );
+        assert_eq!(vk_format_channel_count(vk::Format::B8G8R8A8_SRGB), Some(4));
+        assert_eq!(vk_format_channel_count(vk::Format::D32_SFLOAT), Some(1));
+        assert_eq!(
+            vk_format_channel_count(vk::Format::D24_UNORM_S8_UINT),
+            Some(2)
+        );
+        assert_eq!(vk_format_channel_count(vk::Format::BC7_UNORM_BLOCK), None);
+    }
+
+    #[test]
+    fn bytes_per_pixel() {
+        assert_eq!(vk_format_bytes_per_pixel(vk::Format::R8_UNORM), Some(1));
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::R8G8B8A8_SRGB),
+            Some(4)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::A8B8G8R8_UNORM_PACK32),
+            Some(4)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::R16G16B16A16_SFLOAT),
+            Some(8)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::R32G32B32_SFLOAT),
+            Some(12)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::R64G64B64A64_SFLOAT),
+            Some(32)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::A2B10G10R10_UNORM_PACK32),
+            Some(4)
+        );
+        assert_eq!(vk_format_bytes_per_pixel(vk::Format::D16_UNORM), Some(2));
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::D32_SFLOAT_S8_UINT),
+            Some(5)
+        );
+        assert_eq!(
+            vk_format_bytes_per_pixel(vk::Format::BC1_RGB_UNORM_BLOCK),
+            None
+        );
+    }
+
+    #[test]
+    fn bgra_pixels_are_swizzled_to_rgba() {
+        let bgra = [10, 20, 30, 40, 200, 150, 100, 255];
+        let expected = vec![30, 20, 10, 40, 100, 150, 200, 255];
+        for format in [vk::Format::B8G8R8A8_UNORM, vk::Format::B8G8R8A8_SRGB] {
+            assert_eq!(
+                vk_format_to_rgba8(format, Some(vk::ColorSpaceKHR::SRGB_NONLINEAR), &bgra, true),
+                Some(expected.clone())
+            );
+        }
+        assert_eq!(
+            vk_format_to_rgba8(vk::Format::R8G8B8A8_UNORM, None, &expected, false),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            vk_format_to_rgba8(vk::Format::R16G16B16A16_SFLOAT, None, &bgra, true),
+            None
+        );
+    }
+
+    #[test]
+    fn srgb_conversion_follows_the_surface_color_space() {
+        let pixel = [0, 128, 255, 77];
+
+        // Linear UNORM data is encoded for sRGB output, alpha is untouched
+        let encoded = vk_format_to_rgba8(vk::Format::R8G8B8A8_UNORM, None, &pixel, true).unwrap();
+        assert_eq!(encoded, vec![0, 188, 255, 77]);
+
+        // The same UNORM data presented as sRGB is already encoded
+        let presented = vk_format_to_rgba8(
+            vk::Format::R8G8B8A8_UNORM,
+            Some(vk::ColorSpaceKHR::SRGB_NONLINEAR),
+            &pixel,
+            true,
+        )
+        .unwrap();
+        assert_eq!(presented, pixel.to_vec());
+
+        let decoded = vk_format_to_rgba8(
+            vk::Format::R8G8B8A8_UNORM,
+            Some(vk::ColorSpaceKHR::SRGB_NONLINEAR),
+            &pixel,
+            false,
+        )
+        .unwrap();
+        assert_eq!(decoded, vec![0, 55, 255, 77]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::debug_utils::DebugUtils;
+use crate::debug_utils::{DebugUtils, ValidationSettings};
 use crate::physical_device::PhysicalDevice;
 use crate::{SurfaceHandle, SurfaceKey, VulkanError};
 use ash::prelude::VkResult;
@@ -47,6 +47,10 @@ impl SurfaceList {
     pub fn get(&self, surface_key: SurfaceKey) -> Option<vk::SurfaceKHR> {
         self.0.lock().unwrap().get(surface_key).cloned()
     }
+
+    pub fn surfaces(&self) -> Vec<vk::SurfaceKHR> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
 }
 
 pub struct AshInstance {
@@ -62,9 +66,10 @@ impl AshInstance {
     pub fn new(
         engine_info: &AppInfo,
         app_info: &AppInfo,
-        enable_debug: bool,
+        validation: ValidationSettings,
         display_handle: Option<raw_window_handle::RawDisplayHandle>,
     ) -> VkResult<Self> {
+        let enable_debug = validation.enabled;
         trace!(
             "Creating Vulkan Instance Engine: {:?}, App: {:?}",
             engine_info,
@@ -128,7 +133,7 @@ impl AshInstance {
         let surface = ash::extensions::khr::Surface::new(&entry, &instance);
 
         let debug_utils = if enable_debug {
-            Some(DebugUtils::new(&entry, &instance)?)
+            Some(DebugUtils::new(&entry, &instance, validation)?)
         } else {
             None
         };
@@ -183,9 +188,23 @@ impl Instance {
         engine_info: &AppInfo,
         app_info: &AppInfo,
         display_handle: Option<raw_window_handle::RawDisplayHandle>,
+    ) -> Result<Self, VulkanError> {
+        Self::new_with_validation(
+            engine_info,
+            app_info,
+            ValidationSettings::default(),
+            display_handle,
+        )
+    }
+
+    pub fn new_with_validation(
+        engine_info: &AppInfo,
+        app_info: &AppInfo,
+        validation: ValidationSettings,
+        display_handle: Option<raw_window_handle::RawDisplayHandle>,
     ) -> Result<Self, VulkanError> {
         let instance =
-            AshInstance::new(engine_info, app_info, true, display_handle).map(Arc::new)?;
+            AshInstance::new(engine_info, app_info, validation, display_handle).map(Arc::new)?;
 
         let physical_devices = unsafe { instance.core.enumerate_physical_devices() }
             .expect("Failed to enumerate physical devices")
@@ -1,14 +1,19 @@
 mod buffer;
+mod buffer_suballocator;
 mod debug_utils;
 mod descriptor_set;
 mod device;
+mod frame_arena;
 mod image;
 mod instance;
 mod physical_device;
 mod pipeline;
 mod resource_managers;
 mod sampler;
+mod shader_reflection;
 mod swapchain;
+mod texture_atlas;
+mod uniform_ring;
 
 pub mod basic_render_graph_builder;
 pub mod render_graph;
@@ -23,17 +28,35 @@ pub use gpu_allocator;
 
 use crate::render_graph::BufferIndex;
 
-pub use buffer::BufferUsage;
-pub use device::{Device, DeviceSettings};
-pub use image::{ImageDescription2D, TransientImageDesc, TransientImageSize};
+pub use buffer::{BufferAllocationInfo, BufferUsage};
+pub use buffer_suballocator::{BufferSubAllocation, BufferSuballocator};
+pub use debug_utils::{
+    ValidationCallback, ValidationMessageType, ValidationSettings, ValidationSeverity,
+};
+pub use descriptor_set::DescriptorCount;
+pub use device::{CommandBufferCounts, Device, DeviceSettings, StagingMemoryLocations};
+pub use image::{
+    vk_format_bytes_per_pixel, vk_format_channel_count, vk_format_get_aspect_flags,
+    vk_format_is_depth, vk_format_is_srgb, vk_format_is_stencil, vk_format_to_rgba8, FormatSupport,
+    ImageBindings, ImageDescription2D, TransientImageDesc, TransientImageSize,
+};
 pub use instance::{AppInfo, Instance};
 pub use physical_device::*;
 pub use pipeline::{
-    ColorTargetState, DepthState, FragmentState, FramebufferDesc, PrimitiveState,
+    ColorTargetState, DepthState, DynamicState, FragmentState, FramebufferDesc, PrimitiveState,
     RasterPipelineDescription, ShaderStage, VertexAttribute, VertexBufferLayout, VertexState,
 };
+pub use render_graph_executor::{DescriptorBindFrequency, FrameResult, FrameTimings, PassTiming};
+pub use resource_managers::{
+    BufferResourceAccess, ImageResourceAccess, LiveResources, ResourceCounts,
+};
 pub use sampler::*;
-pub use swapchain::SurfaceSettings;
+pub use shader_reflection::*;
+pub use swapchain::{
+    pick_surface_format, pre_transform_extent, pre_transform_matrix, SurfaceSettings,
+};
+pub use texture_atlas::{SpriteId, TextureAtlas, UvRect};
+pub use uniform_ring::{DynamicUniformRing, UniformBatch};
 
 slotmap::new_key_type! {
     pub struct SurfaceKey;
@@ -107,12 +130,50 @@ pub struct ComputePipelineHandle(ComputePipelineKey);
 #[derive(Copy, Clone, Debug)]
 pub struct RasterPipelineHandle(RasterPipleineKey);
 
+/// How the memory of a buffer or image is allocated
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub enum AllocationScheme {
+    /// Suballocated from a memory block shared with other resources
+    #[default]
+    Managed,
+    /// Gets its own vk::DeviceMemory, avoids fragmenting shared blocks with large or often recreated resources
+    Dedicated,
+}
+
+impl AllocationScheme {
+    pub(crate) fn for_buffer(self, buffer: vk::Buffer) -> gpu_allocator::vulkan::AllocationScheme {
+        match self {
+            Self::Managed => gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+            Self::Dedicated => gpu_allocator::vulkan::AllocationScheme::DedicatedBuffer(buffer),
+        }
+    }
+
+    pub(crate) fn for_image(self, image: vk::Image) -> gpu_allocator::vulkan::AllocationScheme {
+        match self {
+            Self::Managed => gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+            Self::Dedicated => gpu_allocator::vulkan::AllocationScheme::DedicatedImage(image),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum BufferWriteError {
     #[error("Buffer Not Mappable")]
     BufferNotMapped,
     #[error("Write Out Of Bounds")]
     WriteOutOfBounds,
+    #[error("Uniform Ring Full")]
+    UniformRingFull,
+    #[error("Buffer Destroyed")]
+    BufferDestroyed,
+    #[error(
+        "Read of {size} bytes at offset {offset} is outside of the buffer ({buffer_size} bytes)"
+    )]
+    ReadOutOfBounds {
+        offset: usize,
+        size: usize,
+        buffer_size: usize,
+    },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -123,6 +184,77 @@ pub enum VulkanError {
     GpuAllocator(#[from] gpu_allocator::AllocationError),
     #[error("BufferWriteError: {0}")]
     BufferWriteError(#[from] BufferWriteError),
+    #[error("Unsupported Feature: {0}")]
+    UnsupportedFeature(String),
+    #[error("Attachment layers {base_layer}..{} are outside of the image's {array_layers} layers", base_layer + layer_count)]
+    AttachmentLayersOutOfRange {
+        base_layer: u32,
+        layer_count: u32,
+        array_layers: u32,
+    },
+    #[error("Swapchain usage {requested:?} is not supported by the surface, supported usage is {supported:?}")]
+    UnsupportedSwapchainUsage {
+        requested: vk::ImageUsageFlags,
+        supported: vk::ImageUsageFlags,
+    },
+    #[error("Frame {0} has not been submitted yet")]
+    FrameNotSubmitted(u64),
+    #[error("Primitive restart is only supported for strip and fan topologies, not {0:?}")]
+    InvalidPrimitiveRestart(vk::PrimitiveTopology),
+    #[error("Draw {draw_index} of pass {pass} uses a pipeline with color targets {pipeline_formats:?} but the framebuffer has color attachments {framebuffer_formats:?}")]
+    ColorTargetMismatch {
+        pass: String,
+        draw_index: usize,
+        pipeline_formats: Vec<vk::Format>,
+        framebuffer_formats: Vec<vk::Format>,
+    },
+    #[error("Draw {draw_index} of pass {pass} uses a pipeline with depth target {pipeline_format:?} but the framebuffer's depth attachment is {framebuffer_format:?}")]
+    DepthTargetMismatch {
+        pass: String,
+        draw_index: usize,
+        pipeline_format: vk::Format,
+        framebuffer_format: Option<vk::Format>,
+    },
+    #[error("Draw {draw_index} of pass {pass} sets {state:?} but its pipeline doesn't declare it as a dynamic state")]
+    UndeclaredDynamicState {
+        pass: String,
+        draw_index: usize,
+        state: DynamicState,
+    },
+    #[error("Compute entry point {name} exceeds {limit}: {value} > {max}")]
+    ComputeLimitsExceeded {
+        name: String,
+        limit: &'static str,
+        value: u32,
+        max: u32,
+    },
+    #[error("Preserving the contents of a device local buffer on resize requires TRANSFER usage")]
+    ResizeRequiresTransfer,
+    #[error("{0:?} isn't a live persistent buffer")]
+    InvalidBufferHandle(BufferHandle),
+    #[error("Shader module has no {stage:?} entry point named {name}")]
+    MissingEntryPoint {
+        name: String,
+        stage: vk::ShaderStageFlags,
+    },
+    #[error("Sprite data for {size:?} should be {expected} bytes but was {actual}")]
+    SpriteDataSize {
+        size: [u32; 2],
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Texture atlas {name} cannot grow past {max_size} texels")]
+    TextureAtlasFull { name: String, max_size: u32 },
+    #[error("Resource deletion delay {deletion_delay} must match the executor's {frames_in_flight} frames in flight")]
+    DeletionDelayMismatch {
+        deletion_delay: u64,
+        frames_in_flight: u64,
+    },
+    #[error("Staging {usage} memory {location:?} isn't host visible, use CpuToGpu or GpuToCpu")]
+    StagingMemoryNotHostVisible {
+        usage: &'static str,
+        location: gpu_allocator::MemoryLocation,
+    },
 }
 
 /// Similar to promise/future in c++ and rust async. The contained type will be available sometime later
@@ -130,3 +262,28 @@ pub enum VulkanError {
 pub struct VulkanFuture<T> {
     _phantom: std::marker::PhantomData<T>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    #[test]
+    fn dedicated_resources_get_their_own_allocation() {
+        let buffer = vk::Buffer::from_raw(1);
+        let image = vk::Image::from_raw(2);
+
+        assert_eq!(
+            AllocationScheme::Dedicated.for_buffer(buffer),
+            gpu_allocator::vulkan::AllocationScheme::DedicatedBuffer(buffer)
+        );
+        assert_eq!(
+            AllocationScheme::Dedicated.for_image(image),
+            gpu_allocator::vulkan::AllocationScheme::DedicatedImage(image)
+        );
+        assert_eq!(
+            AllocationScheme::default().for_image(image),
+            gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::device::DeviceSettings;
 use crate::instance::AshInstance;
-use crate::{Device, SurfaceHandle, VulkanError};
+use crate::{DescriptorCount, Device, SurfaceHandle, VulkanError};
 use ash::vk;
 use log::error;
 use std::ffi::{c_char, CStr};
@@ -199,6 +199,98 @@ impl Debug for PhysicalDeviceQueueInfo {
 pub struct PhysicalDeviceExtensionInfo {
     pub raytracing_support: bool,
     pub mesh_shader_support: bool,
+    pub multiview_support: bool,
+}
+
+impl PhysicalDeviceExtensionInfo {
+    pub fn supported_features(&self) -> DeviceFeatures {
+        DeviceFeatures {
+            raytracing: self.raytracing_support,
+            mesh_shading: self.mesh_shader_support,
+            multiview: self.multiview_support,
+        }
+    }
+
+    /// Names of every requested feature this device doesn't support
+    pub fn missing_features(&self, requested: &DeviceFeatures) -> Vec<&'static str> {
+        [
+            ("raytracing", requested.raytracing, self.raytracing_support),
+            (
+                "mesh_shading",
+                requested.mesh_shading,
+                self.mesh_shader_support,
+            ),
+            ("multiview", requested.multiview, self.multiview_support),
+        ]
+        .into_iter()
+        .filter(|(_, requested, supported)| *requested && !supported)
+        .map(|(name, _, _)| name)
+        .collect()
+    }
+
+    pub fn validate_features(&self, requested: &DeviceFeatures) -> Result<(), VulkanError> {
+        let missing = self.missing_features(requested);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(VulkanError::UnsupportedFeature(missing.join(", ")))
+        }
+    }
+}
+
+/// Descriptor types that can be written while the bindless set is in use by pending command buffers,
+/// required for every type the set has descriptors of since resources are bound while frames are in flight
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateAfterBindSupport {
+    pub storage_buffers: bool,
+    pub storage_images: bool,
+    /// Also covers samplers
+    pub sampled_images: bool,
+}
+
+impl UpdateAfterBindSupport {
+    /// Names of the update-after-bind features missing for descriptor types with a non-zero count
+    pub fn missing_features(&self, count: &DescriptorCount) -> Vec<&'static str> {
+        [
+            (
+                "descriptorBindingStorageBufferUpdateAfterBind",
+                count.storage_buffers != 0,
+                self.storage_buffers,
+            ),
+            (
+                "descriptorBindingStorageImageUpdateAfterBind",
+                count.storage_images != 0,
+                self.storage_images,
+            ),
+            (
+                "descriptorBindingSampledImageUpdateAfterBind",
+                count.sampled_images != 0 || count.samplers != 0,
+                self.sampled_images,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, needed, supported)| *needed && !supported)
+        .map(|(name, _, _)| name)
+        .collect()
+    }
+
+    /// Whether bindings of this descriptor type may use DescriptorBindingFlags::UPDATE_AFTER_BIND
+    pub fn supports(&self, descriptor_type: vk::DescriptorType) -> bool {
+        match descriptor_type {
+            vk::DescriptorType::STORAGE_BUFFER => self.storage_buffers,
+            vk::DescriptorType::STORAGE_IMAGE => self.storage_images,
+            vk::DescriptorType::SAMPLED_IMAGE | vk::DescriptorType::SAMPLER => self.sampled_images,
+            _ => false,
+        }
+    }
+}
+
+/// Optional features to enable on device creation, only requested features are enabled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceFeatures {
+    pub raytracing: bool,
+    pub mesh_shading: bool,
+    pub multiview: bool,
 }
 
 #[derive(Clone)]
@@ -206,13 +298,13 @@ pub struct PhysicalDevice {
     pub(crate) instance: Arc<AshInstance>,
     pub(crate) handle: vk::PhysicalDevice,
 
-    //TODO: supported extensions list (Raytracing + Mesh Shading)
     //TODO: support 2 transfer queues?
     pub info: PhysicalDeviceInfo,
     pub driver: PhysicalDeviceDriverInfo,
     pub memory: PhysicalDeviceMemoryInfo,
     pub queue: PhysicalDeviceQueueInfo,
     pub extension: PhysicalDeviceExtensionInfo,
+    pub update_after_bind: UpdateAfterBindSupport,
 }
 
 impl PhysicalDevice {
@@ -302,6 +394,33 @@ impl PhysicalDevice {
         }
         .unwrap_or_default();
 
+        let (multiview_support, update_after_bind) = {
+            let mut vulkan_1_1_features = vk::PhysicalDeviceVulkan11Features::default();
+            let mut vulkan_1_2_features = vk::PhysicalDeviceVulkan12Features::default();
+            let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+                .push_next(&mut vulkan_1_1_features)
+                .push_next(&mut vulkan_1_2_features);
+            unsafe {
+                instance
+                    .core
+                    .get_physical_device_features2(physical_device, &mut features2);
+            };
+            (
+                vulkan_1_1_features.multiview == vk::TRUE,
+                UpdateAfterBindSupport {
+                    storage_buffers: vulkan_1_2_features
+                        .descriptor_binding_storage_buffer_update_after_bind
+                        == vk::TRUE,
+                    storage_images: vulkan_1_2_features
+                        .descriptor_binding_storage_image_update_after_bind
+                        == vk::TRUE,
+                    sampled_images: vulkan_1_2_features
+                        .descriptor_binding_sampled_image_update_after_bind
+                        == vk::TRUE,
+                },
+            )
+        };
+
         let extension = PhysicalDeviceExtensionInfo {
             raytracing_support: supports_extension(
                 &extension_list,
@@ -318,6 +437,7 @@ impl PhysicalDevice {
                 &extension_list,
                 ash::extensions::ext::MeshShader::name(),
             ),
+            multiview_support,
         };
 
         Self {
@@ -328,6 +448,7 @@ impl PhysicalDevice {
             memory,
             queue,
             extension,
+            update_after_bind,
         }
     }
 
@@ -344,26 +465,51 @@ impl PhysicalDevice {
     }
 
     pub fn supports_surface(&self, surface_handle: SurfaceHandle) -> bool {
-        if let Some(graphics_queue_family_index) = self.queue.graphics_queue_family_index {
-            if let Some(surface) = self.instance.surface_list.get(surface_handle.0) {
-                unsafe {
-                    match self.instance.surface.get_physical_device_surface_support(
-                        self.handle,
-                        graphics_queue_family_index,
-                        surface,
-                    ) {
-                        Ok(supported) => supported,
-                        Err(err) => {
-                            error!("vkGetPhysicalDeviceSurfaceSupportKHR failed: {}", err);
-                            false
-                        }
-                    }
+        self.supports_graphics() && self.present_queue_family_index(surface_handle).is_some()
+    }
+
+    /// Queue family used to present to the surface, the graphics family when it can present
+    pub fn present_queue_family_index(&self, surface_handle: SurfaceHandle) -> Option<u32> {
+        let surface = self.instance.surface_list.get(surface_handle.0)?;
+        self.present_queue_family_index_for(&[surface])
+    }
+
+    /// Graphics family when it can present to every surface, otherwise the first family that can
+    pub(crate) fn present_queue_family_index_for(
+        &self,
+        surfaces: &[vk::SurfaceKHR],
+    ) -> Option<u32> {
+        let family_count = unsafe {
+            self.instance
+                .core
+                .get_physical_device_queue_family_properties(self.handle)
+        }
+        .len() as u32;
+
+        pick_present_family(
+            self.queue.graphics_queue_family_index,
+            family_count,
+            |family_index| {
+                surfaces
+                    .iter()
+                    .all(|&surface| self.family_supports_surface(family_index, surface))
+            },
+        )
+    }
+
+    fn family_supports_surface(&self, family_index: u32, surface: vk::SurfaceKHR) -> bool {
+        unsafe {
+            match self.instance.surface.get_physical_device_surface_support(
+                self.handle,
+                family_index,
+                surface,
+            ) {
+                Ok(supported) => supported,
+                Err(err) => {
+                    error!("vkGetPhysicalDeviceSurfaceSupportKHR failed: {}", err);
+                    false
                 }
-            } else {
-                false
             }
-        } else {
-            false
         }
     }
 
@@ -384,3 +530,55 @@ impl Debug for PhysicalDevice {
             .finish()
     }
 }
+
+fn pick_present_family(
+    graphics_family: Option<u32>,
+    family_count: u32,
+    can_present: impl Fn(u32) -> bool,
+) -> Option<u32> {
+    graphics_family
+        .into_iter()
+        .chain(0..family_count)
+        .find(|&family_index| can_present(family_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requesting_an_unsupported_feature_names_it() {
+        let extension_info = PhysicalDeviceExtensionInfo {
+            raytracing_support: true,
+            mesh_shader_support: false,
+            multiview_support: true,
+        };
+        let requested = DeviceFeatures {
+            mesh_shading: true,
+            multiview: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            extension_info.missing_features(&requested),
+            ["mesh_shading"]
+        );
+        assert!(matches!(
+            extension_info.validate_features(&requested),
+            Err(VulkanError::UnsupportedFeature(missing)) if missing == "mesh_shading"
+        ));
+        assert!(extension_info
+            .validate_features(&extension_info.supported_features())
+            .is_ok());
+    }
+
+    #[test]
+    fn present_family_prefers_graphics_then_the_first_capable_family() {
+        assert_eq!(pick_present_family(Some(1), 3, |_| true), Some(1));
+        assert_eq!(
+            pick_present_family(Some(0), 3, |family| family == 2),
+            Some(2)
+        );
+        assert_eq!(pick_present_family(Some(0), 3, |_| false), None);
+    }
+}
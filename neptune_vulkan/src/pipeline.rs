@@ -1,8 +1,10 @@
 use crate::device::AshDevice;
+use crate::shader_reflection::{reflect_shader, ShaderEntryPoint};
 use crate::{ComputePipelineKey, RasterPipleineKey, VulkanError};
 use ash::vk;
 use slotmap::SlotMap;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct ShaderStage<'a> {
@@ -10,32 +12,213 @@ pub struct ShaderStage<'a> {
     pub entry: &'a str,
 }
 
+pub(crate) struct ShaderModule {
+    device: Arc<AshDevice>,
+    pub handle: vk::ShaderModule,
+
+    /// None if the module couldn't be reflected, in which case entry points aren't validated
+    entry_points: Option<Vec<ShaderEntryPoint>>,
+}
+
+impl ShaderModule {
+    /// A single module may hold many entry points, so check the requested one exists for this stage
+    pub fn validate_entry_point(
+        &self,
+        name: &str,
+        stage: vk::ShaderStageFlags,
+    ) -> Result<(), VulkanError> {
+        match &self.entry_points {
+            Some(entry_points) => validate_entry_point(entry_points, name, stage),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the entry point's workgroup size and shared memory against the device limits,
+    /// unreflected modules and entry points are left for the driver to reject
+    pub fn validate_compute_limits(
+        &self,
+        name: &str,
+        limits: &vk::PhysicalDeviceLimits,
+    ) -> Result<(), VulkanError> {
+        match self.entry_points.as_ref().and_then(|entry_points| {
+            entry_points.iter().find(|entry_point| {
+                entry_point.name == name && entry_point.stage == vk::ShaderStageFlags::COMPUTE
+            })
+        }) {
+            Some(entry_point) => validate_compute_limits(entry_point, limits),
+            None => Ok(()),
+        }
+    }
+}
+
+fn validate_entry_point(
+    entry_points: &[ShaderEntryPoint],
+    name: &str,
+    stage: vk::ShaderStageFlags,
+) -> Result<(), VulkanError> {
+    if entry_points
+        .iter()
+        .any(|entry_point| entry_point.name == name && entry_point.stage == stage)
+    {
+        Ok(())
+    } else {
+        Err(VulkanError::MissingEntryPoint {
+            name: name.to_string(),
+            stage,
+        })
+    }
+}
+
+fn validate_compute_limits(
+    entry_point: &ShaderEntryPoint,
+    limits: &vk::PhysicalDeviceLimits,
+) -> Result<(), VulkanError> {
+    let exceeded = |limit: &'static str, value: u32, max: u32| {
+        (value > max).then(|| VulkanError::ComputeLimitsExceeded {
+            name: entry_point.name.clone(),
+            limit,
+            value,
+            max,
+        })
+    };
+
+    if let Some(error) = exceeded(
+        "maxComputeSharedMemorySize",
+        entry_point.shared_memory_size,
+        limits.max_compute_shared_memory_size,
+    ) {
+        return Err(error);
+    }
+
+    if let Some(workgroup_size) = entry_point.workgroup_size {
+        const AXIS_LIMITS: [&str; 3] = [
+            "maxComputeWorkGroupSize[0]",
+            "maxComputeWorkGroupSize[1]",
+            "maxComputeWorkGroupSize[2]",
+        ];
+        for ((limit, size), max) in AXIS_LIMITS
+            .into_iter()
+            .zip(workgroup_size)
+            .zip(limits.max_compute_work_group_size)
+        {
+            if let Some(error) = exceeded(limit, size, max) {
+                return Err(error);
+            }
+        }
+
+        let invocations = workgroup_size
+            .iter()
+            .fold(1u32, |total, size| total.saturating_mul(*size));
+        if let Some(error) = exceeded(
+            "maxComputeWorkGroupInvocations",
+            invocations,
+            limits.max_compute_work_group_invocations,
+        ) {
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+impl Drop for ShaderModule {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.core.destroy_shader_module(self.handle, None);
+        }
+    }
+}
+
+/// Shares shader modules between pipelines built from identical SPIR-V.
+/// Pipelines hold a strong reference to their modules, so a module is destroyed once no pipeline uses it.
+/// Keyed on the SPIR-V itself, so a hash collision can't return a module built from different code
+pub(crate) struct ShaderModuleCache<M = ShaderModule> {
+    modules: HashMap<Box<[u32]>, Weak<M>>,
+}
+
+impl<M> Default for ShaderModuleCache<M> {
+    fn default() -> Self {
+        Self {
+            modules: HashMap::new(),
+        }
+    }
+}
+
+impl ShaderModuleCache {
+    pub fn get_or_create(
+        &mut self,
+        device: &Arc<AshDevice>,
+        code: &[u32],
+    ) -> Result<Arc<ShaderModule>, VulkanError> {
+        self.get_or_create_with(code, |code| {
+            let handle = unsafe {
+                device
+                    .core
+                    .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)
+            }?;
+            Ok(ShaderModule {
+                device: device.clone(),
+                handle,
+                entry_points: reflect_shader(code)
+                    .ok()
+                    .map(|reflection| reflection.entry_points),
+            })
+        })
+    }
+}
+
+impl<M> ShaderModuleCache<M> {
+    /// Only calls create when no live module was built from the same code
+    fn get_or_create_with(
+        &mut self,
+        code: &[u32],
+        create: impl FnOnce(&[u32]) -> Result<M, VulkanError>,
+    ) -> Result<Arc<M>, VulkanError> {
+        self.modules.retain(|_, module| module.strong_count() > 0);
+
+        if let Some(module) = self.modules.get(code).and_then(Weak::upgrade) {
+            return Ok(module);
+        }
+
+        let module = Arc::new(create(code)?);
+        self.modules.insert(code.into(), Arc::downgrade(&module));
+        Ok(module)
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules
+            .values()
+            .filter(|module| module.strong_count() > 0)
+            .count()
+    }
+}
+
 pub struct ComputePipeline {
     device: Arc<AshDevice>,
     pub handle: vk::Pipeline,
+    _shader_module: Arc<ShaderModule>,
 }
 
 impl ComputePipeline {
-    pub fn new(
+    pub(crate) fn new(
         device: Arc<AshDevice>,
         pipeline_layout: vk::PipelineLayout,
+        shader_modules: &mut ShaderModuleCache,
+        limits: &vk::PhysicalDeviceLimits,
         shader: &ShaderStage,
     ) -> Result<Self, VulkanError> {
-        let compute_shader_module = unsafe {
-            device.core.create_shader_module(
-                &vk::ShaderModuleCreateInfo::builder().code(shader.code),
-                None,
-            )
-        }?;
+        let compute_shader_module = shader_modules.get_or_create(&device, shader.code)?;
+        compute_shader_module.validate_entry_point(shader.entry, vk::ShaderStageFlags::COMPUTE)?;
+        compute_shader_module.validate_compute_limits(shader.entry, limits)?;
 
         let compute_entry_point_name = std::ffi::CString::new(shader.entry).unwrap();
 
         let compute_shader_stage = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::COMPUTE)
-            .module(compute_shader_module)
+            .module(compute_shader_module.handle)
             .name(&compute_entry_point_name);
 
-        let result = match unsafe {
+        match unsafe {
             device.core.create_compute_pipelines(
                 vk::PipelineCache::null(),
                 &[vk::ComputePipelineCreateInfo::builder()
@@ -48,17 +231,10 @@ impl ComputePipeline {
             Ok(pipelines) => Ok(Self {
                 device: device.clone(),
                 handle: pipelines[0],
+                _shader_module: compute_shader_module,
             }),
-            Err((_, err)) => return Err(err.into()),
-        };
-
-        unsafe {
-            device
-                .core
-                .destroy_shader_module(compute_shader_module, None);
+            Err((_, err)) => Err(err.into()),
         }
-
-        result
     }
 }
 
@@ -100,8 +276,55 @@ pub struct VertexState<'a> {
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct PrimitiveState {
+    /// glTF assets use COUNTER_CLOCKWISE front faces
     pub front_face: vk::FrontFace,
     pub cull_mode: vk::CullModeFlags,
+    pub topology: vk::PrimitiveTopology,
+    /// Only valid for strip and fan topologies
+    pub primitive_restart: bool,
+    /// Draws use a negative height viewport, for shaders written against OpenGL's Y-up clip space
+    pub flip_viewport_y: bool,
+}
+
+impl PrimitiveState {
+    fn supports_primitive_restart(topology: vk::PrimitiveTopology) -> bool {
+        matches!(
+            topology,
+            vk::PrimitiveTopology::LINE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_FAN
+                | vk::PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+                | vk::PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+        )
+    }
+
+    fn input_assembly_state(
+        &self,
+    ) -> Result<vk::PipelineInputAssemblyStateCreateInfo, VulkanError> {
+        if self.primitive_restart && !Self::supports_primitive_restart(self.topology) {
+            return Err(VulkanError::InvalidPrimitiveRestart(self.topology));
+        }
+
+        Ok(vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(self.topology)
+            .primitive_restart_enable(self.primitive_restart)
+            .build())
+    }
+
+    fn rasterization_state(&self, depth_bias: bool) -> vk::PipelineRasterizationStateCreateInfo {
+        vk::PipelineRasterizationStateCreateInfo::builder()
+            .depth_clamp_enable(false)
+            .rasterizer_discard_enable(false)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .depth_bias_enable(depth_bias)
+            .depth_bias_constant_factor(0.0)
+            .depth_bias_clamp(0.0)
+            .depth_bias_slope_factor(0.0)
+            .build()
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
@@ -125,39 +348,74 @@ pub struct FragmentState<'a> {
     pub targets: &'a [ColorTargetState],
 }
 
+/// Pipeline state set per draw instead of baked into the pipeline, viewport and scissor are always dynamic
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub enum DynamicState {
+    /// Widths other than 1.0 need the wideLines feature
+    LineWidth,
+    BlendConstants,
+    /// Also enables depth bias for the pipeline
+    DepthBias,
+    StencilReference,
+}
+
+impl DynamicState {
+    fn to_vk(self) -> vk::DynamicState {
+        match self {
+            Self::LineWidth => vk::DynamicState::LINE_WIDTH,
+            Self::BlendConstants => vk::DynamicState::BLEND_CONSTANTS,
+            Self::DepthBias => vk::DynamicState::DEPTH_BIAS,
+            Self::StencilReference => vk::DynamicState::STENCIL_REFERENCE,
+        }
+    }
+}
+
 //TODO: StencilState
 pub struct RasterPipelineDescription<'a> {
     pub vertex: VertexState<'a>,
     pub primitive: PrimitiveState,
     pub depth_state: Option<DepthState>,
     pub fragment: Option<FragmentState<'a>>,
+    /// Must match the view mask of the raster passes the pipeline is used in, 0 disables multiview
+    pub view_mask: u32,
+    /// States each draw sets itself, see [`crate::render_graph_builder::DynamicStateValues`]
+    pub dynamic_states: &'a [DynamicState],
 }
 
 pub(crate) struct RasterPipeline {
     device: Arc<AshDevice>,
     pub handle: vk::Pipeline,
+    /// Formats of the color targets the pipeline renders to, in attachment order
+    pub color_formats: Vec<vk::Format>,
+    /// Format of the depth target, None for pipelines without a depth state
+    pub depth_format: Option<vk::Format>,
+    pub flip_viewport_y: bool,
+    pub dynamic_states: Vec<DynamicState>,
+    _shader_modules: Vec<Arc<ShaderModule>>,
 }
 
 impl RasterPipeline {
     pub fn new(
         device: Arc<AshDevice>,
         pipeline_layout: vk::PipelineLayout,
+        shader_modules: &mut ShaderModuleCache,
         pipeline_description: &RasterPipelineDescription,
     ) -> Result<Self, VulkanError> {
-        let vertex_shader_module = unsafe {
-            device.core.create_shader_module(
-                &vk::ShaderModuleCreateInfo::builder()
-                    .code(pipeline_description.vertex.shader.code),
-                None,
-            )
-        }?;
+        let vertex_shader_module =
+            shader_modules.get_or_create(&device, pipeline_description.vertex.shader.code)?;
+        vertex_shader_module.validate_entry_point(
+            pipeline_description.vertex.shader.entry,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+
+        // Vertex and fragment stages packed into one module share the same cached module
         let fragment_shader_module = if let Some(fragment_state) = &pipeline_description.fragment {
-            Some(unsafe {
-                device.core.create_shader_module(
-                    &vk::ShaderModuleCreateInfo::builder().code(fragment_state.shader.code),
-                    None,
-                )
-            }?)
+            let module = shader_modules.get_or_create(&device, fragment_state.shader.code)?;
+            module.validate_entry_point(
+                fragment_state.shader.entry,
+                vk::ShaderStageFlags::FRAGMENT,
+            )?;
+            Some(module)
         } else {
             None
         };
@@ -167,13 +425,13 @@ impl RasterPipeline {
 
         let mut shader_stages = vec![vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::VERTEX)
-            .module(vertex_shader_module)
+            .module(vertex_shader_module.handle)
             .name(&vertex_entry_point_name)
             .build()];
 
         //Keep around to guarantee lifetime
         let _fragment_entry_point_name =
-            if let Some(fragment_shader_module) = fragment_shader_module {
+            if let Some(fragment_shader_module) = &fragment_shader_module {
                 let fragment_entry_point_name = std::ffi::CString::new(
                     pipeline_description.fragment.as_ref().unwrap().shader.entry,
                 )
@@ -182,7 +440,7 @@ impl RasterPipeline {
                 shader_stages.push(
                     vk::PipelineShaderStageCreateInfo::builder()
                         .stage(vk::ShaderStageFlags::FRAGMENT)
-                        .module(fragment_shader_module)
+                        .module(fragment_shader_module.handle)
                         .name(&fragment_entry_point_name)
                         .build(),
                 );
@@ -191,11 +449,8 @@ impl RasterPipeline {
                 None
             };
 
-        //TODO: allow config
-        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .primitive_restart_enable(false)
-            .build();
+        let primitive = &pipeline_description.primitive;
+        let input_assembly_state = primitive.input_assembly_state()?;
 
         let mut vertex_binding_descriptions =
             Vec::with_capacity(pipeline_description.vertex.layouts.len());
@@ -247,18 +502,11 @@ impl RasterPipeline {
             .viewports(&viewports)
             .scissors(&scissors);
 
-        //TODO: allow config
-        let rasterizer_state = vk::PipelineRasterizationStateCreateInfo::builder()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::NONE)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .depth_bias_enable(false)
-            .depth_bias_constant_factor(0.0)
-            .depth_bias_clamp(0.0)
-            .depth_bias_slope_factor(0.0);
+        let rasterizer_state = primitive.rasterization_state(
+            pipeline_description
+                .dynamic_states
+                .contains(&DynamicState::DepthBias),
+        );
 
         //Msaa is probably not going to be supported at all. Most modern engines use other AA methods anyways
         let multisampling_state = vk::PipelineMultisampleStateCreateInfo::builder()
@@ -300,13 +548,26 @@ impl RasterPipeline {
             .attachments(&color_attachments_blend_states)
             .build();
 
-        //TODO: allow depth bias (and other?) dynamic state
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_states: Vec<vk::DynamicState> =
+            [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR]
+                .into_iter()
+                .chain(
+                    pipeline_description
+                        .dynamic_states
+                        .iter()
+                        .map(|state| state.to_vk()),
+                )
+                .collect();
         let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
             .dynamic_states(&dynamic_states)
             .build();
 
+        if pipeline_description.view_mask != 0 && !device.multiview_support {
+            return Err(VulkanError::UnsupportedFeature("multiview".to_string()));
+        }
+
         let mut dynamic_rendering = vk::PipelineRenderingCreateInfo::builder()
+            .view_mask(pipeline_description.view_mask)
             .color_attachment_formats(&color_attachments_formats);
         if let Some(depth_state) = &pipeline_description.depth_state {
             dynamic_rendering = dynamic_rendering.depth_attachment_format(depth_state.format);
@@ -329,32 +590,34 @@ impl RasterPipeline {
             .dynamic_state(&dynamic_state)
             .layout(pipeline_layout);
 
-        let result = match unsafe {
+        let handle = match unsafe {
             device.core.create_graphics_pipelines(
                 vk::PipelineCache::null(),
                 &[pipeline_create_info.build()],
                 None,
             )
         } {
-            Ok(pipelines) => Ok(Self {
-                device: device.clone(),
-                handle: pipelines[0],
-            }),
+            Ok(pipelines) => pipelines[0],
             Err((_, err)) => return Err(err.into()),
         };
 
-        unsafe {
-            device
-                .core
-                .destroy_shader_module(vertex_shader_module, None);
-            if let Some(fragment_shader_module) = fragment_shader_module {
-                device
-                    .core
-                    .destroy_shader_module(fragment_shader_module, None);
-            }
-        }
-
-        result
+        let mut shader_modules = vec![vertex_shader_module];
+        shader_modules.extend(
+            fragment_shader_module.filter(|module| !Arc::ptr_eq(module, &shader_modules[0])),
+        );
+
+        Ok(Self {
+            device: device.clone(),
+            handle,
+            color_formats: color_attachments_formats,
+            depth_format: pipeline_description
+                .depth_state
+                .as_ref()
+                .map(|depth_state| depth_state.format),
+            flip_viewport_y: pipeline_description.primitive.flip_viewport_y,
+            dynamic_states: pipeline_description.dynamic_states.to_vec(),
+            _shader_modules: shader_modules,
+        })
     }
 }
 
@@ -371,6 +634,7 @@ pub(crate) struct Pipelines {
     pub(crate) layout: vk::PipelineLayout,
     pub(crate) compute: SlotMap<ComputePipelineKey, ComputePipeline>,
     pub(crate) raster: SlotMap<RasterPipleineKey, RasterPipeline>,
+    pub(crate) shader_modules: ShaderModuleCache,
 }
 
 impl Pipelines {
@@ -380,6 +644,7 @@ impl Pipelines {
             layout,
             compute: SlotMap::with_key(),
             raster: SlotMap::with_key(),
+            shader_modules: ShaderModuleCache::default(),
         }
     }
 }
@@ -394,3 +659,158 @@ impl Drop for Pipelines {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_reflection::tests::{shared_array_shader, vertex_fragment_shader};
+
+    fn limits() -> vk::PhysicalDeviceLimits {
+        vk::PhysicalDeviceLimits {
+            max_compute_shared_memory_size: 1024,
+            max_compute_work_group_size: [128, 128, 64],
+            max_compute_work_group_invocations: 128,
+            ..Default::default()
+        }
+    }
+
+    fn entry_point(code: &[u32]) -> ShaderEntryPoint {
+        reflect_shader(code)
+            .unwrap()
+            .get_entry_point("main")
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn stages_can_share_a_module_with_different_entry_points() {
+        let entry_points = reflect_shader(&vertex_fragment_shader())
+            .unwrap()
+            .entry_points;
+        assert!(
+            validate_entry_point(&entry_points, "vs_main", vk::ShaderStageFlags::VERTEX).is_ok()
+        );
+        assert!(
+            validate_entry_point(&entry_points, "fs_main", vk::ShaderStageFlags::FRAGMENT).is_ok()
+        );
+        assert!(matches!(
+            validate_entry_point(&entry_points, "fs_main", vk::ShaderStageFlags::VERTEX),
+            Err(VulkanError::MissingEntryPoint { .. })
+        ));
+    }
+
+    #[test]
+    fn shared_memory_within_limit_is_valid() {
+        assert!(
+            validate_compute_limits(&entry_point(&shared_array_shader(256)), &limits()).is_ok()
+        );
+    }
+
+    #[test]
+    fn shared_memory_over_limit_is_rejected() {
+        assert!(matches!(
+            validate_compute_limits(&entry_point(&shared_array_shader(512)), &limits()),
+            Err(VulkanError::ComputeLimitsExceeded {
+                limit: "maxComputeSharedMemorySize",
+                value: 2048,
+                max: 1024,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn workgroup_invocations_over_limit_is_rejected() {
+        let mut entry_point = entry_point(&shared_array_shader(1));
+        entry_point.workgroup_size = Some([16, 16, 1]);
+        assert!(matches!(
+            validate_compute_limits(&entry_point, &limits()),
+            Err(VulkanError::ComputeLimitsExceeded {
+                limit: "maxComputeWorkGroupInvocations",
+                value: 256,
+                max: 128,
+                ..
+            })
+        ));
+
+        entry_point.workgroup_size = Some([1, 1, 65]);
+        assert!(matches!(
+            validate_compute_limits(&entry_point, &limits()),
+            Err(VulkanError::ComputeLimitsExceeded {
+                limit: "maxComputeWorkGroupSize[2]",
+                ..
+            })
+        ));
+    }
+
+    fn primitive_state(topology: vk::PrimitiveTopology, primitive_restart: bool) -> PrimitiveState {
+        PrimitiveState {
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            cull_mode: vk::CullModeFlags::NONE,
+            topology,
+            primitive_restart,
+            flip_viewport_y: false,
+        }
+    }
+
+    #[test]
+    fn triangle_strips_can_enable_primitive_restart() {
+        let input_assembly_state = primitive_state(vk::PrimitiveTopology::TRIANGLE_STRIP, true)
+            .input_assembly_state()
+            .unwrap();
+        assert_eq!(
+            input_assembly_state.topology,
+            vk::PrimitiveTopology::TRIANGLE_STRIP
+        );
+        assert_eq!(input_assembly_state.primitive_restart_enable, vk::TRUE);
+
+        assert!(matches!(
+            primitive_state(vk::PrimitiveTopology::TRIANGLE_LIST, true).input_assembly_state(),
+            Err(VulkanError::InvalidPrimitiveRestart(
+                vk::PrimitiveTopology::TRIANGLE_LIST
+            ))
+        ));
+    }
+
+    #[test]
+    fn back_face_culling_with_ccw_winding_reaches_the_rasterizer() {
+        let mut primitive = primitive_state(vk::PrimitiveTopology::TRIANGLE_LIST, false);
+        primitive.cull_mode = vk::CullModeFlags::BACK;
+        let rasterization_state = primitive.rasterization_state(false);
+        assert_eq!(rasterization_state.cull_mode, vk::CullModeFlags::BACK);
+        assert_eq!(
+            rasterization_state.front_face,
+            vk::FrontFace::COUNTER_CLOCKWISE
+        );
+    }
+
+    #[test]
+    fn identical_spirv_shares_a_module_until_the_last_pipeline_is_dropped() {
+        use ash::vk::Handle;
+
+        let mut cache = ShaderModuleCache::<vk::ShaderModule>::default();
+        let mut created = 0;
+        let mut create = |_: &[u32]| {
+            created += 1;
+            Ok(vk::ShaderModule::from_raw(created))
+        };
+        let code = [0x0723_0203, 1, 2, 3];
+
+        // Each pipeline holds its module like ComputePipeline and RasterPipeline do
+        let first_pipeline = cache.get_or_create_with(&code, &mut create).unwrap();
+        let second_pipeline = cache.get_or_create_with(&code, &mut create).unwrap();
+        assert!(Arc::ptr_eq(&first_pipeline, &second_pipeline));
+        assert_eq!(cache.len(), 1);
+
+        drop(first_pipeline);
+        assert_eq!(cache.len(), 1);
+        let third_pipeline = cache.get_or_create_with(&code, &mut create).unwrap();
+        assert_eq!(*third_pipeline, *second_pipeline);
+
+        drop(second_pipeline);
+        drop(third_pipeline);
+        assert_eq!(cache.len(), 0);
+        let rebuilt = cache.get_or_create_with(&code, &mut create).unwrap();
+        assert_eq!(*rebuilt, vk::ShaderModule::from_raw(2));
+    }
+}
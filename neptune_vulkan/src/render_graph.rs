@@ -1,10 +1,15 @@
-use crate::render_graph_builder::{BufferReadCallback, BufferWriteCallback};
+use crate::image::vk_format_get_aspect_flags;
+use crate::render_graph_builder::{
+    AttachmentSubresource, BufferReadCallback, BufferWriteCallback, ClearValue, CustomCommand,
+    DynamicStateValues,
+};
 use crate::resource_managers::{BufferResourceAccess, BufferTempResource, ImageResourceAccess};
 use crate::{
     BufferKey, BufferUsage, ComputePipelineHandle, ImageKey, RasterPipelineHandle, SamplerHandle,
-    SurfaceHandle, TransientImageDesc,
+    SurfaceHandle, TransientImageDesc, TransientImageSize,
 };
 use ash::vk;
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::ops::Range;
 
@@ -64,9 +69,15 @@ pub struct ImageGraphResource {
     pub description: ImageResourceDescription,
     pub first_access: Option<ImageResourceAccess>,
     pub last_access: Option<ImageResourceAccess>,
+
+    /// Non-default subresources rendered to, each needs its own image view
+    pub attachment_views: Vec<AttachmentSubresource>,
+
+    /// Access a persistent image is left in at the end of this frame, overriding its rest access
+    pub release_access: Option<ImageResourceAccess>,
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub struct BufferOffset {
     pub buffer: BufferIndex,
     pub offset: u64,
@@ -74,10 +85,27 @@ pub struct BufferOffset {
 
 #[derive(Debug)]
 pub enum ShaderResourceUsage {
-    StorageBuffer { buffer: BufferIndex, write: bool },
-    StorageImage { image: ImageIndex, write: bool },
+    StorageBuffer {
+        buffer: BufferIndex,
+        write: bool,
+    },
+    StorageImage {
+        image: ImageIndex,
+        write: bool,
+    },
     SampledImage(ImageIndex),
     Sampler(SamplerHandle),
+    DynamicBuffer {
+        buffer: BufferIndex,
+        offset: u32,
+        range: u64,
+    },
+    DynamicStorageBuffer {
+        buffer: BufferIndex,
+        offset: u32,
+        range: u64,
+        write: bool,
+    },
 }
 
 //Transfer
@@ -92,10 +120,24 @@ pub struct ImageCopyBuffer {
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct ImageCopyImage {
     pub image: ImageIndex,
-    pub offset: [u32; 2],
+    pub offset: [u32; 3],
+    pub mip_level: u32,
+    pub base_array_layer: u32,
+}
+
+impl ImageCopyImage {
+    /// The single layer of the mip level the copy reads from or writes to
+    pub fn subresource_layers(&self, format: vk::Format) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers {
+            aspect_mask: vk_format_get_aspect_flags(format),
+            mip_level: self.mip_level,
+            base_array_layer: self.base_array_layer,
+            layer_count: 1,
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Transfer {
     BufferToBuffer {
         src: BufferOffset,
@@ -105,18 +147,25 @@ pub enum Transfer {
     BufferToImage {
         src: ImageCopyBuffer,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     },
     ImageToBuffer {
         src: ImageCopyImage,
         dst: ImageCopyBuffer,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     },
     ImageToImage {
         src: ImageCopyImage,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
+    },
+    FillBuffer {
+        dst: BufferOffset,
+        size: u64,
+        data: u32,
     },
+    /// Inline update, data must be a multiple of 4 bytes and at most 65536 bytes
+    UpdateBuffer { dst: BufferOffset, data: Vec<u8> },
 }
 
 //Compute
@@ -127,7 +176,7 @@ pub enum ComputeDispatch {
 }
 
 //Raster
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub enum IndexType {
     U16,
     U32,
@@ -136,22 +185,25 @@ pub enum IndexType {
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct ColorAttachment {
     pub image: ImageIndex,
-    pub clear: Option<[f32; 4]>,
+    pub clear: Option<ClearValue>,
+    pub subresource: AttachmentSubresource,
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct DepthStencilAttachment {
     pub image: ImageIndex,
-    pub clear: Option<(f32, u32)>,
+    pub clear: Option<ClearValue>,
+    pub subresource: AttachmentSubresource,
 }
 
 #[derive(Default, Debug)]
 pub struct Framebuffer {
     pub color_attachments: Vec<ColorAttachment>,
     pub depth_stencil_attachment: Option<DepthStencilAttachment>,
+    pub view_mask: u32,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Hash)]
 pub enum DrawCommandDispatch {
     Draw {
         vertices: Range<u32>,
@@ -184,6 +236,11 @@ pub struct RasterDrawCommand {
     pub vertex_buffers: Vec<BufferOffset>,
     pub resources: Vec<ShaderResourceUsage>,
     pub dispatch: DrawCommandDispatch,
+    /// Viewport min and max depth, the full 0..1 range when None
+    pub depth_range: Option<[f32; 2]>,
+    /// Clip rect in framebuffer pixels, the whole framebuffer when None
+    pub scissor: Option<vk::Rect2D>,
+    pub dynamic_state: DynamicStateValues,
 }
 
 #[derive(Debug)]
@@ -195,11 +252,21 @@ pub enum RenderPassCommand {
         pipeline: ComputePipelineHandle,
         resources: Vec<ShaderResourceUsage>,
         dispatch: ComputeDispatch,
+        /// Pushed after the resource binding indices
+        push_constants: Vec<u8>,
     },
     Raster {
         framebuffer: Framebuffer,
+        /// Replay draws from a cached secondary command buffer while nothing they use has changed
+        cacheable: bool,
         draw_commands: Vec<RasterDrawCommand>,
     },
+    Custom {
+        /// Resources passed to the command, same order as they were declared
+        buffers: Vec<BufferIndex>,
+        images: Vec<ImageIndex>,
+        command: CustomCommand,
+    },
 }
 
 // TODO: Determine the best pre and/or post frame ownership barriers
@@ -247,10 +314,23 @@ pub enum Queue {
     Transfer,
 }
 
+impl Queue {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Queue::Graphics => "graphics",
+            Queue::Compute => "async-compute",
+            Queue::Transfer => "async-transfer",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RenderPass {
     pub label_name: String,
     pub label_color: [f32; 4],
+    /// Accesses recorded by the builder, only used for introspection
+    pub buffer_usages: Vec<(BufferIndex, BufferResourceAccess)>,
+    pub image_usages: Vec<(ImageIndex, ImageResourceAccess)>,
     pub command: Option<RenderPassCommand>,
 }
 
@@ -269,6 +349,9 @@ pub struct BufferBarrier {
     pub index: BufferIndex,
     pub src: BufferBarrierSource,
     pub dst: BufferResourceAccess,
+    /// Shader stages of the passes on either side, only used by uniform and storage accesses
+    pub src_shader_stages: vk::PipelineStageFlags2,
+    pub dst_shader_stages: vk::PipelineStageFlags2,
 }
 
 #[derive(Debug, Default)]
@@ -297,6 +380,71 @@ pub struct RenderPassSet {
     pub render_passes: Vec<RenderPass>,
 }
 
+impl RenderPassSet {
+    /// A later set can share this set's barrier batch if none of its passes touch a resource written here,
+    /// write a resource read here, read a resource here with a different access (and so stage or layout),
+    /// or need a full memory barrier, e.g. for an execution dependency on an earlier pass
+    fn can_merge(&self, next: &RenderPassSet) -> bool {
+        if !next.memory_barriers.is_empty() {
+            return false;
+        }
+
+        next.render_passes.iter().all(|next_pass| {
+            self.render_passes.iter().all(|pass| {
+                let buffer_hazard = next_pass.buffer_usages.iter().any(|(buffer, access)| {
+                    pass.buffer_usages
+                        .iter()
+                        .any(|(other_buffer, other_access)| {
+                            buffer == other_buffer
+                                && (access.is_write()
+                                    || other_access.is_write()
+                                    || access != other_access)
+                        })
+                });
+                let image_hazard = next_pass.image_usages.iter().any(|(image, access)| {
+                    pass.image_usages.iter().any(|(other_image, other_access)| {
+                        image == other_image && (access.is_write() || access != other_access)
+                    })
+                });
+                !buffer_hazard && !image_hazard
+            })
+        })
+    }
+
+    /// Resources already used by this set are in the same state the next set needs, so their barriers are dropped
+    fn merge(&mut self, next: RenderPassSet) {
+        self.memory_barriers.extend(next.memory_barriers);
+
+        let used_buffer = |index: BufferIndex| {
+            self.render_passes.iter().any(|pass| {
+                pass.buffer_usages
+                    .iter()
+                    .any(|(buffer, _)| *buffer == index)
+            })
+        };
+        let buffer_barriers: Vec<BufferBarrier> = next
+            .buffer_barriers
+            .into_iter()
+            .filter(|barrier| !used_buffer(barrier.index))
+            .collect();
+
+        let used_image = |index: ImageIndex| {
+            self.render_passes
+                .iter()
+                .any(|pass| pass.image_usages.iter().any(|(image, _)| *image == index))
+        };
+        let image_barriers: Vec<ImageBarrier> = next
+            .image_barriers
+            .into_iter()
+            .filter(|barrier| !used_image(barrier.index))
+            .collect();
+
+        self.buffer_barriers.extend(buffer_barriers);
+        self.image_barriers.extend(image_barriers);
+        self.render_passes.extend(next.render_passes);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BufferOwnershipTransfer {
     pub index: BufferIndex,
@@ -347,6 +495,21 @@ pub struct CommandBuffer {
     pub command_buffer_signal_dependencies: Vec<CommandBufferDependency>,
 }
 
+impl CommandBuffer {
+    /// Merges consecutive render pass sets that have no hazards between them,
+    /// reducing the number of pipeline barrier calls without changing pass order
+    pub fn merge_render_pass_sets(&mut self) {
+        let mut merged: Vec<RenderPassSet> = Vec::with_capacity(self.render_pass_sets.len());
+        for render_pass_set in self.render_pass_sets.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.can_merge(&render_pass_set) => last.merge(render_pass_set),
+                _ => merged.push(render_pass_set),
+            }
+        }
+        self.render_pass_sets = merged;
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BufferWrites {
     pub total_write_size: usize,
@@ -405,6 +568,25 @@ impl BufferReads {
     }
 }
 
+/// Semaphore owned outside the renderer, such as by OpenXR or a video encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExternalSemaphore {
+    pub semaphore: vk::Semaphore,
+    /// Value to wait for or signal on a timeline semaphore, None for binary semaphores
+    pub value: Option<u64>,
+    pub stage_mask: vk::PipelineStageFlags2,
+}
+
+impl ExternalSemaphore {
+    pub(crate) fn submit_info(&self) -> vk::SemaphoreSubmitInfo {
+        vk::SemaphoreSubmitInfo::builder()
+            .semaphore(self.semaphore)
+            .value(self.value.unwrap_or_default())
+            .stage_mask(self.stage_mask)
+            .build()
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct CompiledRenderGraph {
     pub buffer_writes: BufferWrites,
@@ -421,5 +603,298 @@ pub struct CompiledRenderGraph {
     /// List of swapchains and swapchain images used by this graph
     pub swapchain_images: Vec<(SurfaceHandle, ImageIndex)>,
 
+    /// Waited on by the first submission of the graph
+    pub external_wait_semaphores: Vec<ExternalSemaphore>,
+    /// Signaled by the last submission of the graph, once all of its work is complete
+    pub external_signal_semaphores: Vec<ExternalSemaphore>,
+
     pub command_buffers: Vec<CommandBuffer>,
+
+    /// Named groups of passes timed together when pass timings are enabled
+    pub timer_scopes: Vec<TimerScope>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimerScope {
+    pub name: String,
+    /// Indices of the covered passes in recording order
+    pub passes: std::ops::Range<usize>,
+}
+
+/// Barrier resolved for a resource before a pass, a src of None means the access is carried over from the previous frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedBarrier<T> {
+    pub src: Option<T>,
+    pub dst: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferPassUsage {
+    pub buffer: BufferIndex,
+    pub access: BufferResourceAccess,
+    pub barrier: Option<ResolvedBarrier<BufferResourceAccess>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePassUsage {
+    pub image: ImageIndex,
+    pub access: ImageResourceAccess,
+    pub barrier: Option<ResolvedBarrier<ImageResourceAccess>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassUsage {
+    pub name: String,
+    pub command_buffer_index: usize,
+    pub buffers: Vec<BufferPassUsage>,
+    pub images: Vec<ImagePassUsage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransientResource {
+    Buffer(BufferIndex),
+    Image(ImageIndex),
+}
+
+/// Range of passes, in submission order, that a transient resource is used in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransientUsageInterval {
+    pub resource: TransientResource,
+    pub first_pass: usize,
+    pub last_pass: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AliasingReport {
+    pub intervals: Vec<TransientUsageInterval>,
+    /// Transient resources with matching descriptions and non-overlapping intervals that could share one allocation
+    pub aliasing_groups: Vec<Vec<TransientResource>>,
+}
+
+/// Summary counts of a compiled graph, cheap enough to compare between builds of the same frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GraphStats {
+    pub pass_count: usize,
+    pub render_pass_set_count: usize,
+    pub buffer_barrier_count: usize,
+    pub image_barrier_count: usize,
+    pub transient_buffer_count: usize,
+    pub transient_image_count: usize,
+    /// Upper bound of staging memory needed for buffer writes and reads, mapped buffers don't use any
+    pub staging_bytes: usize,
+}
+
+impl CompiledRenderGraph {
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats {
+            transient_buffer_count: self
+                .buffer_resources
+                .iter()
+                .filter(|buffer| !buffer.description.is_persistent())
+                .count(),
+            transient_image_count: self
+                .image_resources
+                .iter()
+                .filter(|image| matches!(image.description, ImageResourceDescription::Transient(_)))
+                .count(),
+            staging_bytes: self.buffer_writes.total_write_size + self.buffer_reads.total_read_size,
+            ..Default::default()
+        };
+
+        for render_pass_set in self
+            .command_buffers
+            .iter()
+            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+        {
+            stats.pass_count += render_pass_set.render_passes.len();
+            stats.render_pass_set_count += 1;
+            stats.buffer_barrier_count += render_pass_set.buffer_barriers.len();
+            stats.image_barrier_count += render_pass_set.image_barriers.len();
+        }
+
+        stats
+    }
+
+    /// True if any raster pass renders with a multiview view mask
+    pub fn uses_multiview(&self) -> bool {
+        self.command_buffers
+            .iter()
+            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .any(|render_pass| {
+                matches!(
+                    &render_pass.command,
+                    Some(RenderPassCommand::Raster { framebuffer, .. }) if framebuffer.view_mask != 0
+                )
+            })
+    }
+
+    /// Lists the resources each pass touches along with the barriers placed before it, useful for debugging synchronization
+    pub fn usage_report(&self) -> Vec<PassUsage> {
+        let mut report = Vec::new();
+
+        for (command_buffer_index, command_buffer) in self.command_buffers.iter().enumerate() {
+            for render_pass_set in command_buffer.render_pass_sets.iter() {
+                for render_pass in render_pass_set.render_passes.iter() {
+                    let buffers = render_pass
+                        .buffer_usages
+                        .iter()
+                        .map(|(buffer, access)| BufferPassUsage {
+                            buffer: *buffer,
+                            access: *access,
+                            barrier: render_pass_set
+                                .buffer_barriers
+                                .iter()
+                                .find(|barrier| barrier.index == *buffer)
+                                .map(|barrier| ResolvedBarrier {
+                                    src: match barrier.src {
+                                        BufferBarrierSource::FirstUsage => None,
+                                        BufferBarrierSource::Precalculated(access) => Some(access),
+                                    },
+                                    dst: barrier.dst,
+                                }),
+                        })
+                        .collect();
+
+                    let images = render_pass
+                        .image_usages
+                        .iter()
+                        .map(|(image, access)| ImagePassUsage {
+                            image: *image,
+                            access: *access,
+                            barrier: render_pass_set
+                                .image_barriers
+                                .iter()
+                                .find(|barrier| barrier.index == *image)
+                                .map(|barrier| ResolvedBarrier {
+                                    src: match barrier.src {
+                                        ImageBarrierSource::FirstUsage => None,
+                                        ImageBarrierSource::Precalculated(access) => Some(access),
+                                    },
+                                    dst: barrier.dst,
+                                }),
+                        })
+                        .collect();
+
+                    report.push(PassUsage {
+                        name: render_pass.label_name.clone(),
+                        command_buffer_index,
+                        buffers,
+                        images,
+                    });
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Finds transient resources whose lifetimes don't overlap, no aliasing is performed yet so this only reports what could be shared
+    pub fn aliasing_report(&self) -> AliasingReport {
+        let mut intervals: Vec<TransientUsageInterval> = Vec::new();
+        let mut interval_indices: HashMap<TransientResource, usize> = HashMap::new();
+        let mut record_usage = |resource: TransientResource, pass_index: usize| {
+            match interval_indices.get(&resource) {
+                Some(&index) => intervals[index].last_pass = pass_index,
+                None => {
+                    interval_indices.insert(resource, intervals.len());
+                    intervals.push(TransientUsageInterval {
+                        resource,
+                        first_pass: pass_index,
+                        last_pass: pass_index,
+                    });
+                }
+            }
+        };
+
+        let render_passes = self
+            .command_buffers
+            .iter()
+            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter());
+        for (pass_index, render_pass) in render_passes.enumerate() {
+            for (buffer, _) in render_pass.buffer_usages.iter() {
+                if !self.buffer_resources[*buffer].description.is_persistent() {
+                    record_usage(TransientResource::Buffer(*buffer), pass_index);
+                }
+            }
+            for (image, _) in render_pass.image_usages.iter() {
+                if matches!(
+                    self.image_resources[*image].description,
+                    ImageResourceDescription::Transient(_)
+                ) {
+                    record_usage(TransientResource::Image(*image), pass_index);
+                }
+            }
+        }
+
+        // Greedy interval partitioning, each group tracks the last pass of its latest member
+        let mut sorted_intervals: Vec<&TransientUsageInterval> = intervals.iter().collect();
+        sorted_intervals.sort_by_key(|interval| interval.first_pass);
+        let mut groups: Vec<(usize, Vec<TransientResource>)> = Vec::new();
+        for interval in sorted_intervals {
+            let group = groups.iter_mut().find(|(last_pass, members)| {
+                *last_pass < interval.first_pass
+                    && self.transients_compatible(members[0], interval.resource)
+            });
+            match group {
+                Some((last_pass, members)) => {
+                    *last_pass = interval.last_pass;
+                    members.push(interval.resource);
+                }
+                None => groups.push((interval.last_pass, vec![interval.resource])),
+            }
+        }
+
+        AliasingReport {
+            intervals,
+            aliasing_groups: groups
+                .into_iter()
+                .map(|(_, members)| members)
+                .filter(|members| members.len() > 1)
+                .collect(),
+        }
+    }
+
+    fn transients_compatible(&self, a: TransientResource, b: TransientResource) -> bool {
+        match (a, b) {
+            (TransientResource::Buffer(a), TransientResource::Buffer(b)) => matches!(
+                (&self.buffer_resources[a].description, &self.buffer_resources[b].description),
+                (
+                    BufferResourceDescription::Transient { size: size_a, location: location_a, .. },
+                    BufferResourceDescription::Transient { size: size_b, location: location_b, .. },
+                ) if size_a == size_b && location_a == location_b
+            ),
+            (TransientResource::Image(a), TransientResource::Image(b)) => {
+                match (
+                    &self.image_resources[a].description,
+                    &self.image_resources[b].description,
+                ) {
+                    (
+                        ImageResourceDescription::Transient(desc_a),
+                        ImageResourceDescription::Transient(desc_b),
+                    ) => {
+                        desc_a.format == desc_b.format
+                            && desc_a.mip_levels == desc_b.mip_levels
+                            && desc_a.memory_location == desc_b.memory_location
+                            && match (&desc_a.size, &desc_b.size) {
+                                (TransientImageSize::Exact(a), TransientImageSize::Exact(b)) => {
+                                    a == b
+                                }
+                                (
+                                    TransientImageSize::Relative(scale_a, target_a),
+                                    TransientImageSize::Relative(scale_b, target_b),
+                                ) => scale_a == scale_b && target_a == target_b,
+                                (TransientImageSize::Volume(a), TransientImageSize::Volume(b)) => {
+                                    a == b
+                                }
+                                _ => false,
+                            }
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
 }
@@ -1,7 +1,9 @@
-use crate::render_graph::{CompiledRenderGraph, IndexType, QueueType};
+use crate::image::{vk_format_get_aspect_flags, vk_format_numeric_type, FormatNumericType};
+use crate::render_graph::{CompiledRenderGraph, ExternalSemaphore, IndexType, QueueType};
+use crate::resource_managers::{BufferResourceAccess, ImageResourceAccess};
 use crate::{
-    BufferHandle, BufferUsage, ComputePipelineHandle, ImageHandle, RasterPipelineHandle,
-    SamplerHandle, SurfaceHandle, TransientImageDesc,
+    BufferHandle, BufferUsage, BufferWriteError, ComputePipelineHandle, DynamicState, ImageHandle,
+    RasterPipelineHandle, SamplerHandle, SurfaceHandle, TransientImageDesc, TransientImageSize,
 };
 use ash::vk;
 use std::ops::Range;
@@ -19,6 +21,49 @@ impl BufferWriteCallback {
     }
 }
 
+/// Raw handles of the resources declared by a custom pass, in declaration order
+pub struct CustomPassResources<'a> {
+    pub buffers: &'a [vk::Buffer],
+    pub images: &'a [vk::Image],
+}
+
+type CustomCommandCallbackType =
+    Box<dyn FnOnce(&ash::Device, vk::CommandBuffer, &CustomPassResources)>;
+
+/// Records arbitrary commands into a pass, barriers are only generated for the usages declared with the pass
+pub struct CustomCommand(std::cell::RefCell<Option<CustomCommandCallbackType>>);
+impl CustomCommand {
+    pub fn new(
+        function: impl FnOnce(&ash::Device, vk::CommandBuffer, &CustomPassResources) + 'static,
+    ) -> Self {
+        Self(std::cell::RefCell::new(Some(Box::new(function))))
+    }
+
+    /// Returns false if the command was already recorded
+    pub fn call(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        resources: &CustomPassResources,
+    ) -> bool {
+        match self.0.borrow_mut().take() {
+            Some(function) => {
+                function(device, command_buffer, resources);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for CustomCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomCommand")
+            .field("recorded", &self.0.borrow().is_none())
+            .finish()
+    }
+}
+
 type BufferReadCallbackType = Arc<dyn Fn(&[u8])>;
 
 #[derive(Clone)]
@@ -33,6 +78,64 @@ impl BufferReadCallback {
     }
 }
 
+/// Two persistent resources ping-ponged between frames, so a pass can read what was written last frame,
+/// e.g. temporal history or simulation state. Cross-frame barriers come from the persistent resources' last access tracking
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct DoubleBuffered<T> {
+    resources: [T; 2],
+    current: usize,
+    frames_written: u32,
+}
+
+/// Double buffered image for temporal effects
+pub type HistoryImage = DoubleBuffered<ImageHandle>;
+
+impl<T: Copy> DoubleBuffered<T> {
+    pub fn new(current: T, previous: T) -> Self {
+        Self {
+            resources: [current, previous],
+            current: 0,
+            frames_written: 0,
+        }
+    }
+
+    /// Resource written this frame
+    pub fn current(&self) -> T {
+        self.resources[self.current]
+    }
+
+    /// Resource written last frame
+    pub fn previous(&self) -> T {
+        self.resources[self.current ^ 1]
+    }
+
+    /// False until a frame has been written, the previous resource has undefined contents before that
+    pub fn has_history(&self) -> bool {
+        self.frames_written > 1
+    }
+
+    pub fn resources(&self) -> [T; 2] {
+        self.resources
+    }
+
+    fn advance(&mut self) {
+        if self.frames_written > 0 {
+            self.current ^= 1;
+        }
+        self.frames_written = self.frames_written.saturating_add(1);
+    }
+}
+
+impl HistoryImage {
+    pub fn images(&self) -> [ImageHandle; 2] {
+        self.resources
+    }
+}
+
+/// A pass added to a render graph builder, numbered in recording order
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct PassHandle(pub(crate) usize);
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct BufferOffset {
     pub buffer: BufferHandle,
@@ -50,7 +153,10 @@ pub struct ImageCopyBuffer {
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct ImageCopyImage {
     pub image: ImageHandle,
-    pub offset: [u32; 2],
+    pub offset: [u32; 3],
+    /// Mip level and array layer the copy reads from or writes to
+    pub mip_level: u32,
+    pub base_array_layer: u32,
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -63,26 +169,63 @@ pub enum Transfer {
     CopyBufferToImage {
         src: ImageCopyBuffer,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     },
     CopyImageToBuffer {
         src: ImageCopyImage,
         dst: ImageCopyBuffer,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     },
     CopyImageToImage {
         src: ImageCopyImage,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
+    },
+    /// Offset and size must both be multiples of 4
+    FillBuffer {
+        dst: BufferOffset,
+        size: u64,
+        data: u32,
     },
 }
 
 #[derive(Debug, Clone)]
 pub enum ShaderResourceUsage {
-    StorageBuffer { buffer: BufferHandle, write: bool },
-    StorageImage { image: ImageHandle, write: bool },
+    StorageBuffer {
+        buffer: BufferHandle,
+        write: bool,
+    },
+    StorageImage {
+        image: ImageHandle,
+        write: bool,
+    },
     SampledImage(ImageHandle),
     Sampler(SamplerHandle),
+    /// Bound to set 1 as a UNIFORM_BUFFER_DYNAMIC, offset is passed as the dynamic offset and
+    /// must be aligned to minUniformBufferOffsetAlignment. Doesn't take a push constant slot
+    DynamicBuffer {
+        buffer: BufferHandle,
+        offset: u32,
+        range: u64,
+    },
+    /// Bound to set 2 as a STORAGE_BUFFER_DYNAMIC, offset is passed as the dynamic offset and
+    /// must be aligned to minStorageBufferOffsetAlignment. Doesn't take a push constant slot
+    DynamicStorageBuffer {
+        buffer: BufferHandle,
+        offset: u32,
+        range: u64,
+        write: bool,
+    },
+}
+
+impl ShaderResourceUsage {
+    /// Dynamic buffers are bound to their own sets instead of taking a binding index
+    pub(crate) fn is_dynamic_buffer(&self) -> bool {
+        matches!(
+            self,
+            Self::DynamicBuffer { .. } | Self::DynamicStorageBuffer { .. }
+        )
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -91,22 +234,210 @@ pub enum ComputeDispatch {
     Indirect(BufferOffset),
 }
 
+impl ComputeDispatch {
+    /// Dispatches enough workgroups of the shader's local size to cover the whole extent,
+    /// shaders should bounds check since edge workgroups may run past it
+    pub fn cover(extent: [u32; 3], workgroup: [u32; 3]) -> Self {
+        Self::Size([
+            extent[0].div_ceil(workgroup[0].max(1)),
+            extent[1].div_ceil(workgroup[1].max(1)),
+            extent[2].div_ceil(workgroup[2].max(1)),
+        ])
+    }
+
+    /// Dispatches one workgroup per `elements_per_workgroup` elements along x.
+    /// For counts only known on the GPU use [`ComputeDispatch::Indirect`], the pass producing the count
+    /// must write a `VkDispatchIndirectCommand` with the workgroup count already divided out
+    pub fn over_elements(count: u32, elements_per_workgroup: u32) -> Self {
+        Self::Size([count.div_ceil(elements_per_workgroup.max(1)), 1, 1])
+    }
+}
+
+pub const CUBEMAP_FACE_COUNT: u32 = 6;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CubemapTarget {
+    Color(Option<ClearValue>),
+    DepthStencil(Option<ClearValue>),
+}
+
+/// Column major 90 degree projection for rendering cubemap faces, Y isn't flipped
+/// since Vulkan addresses cube faces from the top row down
+pub fn cubemap_projection_matrix(near_clip: f32, far_clip: f32) -> [[f32; 4]; 4] {
+    let depth_scale = far_clip / (near_clip - far_clip);
+    [
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, depth_scale, -1.0],
+        [0.0, 0.0, depth_scale * near_clip, 0.0],
+    ]
+}
+
+/// Column major view matrices of each cubemap face around a center point,
+/// in the same layer order as [`RasterPassBuilder::new_cubemap_faces`]
+pub fn cubemap_view_matrices(center: [f32; 3]) -> [[[f32; 4]; 4]; CUBEMAP_FACE_COUNT as usize] {
+    let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let cross = |a: [f32; 3], b: [f32; 3]| {
+        [
+            a[1] * b[2] - a[2] * b[1],
+            a[2] * b[0] - a[0] * b[2],
+            a[0] * b[1] - a[1] * b[0],
+        ]
+    };
+
+    [
+        ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+        ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+    ]
+    .map(|(forward, up)| {
+        // Axis aligned, so the cross products are already normalized
+        let side = cross(forward, up);
+        let up = cross(side, forward);
+        [
+            [side[0], up[0], -forward[0], 0.0],
+            [side[1], up[1], -forward[1], 0.0],
+            [side[2], up[2], -forward[2], 0.0],
+            [
+                -dot(side, center),
+                -dot(up, center),
+                dot(forward, center),
+                1.0,
+            ],
+        ]
+    })
+}
+
+/// Mip level and array layers rendered to, mip 0 layer 0 uses the image's default view
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct AttachmentSubresource {
+    pub mip_level: u32,
+    pub base_layer: u32,
+    /// Only more than 1 for multiview passes
+    pub layer_count: u32,
+}
+
+impl Default for AttachmentSubresource {
+    fn default() -> Self {
+        Self {
+            mip_level: 0,
+            base_layer: 0,
+            layer_count: 1,
+        }
+    }
+}
+
+impl AttachmentSubresource {
+    pub fn is_default(&self) -> bool {
+        self.mip_level == 0 && self.base_layer == 0 && self.layer_count == 1
+    }
+}
+
+/// Number of array layers a multiview pass with this view mask renders to
+pub fn view_mask_layer_count(view_mask: u32) -> u32 {
+    u32::BITS - view_mask.leading_zeros()
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ClearValue {
+    Float([f32; 4]),
+    Int([i32; 4]),
+    Uint([u32; 4]),
+    DepthStencil(f32, u32),
+}
+
+impl ClearValue {
+    /// Picks the clear union member matching the attachment format, converting the value if its type differs.
+    /// Non depth stencil values clear depth attachments with their first component
+    pub(crate) fn to_vk(self, format: vk::Format) -> vk::ClearValue {
+        if vk_format_get_aspect_flags(format) != vk::ImageAspectFlags::COLOR {
+            let (depth, stencil) = match self {
+                ClearValue::Float(value) => (value[0], 0),
+                ClearValue::Int(value) => (value[0] as f32, 0),
+                ClearValue::Uint(value) => (value[0] as f32, 0),
+                ClearValue::DepthStencil(depth, stencil) => (depth, stencil),
+            };
+            return vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue { depth, stencil },
+            };
+        }
+
+        let color = match vk_format_numeric_type(format) {
+            FormatNumericType::Float => vk::ClearColorValue {
+                float32: match self {
+                    ClearValue::Float(value) => value,
+                    ClearValue::Int(value) => value.map(|value| value as f32),
+                    ClearValue::Uint(value) => value.map(|value| value as f32),
+                    ClearValue::DepthStencil(depth, _) => [depth; 4],
+                },
+            },
+            FormatNumericType::Sint => vk::ClearColorValue {
+                int32: match self {
+                    ClearValue::Float(value) => value.map(|value| value as i32),
+                    ClearValue::Int(value) => value,
+                    ClearValue::Uint(value) => value.map(|value| value as i32),
+                    ClearValue::DepthStencil(_, stencil) => [stencil as i32; 4],
+                },
+            },
+            FormatNumericType::Uint => vk::ClearColorValue {
+                uint32: match self {
+                    ClearValue::Float(value) => value.map(|value| value as u32),
+                    ClearValue::Int(value) => value.map(|value| value as u32),
+                    ClearValue::Uint(value) => value,
+                    ClearValue::DepthStencil(_, stencil) => [stencil; 4],
+                },
+            },
+        };
+        vk::ClearValue { color }
+    }
+}
+
+impl From<[f32; 4]> for ClearValue {
+    fn from(value: [f32; 4]) -> Self {
+        ClearValue::Float(value)
+    }
+}
+
+impl From<[i32; 4]> for ClearValue {
+    fn from(value: [i32; 4]) -> Self {
+        ClearValue::Int(value)
+    }
+}
+
+impl From<[u32; 4]> for ClearValue {
+    fn from(value: [u32; 4]) -> Self {
+        ClearValue::Uint(value)
+    }
+}
+
+impl From<(f32, u32)> for ClearValue {
+    fn from(value: (f32, u32)) -> Self {
+        ClearValue::DepthStencil(value.0, value.1)
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct ColorAttachment {
     pub image: ImageHandle,
-    pub clear: Option<[f32; 4]>,
+    pub clear: Option<ClearValue>,
+    pub subresource: AttachmentSubresource,
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct DepthStencilAttachment {
     pub image: ImageHandle,
-    pub clear: Option<(f32, u32)>,
+    pub clear: Option<ClearValue>,
+    pub subresource: AttachmentSubresource,
 }
 
 #[derive(Default, Debug)]
 pub struct Framebuffer {
     pub color_attachments: Vec<ColorAttachment>,
     pub depth_stencil_attachment: Option<DepthStencilAttachment>,
+    pub view_mask: u32,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -136,14 +467,286 @@ pub enum DrawCommandDispatch {
     },
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub clamp: f32,
+    pub slope_factor: f32,
+}
+
+/// Values for the draw pipeline's dynamic states, each set value must be declared by the pipeline.
+/// Declared states left unset use 1.0 line width and zero for everything else
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct DynamicStateValues {
+    pub line_width: Option<f32>,
+    pub blend_constants: Option<[f32; 4]>,
+    pub depth_bias: Option<DepthBias>,
+    /// Used for both front and back faces
+    pub stencil_reference: Option<u32>,
+}
+
+impl DynamicStateValues {
+    pub(crate) fn set_states(&self) -> impl Iterator<Item = DynamicState> {
+        [
+            self.line_width.map(|_| DynamicState::LineWidth),
+            self.blend_constants.map(|_| DynamicState::BlendConstants),
+            self.depth_bias.map(|_| DynamicState::DepthBias),
+            self.stencil_reference
+                .map(|_| DynamicState::StencilReference),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
 #[derive(Debug)]
 pub struct RasterDrawCommand {
     pub pipeline: RasterPipelineHandle,
     pub vertex_buffers: Vec<BufferOffset>,
     pub resources: Vec<ShaderResourceUsage>,
     pub dispatch: DrawCommandDispatch,
+    /// Viewport min and max depth, the full 0..1 range when None
+    pub depth_range: Option<[f32; 2]>,
+    /// Clip rect in framebuffer pixels, the whole framebuffer when None
+    pub scissor: Option<vk::Rect2D>,
+    pub dynamic_state: DynamicStateValues,
+}
+
+/// Overlap of two scissor rects, zero sized when they don't overlap
+pub fn intersect_scissor(a: vk::Rect2D, b: vk::Rect2D) -> vk::Rect2D {
+    let min = [a.offset.x.max(b.offset.x), a.offset.y.max(b.offset.y)];
+    let max = [
+        (a.offset.x as i64 + a.extent.width as i64).min(b.offset.x as i64 + b.extent.width as i64),
+        (a.offset.y as i64 + a.extent.height as i64)
+            .min(b.offset.y as i64 + b.extent.height as i64),
+    ];
+    vk::Rect2D {
+        offset: vk::Offset2D {
+            x: min[0],
+            y: min[1],
+        },
+        extent: vk::Extent2D {
+            width: (max[0] - min[0] as i64).max(0) as u32,
+            height: (max[1] - min[1] as i64).max(0) as u32,
+        },
+    }
+}
+
+impl RasterDrawCommand {
+    /// Draws a single triangle covering the whole screen with no vertex buffers bound,
+    /// the vertex shader is expected to generate positions from gl_VertexIndex
+    pub fn fullscreen(pipeline: RasterPipelineHandle, resources: Vec<ShaderResourceUsage>) -> Self {
+        Self {
+            pipeline,
+            vertex_buffers: Vec::new(),
+            resources,
+            dispatch: DrawCommandDispatch::Draw {
+                vertices: 0..3,
+                instances: 0..1,
+            },
+            depth_range: None,
+            scissor: None,
+            dynamic_state: DynamicStateValues::default(),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RenderGraphError {
+    #[error("Fill size {size} or offset {offset} is not a multiple of 4")]
+    UnalignedFill { offset: usize, size: usize },
+    #[error("Fill of {size} bytes at offset {offset} is outside of buffer {buffer:?} ({buffer_size} bytes)")]
+    FillOutOfBounds {
+        buffer: BufferHandle,
+        offset: usize,
+        size: usize,
+        buffer_size: usize,
+    },
+    #[error("Update of {size} bytes at offset {offset} is outside of buffer {buffer:?} ({buffer_size} bytes)")]
+    UpdateOutOfBounds {
+        buffer: BufferHandle,
+        offset: usize,
+        size: usize,
+        buffer_size: usize,
+    },
+    #[error("Update of buffer {buffer:?} has no data")]
+    EmptyUpdate { buffer: BufferHandle },
+    #[error("Pass {pass} reads a sampled image but doesn't provide a sampler")]
+    MissingSampler { pass: String },
+    #[error("Transient buffer {buffer:?} is used as {} but wasn't created with that usage", missing.names())]
+    MissingBufferUsage {
+        buffer: BufferHandle,
+        missing: BufferUsage,
+    },
+    #[error(
+        "Transient image {image:?} format {format:?} doesn't support being used as {unsupported:?}"
+    )]
+    UnsupportedTransientFormat {
+        image: ImageHandle,
+        format: vk::Format,
+        unsupported: vk::ImageUsageFlags,
+    },
+    #[error("Pass {pass} samples image {image:?} while also rendering to it")]
+    SampledAttachment { pass: String, image: ImageHandle },
+    #[error("Transient {resource} is written by {first_pass} and again by {second_pass} without being read in between")]
+    UnorderedWrites {
+        resource: String,
+        first_pass: String,
+        second_pass: String,
+    },
+    #[error("Draw {draw_index} of pass {pass} has a depth range outside of 0..=1")]
+    InvalidDepthRange { pass: String, draw_index: usize },
+    #[error("Pass {pass} pushes {size} bytes of binding indices and push constants, more than the device's {max} byte limit")]
+    PushConstantsTooLarge { pass: String, size: usize, max: u32 },
+    #[error("Timer {name} has unbalanced begin_timer and end_timer calls")]
+    UnbalancedTimer { name: String },
+    #[error(
+        "Pass {pass} binds more than one dynamic uniform or more than one dynamic storage buffer"
+    )]
+    MultipleDynamicBuffers { pass: String },
+    #[error("Pass {pass} binds buffer {buffer:?} at dynamic offset {offset}, which isn't a multiple of {alignment}")]
+    UnalignedDynamicBuffer {
+        pass: String,
+        buffer: BufferHandle,
+        offset: u32,
+        alignment: u64,
+    },
+    #[error("Pass {pass} binds {range} bytes at offset {offset} of buffer {buffer:?}, which is only {buffer_size} bytes")]
+    DynamicBufferOutOfBounds {
+        pass: String,
+        buffer: BufferHandle,
+        offset: u32,
+        range: u64,
+        buffer_size: u64,
+    },
+    #[error("Pass {pass} binds {range} bytes of buffer {buffer:?} as a dynamic buffer, more than the device's {max} byte limit")]
+    DynamicBufferRangeTooLarge {
+        pass: String,
+        buffer: BufferHandle,
+        range: u64,
+        max: u64,
+    },
+    #[error("Pass {pass} binds buffer {buffer:?} as a uniform buffer but it wasn't created with UNIFORM usage")]
+    BufferNotUniformCapable { pass: String, buffer: BufferHandle },
+    #[error("{resource} was used in the render graph after being destroyed")]
+    StaleHandle { resource: String },
+    #[error("Pass {pass} samples image {image:?} which wasn't created with SAMPLED usage")]
+    ImageNotSampledCapable { pass: String, image: ImageHandle },
+    #[error("Pass {pass} uses image {image:?} as a storage image but it wasn't created with STORAGE usage")]
+    ImageNotStorageCapable { pass: String, image: ImageHandle },
+    #[error("Pass {pass} uses buffer {buffer:?} as a storage buffer but it wasn't created with STORAGE usage")]
+    BufferNotStorageCapable { pass: String, buffer: BufferHandle },
+    #[error("Image {image:?} can't be given a release access, only persistent images can")]
+    NonPersistentRelease { image: ImageHandle },
+    #[error("Pass {after:?} can't depend on pass {before:?}, both must exist and {before:?} must be added first")]
+    InvalidExecutionDependency {
+        before: PassHandle,
+        after: PassHandle,
+    },
+    #[error("Transient buffer {buffer:?} wasn't created by this render graph builder")]
+    InvalidBufferHandle { buffer: BufferHandle },
+    #[error("Transient image {image:?} wasn't created by this render graph builder")]
+    InvalidImageHandle { image: ImageHandle },
+    #[error("Pass {pass} reads transient {resource} before anything writes to it")]
+    UninitializedRead { pass: String, resource: String },
+    #[error("Draw {draw_index} of pass {pass} has no draw dispatch set")]
+    MissingDrawDispatch { pass: String, draw_index: usize },
+    #[error("Raster pass {pass} has neither color nor depth stencil attachments")]
+    NoAttachments { pass: String },
+    #[error("Pass {pass} renders to mip {} layers {}..{} of image {image:?}, which only has {mip_levels} mips and {array_layers} layers", subresource.mip_level, subresource.base_layer, subresource.base_layer + subresource.layer_count)]
+    AttachmentSubresourceOutOfRange {
+        pass: String,
+        image: ImageHandle,
+        subresource: AttachmentSubresource,
+        mip_levels: u32,
+        array_layers: u32,
+    },
+}
+
+/// Sampled images and samplers are bound separately, so any set of resources that samples an image needs a sampler as well
+pub(crate) fn validate_sampled_resources(
+    pass: &str,
+    resources: &[ShaderResourceUsage],
+) -> Result<(), RenderGraphError> {
+    let has_sampled_image = resources
+        .iter()
+        .any(|resource| matches!(resource, ShaderResourceUsage::SampledImage(_)));
+    let has_sampler = resources
+        .iter()
+        .any(|resource| matches!(resource, ShaderResourceUsage::Sampler(_)));
+
+    if has_sampled_image && !has_sampler {
+        Err(RenderGraphError::MissingSampler {
+            pass: pass.to_string(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets 1 and 2 only have a single dynamic buffer binding each
+pub(crate) fn validate_dynamic_buffers(
+    pass: &str,
+    resources: &[ShaderResourceUsage],
+) -> Result<(), RenderGraphError> {
+    let uniform_count = resources
+        .iter()
+        .filter(|resource| matches!(resource, ShaderResourceUsage::DynamicBuffer { .. }))
+        .count();
+    let storage_count = resources
+        .iter()
+        .filter(|resource| matches!(resource, ShaderResourceUsage::DynamicStorageBuffer { .. }))
+        .count();
+    if uniform_count > 1 || storage_count > 1 {
+        Err(RenderGraphError::MultipleDynamicBuffers {
+            pass: pass.to_string(),
+        })
+    } else {
+        Ok(())
+    }
 }
 
+pub(crate) fn validate_push_constant_size(
+    pass: &str,
+    resources: &[ShaderResourceUsage],
+    push_constants: &[u8],
+    max_push_constants_size: u32,
+) -> Result<(), RenderGraphError> {
+    let binding_count = resources
+        .iter()
+        .filter(|resource| !resource.is_dynamic_buffer())
+        .count();
+    let size = binding_count * std::mem::size_of::<u32>() + push_constants.len();
+    if size > max_push_constants_size as usize {
+        Err(RenderGraphError::PushConstantsTooLarge {
+            pass: pass.to_string(),
+            size,
+            max: max_push_constants_size,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn validate_depth_range(
+    pass: &str,
+    draw_index: usize,
+    depth_range: Option<[f32; 2]>,
+) -> Result<(), RenderGraphError> {
+    match depth_range {
+        Some(range) if !range.iter().all(|depth| (0.0..=1.0).contains(depth)) => {
+            Err(RenderGraphError::InvalidDepthRange {
+                pass: pass.to_string(),
+                draw_index,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Limit for vkCmdUpdateBuffer set by the Vulkan spec
+pub const MAX_INLINE_BUFFER_UPDATE_SIZE: usize = 65536;
+
 // Render Graph Builder Evolution
 // 0. Whole pipeline barriers between passes, no image layout changes (only general layout), no pass order changes, no dead-code culling (DONE!)
 // 1. Specific pipeline barriers between passes with image layout changes, no pass order changes, no dead-code culling
@@ -158,13 +761,14 @@ pub trait RenderGraphBuilderTrait {
         callback: BufferWriteCallback,
     );
 
-    /// Downloads data to a buffer, if certain conditions are meet the read may be direct otherwise a staging buffer is used
+    /// Downloads data to a buffer, if certain conditions are meet the read may be direct otherwise a staging buffer is used.
+    /// Transient reads are bounds checked here, persistent reads when the graph is submitted
     fn add_buffer_read(
         &mut self,
         buffer_offset: BufferOffset,
         read_size: usize,
         callback: BufferReadCallback,
-    );
+    ) -> Result<(), BufferWriteError>;
 
     fn create_transient_buffer(
         &mut self,
@@ -175,6 +779,12 @@ pub trait RenderGraphBuilderTrait {
     fn create_transient_image(&mut self, desc: TransientImageDesc) -> ImageHandle;
     fn acquire_swapchain_image(&mut self, surface_handle: SurfaceHandle) -> ImageHandle;
 
+    /// Makes the graph's work wait on a semaphore signaled outside the renderer
+    fn add_external_wait_semaphore(&mut self, semaphore: ExternalSemaphore);
+
+    /// Signals a semaphore owned outside the renderer once the graph's work is complete
+    fn add_external_signal_semaphore(&mut self, semaphore: ExternalSemaphore);
+
     // fn create_transient_buffer_set(&mut self, buffer_handles: &[BufferHandle]) -> BufferSetHandle;
     // fn create_transient_image_set(&mut self, image_handles: &[ImageHandle]) -> ImageSetHandle;
 
@@ -184,7 +794,25 @@ pub trait RenderGraphBuilderTrait {
         color: [f32; 4],
         queue: QueueType,
         transfers: &[Transfer],
-    );
+    ) -> PassHandle;
+
+    /// Fills a range of a buffer with a repeated u32 value, size and offset must be multiples of 4
+    fn add_fill_buffer_pass(
+        &mut self,
+        buffer_offset: BufferOffset,
+        size: usize,
+        value: u32,
+    ) -> Result<PassHandle, RenderGraphError>;
+
+    /// Writes small data directly into the command buffer with vkCmdUpdateBuffer.
+    /// Falls back to copying from a staging buffer if the data is larger than 65536 bytes or not 4 byte aligned,
+    /// either way the update runs in pass order
+    fn add_update_buffer_pass(
+        &mut self,
+        buffer_offset: BufferOffset,
+        data: &[u8],
+    ) -> Result<PassHandle, RenderGraphError>;
+
     fn add_compute_pass(
         &mut self,
         name: String,
@@ -193,17 +821,208 @@ pub trait RenderGraphBuilderTrait {
         pipeline: ComputePipelineHandle,
         dispatch: ComputeDispatch,
         resources: &[ShaderResourceUsage],
-    );
+    ) -> PassHandle {
+        self.add_compute_pass_with_push_constants(
+            name,
+            color,
+            queue,
+            pipeline,
+            dispatch,
+            resources,
+            &[],
+        )
+    }
+    /// push_constants are pushed after the resource binding indices, the total must fit in maxPushConstantsSize
+    #[allow(clippy::too_many_arguments)]
+    fn add_compute_pass_with_push_constants(
+        &mut self,
+        name: String,
+        color: [f32; 4],
+        queue: QueueType,
+        pipeline: ComputePipelineHandle,
+        dispatch: ComputeDispatch,
+        resources: &[ShaderResourceUsage],
+        push_constants: &[u8],
+    ) -> PassHandle;
+    /// Same as add_compute_pass_with_push_constants with a typed push constant block, see [`push_constant_bytes`]
+    #[allow(clippy::too_many_arguments)]
+    fn add_compute_pass_typed<P: bytemuck::Pod>(
+        &mut self,
+        name: String,
+        color: [f32; 4],
+        queue: QueueType,
+        pipeline: ComputePipelineHandle,
+        dispatch: ComputeDispatch,
+        resources: &[ShaderResourceUsage],
+        push: P,
+    ) -> PassHandle
+    where
+        Self: Sized,
+    {
+        self.add_compute_pass_with_push_constants(
+            name,
+            color,
+            queue,
+            pipeline,
+            dispatch,
+            resources,
+            &push_constant_bytes(&push),
+        )
+    }
+    /// Escape hatch for commands the graph doesn't expose, the usages must cover everything the command touches
+    fn add_custom_pass(
+        &mut self,
+        name: String,
+        color: [f32; 4],
+        buffer_usages: &[(BufferHandle, BufferResourceAccess)],
+        image_usages: &[(ImageHandle, ImageResourceAccess)],
+        command: CustomCommand,
+    ) -> PassHandle;
+    #[allow(clippy::too_many_arguments)]
     fn add_raster_pass(
         &mut self,
         name: String,
         color: [f32; 4],
         color_attachments: &[ColorAttachment],
         depth_stencil_attachment: Option<DepthStencilAttachment>,
+        view_mask: u32,
+        cacheable: bool,
         raster_draw_commands: &[RasterDrawCommand],
-    );
+    ) -> PassHandle;
+
+    /// Transitions a persistent image into access at the end of the frame, overriding its rest access for this frame.
+    /// Used to hand images back to external APIs in the layout they expect, the image must be used by a pass
+    fn set_image_release_access(&mut self, image: ImageHandle, access: ImageResourceAccess);
 
-    fn build(self) -> CompiledRenderGraph;
+    /// Forces after to execute after before, even if they share no resources.
+    /// Both passes must already be added, before first
+    fn add_execution_dependency(&mut self, before: PassHandle, after: PassHandle);
+
+    /// Times every pass added until the matching end_timer as one scope, reported in FrameTimings::scopes
+    /// when pass timings are enabled. Scopes may nest but each open scope needs a unique name
+    fn begin_timer(&mut self, name: &str);
+    fn end_timer(&mut self, name: &str);
+
+    /// Swaps a history image pair for this frame, returning the (current, previous) images.
+    /// Should be called once per frame before any pass uses the images
+    fn history_image(&mut self, history: &mut HistoryImage) -> (ImageHandle, ImageHandle) {
+        history.advance();
+        (history.current(), history.previous())
+    }
+
+    /// Same as history_image for any double buffered resource, e.g. a pair of simulation state buffers
+    fn double_buffered<T: Copy>(&mut self, resource: &mut DoubleBuffered<T>) -> (T, T)
+    where
+        Self: Sized,
+    {
+        resource.advance();
+        (resource.current(), resource.previous())
+    }
+
+    /// Renders offscreen into a new transient color target, plus a transient depth target when a depth format is given,
+    /// and returns the color image so later passes can sample it.
+    /// Color is cleared to zero and depth to 1.0, draw_fn adds the draw commands to the pass
+    fn render_to_texture(
+        &mut self,
+        name: &str,
+        size: TransientImageSize,
+        format: vk::Format,
+        depth_format: Option<vk::Format>,
+        draw_fn: impl FnOnce(&mut RasterPassBuilder),
+    ) -> ImageHandle
+    where
+        Self: Sized,
+    {
+        let color_image = self.create_transient_image(TransientImageDesc {
+            size: size.clone(),
+            format,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+
+        let mut raster_pass = RasterPassBuilder::new(name);
+        raster_pass.add_color_attachment(color_image, Some(ClearValue::Float([0.0; 4])));
+
+        if let Some(depth_format) = depth_format {
+            let depth_image = self.create_transient_image(TransientImageDesc {
+                size,
+                format: depth_format,
+                usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                mip_levels: 1,
+                memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+            });
+            raster_pass
+                .add_depth_stencil_attachment(depth_image, Some(ClearValue::DepthStencil(1.0, 0)));
+        }
+
+        draw_fn(&mut raster_pass);
+        raster_pass.build(self);
+        color_image
+    }
+
+    /// Depth only counterpart of render_to_texture, the returned image can be sampled afterwards, e.g. as a shadow map.
+    /// Pipelines drawn into it need a depth state with depth_format and no color targets
+    fn render_depth_to_texture(
+        &mut self,
+        name: &str,
+        size: TransientImageSize,
+        depth_format: vk::Format,
+        draw_fn: impl FnOnce(&mut RasterPassBuilder),
+    ) -> ImageHandle
+    where
+        Self: Sized,
+    {
+        let depth_image = self.create_transient_image(TransientImageDesc {
+            size,
+            format: depth_format,
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+
+        let mut raster_pass = RasterPassBuilder::new(name);
+        raster_pass
+            .add_depth_stencil_attachment(depth_image, Some(ClearValue::DepthStencil(1.0, 0)));
+
+        draw_fn(&mut raster_pass);
+        raster_pass.build(self);
+        depth_image
+    }
+
+    /// Copies an acquired swapchain image into dst after every pass added so far, e.g. for video capture or streaming.
+    /// The surface needs TRANSFER_SRC usage and dst room for size[0] * size[1] tightly packed texels,
+    /// usually a GpuToCpu buffer read back with add_buffer_read. The image is still presented in PRESENT_SRC_KHR.
+    /// Texels are in the surface format's channel order, vk_format_to_rgba8 converts them for encoding given the surface's format and color space
+    fn add_swapchain_readback(
+        &mut self,
+        swapchain_image: ImageHandle,
+        size: [u32; 2],
+        dst: BufferOffset,
+    ) -> PassHandle {
+        self.add_transfer_pass(
+            String::from("Swapchain Readback"),
+            [1.0, 0.0, 1.0, 1.0],
+            QueueType::Graphics,
+            &[Transfer::CopyImageToBuffer {
+                src: ImageCopyImage {
+                    image: swapchain_image,
+                    offset: [0; 3],
+                    mip_level: 0,
+                    base_array_layer: 0,
+                },
+                dst: ImageCopyBuffer {
+                    buffer: dst.buffer,
+                    offset: dst.offset as u64,
+                    row_length: None,
+                    row_height: None,
+                },
+                copy_size: [size[0], size[1], 1],
+            }],
+        )
+    }
+
+    fn build(self) -> Result<CompiledRenderGraph, RenderGraphError>;
 }
 
 //Helper Structs
@@ -245,7 +1064,7 @@ impl TransferPassBuilder {
         &mut self,
         src: ImageCopyBuffer,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     ) {
         self.transfers.push(Transfer::CopyBufferToImage {
             src,
@@ -258,7 +1077,7 @@ impl TransferPassBuilder {
         &mut self,
         src: ImageCopyImage,
         dst: ImageCopyBuffer,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     ) {
         self.transfers.push(Transfer::CopyImageToBuffer {
             src,
@@ -271,7 +1090,7 @@ impl TransferPassBuilder {
         &mut self,
         src: ImageCopyImage,
         dst: ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     ) {
         self.transfers.push(Transfer::CopyImageToImage {
             src,
@@ -280,8 +1099,58 @@ impl TransferPassBuilder {
         });
     }
 
-    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) {
-        render_graph_builder.add_transfer_pass(self.name, self.color, self.queue, &self.transfers);
+    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) -> PassHandle {
+        render_graph_builder.add_transfer_pass(self.name, self.color, self.queue, &self.transfers)
+    }
+}
+
+/// Bytes of a push constant block. Fails to compile unless P is a multiple of 4 bytes and fits in the
+/// 128 bytes every device supports, the binding indices pushed before it are checked against the device limit in build()
+pub fn push_constant_bytes<P: bytemuck::Pod>(push: &P) -> Vec<u8> {
+    const {
+        assert!(
+            std::mem::size_of::<P>().is_multiple_of(4),
+            "Push constant size must be a multiple of 4"
+        );
+        assert!(
+            std::mem::size_of::<P>() <= MIN_MAX_PUSH_CONSTANTS_SIZE as usize,
+            "Push constant is larger than the guaranteed maxPushConstantsSize"
+        );
+    }
+    bytemuck::bytes_of(push).to_vec()
+}
+
+/// Lowest maxPushConstantsSize allowed by the Vulkan spec
+pub const MIN_MAX_PUSH_CONSTANTS_SIZE: u32 = 128;
+
+/// Device limits dynamic buffer bindings are checked against, defaults to the values guaranteed by the Vulkan spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DynamicBufferLimits {
+    pub min_uniform_buffer_offset_alignment: u64,
+    pub max_uniform_buffer_range: u64,
+    pub min_storage_buffer_offset_alignment: u64,
+    pub max_storage_buffer_range: u64,
+}
+
+impl Default for DynamicBufferLimits {
+    fn default() -> Self {
+        Self {
+            min_uniform_buffer_offset_alignment: 256,
+            max_uniform_buffer_range: 16384,
+            min_storage_buffer_offset_alignment: 256,
+            max_storage_buffer_range: 1 << 27,
+        }
+    }
+}
+
+impl DynamicBufferLimits {
+    pub fn from_device_limits(limits: &vk::PhysicalDeviceLimits) -> Self {
+        Self {
+            min_uniform_buffer_offset_alignment: limits.min_uniform_buffer_offset_alignment,
+            max_uniform_buffer_range: limits.max_uniform_buffer_range as u64,
+            min_storage_buffer_offset_alignment: limits.min_storage_buffer_offset_alignment,
+            max_storage_buffer_range: limits.max_storage_buffer_range as u64,
+        }
     }
 }
 
@@ -292,6 +1161,7 @@ pub struct ComputePassBuilder {
     pipeline: ComputePipelineHandle,
     resources: Vec<ShaderResourceUsage>,
     dispatch: ComputeDispatch,
+    push_constants: Vec<u8>,
 }
 
 impl ComputePassBuilder {
@@ -303,9 +1173,15 @@ impl ComputePassBuilder {
             pipeline,
             resources: Vec::new(),
             dispatch: ComputeDispatch::Size([1; 3]),
+            push_constants: Vec::new(),
         }
     }
 
+    /// Pushed after the resource binding indices, see [`push_constant_bytes`]
+    pub fn set_push_constants<P: bytemuck::Pod>(&mut self, push: P) {
+        self.push_constants = push_constant_bytes(&push);
+    }
+
     pub fn override_label_color(&mut self, color: [f32; 4]) {
         self.color = color;
     }
@@ -353,15 +1229,52 @@ impl ComputePassBuilder {
         self.resources.push(ShaderResourceUsage::Sampler(sampler));
     }
 
-    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) {
-        render_graph_builder.add_compute_pass(
+    /// Binds range bytes of the buffer starting at offset to set 1, see [`ShaderResourceUsage::DynamicBuffer`]
+    pub fn read_dynamic_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources.push(ShaderResourceUsage::DynamicBuffer {
+            buffer,
+            offset,
+            range,
+        });
+    }
+
+    /// Binds range bytes of the buffer starting at offset to set 2, see [`ShaderResourceUsage::DynamicStorageBuffer`]
+    pub fn read_dynamic_storage_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources
+            .push(ShaderResourceUsage::DynamicStorageBuffer {
+                buffer,
+                offset,
+                range,
+                write: false,
+            });
+    }
+
+    pub fn write_dynamic_storage_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources
+            .push(ShaderResourceUsage::DynamicStorageBuffer {
+                buffer,
+                offset,
+                range,
+                write: true,
+            });
+    }
+
+    /// Binds a sampled image and the sampler to read it with, both binding indices are pushed in that order
+    pub fn sample_image(&mut self, image: ImageHandle, sampler: SamplerHandle) {
+        self.read_sampled_image(image);
+        self.read_sampler(sampler);
+    }
+
+    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) -> PassHandle {
+        render_graph_builder.add_compute_pass_with_push_constants(
             self.name,
             self.color,
             self.queue,
             self.pipeline,
             self.dispatch,
             &self.resources,
-        );
+            &self.push_constants,
+        )
     }
 }
 
@@ -369,7 +1282,9 @@ pub struct RasterPassBuilder {
     name: String,
     color: [f32; 4],
     framebuffer: Framebuffer,
+    cacheable: bool,
     draw_commands: Vec<RasterDrawCommand>,
+    scissor_stack: Vec<vk::Rect2D>,
 }
 
 impl RasterPassBuilder {
@@ -378,36 +1293,164 @@ impl RasterPassBuilder {
             name: name.to_string(),
             color: [0.0, 1.0, 0.0, 1.0],
             framebuffer: Framebuffer::default(),
+            cacheable: false,
             draw_commands: Vec::new(),
+            scissor_stack: Vec::new(),
         }
     }
 
+    /// Creates one pass per face of a cube image, each rendering into that face's array layer.
+    /// Faces are in layer order +X, -X, +Y, -Y, +Z, -Z
+    pub fn new_cubemap_faces(
+        name: &str,
+        cube_image: ImageHandle,
+        mip_level: u32,
+        target: CubemapTarget,
+    ) -> Vec<Self> {
+        (0..CUBEMAP_FACE_COUNT)
+            .map(|face| {
+                let mut pass = Self::new(&format!("{} Face {}", name, face));
+                let subresource = AttachmentSubresource {
+                    mip_level,
+                    base_layer: face,
+                    ..Default::default()
+                };
+                match target {
+                    CubemapTarget::Color(clear) => {
+                        pass.add_color_attachment_subresource(cube_image, clear, subresource)
+                    }
+                    CubemapTarget::DepthStencil(clear) => pass
+                        .add_depth_stencil_attachment_subresource(cube_image, clear, subresource),
+                }
+                pass
+            })
+            .collect()
+    }
+
+    /// Creates a single multiview pass rendering all faces of a cube image at once,
+    /// the shader should select the face with gl_ViewIndex. Requires multiview support
+    pub fn new_cubemap_multiview(
+        name: &str,
+        cube_image: ImageHandle,
+        mip_level: u32,
+        target: CubemapTarget,
+    ) -> Self {
+        let mut pass = Self::new(name);
+        pass.set_view_mask((1 << CUBEMAP_FACE_COUNT) - 1);
+        let subresource = AttachmentSubresource {
+            mip_level,
+            ..Default::default()
+        };
+        match target {
+            CubemapTarget::Color(clear) => {
+                pass.add_color_attachment_subresource(cube_image, clear, subresource)
+            }
+            CubemapTarget::DepthStencil(clear) => {
+                pass.add_depth_stencil_attachment_subresource(cube_image, clear, subresource)
+            }
+        }
+        pass
+    }
+
+    /// Renders to every array layer set in the mask in a single pass, attachments must have enough layers
+    /// and pipelines used in the pass must be created with the same mask
+    pub fn set_view_mask(&mut self, view_mask: u32) {
+        self.framebuffer.view_mask = view_mask;
+    }
+
+    /// Records the draws into a secondary command buffer that is replayed on later frames,
+    /// it's re-recorded whenever the resolved attachments, buffers, pipelines or draws change.
+    /// The pass is identified across frames by its name, so it must be unique
+    pub fn set_cacheable(&mut self, cacheable: bool) {
+        self.cacheable = cacheable;
+    }
+
     pub fn override_label_color(&mut self, color: [f32; 4]) {
         self.color = color;
     }
 
-    pub fn add_color_attachment(&mut self, image: ImageHandle, clear: Option<[f32; 4]>) {
-        self.framebuffer
-            .color_attachments
-            .push(ColorAttachment { image, clear });
+    pub fn add_color_attachment(&mut self, image: ImageHandle, clear: Option<ClearValue>) {
+        self.add_color_attachment_subresource(image, clear, AttachmentSubresource::default());
+    }
+
+    /// Renders into a single mip level and layer of the image, the render area is that mip's extent
+    pub fn add_color_attachment_subresource(
+        &mut self,
+        image: ImageHandle,
+        clear: Option<ClearValue>,
+        subresource: AttachmentSubresource,
+    ) {
+        self.framebuffer.color_attachments.push(ColorAttachment {
+            image,
+            clear,
+            subresource,
+        });
     }
 
-    pub fn add_depth_stencil_attachment(&mut self, image: ImageHandle, clear: Option<(f32, u32)>) {
-        self.framebuffer.depth_stencil_attachment = Some(DepthStencilAttachment { image, clear });
+    pub fn add_depth_stencil_attachment(&mut self, image: ImageHandle, clear: Option<ClearValue>) {
+        self.add_depth_stencil_attachment_subresource(
+            image,
+            clear,
+            AttachmentSubresource::default(),
+        );
     }
 
-    pub fn add_draw_command(&mut self, draw_command: RasterDrawCommand) {
+    pub fn add_depth_stencil_attachment_subresource(
+        &mut self,
+        image: ImageHandle,
+        clear: Option<ClearValue>,
+        subresource: AttachmentSubresource,
+    ) {
+        self.framebuffer.depth_stencil_attachment = Some(DepthStencilAttachment {
+            image,
+            clear,
+            subresource,
+        });
+    }
+
+    /// Clips draws added until the matching pop_scissor to rect, intersected with any enclosing clip rect
+    pub fn push_scissor(&mut self, rect: vk::Rect2D) {
+        let rect = match self.scissor_stack.last() {
+            Some(parent) => intersect_scissor(*parent, rect),
+            None => rect,
+        };
+        self.scissor_stack.push(rect);
+    }
+
+    /// Restores the enclosing clip rect
+    pub fn pop_scissor(&mut self) {
+        self.scissor_stack.pop();
+    }
+
+    /// Clip rect applied to draws added now, None when the stack is empty
+    pub fn current_scissor(&self) -> Option<vk::Rect2D> {
+        self.scissor_stack.last().copied()
+    }
+
+    fn clip_draw_command(&self, draw_command: &mut RasterDrawCommand) {
+        if let Some(parent) = self.current_scissor() {
+            draw_command.scissor = Some(match draw_command.scissor {
+                Some(scissor) => intersect_scissor(parent, scissor),
+                None => parent,
+            });
+        }
+    }
+
+    pub fn add_draw_command(&mut self, mut draw_command: RasterDrawCommand) {
+        self.clip_draw_command(&mut draw_command);
         self.draw_commands.push(draw_command);
     }
 
-    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) {
+    pub fn build<T: RenderGraphBuilderTrait>(self, render_graph_builder: &mut T) -> PassHandle {
         render_graph_builder.add_raster_pass(
             self.name,
             self.color,
             &self.framebuffer.color_attachments,
             self.framebuffer.depth_stencil_attachment,
+            self.framebuffer.view_mask,
+            self.cacheable,
             &self.draw_commands,
-        );
+        )
     }
 }
 
@@ -416,6 +1459,9 @@ pub struct RasterDrawCommandBuilder {
     pub vertex_buffers: Vec<BufferOffset>,
     pub resources: Vec<ShaderResourceUsage>,
     pub dispatch: Option<DrawCommandDispatch>,
+    pub depth_range: Option<[f32; 2]>,
+    pub scissor: Option<vk::Rect2D>,
+    pub dynamic_state: DynamicStateValues,
 }
 
 impl RasterDrawCommandBuilder {
@@ -425,9 +1471,42 @@ impl RasterDrawCommandBuilder {
             vertex_buffers: Vec::new(),
             resources: Vec::new(),
             dispatch: None,
+            depth_range: None,
+            scissor: None,
+            dynamic_state: DynamicStateValues::default(),
         }
     }
 
+    /// Restricts the viewport depth range for this draw, both values must be within 0..=1
+    pub fn set_depth_range(&mut self, min_depth: f32, max_depth: f32) {
+        self.depth_range = Some([min_depth, max_depth]);
+    }
+
+    /// Clips this draw to rect, intersected with the pass's current scissor
+    pub fn set_scissor(&mut self, rect: vk::Rect2D) {
+        self.scissor = Some(rect);
+    }
+
+    /// The pipeline must declare DynamicState::LineWidth
+    pub fn set_line_width(&mut self, line_width: f32) {
+        self.dynamic_state.line_width = Some(line_width);
+    }
+
+    /// The pipeline must declare DynamicState::BlendConstants
+    pub fn set_blend_constants(&mut self, blend_constants: [f32; 4]) {
+        self.dynamic_state.blend_constants = Some(blend_constants);
+    }
+
+    /// The pipeline must declare DynamicState::DepthBias
+    pub fn set_depth_bias(&mut self, depth_bias: DepthBias) {
+        self.dynamic_state.depth_bias = Some(depth_bias);
+    }
+
+    /// The pipeline must declare DynamicState::StencilReference
+    pub fn set_stencil_reference(&mut self, reference: u32) {
+        self.dynamic_state.stencil_reference = Some(reference);
+    }
+
     pub fn add_vertex_buffer(&mut self, buffer_offset: BufferOffset) {
         self.vertex_buffers.push(buffer_offset);
     }
@@ -467,6 +1546,42 @@ impl RasterDrawCommandBuilder {
         self.resources.push(ShaderResourceUsage::Sampler(sampler));
     }
 
+    /// Binds range bytes of the buffer starting at offset to set 1, see [`ShaderResourceUsage::DynamicBuffer`]
+    pub fn read_dynamic_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources.push(ShaderResourceUsage::DynamicBuffer {
+            buffer,
+            offset,
+            range,
+        });
+    }
+
+    /// Binds range bytes of the buffer starting at offset to set 2, see [`ShaderResourceUsage::DynamicStorageBuffer`]
+    pub fn read_dynamic_storage_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources
+            .push(ShaderResourceUsage::DynamicStorageBuffer {
+                buffer,
+                offset,
+                range,
+                write: false,
+            });
+    }
+
+    pub fn write_dynamic_storage_buffer(&mut self, buffer: BufferHandle, offset: u32, range: u64) {
+        self.resources
+            .push(ShaderResourceUsage::DynamicStorageBuffer {
+                buffer,
+                offset,
+                range,
+                write: true,
+            });
+    }
+
+    /// Binds a sampled image and the sampler to read it with, both binding indices are pushed in that order
+    pub fn sample_image(&mut self, image: ImageHandle, sampler: SamplerHandle) {
+        self.read_sampled_image(image);
+        self.read_sampler(sampler);
+    }
+
     pub fn draw(&mut self, vertices: Range<u32>, instances: Range<u32>) {
         self.dispatch = Some(DrawCommandDispatch::Draw {
             vertices,
@@ -516,14 +1631,195 @@ impl RasterDrawCommandBuilder {
         });
     }
 
-    pub fn build(self, raster_pass_builder: &mut RasterPassBuilder) {
-        raster_pass_builder.draw_commands.push(RasterDrawCommand {
+    pub fn build(
+        self,
+        raster_pass_builder: &mut RasterPassBuilder,
+    ) -> Result<(), RenderGraphError> {
+        let Some(dispatch) = self.dispatch else {
+            return Err(RenderGraphError::MissingDrawDispatch {
+                pass: raster_pass_builder.name.clone(),
+                draw_index: raster_pass_builder.draw_commands.len(),
+            });
+        };
+
+        raster_pass_builder.add_draw_command(RasterDrawCommand {
             pipeline: self.pipeline,
             vertex_buffers: self.vertex_buffers,
             resources: self.resources,
-            dispatch: self
-                .dispatch
-                .expect("No draw command dispatch set for this draw command"),
-        })
+            dispatch,
+            depth_range: self.depth_range,
+            scissor: self.scissor,
+            dynamic_state: self.dynamic_state,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(matrix: &[[f32; 4]; 4], point: [f32; 4]) -> [f32; 4] {
+        let mut result = [0.0; 4];
+        for (column, value) in matrix.iter().zip(point) {
+            for (row, element) in result.iter_mut().enumerate() {
+                *element += column[row] * value;
+            }
+        }
+        result
+    }
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> vk::Rect2D {
+        vk::Rect2D {
+            offset: vk::Offset2D { x, y },
+            extent: vk::Extent2D { width, height },
+        }
+    }
+
+    #[test]
+    fn nested_scissors_intersect_and_pop_restores_the_parent() {
+        let mut raster_pass = RasterPassBuilder::new("ui");
+        assert_eq!(raster_pass.current_scissor(), None);
+
+        raster_pass.push_scissor(rect(0, 0, 100, 100));
+        raster_pass.push_scissor(rect(50, 80, 100, 100));
+        assert_eq!(raster_pass.current_scissor(), Some(rect(50, 80, 50, 20)));
+
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.set_scissor(rect(60, 0, 10, 200));
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        assert_eq!(
+            raster_pass.draw_commands[0].scissor,
+            Some(rect(60, 80, 10, 20))
+        );
+
+        raster_pass.pop_scissor();
+        assert_eq!(raster_pass.current_scissor(), Some(rect(0, 0, 100, 100)));
+        raster_pass.pop_scissor();
+        assert_eq!(raster_pass.current_scissor(), None);
+
+        // Disjoint clip rects leave nothing to draw
+        assert_eq!(
+            intersect_scissor(rect(0, 0, 10, 10), rect(20, 20, 10, 10)).extent,
+            vk::Extent2D::default()
+        );
+    }
+
+    #[test]
+    fn cover_rounds_workgroup_counts_up() {
+        assert_eq!(
+            ComputeDispatch::cover([1920, 1080, 1], [8, 8, 1]),
+            ComputeDispatch::Size([240, 135, 1])
+        );
+        assert_eq!(
+            ComputeDispatch::cover([1921, 1, 1], [8, 8, 1]),
+            ComputeDispatch::Size([241, 1, 1])
+        );
+    }
+
+    #[test]
+    fn over_elements_dispatches_partial_workgroups() {
+        assert_eq!(
+            ComputeDispatch::over_elements(1000, 64),
+            ComputeDispatch::Size([16, 1, 1])
+        );
+        assert_eq!(
+            ComputeDispatch::over_elements(0, 64),
+            ComputeDispatch::Size([0, 1, 1])
+        );
+    }
+
+    #[test]
+    fn cubemap_faces_look_down_their_axis() {
+        let center = [1.0, 2.0, 3.0];
+        let faces = [
+            ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+        ];
+        for (view, (forward, up)) in cubemap_view_matrices(center).iter().zip(faces) {
+            let offset = |direction: [f32; 3]| {
+                [
+                    center[0] + direction[0],
+                    center[1] + direction[1],
+                    center[2] + direction[2],
+                    1.0,
+                ]
+            };
+            assert_eq!(transform(view, offset(forward)), [0.0, 0.0, -1.0, 1.0]);
+            assert_eq!(transform(view, offset(up)), [0.0, 1.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn cubemap_projection_maps_clip_planes_to_depth_range() {
+        let projection = cubemap_projection_matrix(0.5, 10.0);
+        let depth = |distance: f32| {
+            let clip = transform(&projection, [distance, distance, -distance, 1.0]);
+            [clip[0] / clip[3], clip[1] / clip[3], clip[2] / clip[3]]
+        };
+        assert_eq!(depth(0.5), [1.0, 1.0, 0.0]);
+        assert_eq!(depth(10.0), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn clear_values_use_the_union_member_of_the_format() {
+        let uint_clear = ClearValue::Uint([1, 2, 3, 255]).to_vk(vk::Format::R8G8B8A8_UINT);
+        assert_eq!(unsafe { uint_clear.color.uint32 }, [1, 2, 3, 255]);
+        let converted = ClearValue::Float([1.0, 2.0, 3.0, 255.0]).to_vk(vk::Format::R8G8B8A8_UINT);
+        assert_eq!(unsafe { converted.color.uint32 }, [1, 2, 3, 255]);
+
+        let sint_clear = ClearValue::Int([-1, 0, 1, 2]).to_vk(vk::Format::R16G16B16A16_SINT);
+        assert_eq!(unsafe { sint_clear.color.int32 }, [-1, 0, 1, 2]);
+
+        let depth_clear = ClearValue::DepthStencil(0.5, 7).to_vk(vk::Format::D24_UNORM_S8_UINT);
+        let depth_stencil = unsafe { depth_clear.depth_stencil };
+        assert_eq!((depth_stencil.depth, depth_stencil.stencil), (0.5, 7));
+    }
+
+    /// A device without any loaded functions, for passing to code that doesn't call into it
+    fn unloaded_device() -> ash::Device {
+        unsafe extern "system" fn get_device_proc_addr(
+            _device: vk::Device,
+            _name: *const std::ffi::c_char,
+        ) -> vk::PFN_vkVoidFunction {
+            None
+        }
+        let instance_fn = vk::InstanceFnV1_0::load(|name| {
+            if name.to_bytes() == b"vkGetDeviceProcAddr" {
+                get_device_proc_addr as *const std::ffi::c_void
+            } else {
+                std::ptr::null()
+            }
+        });
+        unsafe { ash::Device::load(&instance_fn, vk::Device::null()) }
+    }
+
+    #[test]
+    fn custom_commands_are_called_once_with_the_command_buffer() {
+        let calls = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let command = CustomCommand::new({
+            let calls = calls.clone();
+            move |_device, command_buffer, resources| {
+                calls
+                    .borrow_mut()
+                    .push((command_buffer, resources.buffers.to_vec()));
+            }
+        });
+
+        let device = unloaded_device();
+        let command_buffer = <vk::CommandBuffer as vk::Handle>::from_raw(3);
+        let buffers = [<vk::Buffer as vk::Handle>::from_raw(5)];
+        let resources = CustomPassResources {
+            buffers: &buffers,
+            images: &[],
+        };
+        assert!(command.call(&device, command_buffer, &resources));
+        assert!(!command.call(&device, command_buffer, &resources));
+        assert_eq!(*calls.borrow(), vec![(command_buffer, buffers.to_vec())]);
     }
 }
@@ -1,22 +1,31 @@
 use crate::descriptor_set::GpuBindingIndex;
-use crate::device::{AshDevice, AshQueue};
+use crate::device::{AshDevice, AshQueue, CommandBufferCounts};
+use crate::frame_arena::{FrameArena, ScratchAllocator, ScratchVec};
 use crate::image::vk_format_get_aspect_flags;
 use crate::pipeline::Pipelines;
+use crate::render_graph::BufferIndex;
 use crate::render_graph::{
     BufferBarrierSource, BufferOffset, CommandBuffer, CommandBufferDependency, CompiledRenderGraph,
-    ComputeDispatch, DrawCommandDispatch, Framebuffer, ImageBarrierSource, ImageIndex, IndexType,
-    RasterDrawCommand, RenderPassCommand, ShaderResourceUsage, Transfer,
+    ComputeDispatch, DrawCommandDispatch, ExternalSemaphore, Framebuffer, ImageBarrierSource,
+    ImageGraphResource, ImageIndex, ImageResourceDescription, IndexType, Queue, RasterDrawCommand,
+    RenderPassCommand, ShaderResourceUsage, TimerScope, Transfer,
 };
+use crate::render_graph_builder::{intersect_scissor, CustomPassResources, DynamicStateValues};
 use crate::resource_managers::{
-    BufferResourceAccess, BufferTempResource, ImageTempResource, ResourceManager,
+    BufferResourceAccess, BufferTempResource, ImageResourceAccess, ImageTempResource,
+    ResourceManager,
 };
 use crate::swapchain::{AcquiredSwapchainImage, SwapchainManager};
 use crate::upload_queue::UploadPass;
 use crate::{
-    ComputePipelineHandle, RasterPipelineHandle, Sampler, SamplerHandle, SurfaceHandle, VulkanError,
+    ComputePipelineHandle, DynamicState, RasterPipelineHandle, Sampler, SamplerHandle,
+    SurfaceHandle, VulkanError,
 };
 use ash::vk;
-use log::info;
+use log::{error, info, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
 // Render Graph Executor Evolution
@@ -26,11 +35,49 @@ use std::sync::Arc;
 // 3. Multi-Queue execution
 // 4. Sub resource tracking. Allowing image levels/layers and buffer regions to be transition and accessed in parallel
 
+/// Command buffers allocated from a pool, handed out in order and reused after every reset
+struct CommandBufferList {
+    command_buffers: Vec<vk::CommandBuffer>,
+    next_index: usize,
+    /// Most command buffers used between two resets
+    high_water_mark: usize,
+}
+
+impl CommandBufferList {
+    fn new(command_buffers: Vec<vk::CommandBuffer>) -> Self {
+        Self {
+            command_buffers,
+            next_index: 0,
+            high_water_mark: 0,
+        }
+    }
+
+    /// How many command buffers to allocate before the next one can be handed out, doubling the list when it runs out
+    fn grow_count(&self) -> u32 {
+        if self.next_index >= self.command_buffers.len() {
+            self.command_buffers.len().max(2) as u32
+        } else {
+            0
+        }
+    }
+
+    /// The caller must first allocate grow_count more command buffers
+    fn next(&mut self) -> vk::CommandBuffer {
+        let command_buffer = self.command_buffers[self.next_index];
+        self.next_index += 1;
+        self.high_water_mark = self.high_water_mark.max(self.next_index);
+        command_buffer
+    }
+
+    fn rewind(&mut self) {
+        self.next_index = 0;
+    }
+}
+
 struct AshCommandPool {
     device: Arc<AshDevice>,
     handle: vk::CommandPool,
-    command_buffers: Vec<vk::CommandBuffer>,
-    next_index: usize,
+    command_buffers: CommandBufferList,
 }
 
 impl AshCommandPool {
@@ -43,7 +90,6 @@ impl AshCommandPool {
             device.core.create_command_pool(
                 &vk::CommandPoolCreateInfo::builder()
                     .queue_family_index(queue.family_index)
-                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
                     .build(),
                 None,
             )
@@ -60,30 +106,31 @@ impl AshCommandPool {
         Ok(Self {
             device,
             handle,
-            command_buffers,
-            next_index: 0,
+            command_buffers: CommandBufferList::new(command_buffers),
         })
     }
 
     pub fn get(&mut self) -> ash::prelude::VkResult<vk::CommandBuffer> {
-        if self.next_index >= self.command_buffers.len() {
+        let grow_count = self.command_buffers.grow_count();
+        if grow_count > 0 {
             let mut new_command_buffers = unsafe {
                 self.device.core.allocate_command_buffers(
                     &vk::CommandBufferAllocateInfo::builder()
                         .command_pool(self.handle)
-                        .command_buffer_count(self.command_buffers.len().max(2) as u32),
+                        .command_buffer_count(grow_count),
                 )?
             };
-            self.command_buffers.append(&mut new_command_buffers);
+            self.command_buffers
+                .command_buffers
+                .append(&mut new_command_buffers);
         }
 
-        let command_buffer = self.command_buffers[self.next_index];
-        self.next_index += 1;
-        Ok(command_buffer)
+        Ok(self.command_buffers.next())
     }
 
+    /// Resets the whole pool at once, command buffers are kept allocated and reused
     pub fn reset(&mut self) -> ash::prelude::VkResult<()> {
-        self.next_index = 0;
+        self.command_buffers.rewind();
         unsafe {
             self.device
                 .core
@@ -222,32 +269,384 @@ impl Drop for AshFencePool {
     }
 }
 
+/// Timestamp query pool for one frame context, written at the start and end of every render pass
+struct PassTimestampQueries {
+    device: Arc<AshDevice>,
+    pool: vk::QueryPool,
+    capacity: u32,
+    pass_names: Vec<String>,
+    timer_scopes: Vec<TimerScope>,
+}
+
+impl PassTimestampQueries {
+    fn new(device: Arc<AshDevice>) -> Self {
+        Self {
+            device,
+            pool: vk::QueryPool::null(),
+            capacity: 0,
+            pass_names: Vec::new(),
+            timer_scopes: Vec::new(),
+        }
+    }
+
+    /// Makes room for pass_count passes and resets the queries, must be called before any pass is recorded.
+    /// Timer scopes are timed from the start of their first pass to the end of their last, needing no extra queries
+    fn begin_frame(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        pass_count: usize,
+        timer_scopes: &[TimerScope],
+    ) -> ash::prelude::VkResult<()> {
+        let query_count = (pass_count * 2) as u32;
+        if query_count > self.capacity {
+            unsafe {
+                self.device.core.destroy_query_pool(self.pool, None);
+                self.pool = vk::QueryPool::null();
+                self.capacity = 0;
+                self.pool = self.device.core.create_query_pool(
+                    &vk::QueryPoolCreateInfo::builder()
+                        .query_type(vk::QueryType::TIMESTAMP)
+                        .query_count(query_count),
+                    None,
+                )?;
+            }
+            self.capacity = query_count;
+        }
+
+        self.pass_names.clear();
+        self.timer_scopes = timer_scopes.to_vec();
+        if query_count != 0 {
+            unsafe {
+                self.device
+                    .core
+                    .cmd_reset_query_pool(command_buffer, self.pool, 0, query_count);
+            }
+        }
+        Ok(())
+    }
+
+    fn begin_pass(&mut self, command_buffer: vk::CommandBuffer, name: &str) {
+        let query = self.pass_names.len() as u32 * 2;
+        self.pass_names.push(name.to_string());
+        unsafe {
+            self.device.core.cmd_write_timestamp2(
+                command_buffer,
+                vk::PipelineStageFlags2::TOP_OF_PIPE,
+                self.pool,
+                query,
+            );
+        }
+    }
+
+    fn end_pass(&mut self, command_buffer: vk::CommandBuffer) {
+        let query = self.pass_names.len() as u32 * 2 - 1;
+        unsafe {
+            self.device.core.cmd_write_timestamp2(
+                command_buffer,
+                vk::PipelineStageFlags2::BOTTOM_OF_PIPE,
+                self.pool,
+                query,
+            );
+        }
+    }
+
+    /// Reads back the timings written by the last frame, the frame's fences must have already signaled
+    fn read_timings(
+        &mut self,
+        frame_id: u64,
+        timestamp_period_ns: f32,
+    ) -> ash::prelude::VkResult<Option<FrameTimings>> {
+        if self.pass_names.is_empty() {
+            return Ok(None);
+        }
+
+        let mut timestamps = vec![0u64; self.pass_names.len() * 2];
+        unsafe {
+            self.device.core.get_query_pool_results(
+                self.pool,
+                0,
+                timestamps.len() as u32,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )?;
+        }
+
+        Ok(Some(FrameTimings::from_timestamps(
+            frame_id,
+            std::mem::take(&mut self.pass_names),
+            std::mem::take(&mut self.timer_scopes),
+            &timestamps,
+            timestamp_period_ns,
+        )))
+    }
+}
+
+impl Drop for PassTimestampQueries {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.core.destroy_query_pool(self.pool, None);
+        }
+    }
+}
+
+struct CachedRasterPass {
+    command_buffer: vk::CommandBuffer,
+    signature: u64,
+    last_used_frame: u64,
+}
+
+/// Which cached command buffers are live or retired for each frame, kept apart from the pool that allocates them
+struct CachedRasterPasses {
+    frames_in_flight: u64,
+    frame: u64,
+    passes: HashMap<String, CachedRasterPass>,
+    retired: Vec<(vk::CommandBuffer, u64)>,
+}
+
+impl CachedRasterPasses {
+    fn new(frames_in_flight: u64) -> Self {
+        Self {
+            frames_in_flight,
+            frame: 0,
+            passes: HashMap::new(),
+            retired: Vec::new(),
+        }
+    }
+
+    /// Retires passes that weren't used last frame, returning the retired command buffers no in-flight frame uses
+    fn begin_frame(&mut self) -> Vec<vk::CommandBuffer> {
+        self.frame += 1;
+        let frame = self.frame;
+
+        let retired = &mut self.retired;
+        self.passes.retain(|_, pass| {
+            let keep = pass.last_used_frame + 1 >= frame;
+            if !keep {
+                retired.push((pass.command_buffer, pass.last_used_frame));
+            }
+            keep
+        });
+
+        let frames_in_flight = self.frames_in_flight;
+        let (free, keep): (Vec<_>, Vec<_>) = self
+            .retired
+            .drain(..)
+            .partition(|(_, last_used_frame)| last_used_frame + frames_in_flight <= frame);
+        self.retired = keep;
+        free.into_iter()
+            .map(|(command_buffer, _)| command_buffer)
+            .collect()
+    }
+
+    /// Returns the cached command buffer if it was recorded with the same signature
+    fn get(&mut self, name: &str, signature: u64) -> Option<vk::CommandBuffer> {
+        let frame = self.frame;
+        let pass = self.passes.get_mut(name)?;
+        if pass.signature != signature {
+            return None;
+        }
+        pass.last_used_frame = frame;
+        Some(pass.command_buffer)
+    }
+
+    /// Caches a newly recorded command buffer for the pass, retiring any stale one
+    fn insert(&mut self, name: &str, signature: u64, command_buffer: vk::CommandBuffer) {
+        if let Some(old_pass) = self.passes.insert(
+            name.to_string(),
+            CachedRasterPass {
+                command_buffer,
+                signature,
+                last_used_frame: self.frame,
+            },
+        ) {
+            self.retired
+                .push((old_pass.command_buffer, old_pass.last_used_frame));
+        }
+    }
+}
+
+/// Pipeline layout the bindless set was last bound with for each bind point,
+/// the set is only rebound when a pass uses a different layout than the one bound
+struct BoundDescriptorSets {
+    set: vk::DescriptorSet,
+    compute_layout: Option<vk::PipelineLayout>,
+    graphics_layout: Option<vk::PipelineLayout>,
+}
+
+impl BoundDescriptorSets {
+    fn new(set: vk::DescriptorSet) -> Self {
+        Self {
+            set,
+            compute_layout: None,
+            graphics_layout: None,
+        }
+    }
+
+    /// Forgets what is bound, so the next bind for every bind point is recorded
+    fn reset(&mut self) {
+        self.compute_layout = None;
+        self.graphics_layout = None;
+    }
+
+    /// Marks `layout` as bound for `bind_point`, returns true if a bind needs to be recorded
+    fn mark_bound(
+        &mut self,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+    ) -> bool {
+        let bound_layout = match bind_point {
+            vk::PipelineBindPoint::COMPUTE => &mut self.compute_layout,
+            vk::PipelineBindPoint::GRAPHICS => &mut self.graphics_layout,
+            _ => unreachable!("Unsupported bind point {:?}", bind_point),
+        };
+
+        let needs_bind = *bound_layout != Some(layout);
+        *bound_layout = Some(layout);
+        needs_bind
+    }
+
+    fn bind(
+        &mut self,
+        device: &AshDevice,
+        command_buffer: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+    ) {
+        if self.mark_bound(bind_point, layout) {
+            unsafe {
+                device.core.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    bind_point,
+                    layout,
+                    0,
+                    &[self.set],
+                    &[],
+                );
+            }
+        }
+    }
+}
+
+/// Secondary command buffers for cacheable raster passes, keyed by pass name.
+/// Buffers that may still be in use by an in-flight frame are retired and only freed once that frame has finished
+struct SecondaryCommandCache {
+    device: Arc<AshDevice>,
+    pool: vk::CommandPool,
+    passes: CachedRasterPasses,
+}
+
+impl SecondaryCommandCache {
+    fn new(device: Arc<AshDevice>, frames_in_flight: u64) -> ash::prelude::VkResult<Self> {
+        let pool = unsafe {
+            device.core.create_command_pool(
+                &vk::CommandPoolCreateInfo::builder()
+                    .queue_family_index(
+                        device
+                            .graphics_queue
+                            .expect("Requires a graphics queue")
+                            .family_index,
+                    )
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                    .build(),
+                None,
+            )
+        }?;
+
+        Ok(Self {
+            device,
+            pool,
+            passes: CachedRasterPasses::new(frames_in_flight),
+        })
+    }
+
+    /// Called once the frame context has been waited on, retires passes that weren't used last frame
+    fn begin_frame(&mut self) {
+        let command_buffers = self.passes.begin_frame();
+        if !command_buffers.is_empty() {
+            unsafe {
+                self.device
+                    .core
+                    .free_command_buffers(self.pool, &command_buffers)
+            };
+        }
+    }
+
+    /// Returns the cached command buffer if it was recorded with the same signature
+    fn get(&mut self, name: &str, signature: u64) -> Option<vk::CommandBuffer> {
+        self.passes.get(name, signature)
+    }
+
+    /// Allocates a new command buffer for the pass, retiring any stale one
+    fn allocate(
+        &mut self,
+        name: &str,
+        signature: u64,
+    ) -> ash::prelude::VkResult<vk::CommandBuffer> {
+        let command_buffer = unsafe {
+            self.device.core.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(self.pool)
+                    .level(vk::CommandBufferLevel::SECONDARY)
+                    .command_buffer_count(1),
+            )?
+        }[0];
+
+        self.passes.insert(name, signature, command_buffer);
+        Ok(command_buffer)
+    }
+}
+
+impl Drop for SecondaryCommandCache {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.core.destroy_command_pool(self.pool, None);
+        }
+    }
+}
+
 struct FrameContext {
     graphics_command_pool: AshCommandPool,
     async_compute_command_pool: Option<AshCommandPool>,
     async_transfer_command_pool: Option<AshCommandPool>,
     semaphore_pool: AshSemaphorePool,
     fence_pool: AshFencePool,
+    timestamp_queries: PassTimestampQueries,
+
+    /// Id of the last frame submitted with this context
+    frame_id: Option<u64>,
 }
 
 impl FrameContext {
-    pub fn new(device: Arc<AshDevice>) -> ash::prelude::VkResult<Self> {
+    pub fn new(
+        device: Arc<AshDevice>,
+        command_buffer_capacity: CommandBufferCounts,
+    ) -> ash::prelude::VkResult<Self> {
         Ok(Self {
             graphics_command_pool: AshCommandPool::new(
                 device.clone(),
                 device.graphics_queue.expect("Requires a graphics queue"),
-                8,
+                command_buffer_capacity.graphics,
             )?,
             async_compute_command_pool: match device.compute_queue {
                 None => None,
-                Some(queue) => Some(AshCommandPool::new(device.clone(), queue, 4)?),
+                Some(queue) => Some(AshCommandPool::new(
+                    device.clone(),
+                    queue,
+                    command_buffer_capacity.async_compute,
+                )?),
             },
             async_transfer_command_pool: match device.transfer_queue {
                 None => None,
-                Some(queue) => Some(AshCommandPool::new(device.clone(), queue, 4)?),
+                Some(queue) => Some(AshCommandPool::new(
+                    device.clone(),
+                    queue,
+                    command_buffer_capacity.async_transfer,
+                )?),
             },
             semaphore_pool: AshSemaphorePool::new(device.clone()),
-            fence_pool: AshFencePool::new(device),
+            fence_pool: AshFencePool::new(device.clone()),
+            timestamp_queries: PassTimestampQueries::new(device),
+            frame_id: None,
         })
     }
 
@@ -273,18 +672,67 @@ pub struct RenderGraphExecutor {
     device: Arc<AshDevice>,
     frame_contexts: Vec<FrameContext>,
     frame_index: usize,
+    submitted_frames: u64,
+    secondary_command_cache: SecondaryCommandCache,
+
+    /// Scratch memory for per-frame collections, shared with the device's render graph builders
+    arena: FrameArena,
+
+    /// None when the graphics queue doesn't support timestamps
+    timestamp_period_ns: Option<f32>,
+    pass_timings_enabled: bool,
+    last_frame_timings: Option<FrameTimings>,
+    descriptor_bind_frequency: DescriptorBindFrequency,
+
+    #[cfg(feature = "track-access-history")]
+    access_history: AccessHistory,
 }
 
 impl RenderGraphExecutor {
-    pub fn new(device: Arc<AshDevice>, frame_in_flight_count: u32) -> ash::prelude::VkResult<Self> {
+    pub fn new(
+        device: Arc<AshDevice>,
+        frame_in_flight_count: u32,
+        command_buffer_capacity: CommandBufferCounts,
+        arena: FrameArena,
+    ) -> ash::prelude::VkResult<Self> {
         let mut frame_contexts = Vec::with_capacity(frame_in_flight_count as usize);
         for _ in 0..frame_contexts.capacity() {
-            frame_contexts.push(FrameContext::new(device.clone())?)
+            frame_contexts.push(FrameContext::new(device.clone(), command_buffer_capacity)?)
         }
+
+        let timestamp_period_ns = unsafe {
+            let instance = &device.instance.core;
+            let graphics_family = device
+                .graphics_queue
+                .expect("Requires a graphics queue")
+                .family_index;
+            let timestamp_valid_bits = instance
+                .get_physical_device_queue_family_properties(device.physical)
+                [graphics_family as usize]
+                .timestamp_valid_bits;
+            let limits = instance
+                .get_physical_device_properties(device.physical)
+                .limits;
+            (timestamp_valid_bits != 0 && limits.timestamp_compute_and_graphics == vk::TRUE)
+                .then_some(limits.timestamp_period)
+        };
+
         Ok(Self {
+            secondary_command_cache: SecondaryCommandCache::new(
+                device.clone(),
+                frame_in_flight_count as u64,
+            )?,
             device,
             frame_contexts,
             frame_index: 0,
+            submitted_frames: 0,
+            arena,
+            timestamp_period_ns,
+            pass_timings_enabled: false,
+            last_frame_timings: None,
+            descriptor_bind_frequency: DescriptorBindFrequency::default(),
+            #[cfg(feature = "track-access-history")]
+            access_history: AccessHistory::default(),
         })
     }
 
@@ -295,14 +743,37 @@ impl RenderGraphExecutor {
         pipelines: &Pipelines,
         upload_pass: Option<UploadPass>,
         render_graph: &CompiledRenderGraph,
-    ) -> Result<(), VulkanError> {
+    ) -> Result<FrameResult, VulkanError> {
         const TIMEOUT_NS: u64 = std::time::Duration::from_secs(2).as_nanos() as u64;
+
+        // Checked before anything is acquired or submitted so a mismatch doesn't leave a swapchain image acquired
+        validate_attachment_formats(resource_manager, swapchain_manager, pipelines, render_graph)?;
+
         self.frame_index = (self.frame_index + 1) % self.frame_contexts.len();
 
         let frame_context = &mut self.frame_contexts[self.frame_index];
 
         frame_context.wait_and_reset(TIMEOUT_NS)?;
+        if let (Some(frame_id), Some(timestamp_period_ns)) =
+            (frame_context.frame_id, self.timestamp_period_ns)
+        {
+            if let Some(frame_timings) = frame_context
+                .timestamp_queries
+                .read_timings(frame_id, timestamp_period_ns)?
+            {
+                if self.pass_timings_enabled {
+                    self.last_frame_timings = Some(frame_timings);
+                }
+            }
+        }
+        frame_context.frame_id = Some(self.submitted_frames);
+        self.submitted_frames += 1;
+        self.secondary_command_cache.begin_frame();
         resource_manager.flush_frame();
+        // Scratch collections are only alive while the frame is recorded and submitted
+        self.arena.reset();
+        let arena_guard = self.arena.lock();
+        let arena = arena_guard.allocator();
 
         //Upload Pass
         if let Some(upload_pass) = upload_pass {
@@ -328,9 +799,19 @@ impl RenderGraphExecutor {
 
             record_command_buffer(
                 &self.device,
+                arena,
+                &mut self.secondary_command_cache,
+                None,
+                self.descriptor_bind_frequency,
                 upload_command_buffer,
                 &upload_pass.command_buffer,
                 &mut resources,
+            )?;
+            record_rest_layout_transitions(
+                &self.device,
+                upload_command_buffer,
+                &upload_pass.image_resources,
+                &images,
             );
 
             unsafe {
@@ -339,13 +820,21 @@ impl RenderGraphExecutor {
                 let command_buffer_info = vk::CommandBufferSubmitInfo::builder()
                     .command_buffer(upload_command_buffer)
                     .build();
-                self.device.core.queue_submit2(
-                    self.device.graphics_queue.unwrap().handle,
+                let upload_queue = self.device.graphics_queue.unwrap().handle;
+                if let Some(debug_util) = &self.device.instance.debug_utils {
+                    debug_util.queue_begin_label(upload_queue, "upload", [1.0, 0.0, 1.0, 1.0]);
+                }
+                let result = self.device.core.queue_submit2(
+                    upload_queue,
                     &[vk::SubmitInfo2::builder()
                         .command_buffer_infos(&[command_buffer_info])
                         .build()],
                     vk::Fence::null(),
-                )?;
+                );
+                if let Some(debug_util) = &self.device.instance.debug_utils {
+                    debug_util.queue_end_label(upload_queue);
+                }
+                result?;
             }
         }
 
@@ -354,11 +843,8 @@ impl RenderGraphExecutor {
             &render_graph.command_buffers,
         )?;
 
-        let acquired_swapchains = acquire_swapchain_images(
-            &mut frame_context.semaphore_pool,
-            swapchain_manager,
-            &render_graph.swapchain_images,
-        )?;
+        let acquired_swapchains =
+            acquire_swapchain_images(swapchain_manager, &render_graph.swapchain_images)?;
         let acquired_swapchain_images: Vec<AcquiredSwapchainImage> = acquired_swapchains
             .iter()
             .map(|swapchain| swapchain.image.clone())
@@ -375,28 +861,30 @@ impl RenderGraphExecutor {
         )?;
 
         // Write data to buffers
-        let mut staging_buffer_copies: Vec<(BufferOffset, usize, usize)> = Vec::new();
+        let mut staging_buffer_copies: ScratchVec<(BufferOffset, usize, usize)> =
+            ScratchVec::new_in(arena);
         for buffer_write in render_graph.buffer_writes.buffer_writes.iter() {
-            if let Some(mapped_slice) = &mut buffers[buffer_write.buffer_offset.buffer]
+            if let Some(mapped_slice) = buffers[buffer_write.buffer_offset.buffer]
                 .mapped_slice
                 .as_mut()
-                .map(|mapped_slice| mapped_slice.slice_mut())
             {
                 // If buffer is mapped write directly
                 let write_start = buffer_write.buffer_offset.offset as usize;
                 let write_end = write_start + buffer_write.write_size;
                 buffer_write
                     .callback
-                    .call(&mut mapped_slice[write_start..write_end]);
+                    .call(&mut mapped_slice.slice_mut()[write_start..write_end]);
+                mapped_slice.flush(&self.device, write_start..write_end)?;
             } else {
                 // Else write to staging buffer and copy to final buffer
                 let staging_buffer = staging_buffer.as_mut().unwrap();
-                let mapped_slice = staging_buffer.mapped_slice.as_mut().unwrap().slice_mut();
+                let mapped_slice = staging_buffer.mapped_slice.as_mut().unwrap();
                 let write_start = staging_buffer_offset;
                 let write_end = write_start + buffer_write.write_size;
                 buffer_write
                     .callback
-                    .call(&mut mapped_slice[write_start..write_end]);
+                    .call(&mut mapped_slice.slice_mut()[write_start..write_end]);
+                mapped_slice.flush(&self.device, write_start..write_end)?;
                 staging_buffer_copies.push((
                     buffer_write.buffer_offset,
                     buffer_write.write_size,
@@ -413,6 +901,10 @@ impl RenderGraphExecutor {
 
         let submit_queue = self.device.graphics_queue.unwrap().handle;
 
+        let mut timestamp_queries = (self.pass_timings_enabled
+            && self.timestamp_period_ns.is_some())
+        .then_some(&mut frame_context.timestamp_queries);
+
         for (command_buffer_index, graph_command_buffer) in
             render_graph.command_buffers.iter().enumerate()
         {
@@ -430,6 +922,22 @@ impl RenderGraphExecutor {
                     &vk::CommandBufferBeginInfo::builder(),
                 )?;
 
+                if is_first_command_buffer {
+                    if let Some(timestamp_queries) = &mut timestamp_queries {
+                        let pass_count = render_graph
+                            .command_buffers
+                            .iter()
+                            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+                            .map(|render_pass_set| render_pass_set.render_passes.len())
+                            .sum();
+                        timestamp_queries.begin_frame(
+                            vulkan_command_buffer,
+                            pass_count,
+                            &render_graph.timer_scopes,
+                        )?;
+                    }
+                }
+
                 //TODO: Properly schedule and barrier staging uploads
                 //TODO: Make a separate command_buffer?
                 if is_first_command_buffer {
@@ -479,25 +987,6 @@ impl RenderGraphExecutor {
                     }
                 }
 
-                //Bind descriptor set
-                {
-                    let pipeline_bind_points = [
-                        vk::PipelineBindPoint::COMPUTE,
-                        vk::PipelineBindPoint::GRAPHICS,
-                    ];
-                    let set = resource_manager.descriptor_set.get_set();
-                    for pipeline_bind_point in pipeline_bind_points {
-                        self.device.core.cmd_bind_descriptor_sets(
-                            vulkan_command_buffer,
-                            pipeline_bind_point,
-                            pipelines.layout,
-                            0,
-                            &[set],
-                            &[],
-                        );
-                    }
-                }
-
                 if let Some(debug_util) = &self.device.instance.debug_utils {
                     debug_util.cmd_begin_label(
                         vulkan_command_buffer,
@@ -516,10 +1005,14 @@ impl RenderGraphExecutor {
                 };
                 record_command_buffer(
                     &self.device,
+                    arena,
+                    &mut self.secondary_command_cache,
+                    timestamp_queries.as_deref_mut(),
+                    self.descriptor_bind_frequency,
                     vulkan_command_buffer,
                     graph_command_buffer,
                     &mut resources,
-                );
+                )?;
 
                 //TODO: release resource ownership
 
@@ -536,30 +1029,31 @@ impl RenderGraphExecutor {
                         layer_count: 1,
                     };
 
-                let swapchain_transitions: Vec<vk::ImageMemoryBarrier2> = graph_command_buffer
-                    .command_buffer_signal_dependencies
-                    .iter()
-                    .filter_map(|signal_dependency| match signal_dependency {
-                        CommandBufferDependency::Swapchain { index, access } => {
-                            Some((index, access))
-                        }
-                        _ => None,
-                    })
-                    .map(|(&swapchain_index, access)| {
-                        let src = access.get_barrier_flags(true);
-                        //Get last swapchain usages
-                        vk::ImageMemoryBarrier2::builder()
-                            .image(acquired_swapchain_images[swapchain_index].image.handle)
-                            .old_layout(src.layout)
-                            .src_stage_mask(src.stage_mask)
-                            .src_access_mask(src.access_mask)
-                            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                            .dst_stage_mask(vk::PipelineStageFlags2::NONE)
-                            .dst_access_mask(vk::AccessFlags2::NONE)
-                            .subresource_range(SWAPCHAIN_SUBRESOURCE_RANGE)
-                            .build()
-                    })
-                    .collect();
+                let swapchain_transitions = arena.collect(
+                    graph_command_buffer
+                        .command_buffer_signal_dependencies
+                        .iter()
+                        .filter_map(|signal_dependency| match signal_dependency {
+                            CommandBufferDependency::Swapchain { index, access } => {
+                                Some((index, access))
+                            }
+                            _ => None,
+                        })
+                        .map(|(&swapchain_index, access)| {
+                            let src = access.get_barrier_flags(true);
+                            //Get last swapchain usages
+                            vk::ImageMemoryBarrier2::builder()
+                                .image(acquired_swapchain_images[swapchain_index].image.handle)
+                                .old_layout(src.layout)
+                                .src_stage_mask(src.stage_mask)
+                                .src_access_mask(src.access_mask)
+                                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                                .dst_stage_mask(vk::PipelineStageFlags2::NONE)
+                                .dst_access_mask(vk::AccessFlags2::NONE)
+                                .subresource_range(SWAPCHAIN_SUBRESOURCE_RANGE)
+                                .build()
+                        }),
+                );
 
                 if !swapchain_transitions.is_empty() {
                     self.device.core.cmd_pipeline_barrier2(
@@ -570,6 +1064,15 @@ impl RenderGraphExecutor {
                     );
                 }
 
+                if is_last_command_buffer {
+                    record_rest_layout_transitions(
+                        &self.device,
+                        vulkan_command_buffer,
+                        &render_graph.image_resources,
+                        &images,
+                    );
+                }
+
                 //TODO: Properly schedule and barrier staging downloads
                 //TODO: Make a separate command_buffer?
                 if is_last_command_buffer {
@@ -633,59 +1136,79 @@ impl RenderGraphExecutor {
                     .command_buffer(vulkan_command_buffer)
                     .build()];
 
-                let wait_semaphore_infos: Vec<vk::SemaphoreSubmitInfo> = graph_command_buffer
-                    .command_buffer_wait_dependencies
-                    .iter()
-                    .map(|dependency| match dependency {
-                        CommandBufferDependency::CommandBuffer {
-                            command_buffer_index,
-                            dependency_index,
-                            stage_mask,
-                            ..
-                        } => vk::SemaphoreSubmitInfo::builder()
-                            .semaphore(
-                                command_buffer_dependency_semaphores[*command_buffer_index]
-                                    [*dependency_index],
-                            )
-                            .stage_mask(*stage_mask)
-                            .build(),
-                        CommandBufferDependency::Swapchain { index, access } => {
-                            vk::SemaphoreSubmitInfo::builder()
-                                .semaphore(acquired_swapchains[*index].image_ready_semaphore)
-                                .stage_mask(access.get_barrier_flags(true).stage_mask)
-                                .build()
-                        }
-                    })
-                    .collect();
-
-                let mut command_buffer_dependency: u32 = 0;
-                let signal_semaphore_infos: Vec<vk::SemaphoreSubmitInfo> = graph_command_buffer
-                    .command_buffer_signal_dependencies
-                    .iter()
-                    .map(|dependency| match dependency {
-                        CommandBufferDependency::CommandBuffer {
-                            command_buffer_index,
-                            dependency_index,
-                            stage_mask,
-                            ..
-                        } => {
-                            command_buffer_dependency += 1;
-                            vk::SemaphoreSubmitInfo::builder()
+                let mut wait_semaphore_infos = arena.collect(
+                    graph_command_buffer
+                        .command_buffer_wait_dependencies
+                        .iter()
+                        .map(|dependency| match dependency {
+                            CommandBufferDependency::CommandBuffer {
+                                command_buffer_index,
+                                dependency_index,
+                                stage_mask,
+                                ..
+                            } => vk::SemaphoreSubmitInfo::builder()
                                 .semaphore(
                                     command_buffer_dependency_semaphores[*command_buffer_index]
                                         [*dependency_index],
                                 )
                                 .stage_mask(*stage_mask)
-                                .build()
-                        }
-                        CommandBufferDependency::Swapchain { index, access } => {
-                            vk::SemaphoreSubmitInfo::builder()
-                                .semaphore(acquired_swapchains[*index].present_ready_semaphore)
-                                .stage_mask(access.get_barrier_flags(true).stage_mask)
-                                .build()
-                        }
-                    })
-                    .collect();
+                                .build(),
+                            CommandBufferDependency::Swapchain { index, access } => {
+                                vk::SemaphoreSubmitInfo::builder()
+                                    .semaphore(acquired_swapchains[*index].image_ready_semaphore)
+                                    .stage_mask(access.get_barrier_flags(true).stage_mask)
+                                    .build()
+                            }
+                        }),
+                );
+
+                if is_first_command_buffer {
+                    wait_semaphore_infos.extend(
+                        render_graph
+                            .external_wait_semaphores
+                            .iter()
+                            .map(ExternalSemaphore::submit_info),
+                    );
+                }
+
+                let mut command_buffer_dependency: u32 = 0;
+                let mut signal_semaphore_infos = arena.collect(
+                    graph_command_buffer
+                        .command_buffer_signal_dependencies
+                        .iter()
+                        .map(|dependency| match dependency {
+                            CommandBufferDependency::CommandBuffer {
+                                command_buffer_index,
+                                dependency_index,
+                                stage_mask,
+                                ..
+                            } => {
+                                command_buffer_dependency += 1;
+                                vk::SemaphoreSubmitInfo::builder()
+                                    .semaphore(
+                                        command_buffer_dependency_semaphores[*command_buffer_index]
+                                            [*dependency_index],
+                                    )
+                                    .stage_mask(*stage_mask)
+                                    .build()
+                            }
+                            CommandBufferDependency::Swapchain { index, access } => {
+                                vk::SemaphoreSubmitInfo::builder()
+                                    .semaphore(acquired_swapchains[*index].present_ready_semaphore)
+                                    .stage_mask(access.get_barrier_flags(true).stage_mask)
+                                    .build()
+                            }
+                        }),
+                );
+
+                if is_last_command_buffer {
+                    signal_semaphore_infos.extend(
+                        render_graph
+                            .external_signal_semaphores
+                            .iter()
+                            .map(ExternalSemaphore::submit_info),
+                    );
+                }
 
                 // If the command buffer has no signal dependencies on other command buffers, that means it is a root node and should use a fence instead
                 let command_buffer_done_fence = if command_buffer_dependency == 0 {
@@ -694,7 +1217,15 @@ impl RenderGraphExecutor {
                     vk::Fence::null()
                 };
 
-                self.device.core.queue_submit2(
+                // Labels the submission so profilers can tell batches apart on the queue timeline
+                if let Some(debug_util) = &self.device.instance.debug_utils {
+                    debug_util.queue_begin_label(
+                        submit_queue,
+                        &submission_label(graph_command_buffer.queue, command_buffer_index),
+                        [1.0; 4],
+                    );
+                }
+                let result = self.device.core.queue_submit2(
                     submit_queue,
                     &[vk::SubmitInfo2::builder()
                         .command_buffer_infos(&command_buffer_info)
@@ -702,35 +1233,219 @@ impl RenderGraphExecutor {
                         .signal_semaphore_infos(&signal_semaphore_infos)
                         .build()],
                     command_buffer_done_fence,
-                )?;
-            }
+                );
+                if let Some(debug_util) = &self.device.instance.debug_utils {
+                    debug_util.queue_end_label(submit_queue);
+                }
+                result?;
+            }
+        }
+
+        #[cfg(feature = "track-access-history")]
+        {
+            self.access_history = AccessHistory::new(render_graph);
+        }
+
+        let mut frame_result = FrameResult::default();
+        for acquired_swapchain in acquired_swapchains.iter() {
+            if acquired_swapchain.rebuilt {
+                frame_result
+                    .resized_surfaces
+                    .push(acquired_swapchain.surface);
+            }
         }
 
         //Submit Swapchains
         if !acquired_swapchains.is_empty() {
-            let mut swapchains = Vec::with_capacity(acquired_swapchains.len());
-            let mut swapchain_indies = Vec::with_capacity(acquired_swapchains.len());
-            let mut wait_semaphores = Vec::with_capacity(acquired_swapchains.len());
+            let mut swapchains = ScratchVec::with_capacity_in(acquired_swapchains.len(), arena);
+            let mut swapchain_indies =
+                ScratchVec::with_capacity_in(acquired_swapchains.len(), arena);
+            let mut wait_semaphores =
+                ScratchVec::with_capacity_in(acquired_swapchains.len(), arena);
             for acquired_swapchain in acquired_swapchains.iter() {
                 swapchains.push(acquired_swapchain.image.swapchain_handle);
                 swapchain_indies.push(acquired_swapchain.image.image_index);
                 wait_semaphores.push(acquired_swapchain.present_ready_semaphore);
             }
+            let mut present_results = arena.collect(std::iter::repeat_n(
+                vk::Result::SUCCESS,
+                acquired_swapchains.len(),
+            ));
+            let present_queue = self
+                .device
+                .present_queue
+                .map(|queue| queue.handle)
+                .unwrap_or(submit_queue);
             unsafe {
                 let _ = self.device.swapchain.queue_present(
-                    submit_queue,
+                    present_queue,
                     &vk::PresentInfoKHR::builder()
                         .swapchains(&swapchains)
                         .image_indices(&swapchain_indies)
-                        .wait_semaphores(&wait_semaphores),
+                        .wait_semaphores(&wait_semaphores)
+                        .results(&mut present_results),
                 );
             }
+
+            // Out of date swapchains get rebuilt on their next acquire
+            for (acquired_swapchain, present_result) in
+                acquired_swapchains.iter().zip(present_results.iter())
+            {
+                if acquired_swapchain.suboptimal
+                    || *present_result == vk::Result::SUBOPTIMAL_KHR
+                    || *present_result == vk::Result::ERROR_OUT_OF_DATE_KHR
+                {
+                    frame_result
+                        .suboptimal_surfaces
+                        .push(acquired_swapchain.surface);
+                }
+            }
         }
 
-        Ok(())
+        Ok(frame_result)
     }
 }
 
+impl RenderGraphExecutor {
+    /// Most command buffers any frame context has needed per queue since creation
+    pub(crate) fn command_buffer_high_water_mark(&self) -> CommandBufferCounts {
+        let high_water_mark = |pool: Option<&AshCommandPool>| {
+            pool.map(|pool| pool.command_buffers.high_water_mark as u32)
+                .unwrap_or_default()
+        };
+        self.frame_contexts.iter().fold(
+            CommandBufferCounts {
+                graphics: 0,
+                async_compute: 0,
+                async_transfer: 0,
+            },
+            |counts, frame_context| CommandBufferCounts {
+                graphics: counts
+                    .graphics
+                    .max(high_water_mark(Some(&frame_context.graphics_command_pool))),
+                async_compute: counts.async_compute.max(high_water_mark(
+                    frame_context.async_compute_command_pool.as_ref(),
+                )),
+                async_transfer: counts.async_transfer.max(high_water_mark(
+                    frame_context.async_transfer_command_pool.as_ref(),
+                )),
+            },
+        )
+    }
+
+    pub(crate) fn frames_in_flight(&self) -> u64 {
+        self.frame_contexts.len() as u64
+    }
+
+    /// Returns false if the graphics queue can't write timestamps
+    pub(crate) fn set_pass_timings_enabled(&mut self, enabled: bool) -> bool {
+        self.pass_timings_enabled = enabled && self.timestamp_period_ns.is_some();
+        if !self.pass_timings_enabled {
+            self.last_frame_timings = None;
+        }
+        self.timestamp_period_ns.is_some() || !enabled
+    }
+
+    pub(crate) fn last_frame_timings(&self) -> Option<&FrameTimings> {
+        self.last_frame_timings.as_ref()
+    }
+
+    pub(crate) fn set_descriptor_bind_frequency(&mut self, frequency: DescriptorBindFrequency) {
+        self.descriptor_bind_frequency = frequency;
+    }
+
+    /// Id of the most recently submitted frame, ids start at 0 and increase by one per submit
+    pub(crate) fn last_submitted_frame(&self) -> Option<u64> {
+        self.submitted_frames.checked_sub(1)
+    }
+
+    /// Blocks until the gpu work of a previously submitted frame has finished
+    pub(crate) fn wait_for_frame(&self, frame_id: u64, timeout_ns: u64) -> Result<(), VulkanError> {
+        let context_frame_ids = self
+            .frame_contexts
+            .iter()
+            .map(|frame_context| frame_context.frame_id);
+        match pending_frame_context(context_frame_ids, self.submitted_frames, frame_id)? {
+            Some(index) => Ok(self.frame_contexts[index]
+                .fence_pool
+                .wait_for_all(timeout_ns)?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Index of the frame context that still has to be waited on for frame_id.
+/// A context that has moved on to a newer frame already waited for this one before being reused, so there is nothing to wait on
+fn pending_frame_context(
+    mut context_frame_ids: impl Iterator<Item = Option<u64>>,
+    submitted_frames: u64,
+    frame_id: u64,
+) -> Result<Option<usize>, VulkanError> {
+    if frame_id >= submitted_frames {
+        return Err(VulkanError::FrameNotSubmitted(frame_id));
+    }
+    Ok(context_frame_ids.position(|context_frame_id| context_frame_id == Some(frame_id)))
+}
+
+#[cfg(feature = "track-access-history")]
+impl RenderGraphExecutor {
+    /// Ordered (pass label, access) list for a buffer of the last submitted graph
+    pub(crate) fn buffer_access_history(
+        &self,
+        index: BufferIndex,
+    ) -> &[(String, BufferResourceAccess)] {
+        self.access_history
+            .buffers
+            .get(index)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Ordered (pass label, access) list for an image of the last submitted graph
+    pub(crate) fn image_access_history(
+        &self,
+        index: ImageIndex,
+    ) -> &[(String, ImageResourceAccess)] {
+        self.access_history
+            .images
+            .get(index)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// Ordered (pass label, access) lists for each resource of a graph, in the order its passes are recorded
+#[cfg(feature = "track-access-history")]
+#[derive(Default)]
+struct AccessHistory {
+    buffers: Vec<Vec<(String, BufferResourceAccess)>>,
+    images: Vec<Vec<(String, ImageResourceAccess)>>,
+}
+
+#[cfg(feature = "track-access-history")]
+impl AccessHistory {
+    fn new(render_graph: &CompiledRenderGraph) -> Self {
+        let mut history = Self {
+            buffers: vec![Vec::new(); render_graph.buffer_resources.len()],
+            images: vec![Vec::new(); render_graph.image_resources.len()],
+        };
+        for pass in render_graph.usage_report() {
+            for usage in pass.buffers {
+                history.buffers[usage.buffer].push((pass.name.clone(), usage.access));
+            }
+            for usage in pass.images {
+                history.images[usage.image].push((pass.name.clone(), usage.access));
+            }
+        }
+        history
+    }
+}
+
+/// Queue label for a graph submission, e.g. "async-compute batch 2"
+fn submission_label(queue: Queue, command_buffer_index: usize) -> String {
+    format!("{} batch {}", queue.name(), command_buffer_index)
+}
+
 fn allocate_command_buffer_semaphores(
     semaphore_pool: &mut AshSemaphorePool,
     command_buffers: &[CommandBuffer],
@@ -743,13 +1458,107 @@ fn allocate_command_buffer_semaphores(
 }
 
 struct AcquiredSwapchain {
+    surface: SurfaceHandle,
     image: AcquiredSwapchainImage,
     image_ready_semaphore: vk::Semaphore,
     present_ready_semaphore: vk::Semaphore,
+    /// The swapchain was out of date and rebuilt before acquiring
+    rebuilt: bool,
+    suboptimal: bool,
+}
+
+/// Gpu time spent in a single render pass or timer scope
+#[derive(Clone, Debug)]
+pub struct PassTiming {
+    pub name: String,
+    pub gpu_time: std::time::Duration,
+}
+
+/// Per pass gpu timings of a frame the gpu has finished
+#[derive(Clone, Debug, Default)]
+pub struct FrameTimings {
+    pub frame_id: u64,
+    pub passes: Vec<PassTiming>,
+    /// Timer scopes in the order they were ended, each from the start of its first pass to the end of its last
+    pub scopes: Vec<PassTiming>,
+}
+
+impl FrameTimings {
+    /// Converts a begin and end timestamp per pass into timings, in the order the passes were recorded
+    fn from_timestamps(
+        frame_id: u64,
+        pass_names: Vec<String>,
+        timer_scopes: Vec<TimerScope>,
+        timestamps: &[u64],
+        timestamp_period_ns: f32,
+    ) -> Self {
+        let gpu_time = |begin: u64, end: u64| {
+            std::time::Duration::from_nanos(
+                (end.saturating_sub(begin) as f64 * timestamp_period_ns as f64) as u64,
+            )
+        };
+        let passes = pass_names
+            .into_iter()
+            .zip(timestamps.chunks_exact(2))
+            .map(|(name, timestamps)| PassTiming {
+                name,
+                gpu_time: gpu_time(timestamps[0], timestamps[1]),
+            })
+            .collect();
+        let scopes = timer_scopes
+            .into_iter()
+            .filter(|scope| scope.passes.end * 2 <= timestamps.len())
+            .map(|scope| PassTiming {
+                gpu_time: gpu_time(
+                    timestamps[scope.passes.start * 2],
+                    timestamps[scope.passes.end * 2 - 1],
+                ),
+                name: scope.name,
+            })
+            .collect();
+
+        Self {
+            frame_id,
+            passes,
+            scopes,
+        }
+    }
+
+    /// Sum of all pass timings, excludes any gpu time spent between passes
+    pub fn total_gpu_time(&self) -> std::time::Duration {
+        self.passes.iter().map(|pass| pass.gpu_time).sum()
+    }
+}
+
+/// How often the bindless descriptor set is bound while recording a frame
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DescriptorBindFrequency {
+    /// Bound once per command buffer, and again only when the pipeline layout changes
+    #[default]
+    PerFrame,
+    /// Rebound at the start of every pass, useful to catch passes relying on state from a previous pass
+    PerPass,
+}
+
+/// Swapchain status of a submitted frame
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameResult {
+    /// Surfaces whose swapchain was rebuilt this frame, usually because the surface was resized
+    pub resized_surfaces: Vec<SurfaceHandle>,
+    /// Surfaces that reported their swapchain as suboptimal or out of date, out of date swapchains are rebuilt on the next acquire
+    pub suboptimal_surfaces: Vec<SurfaceHandle>,
+    /// Surfaces with a zero extent, e.g. a minimized window. The frame isn't submitted at all when this isn't empty
+    pub minimized_surfaces: Vec<SurfaceHandle>,
+}
+
+impl FrameResult {
+    /// True if the graph wasn't submitted, its buffer reads and writes won't have happened either
+    pub fn skipped(&self) -> bool {
+        !self.minimized_surfaces.is_empty()
+    }
 }
 
 fn acquire_swapchain_images(
-    semaphore_pool: &mut AshSemaphorePool,
     swapchain_manager: &mut SwapchainManager,
     swapchain_images: &[(SurfaceHandle, ImageIndex)],
 ) -> ash::prelude::VkResult<Vec<AcquiredSwapchain>> {
@@ -759,35 +1568,124 @@ fn acquire_swapchain_images(
         let swapchain = swapchain_manager
             .get(*surface)
             .expect("Failed to find swapchain");
-        let image_ready_semaphore = semaphore_pool.get()?;
-        let present_ready_semaphore = semaphore_pool.get()?;
 
+        let timeout_ns = swapchain.settings().acquire_timeout_ns;
         let mut swapchain_result: ash::prelude::VkResult<(AcquiredSwapchainImage, bool)> =
-            swapchain.acquire_next_image(image_ready_semaphore);
+            swapchain.acquire_next_image(timeout_ns);
 
+        let mut rebuilt = false;
         while let Err(vk::Result::ERROR_OUT_OF_DATE_KHR) = &swapchain_result {
             info!("Swapchain Out of Data, Rebuilding");
             swapchain.rebuild()?;
-            swapchain_result = swapchain.acquire_next_image(image_ready_semaphore);
+            rebuilt = true;
+            swapchain_result = swapchain.acquire_next_image(timeout_ns);
         }
-        let image = swapchain_result.unwrap().0;
+        let (image, suboptimal) = swapchain_result?;
 
         acquire_swapchains.push(AcquiredSwapchain {
+            surface: *surface,
+            image_ready_semaphore: image.image_ready_semaphore,
+            present_ready_semaphore: image.present_ready_semaphore,
             image,
-            image_ready_semaphore,
-            present_ready_semaphore,
+            rebuilt,
+            suboptimal,
         });
     }
 
     Ok(acquire_swapchains)
 }
 
+/// Reads in an unchanged layout don't need a barrier, e.g. images resting in their sampled layout
+fn needs_image_barrier(src: ImageResourceAccess, dst: ImageResourceAccess) -> bool {
+    src != dst || src.is_write()
+}
+
+/// Accesses to transition between at the end of the frame, None if the image wasn't used or is already at rest
+fn rest_transition(
+    last_access: Option<ImageResourceAccess>,
+    rest_access: Option<ImageResourceAccess>,
+) -> Option<(ImageResourceAccess, ImageResourceAccess)> {
+    let last_access = last_access?;
+    let rest_access = rest_access?;
+    (last_access != rest_access).then_some((last_access, rest_access))
+}
+
+/// Transitions persistent images with a rest access into it after the last pass of the frame
+fn record_rest_layout_transitions(
+    device: &AshDevice,
+    vulkan_command_buffer: vk::CommandBuffer,
+    graph_images: &[ImageGraphResource],
+    images: &[ImageTempResource],
+) {
+    let image_barriers: Vec<vk::ImageMemoryBarrier2> = graph_images
+        .iter()
+        .zip(images.iter())
+        .filter_map(|(graph_image, image)| {
+            let (last_access, rest_access) =
+                rest_transition(graph_image.last_access, image.rest_access)?;
+
+            let is_color = image.image.is_color();
+            let src = last_access.get_barrier_flags(is_color);
+            let dst = rest_access.get_barrier_flags(is_color);
+            Some(
+                vk::ImageMemoryBarrier2::builder()
+                    .image(image.image.handle)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk_format_get_aspect_flags(image.image.format),
+                        base_mip_level: 0,
+                        level_count: vk::REMAINING_MIP_LEVELS,
+                        base_array_layer: 0,
+                        layer_count: vk::REMAINING_ARRAY_LAYERS,
+                    })
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(src.layout)
+                    .src_stage_mask(src.stage_mask)
+                    .src_access_mask(src.access_mask)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .new_layout(dst.layout)
+                    .dst_stage_mask(dst.stage_mask)
+                    .dst_access_mask(dst.access_mask)
+                    .build(),
+            )
+        })
+        .collect();
+
+    if image_barriers.is_empty() {
+        return;
+    }
+
+    unsafe {
+        if let Some(debug_util) = &device.instance.debug_utils {
+            debug_util.cmd_begin_label(vulkan_command_buffer, "Rest Layouts", [1.0; 4]);
+        }
+
+        device.core.cmd_pipeline_barrier2(
+            vulkan_command_buffer,
+            &vk::DependencyInfo::builder()
+                .image_memory_barriers(&image_barriers)
+                .build(),
+        );
+
+        if let Some(debug_util) = &device.instance.debug_utils {
+            debug_util.cmd_end_label(vulkan_command_buffer);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn record_command_buffer(
     device: &AshDevice,
+    arena: ScratchAllocator,
+    secondary_command_cache: &mut SecondaryCommandCache,
+    mut timestamp_queries: Option<&mut PassTimestampQueries>,
+    bind_frequency: DescriptorBindFrequency,
     vulkan_command_buffer: vk::CommandBuffer,
     graph_command_buffer: &CommandBuffer,
     graph_resources: &mut RenderGraphResources,
-) {
+) -> Result<(), VulkanError> {
+    let mut bound_sets =
+        BoundDescriptorSets::new(graph_resources.persistent.descriptor_set.get_set());
+
     for (render_pass_set_index, render_pass_set) in
         graph_command_buffer.render_pass_sets.iter().enumerate()
     {
@@ -799,62 +1697,84 @@ fn record_command_buffer(
             );
         }
 
-        let buffer_barriers: Vec<vk::BufferMemoryBarrier2> = render_pass_set
-            .buffer_barriers
-            .iter()
-            .map(|buffer_barrier| {
+        let buffer_barriers = arena.collect(render_pass_set.buffer_barriers.iter().filter_map(
+            |buffer_barrier| {
                 let buffer = &graph_resources.buffers[buffer_barrier.index];
-                let src = match buffer_barrier.src {
-                    BufferBarrierSource::FirstUsage => buffer.last_access,
-                    BufferBarrierSource::Precalculated(flags) => flags,
+                let (src_access, src_shader_stages) = match buffer_barrier.src {
+                    BufferBarrierSource::FirstUsage => {
+                        (buffer.last_access, BufferResourceAccess::ALL_SHADER_STAGES)
+                    }
+                    BufferBarrierSource::Precalculated(access) => {
+                        (access, buffer_barrier.src_shader_stages)
+                    }
+                };
+
+                // Nothing to wait on for an unused buffer or a repeated read in the same stages
+                if src_access == BufferResourceAccess::None
+                    || (src_access == buffer_barrier.dst
+                        && !src_access.is_write()
+                        && src_shader_stages == buffer_barrier.dst_shader_stages)
+                {
+                    return None;
                 }
-                .get_barrier_flags();
-                let dst = buffer_barrier.dst.get_barrier_flags();
-                vk::BufferMemoryBarrier2::builder()
-                    .buffer(buffer.buffer.handle)
-                    .offset(0)
-                    .size(vk::WHOLE_SIZE)
-                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                    .src_stage_mask(src.stage_mask)
-                    .src_access_mask(src.access_mask)
-                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                    .dst_stage_mask(dst.stage_mask)
-                    .src_access_mask(dst.access_mask)
-                    .build()
-            })
-            .collect();
 
-        let image_barriers: Vec<vk::ImageMemoryBarrier2> = render_pass_set
-            .image_barriers
-            .iter()
-            .map(|image_barrier| {
+                let src = src_access.get_stage_barrier_flags(src_shader_stages);
+                let dst = buffer_barrier
+                    .dst
+                    .get_stage_barrier_flags(buffer_barrier.dst_shader_stages);
+                Some(
+                    vk::BufferMemoryBarrier2::builder()
+                        .buffer(buffer.buffer.handle)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .src_stage_mask(src.stage_mask)
+                        .src_access_mask(src.access_mask)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_stage_mask(dst.stage_mask)
+                        .dst_access_mask(dst.access_mask)
+                        .build(),
+                )
+            },
+        ));
+
+        let image_barriers = arena.collect(render_pass_set.image_barriers.iter().filter_map(
+            |image_barrier| {
                 let image = &graph_resources.images[image_barrier.index];
-                let is_color = image.image.is_color();
-                let src = match image_barrier.src {
-                    ImageBarrierSource::FirstUsage => image.last_access.get_barrier_flags(is_color),
-                    ImageBarrierSource::Precalculated(flags) => flags.get_barrier_flags(is_color),
+                let src_access = match image_barrier.src {
+                    ImageBarrierSource::FirstUsage => image.last_access,
+                    ImageBarrierSource::Precalculated(access) => access,
                 };
+
+                if !needs_image_barrier(src_access, image_barrier.dst) {
+                    return None;
+                }
+
+                let is_color = image.image.is_color();
+                let src = src_access.get_barrier_flags(is_color);
                 let dst = image_barrier.dst.get_barrier_flags(is_color);
-                vk::ImageMemoryBarrier2::builder()
-                    .image(image.image.handle)
-                    .subresource_range(vk::ImageSubresourceRange {
-                        aspect_mask: vk_format_get_aspect_flags(image.image.format),
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    })
-                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                    .old_layout(src.layout)
-                    .src_stage_mask(src.stage_mask)
-                    .src_access_mask(src.access_mask)
-                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                    .new_layout(dst.layout)
-                    .dst_stage_mask(dst.stage_mask)
-                    .dst_access_mask(dst.access_mask)
-                    .build()
-            })
-            .collect();
+                Some(
+                    vk::ImageMemoryBarrier2::builder()
+                        .image(image.image.handle)
+                        .subresource_range(vk::ImageSubresourceRange {
+                            aspect_mask: vk_format_get_aspect_flags(image.image.format),
+                            base_mip_level: 0,
+                            level_count: vk::REMAINING_MIP_LEVELS,
+                            base_array_layer: 0,
+                            layer_count: vk::REMAINING_ARRAY_LAYERS,
+                        })
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .old_layout(src.layout)
+                        .src_stage_mask(src.stage_mask)
+                        .src_access_mask(src.access_mask)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .new_layout(dst.layout)
+                        .dst_stage_mask(dst.stage_mask)
+                        .dst_access_mask(dst.access_mask)
+                        .build(),
+                )
+            },
+        ));
 
         unsafe {
             device.core.cmd_pipeline_barrier2(
@@ -876,6 +1796,14 @@ fn record_command_buffer(
                 );
             }
 
+            if let Some(timestamp_queries) = &mut timestamp_queries {
+                timestamp_queries.begin_pass(vulkan_command_buffer, &render_pass.label_name);
+            }
+
+            if bind_frequency == DescriptorBindFrequency::PerPass {
+                bound_sets.reset();
+            }
+
             if let Some(render_pass_command) = &render_pass.command {
                 match render_pass_command {
                     RenderPassCommand::Transfer { transfers } => {
@@ -890,27 +1818,87 @@ fn record_command_buffer(
                         pipeline,
                         resources,
                         dispatch,
+                        push_constants,
                     } => record_compute_pass(
                         device,
                         vulkan_command_buffer,
+                        &mut bound_sets,
                         graph_resources,
                         *pipeline,
                         resources,
                         dispatch,
-                    ),
+                        push_constants,
+                    )?,
                     RenderPassCommand::Raster {
+                        framebuffer,
+                        cacheable: true,
+                        draw_commands,
+                    } => record_cached_raster_pass(
+                        device,
+                        secondary_command_cache,
+                        vulkan_command_buffer,
+                        graph_resources,
+                        &render_pass.label_name,
                         framebuffer,
                         draw_commands,
+                    )?,
+                    RenderPassCommand::Raster {
+                        framebuffer,
+                        draw_commands,
+                        ..
                     } => record_raster_pass(
                         device,
                         vulkan_command_buffer,
+                        &mut bound_sets,
                         graph_resources,
                         framebuffer,
                         draw_commands,
-                    ),
+                    )?,
+                    RenderPassCommand::Custom {
+                        buffers,
+                        images,
+                        command,
+                    } => {
+                        let layout = graph_resources.get_pipeline_layout();
+                        for bind_point in [
+                            vk::PipelineBindPoint::COMPUTE,
+                            vk::PipelineBindPoint::GRAPHICS,
+                        ] {
+                            bound_sets.bind(device, vulkan_command_buffer, bind_point, layout);
+                        }
+
+                        let buffers: Vec<vk::Buffer> = buffers
+                            .iter()
+                            .map(|&index| graph_resources.buffers[index].buffer.handle)
+                            .collect();
+                        let images: Vec<vk::Image> = images
+                            .iter()
+                            .map(|&index| graph_resources.images[index].image.handle)
+                            .collect();
+                        if !command.call(
+                            &device.core,
+                            vulkan_command_buffer,
+                            &CustomPassResources {
+                                buffers: &buffers,
+                                images: &images,
+                            },
+                        ) {
+                            warn!(
+                                "Custom command for pass {} was already recorded",
+                                render_pass.label_name
+                            );
+                        }
+
+                        // Custom commands may bind their own descriptor sets
+                        bound_sets.reset();
+                    }
                 }
             }
 
+            if let Some(timestamp_queries) = &mut timestamp_queries {
+                timestamp_queries.end_pass(vulkan_command_buffer);
+            }
+
             if let Some(debug_util) = &device.instance.debug_utils {
                 debug_util.cmd_end_label(vulkan_command_buffer);
             }
@@ -920,6 +1908,8 @@ fn record_command_buffer(
             debug_util.cmd_end_label(vulkan_command_buffer);
         }
     }
+
+    Ok(())
 }
 
 pub fn record_transfer_pass(
@@ -972,19 +1962,14 @@ pub fn record_transfer_pass(
                                 .image_offset(vk::Offset3D {
                                     x: dst.offset[0] as i32,
                                     y: dst.offset[1] as i32,
-                                    z: 0,
+                                    z: dst.offset[2] as i32,
                                 })
                                 .image_extent(vk::Extent3D {
                                     width: copy_size[0],
                                     height: copy_size[1],
-                                    depth: 1,
-                                })
-                                .image_subresource(vk::ImageSubresourceLayers {
-                                    aspect_mask: vk_format_get_aspect_flags(dst_image.format),
-                                    mip_level: 0,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
+                                    depth: copy_size[2],
                                 })
+                                .image_subresource(dst.subresource_layers(dst_image.format))
                                 .build()]),
                     );
                 }
@@ -1010,19 +1995,14 @@ pub fn record_transfer_pass(
                                 .image_offset(vk::Offset3D {
                                     x: src.offset[0] as i32,
                                     y: src.offset[1] as i32,
-                                    z: 0,
+                                    z: src.offset[2] as i32,
                                 })
                                 .image_extent(vk::Extent3D {
                                     width: copy_size[0],
                                     height: copy_size[1],
-                                    depth: 1,
-                                })
-                                .image_subresource(vk::ImageSubresourceLayers {
-                                    aspect_mask: vk_format_get_aspect_flags(src_image.format),
-                                    mip_level: 0,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
+                                    depth: copy_size[2],
                                 })
+                                .image_subresource(src.subresource_layers(src_image.format))
                                 .build()]),
                     );
                 }
@@ -1047,47 +2027,94 @@ pub fn record_transfer_pass(
                                 .src_offset(vk::Offset3D {
                                     x: src.offset[0] as i32,
                                     y: src.offset[1] as i32,
-                                    z: 0,
+                                    z: src.offset[2] as i32,
                                 })
                                 .dst_offset(vk::Offset3D {
                                     x: dst.offset[0] as i32,
                                     y: dst.offset[1] as i32,
-                                    z: 0,
+                                    z: dst.offset[2] as i32,
                                 })
                                 .extent(vk::Extent3D {
                                     width: copy_size[0],
                                     height: copy_size[1],
-                                    depth: 1,
-                                })
-                                .src_subresource(vk::ImageSubresourceLayers {
-                                    aspect_mask: vk_format_get_aspect_flags(src_image.format),
-                                    mip_level: 0,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                })
-                                .dst_subresource(vk::ImageSubresourceLayers {
-                                    aspect_mask: vk_format_get_aspect_flags(dst_image.format),
-                                    mip_level: 0,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
+                                    depth: copy_size[2],
                                 })
+                                .src_subresource(src.subresource_layers(src_image.format))
+                                .dst_subresource(dst.subresource_layers(dst_image.format))
                                 .build()]),
                     )
                 }
             }
+            Transfer::FillBuffer { dst, size, data } => {
+                let dst_buffer = &graph_resources.buffers[dst.buffer].buffer;
+                if dst.offset + size > dst_buffer.size {
+                    error!(
+                        "Fill of {} bytes at offset {} is outside of buffer ({} bytes), skipping",
+                        size, dst.offset, dst_buffer.size
+                    );
+                    continue;
+                }
+
+                unsafe {
+                    device.core.cmd_fill_buffer(
+                        command_buffer,
+                        dst_buffer.handle,
+                        dst.offset,
+                        *size,
+                        *data,
+                    );
+                }
+            }
+            Transfer::UpdateBuffer { dst, data } => {
+                let dst_buffer = &graph_resources.buffers[dst.buffer].buffer;
+                if dst.offset + data.len() as u64 > dst_buffer.size {
+                    error!(
+                        "Update of {} bytes at offset {} is outside of buffer ({} bytes), skipping",
+                        data.len(),
+                        dst.offset,
+                        dst_buffer.size
+                    );
+                    continue;
+                }
+
+                unsafe {
+                    device.core.cmd_update_buffer(
+                        command_buffer,
+                        dst_buffer.handle,
+                        dst.offset,
+                        data,
+                    );
+                }
+            }
         }
     }
 }
 
-pub fn record_compute_pass(
+#[allow(clippy::too_many_arguments)]
+fn record_compute_pass(
     device: &AshDevice,
     command_buffer: vk::CommandBuffer,
+    bound_sets: &mut BoundDescriptorSets,
     graph_resources: &RenderGraphResources,
     pipeline: ComputePipelineHandle,
     resources: &[ShaderResourceUsage],
     dispatch: &ComputeDispatch,
-) {
-    record_shader_resources(device, command_buffer, graph_resources, resources);
+    push_constants: &[u8],
+) -> ash::prelude::VkResult<()> {
+    bound_sets.bind(
+        device,
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        graph_resources.get_pipeline_layout(),
+    );
+    record_shader_resources(
+        device,
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        graph_resources,
+        resources,
+        push_constants,
+    )?;
 
     unsafe {
         device.core.cmd_bind_pipeline(
@@ -1110,78 +2137,388 @@ pub fn record_compute_pass(
             }
         }
     }
+    Ok(())
+}
+
+/// Checks that every draw's pipeline was created for the framebuffer's color and depth attachment formats.
+/// Swapchain formats come from the surface settings, so this runs before any image is acquired
+fn validate_attachment_formats(
+    resource_manager: &ResourceManager,
+    swapchain_manager: &mut SwapchainManager,
+    pipelines: &Pipelines,
+    render_graph: &CompiledRenderGraph,
+) -> Result<(), VulkanError> {
+    let mut image_format = |image: ImageIndex| -> Option<vk::Format> {
+        match &render_graph.image_resources[image].description {
+            ImageResourceDescription::Persistent(key) => {
+                resource_manager.get_image(*key).map(|image| image.format)
+            }
+            ImageResourceDescription::Transient(description) => Some(description.format),
+            ImageResourceDescription::Swapchain(index) => swapchain_manager
+                .get(render_graph.swapchain_images[*index].0)
+                .map(|swapchain| swapchain.settings().format.format),
+        }
+    };
+
+    let render_passes = render_graph
+        .command_buffers
+        .iter()
+        .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+        .flat_map(|render_pass_set| render_pass_set.render_passes.iter());
+    for render_pass in render_passes {
+        let Some(RenderPassCommand::Raster {
+            framebuffer,
+            draw_commands,
+            ..
+        }) = &render_pass.command
+        else {
+            continue;
+        };
+
+        // Stale handles are reported when the frame's resources are resolved
+        let Some(framebuffer_formats) = framebuffer
+            .color_attachments
+            .iter()
+            .map(|attachment| image_format(attachment.image))
+            .collect::<Option<Vec<vk::Format>>>()
+        else {
+            continue;
+        };
+        let framebuffer_depth_format = match framebuffer.depth_stencil_attachment {
+            Some(attachment) => match image_format(attachment.image) {
+                Some(format) => Some(format),
+                None => continue,
+            },
+            None => None,
+        };
+
+        for (draw_index, draw_command) in draw_commands.iter().enumerate() {
+            let pipeline = pipelines.raster.get(draw_command.pipeline.0).unwrap();
+            check_color_targets(
+                &render_pass.label_name,
+                draw_index,
+                &pipeline.color_formats,
+                &framebuffer_formats,
+            )?;
+
+            check_depth_target(
+                &render_pass.label_name,
+                draw_index,
+                pipeline.depth_format,
+                framebuffer_depth_format,
+            )?;
+
+            check_dynamic_states(
+                &render_pass.label_name,
+                draw_index,
+                &pipeline.dynamic_states,
+                &draw_command.dynamic_state,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The pipeline's color target formats must match the framebuffer's color attachments in count and order
+fn check_color_targets(
+    pass_name: &str,
+    draw_index: usize,
+    pipeline_formats: &[vk::Format],
+    framebuffer_formats: &[vk::Format],
+) -> Result<(), VulkanError> {
+    if pipeline_formats != framebuffer_formats {
+        return Err(VulkanError::ColorTargetMismatch {
+            pass: pass_name.to_string(),
+            draw_index,
+            pipeline_formats: pipeline_formats.to_vec(),
+            framebuffer_formats: framebuffer_formats.to_vec(),
+        });
+    }
+    Ok(())
 }
 
-pub fn record_raster_pass(
+/// A pipeline with a depth state needs a framebuffer depth attachment of the same format
+fn check_depth_target(
+    pass_name: &str,
+    draw_index: usize,
+    pipeline_format: Option<vk::Format>,
+    framebuffer_format: Option<vk::Format>,
+) -> Result<(), VulkanError> {
+    if let Some(pipeline_format) = pipeline_format {
+        if framebuffer_format != Some(pipeline_format) {
+            return Err(VulkanError::DepthTargetMismatch {
+                pass: pass_name.to_string(),
+                draw_index,
+                pipeline_format,
+                framebuffer_format,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Every dynamic state a draw sets must be declared by its pipeline
+fn check_dynamic_states(
+    pass_name: &str,
+    draw_index: usize,
+    pipeline_states: &[DynamicState],
+    values: &DynamicStateValues,
+) -> Result<(), VulkanError> {
+    if let Some(state) = values
+        .set_states()
+        .find(|state| !pipeline_states.contains(state))
+    {
+        return Err(VulkanError::UndeclaredDynamicState {
+            pass: pass_name.to_string(),
+            draw_index,
+            state,
+        });
+    }
+    Ok(())
+}
+
+fn record_raster_pass(
     device: &AshDevice,
     command_buffer: vk::CommandBuffer,
+    bound_sets: &mut BoundDescriptorSets,
     graph_resources: &RenderGraphResources,
     framebuffer: &Framebuffer,
     draw_commands: &[RasterDrawCommand],
-) {
-    //Begin Rendering
-    {
-        let mut rendering_info_builder = vk::RenderingInfo::builder().layer_count(1);
-
-        let mut extent = None;
-        let mut color_attachments = Vec::new();
+) -> ash::prelude::VkResult<()> {
+    let extent = begin_rendering(
+        device,
+        command_buffer,
+        graph_resources,
+        framebuffer,
+        vk::RenderingFlags::empty(),
+    );
+    record_raster_draws(
+        device,
+        command_buffer,
+        bound_sets,
+        graph_resources,
+        extent,
+        draw_commands,
+    )?;
+    unsafe {
+        device.core.cmd_end_rendering(command_buffer);
+    }
+    Ok(())
+}
 
-        for color_attachment in framebuffer.color_attachments.iter() {
-            let image = graph_resources.images[color_attachment.image].image;
+/// Records a cacheable raster pass, replaying the cached secondary command buffer when its signature still matches
+fn record_cached_raster_pass(
+    device: &AshDevice,
+    secondary_command_cache: &mut SecondaryCommandCache,
+    command_buffer: vk::CommandBuffer,
+    graph_resources: &RenderGraphResources,
+    name: &str,
+    framebuffer: &Framebuffer,
+    draw_commands: &[RasterDrawCommand],
+) -> ash::prelude::VkResult<()> {
+    let extent = begin_rendering(
+        device,
+        command_buffer,
+        graph_resources,
+        framebuffer,
+        vk::RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS,
+    );
+
+    let signature = raster_pass_signature(graph_resources, framebuffer, extent, draw_commands);
+    let secondary_command_buffer = match secondary_command_cache.get(name, signature) {
+        Some(secondary_command_buffer) => secondary_command_buffer,
+        None => {
+            let secondary_command_buffer = secondary_command_cache.allocate(name, signature)?;
+
+            let color_formats: Vec<vk::Format> = framebuffer
+                .color_attachments
+                .iter()
+                .map(|attachment| graph_resources.images[attachment.image].image.format)
+                .collect();
+            let depth_format = framebuffer
+                .depth_stencil_attachment
+                .map(|attachment| graph_resources.images[attachment.image].image.format)
+                .unwrap_or(vk::Format::UNDEFINED);
+
+            let mut inheritance_rendering_info =
+                vk::CommandBufferInheritanceRenderingInfo::builder()
+                    .view_mask(framebuffer.view_mask)
+                    .color_attachment_formats(&color_formats)
+                    .depth_attachment_format(depth_format)
+                    .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+            let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+                .push_next(&mut inheritance_rendering_info);
 
-            if let Some(extent) = extent {
-                if extent != image.size {
-                    panic!("Framebuffer color attachment extent does not match");
-                }
-            } else {
-                extent = Some(image.size);
+            unsafe {
+                device.core.begin_command_buffer(
+                    secondary_command_buffer,
+                    &vk::CommandBufferBeginInfo::builder()
+                        .flags(
+                            vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE
+                                | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+                        )
+                        .inheritance_info(&inheritance_info),
+                )?;
             }
 
-            let color_clear = color_attachment.clear.map(|color| vk::ClearValue {
-                color: vk::ClearColorValue { float32: color },
-            });
+            // Secondary command buffers don't inherit bound descriptor sets
+            let mut bound_sets =
+                BoundDescriptorSets::new(graph_resources.persistent.descriptor_set.get_set());
+            record_raster_draws(
+                device,
+                secondary_command_buffer,
+                &mut bound_sets,
+                graph_resources,
+                extent,
+                draw_commands,
+            )?;
+
+            unsafe { device.core.end_command_buffer(secondary_command_buffer)? };
+            secondary_command_buffer
+        }
+    };
 
-            color_attachments.push(
-                vk::RenderingAttachmentInfo::builder()
-                    .image_view(image.view)
-                    .image_layout(vk::ImageLayout::GENERAL)
-                    .load_op(if color_clear.is_some() {
-                        vk::AttachmentLoadOp::CLEAR
-                    } else {
-                        vk::AttachmentLoadOp::LOAD
-                    })
-                    .store_op(vk::AttachmentStoreOp::STORE)
-                    .clear_value(color_clear.unwrap_or_default())
-                    .build(),
-            );
+    unsafe {
+        device
+            .core
+            .cmd_execute_commands(command_buffer, &[secondary_command_buffer]);
+        device.core.cmd_end_rendering(command_buffer);
+    }
+    Ok(())
+}
+
+/// Hashes everything a recorded raster pass depends on, resolved to vulkan handles and binding indices.
+/// Attachment views aren't recorded into the secondary command buffer, only their formats are inherited,
+/// so swapchain images or re-allocated transients don't invalidate the cache
+fn raster_pass_signature(
+    graph_resources: &RenderGraphResources,
+    framebuffer: &Framebuffer,
+    extent: vk::Extent2D,
+    draw_commands: &[RasterDrawCommand],
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    extent.width.hash(&mut hasher);
+    extent.height.hash(&mut hasher);
+    framebuffer.view_mask.hash(&mut hasher);
+
+    for attachment in framebuffer.color_attachments.iter() {
+        graph_resources.images[attachment.image]
+            .image
+            .format
+            .hash(&mut hasher);
+    }
+    framebuffer
+        .depth_stencil_attachment
+        .map(|attachment| graph_resources.images[attachment.image].image.format)
+        .hash(&mut hasher);
+
+    for draw_command in draw_commands {
+        graph_resources
+            .get_raster_pipeline(draw_command.pipeline)
+            .hash(&mut hasher);
+
+        let dispatch_buffers = match &draw_command.dispatch {
+            DrawCommandDispatch::Draw { .. } => [None, None],
+            DrawCommandDispatch::DrawIndexed { index_buffer, .. } => [Some(index_buffer), None],
+            DrawCommandDispatch::DrawIndirect {
+                indirect_buffer, ..
+            } => [Some(indirect_buffer), None],
+            DrawCommandDispatch::DrawIndirectIndexed {
+                indirect_buffer,
+                index_buffer,
+                ..
+            } => [Some(indirect_buffer), Some(index_buffer)],
+        };
+        for buffer_offset in draw_command
+            .vertex_buffers
+            .iter()
+            .chain(dispatch_buffers.into_iter().flatten())
+        {
+            graph_resources.buffers[buffer_offset.buffer]
+                .buffer
+                .handle
+                .hash(&mut hasher);
+            buffer_offset.offset.hash(&mut hasher);
         }
 
-        rendering_info_builder = rendering_info_builder.color_attachments(&color_attachments);
+        for binding in get_shader_resource_bindings(graph_resources, &draw_command.resources) {
+            binding.to_bytes().hash(&mut hasher);
+        }
+        for bind in get_dynamic_buffer_binds(&draw_command.resources) {
+            bind.storage.hash(&mut hasher);
+            graph_resources.buffers[bind.buffer]
+                .buffer
+                .handle
+                .hash(&mut hasher);
+            bind.offset.hash(&mut hasher);
+            bind.range.hash(&mut hasher);
+        }
 
-        let depth_stencil_attachment_info: vk::RenderingAttachmentInfo;
-        if let Some(depth_stencil_image) = &framebuffer.depth_stencil_attachment {
-            let image = graph_resources.images[depth_stencil_image.image].image;
+        draw_command.dispatch.hash(&mut hasher);
+        draw_command
+            .depth_range
+            .map(|range| range.map(f32::to_bits))
+            .hash(&mut hasher);
+        draw_command.scissor.hash(&mut hasher);
+
+        let dynamic_state = &draw_command.dynamic_state;
+        dynamic_state.line_width.map(f32::to_bits).hash(&mut hasher);
+        dynamic_state
+            .blend_constants
+            .map(|constants| constants.map(f32::to_bits))
+            .hash(&mut hasher);
+        dynamic_state
+            .depth_bias
+            .map(|bias| [bias.constant_factor, bias.clamp, bias.slope_factor].map(f32::to_bits))
+            .hash(&mut hasher);
+        dynamic_state.stencil_reference.hash(&mut hasher);
+    }
 
-            if let Some(extent) = extent {
-                if extent != image.size {
-                    panic!("Framebuffer depth stencil attachment extent does not match");
-                }
-            } else {
-                extent = Some(image.size);
+    hasher.finish()
+}
+
+fn begin_rendering(
+    device: &AshDevice,
+    command_buffer: vk::CommandBuffer,
+    graph_resources: &RenderGraphResources,
+    framebuffer: &Framebuffer,
+    flags: vk::RenderingFlags,
+) -> vk::Extent2D {
+    let mut rendering_info_builder = vk::RenderingInfo::builder()
+        .flags(flags)
+        .layer_count(1)
+        .view_mask(framebuffer.view_mask);
+
+    let mut extent = None;
+    let mut color_attachments = Vec::new();
+
+    for color_attachment in framebuffer.color_attachments.iter() {
+        let image_resource = &graph_resources.images[color_attachment.image];
+        let image_view = image_resource.get_attachment_view(color_attachment.subresource);
+        let image_extent = image_resource
+            .image
+            .mip_extent(color_attachment.subresource.mip_level);
+
+        if let Some(extent) = extent {
+            if extent != image_extent {
+                panic!("Framebuffer color attachment extent does not match");
             }
+        } else {
+            extent = Some(image_extent);
+        }
 
-            let color_clear = depth_stencil_image
-                .clear
-                .map(|depth_stencil| vk::ClearValue {
-                    depth_stencil: vk::ClearDepthStencilValue {
-                        depth: depth_stencil.0,
-                        stencil: depth_stencil.1,
-                    },
-                });
-
-            depth_stencil_attachment_info = vk::RenderingAttachmentInfo::builder()
-                .image_view(image.view)
-                .image_layout(vk::ImageLayout::GENERAL)
+        let color_clear = color_attachment
+            .clear
+            .map(|clear| clear.to_vk(image_resource.image.format));
+
+        color_attachments.push(
+            vk::RenderingAttachmentInfo::builder()
+                .image_view(image_view)
+                .image_layout(
+                    ImageResourceAccess::AttachmentWrite
+                        .get_barrier_flags(true)
+                        .layout,
+                )
                 .load_op(if color_clear.is_some() {
                     vk::AttachmentLoadOp::CLEAR
                 } else {
@@ -1189,50 +2526,183 @@ pub fn record_raster_pass(
                 })
                 .store_op(vk::AttachmentStoreOp::STORE)
                 .clear_value(color_clear.unwrap_or_default())
-                .build();
+                .build(),
+        );
+    }
 
-            rendering_info_builder =
-                rendering_info_builder.depth_attachment(&depth_stencil_attachment_info);
+    rendering_info_builder = rendering_info_builder.color_attachments(&color_attachments);
+
+    let depth_stencil_attachment_info: vk::RenderingAttachmentInfo;
+    if let Some(depth_stencil_image) = &framebuffer.depth_stencil_attachment {
+        let image_resource = &graph_resources.images[depth_stencil_image.image];
+        let image_view = image_resource.get_attachment_view(depth_stencil_image.subresource);
+        let image_extent = image_resource
+            .image
+            .mip_extent(depth_stencil_image.subresource.mip_level);
+
+        if let Some(extent) = extent {
+            if extent != image_extent {
+                panic!("Framebuffer depth stencil attachment extent does not match");
+            }
+        } else {
+            extent = Some(image_extent);
         }
 
-        let extent = extent.expect("Framebuffer has no attachments");
+        let color_clear = depth_stencil_image
+            .clear
+            .map(|clear| clear.to_vk(image_resource.image.format));
+
+        // Must match the layout the pass barrier transitioned to, so the image can later move to a read only layout for sampling
+        depth_stencil_attachment_info = vk::RenderingAttachmentInfo::builder()
+            .image_view(image_view)
+            .image_layout(
+                ImageResourceAccess::AttachmentWrite
+                    .get_barrier_flags(false)
+                    .layout,
+            )
+            .load_op(if color_clear.is_some() {
+                vk::AttachmentLoadOp::CLEAR
+            } else {
+                vk::AttachmentLoadOp::LOAD
+            })
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(color_clear.unwrap_or_default())
+            .build();
 
-        let render_area = vk::Rect2D {
-            offset: vk::Offset2D::default(),
-            extent,
-        };
+        rendering_info_builder =
+            rendering_info_builder.depth_attachment(&depth_stencil_attachment_info);
+    }
 
-        rendering_info_builder = rendering_info_builder
-            .color_attachments(&color_attachments)
-            .render_area(render_area);
+    // The builder rejects raster passes without any attachments
+    let extent = extent.expect("Framebuffer has no attachments");
 
-        unsafe {
-            device
-                .core
-                .cmd_begin_rendering(command_buffer, &rendering_info_builder);
+    let render_area = vk::Rect2D {
+        offset: vk::Offset2D::default(),
+        extent,
+    };
 
-            device.core.cmd_set_viewport(
-                command_buffer,
-                0,
-                &[vk::Viewport {
-                    x: 0.0,
-                    y: 0.0,
-                    width: extent.width as f32,
-                    height: extent.height as f32,
-                    min_depth: 0.0,
-                    max_depth: 1.0,
-                }],
-            );
+    rendering_info_builder = rendering_info_builder
+        .color_attachments(&color_attachments)
+        .render_area(render_area);
 
-            device
-                .core
-                .cmd_set_scissor(command_buffer, 0, &[render_area])
+    unsafe {
+        device
+            .core
+            .cmd_begin_rendering(command_buffer, &rendering_info_builder);
+    }
+
+    extent
+}
+
+/// Viewport covering the whole render area, flipping Y moves the origin to the bottom edge and negates the height
+fn raster_viewport(extent: vk::Extent2D, depth_range: [f32; 2], flip_y: bool) -> vk::Viewport {
+    let (y, height) = if flip_y {
+        (extent.height as f32, -(extent.height as f32))
+    } else {
+        (0.0, extent.height as f32)
+    };
+    vk::Viewport {
+        x: 0.0,
+        y,
+        width: extent.width as f32,
+        height,
+        min_depth: depth_range[0],
+        max_depth: depth_range[1],
+    }
+}
+
+fn record_dynamic_states(
+    device: &AshDevice,
+    command_buffer: vk::CommandBuffer,
+    pipeline_states: &[DynamicState],
+    values: &DynamicStateValues,
+) {
+    for state in pipeline_states {
+        unsafe {
+            match state {
+                DynamicState::LineWidth => device
+                    .core
+                    .cmd_set_line_width(command_buffer, values.line_width.unwrap_or(1.0)),
+                DynamicState::BlendConstants => device.core.cmd_set_blend_constants(
+                    command_buffer,
+                    &values.blend_constants.unwrap_or_default(),
+                ),
+                DynamicState::DepthBias => {
+                    let depth_bias = values.depth_bias.unwrap_or_default();
+                    device.core.cmd_set_depth_bias(
+                        command_buffer,
+                        depth_bias.constant_factor,
+                        depth_bias.clamp,
+                        depth_bias.slope_factor,
+                    )
+                }
+                DynamicState::StencilReference => device.core.cmd_set_stencil_reference(
+                    command_buffer,
+                    vk::StencilFaceFlags::FRONT_AND_BACK,
+                    values.stencil_reference.unwrap_or_default(),
+                ),
+            }
         }
     }
+}
+
+fn record_raster_draws(
+    device: &AshDevice,
+    command_buffer: vk::CommandBuffer,
+    bound_sets: &mut BoundDescriptorSets,
+    graph_resources: &RenderGraphResources,
+    extent: vk::Extent2D,
+    draw_commands: &[RasterDrawCommand],
+) -> ash::prelude::VkResult<()> {
+    const FULL_DEPTH_RANGE: [f32; 2] = [0.0, 1.0];
+    let set_viewport = |depth_range: [f32; 2], flip_y: bool| unsafe {
+        device.core.cmd_set_viewport(
+            command_buffer,
+            0,
+            &[raster_viewport(extent, depth_range, flip_y)],
+        );
+    };
+    let mut current_viewport = (FULL_DEPTH_RANGE, false);
+    set_viewport(current_viewport.0, current_viewport.1);
+
+    let full_scissor = vk::Rect2D {
+        offset: vk::Offset2D::default(),
+        extent,
+    };
+    let set_scissor = |scissor: vk::Rect2D| unsafe {
+        device.core.cmd_set_scissor(command_buffer, 0, &[scissor]);
+    };
+    let mut current_scissor = full_scissor;
+    set_scissor(current_scissor);
 
     //Draw calls
     for draw_call in draw_commands {
+        let viewport = (
+            draw_call.depth_range.unwrap_or(FULL_DEPTH_RANGE),
+            graph_resources.get_raster_pipeline_flip_viewport_y(draw_call.pipeline),
+        );
+        if viewport != current_viewport {
+            set_viewport(viewport.0, viewport.1);
+            current_viewport = viewport;
+        }
+
+        // Clamped to the framebuffer since scissor offsets can't be negative
+        let scissor = draw_call
+            .scissor
+            .map(|scissor| intersect_scissor(full_scissor, scissor))
+            .unwrap_or(full_scissor);
+        if scissor != current_scissor {
+            set_scissor(scissor);
+            current_scissor = scissor;
+        }
+
         //Bind Pipeline
+        bound_sets.bind(
+            device,
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            graph_resources.get_pipeline_layout(),
+        );
         unsafe {
             device.core.cmd_bind_pipeline(
                 command_buffer,
@@ -1240,6 +2710,12 @@ pub fn record_raster_pass(
                 graph_resources.get_raster_pipeline(draw_call.pipeline),
             );
         }
+        record_dynamic_states(
+            device,
+            command_buffer,
+            graph_resources.get_raster_pipeline_dynamic_states(draw_call.pipeline),
+            &draw_call.dynamic_state,
+        );
 
         //Bind Vertex Buffers
         if !draw_call.vertex_buffers.is_empty() {
@@ -1269,9 +2745,11 @@ pub fn record_raster_pass(
         record_shader_resources(
             device,
             command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
             graph_resources,
             &draw_call.resources,
-        );
+            &[],
+        )?;
 
         //Dispatch
         unsafe {
@@ -1350,19 +2828,100 @@ pub fn record_raster_pass(
             }
         }
     }
+    Ok(())
+}
 
-    //End Rendering
-    unsafe {
-        device.core.cmd_end_rendering(command_buffer);
-    }
+/// A dynamic buffer resource as bound at record time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DynamicBufferBind {
+    storage: bool,
+    buffer: BufferIndex,
+    range: vk::DeviceSize,
+    offset: u32,
+}
+
+fn get_dynamic_buffer_binds(resources: &[ShaderResourceUsage]) -> Vec<DynamicBufferBind> {
+    resources
+        .iter()
+        .filter_map(|resource| match *resource {
+            ShaderResourceUsage::DynamicBuffer {
+                buffer,
+                offset,
+                range,
+            } => Some(DynamicBufferBind {
+                storage: false,
+                buffer,
+                range,
+                offset,
+            }),
+            ShaderResourceUsage::DynamicStorageBuffer {
+                buffer,
+                offset,
+                range,
+                ..
+            } => Some(DynamicBufferBind {
+                storage: true,
+                buffer,
+                range,
+                offset,
+            }),
+            _ => None,
+        })
+        .collect()
 }
 
 fn record_shader_resources(
     device: &AshDevice,
     command_buffer: vk::CommandBuffer,
+    bind_point: vk::PipelineBindPoint,
     graph_resources: &RenderGraphResources,
     resources: &[ShaderResourceUsage],
-) {
+    push_constants: &[u8],
+) -> ash::prelude::VkResult<()> {
+    for bind in get_dynamic_buffer_binds(resources) {
+        let dynamic_sets = if bind.storage {
+            &graph_resources.persistent.dynamic_storage_buffer_sets
+        } else {
+            &graph_resources.persistent.dynamic_buffer_sets
+        };
+        let set = dynamic_sets.get_or_create(
+            graph_resources.buffers[bind.buffer].buffer.handle,
+            bind.range,
+        )?;
+        unsafe {
+            device.core.cmd_bind_descriptor_sets(
+                command_buffer,
+                bind_point,
+                graph_resources.get_pipeline_layout(),
+                dynamic_sets.set_index(),
+                &[set],
+                &[bind.offset],
+            );
+        }
+    }
+
+    let push_data_bytes: Vec<u8> = get_shader_resource_bindings(graph_resources, resources)
+        .into_iter()
+        .flat_map(|binding| binding.to_bytes())
+        .chain(push_constants.iter().copied())
+        .collect();
+
+    unsafe {
+        device.core.cmd_push_constants(
+            command_buffer,
+            graph_resources.get_pipeline_layout(),
+            vk::ShaderStageFlags::ALL,
+            0,
+            &push_data_bytes,
+        );
+    }
+    Ok(())
+}
+
+fn get_shader_resource_bindings(
+    graph_resources: &RenderGraphResources,
+    resources: &[ShaderResourceUsage],
+) -> Vec<GpuBindingIndex> {
     let mut push_bindings: Vec<GpuBindingIndex> = Vec::with_capacity(resources.len());
 
     for resource in resources.iter() {
@@ -1385,23 +2944,13 @@ fn record_shader_resources(
                 .as_ref()
                 .expect("Sampler is not bound")
                 .index(),
+            // Bound through sets 1 and 2 instead of a push constant index
+            ShaderResourceUsage::DynamicBuffer { .. }
+            | ShaderResourceUsage::DynamicStorageBuffer { .. } => continue,
         });
     }
 
-    let push_data_bytes: Vec<u8> = push_bindings
-        .drain(..)
-        .flat_map(|binding| binding.to_bytes())
-        .collect();
-
-    unsafe {
-        device.core.cmd_push_constants(
-            command_buffer,
-            graph_resources.get_pipeline_layout(),
-            vk::ShaderStageFlags::ALL,
-            0,
-            &push_data_bytes,
-        );
-    }
+    push_bindings
 }
 
 pub struct RenderGraphResources<'a> {
@@ -1426,7 +2975,493 @@ impl<'a> RenderGraphResources<'a> {
         self.pipelines.raster.get(pipeline.0).unwrap().handle
     }
 
+    pub(crate) fn get_raster_pipeline_dynamic_states(
+        &self,
+        pipeline: RasterPipelineHandle,
+    ) -> &[DynamicState] {
+        &self
+            .pipelines
+            .raster
+            .get(pipeline.0)
+            .unwrap()
+            .dynamic_states
+    }
+
+    pub(crate) fn get_raster_pipeline_flip_viewport_y(
+        &self,
+        pipeline: RasterPipelineHandle,
+    ) -> bool {
+        self.pipelines
+            .raster
+            .get(pipeline.0)
+            .unwrap()
+            .flip_viewport_y
+    }
+
     pub fn get_pipeline_layout(&self) -> vk::PipelineLayout {
         self.pipelines.layout
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_render_graph_builder::BasicRenderGraphBuilder;
+    use crate::render_graph_builder::{
+        BufferOffset, RasterDrawCommandBuilder, RasterPassBuilder, RenderGraphBuilderTrait,
+    };
+    use crate::{BufferUsage, TransientImageDesc, TransientImageSize};
+
+    #[test]
+    fn dynamic_offsets_are_recorded_per_draw() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let buffer = builder.create_transient_buffer(
+            512,
+            BufferUsage::UNIFORM | BufferUsage::TRANSFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        builder
+            .add_fill_buffer_pass(BufferOffset { buffer, offset: 0 }, 512, 0)
+            .unwrap();
+
+        let mut raster_pass = RasterPassBuilder::new("dynamic_pass");
+        raster_pass.add_color_attachment(image, None);
+        for offset in [0, 256] {
+            let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+            draw.read_dynamic_buffer(buffer, offset, 64);
+            draw.draw(0..3, 0..1);
+            draw.build(&mut raster_pass).unwrap();
+        }
+        raster_pass.build(&mut builder);
+        let render_graph = builder.build().unwrap();
+
+        let draw_commands = render_graph
+            .command_buffers
+            .iter()
+            .flat_map(|command_buffer| command_buffer.render_pass_sets.iter())
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .find_map(|render_pass| match &render_pass.command {
+                Some(RenderPassCommand::Raster { draw_commands, .. }) => Some(draw_commands),
+                _ => None,
+            })
+            .expect("Expected a raster pass");
+        let offsets: Vec<Vec<u32>> = draw_commands
+            .iter()
+            .map(|draw_command| {
+                get_dynamic_buffer_binds(&draw_command.resources)
+                    .iter()
+                    .map(|bind| {
+                        assert!(!bind.storage);
+                        assert_eq!(bind.range, 64);
+                        bind.offset
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(offsets, vec![vec![0], vec![256]]);
+    }
+
+    #[test]
+    fn submissions_are_labeled_with_their_queue_and_batch() {
+        assert_eq!(submission_label(Queue::Graphics, 0), "graphics batch 0");
+        assert_eq!(submission_label(Queue::Compute, 2), "async-compute batch 2");
+        assert_eq!(
+            submission_label(Queue::Transfer, 1),
+            "async-transfer batch 1"
+        );
+    }
+
+    #[test]
+    fn cached_passes_replay_until_their_signature_changes() {
+        let [first, second] = [1u64, 2].map(<vk::CommandBuffer as vk::Handle>::from_raw);
+        let mut passes = CachedRasterPasses::new(2);
+
+        assert!(passes.begin_frame().is_empty());
+        assert_eq!(passes.get("static_geometry", 7), None);
+        passes.insert("static_geometry", 7, first);
+
+        assert!(passes.begin_frame().is_empty());
+        assert_eq!(passes.get("static_geometry", 7), Some(first));
+
+        // A changed signature re-records, the old buffer is freed once no frame in flight can use it
+        assert!(passes.begin_frame().is_empty());
+        assert_eq!(passes.get("static_geometry", 8), None);
+        passes.insert("static_geometry", 8, second);
+        assert_eq!(passes.begin_frame(), vec![first]);
+        assert_eq!(passes.get("static_geometry", 8), Some(second));
+    }
+
+    #[test]
+    fn unused_cached_passes_are_freed_after_the_frames_in_flight() {
+        let command_buffer = <vk::CommandBuffer as vk::Handle>::from_raw(1);
+        let mut passes = CachedRasterPasses::new(2);
+        passes.begin_frame();
+        passes.insert("static_geometry", 7, command_buffer);
+
+        assert!(passes.begin_frame().is_empty());
+        assert_eq!(passes.begin_frame(), vec![command_buffer]);
+        assert_eq!(passes.get("static_geometry", 7), None);
+    }
+
+    #[cfg(feature = "track-access-history")]
+    #[test]
+    fn access_history_lists_each_pass_in_order() {
+        use crate::render_graph_builder::{ClearValue, ComputeDispatch, ShaderResourceUsage};
+        use crate::resource_managers::ImageResourceAccess;
+
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let mut raster_pass = RasterPassBuilder::new("draw");
+        raster_pass.add_color_attachment(image, Some(ClearValue::Float([0.0; 4])));
+        raster_pass.build(&mut builder);
+        builder.add_compute_pass(
+            "sample".to_string(),
+            [0.0; 4],
+            crate::render_graph::QueueType::Graphics,
+            crate::ComputePipelineHandle(Default::default()),
+            ComputeDispatch::Size([1, 1, 1]),
+            &[
+                ShaderResourceUsage::SampledImage(image),
+                ShaderResourceUsage::Sampler(crate::SamplerHandle(Default::default())),
+            ],
+        );
+        let image = builder.get_image_index(image);
+
+        let history = AccessHistory::new(&builder.build().unwrap());
+        assert_eq!(
+            history.images[image],
+            [
+                ("draw".to_string(), ImageResourceAccess::AttachmentWrite),
+                ("sample".to_string(), ImageResourceAccess::SampledRead),
+            ]
+        );
+    }
+
+    #[test]
+    fn waiting_on_a_frame_finds_its_context_while_in_flight() {
+        // Five frames submitted with two contexts, the contexts hold frames 4 and 3
+        let context_frame_ids = [Some(4), Some(3)];
+        let pending = |frame_id| pending_frame_context(context_frame_ids.into_iter(), 5, frame_id);
+
+        assert_eq!(pending(3).unwrap(), Some(1));
+        assert_eq!(pending(4).unwrap(), Some(0));
+        assert_eq!(pending(1).unwrap(), None);
+        assert!(matches!(pending(5), Err(VulkanError::FrameNotSubmitted(5))));
+    }
+
+    #[test]
+    fn command_buffers_are_reused_after_warm_up() {
+        let mut list = CommandBufferList::new(Vec::new());
+        let mut allocated = 0;
+        let mut run_frame = |list: &mut CommandBufferList, count| {
+            let mut new_allocations = 0;
+            for _ in 0..count {
+                let grow_count = list.grow_count();
+                new_allocations += grow_count;
+                list.command_buffers.extend((0..grow_count).map(|_| {
+                    allocated += 1;
+                    <vk::CommandBuffer as vk::Handle>::from_raw(allocated)
+                }));
+                list.next();
+            }
+            list.rewind();
+            new_allocations
+        };
+
+        assert!(run_frame(&mut list, 5) > 0);
+        assert_eq!(run_frame(&mut list, 5), 0);
+        assert_eq!(run_frame(&mut list, 3), 0);
+        assert_eq!(list.high_water_mark, 5);
+    }
+
+    fn depth_range_graph(
+        depth_range: [f32; 2],
+    ) -> Result<CompiledRenderGraph, crate::render_graph_builder::RenderGraphError> {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let mut raster_pass = RasterPassBuilder::new("decals");
+        raster_pass.add_color_attachment(image, None);
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.set_depth_range(depth_range[0], depth_range[1]);
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+        builder.build()
+    }
+
+    #[test]
+    fn draw_depth_range_reaches_the_viewport() {
+        let render_graph = depth_range_graph([0.25, 0.5]).unwrap();
+        let depth_range = render_graph.command_buffers[0].render_pass_sets[0].render_passes[0]
+            .command
+            .as_ref()
+            .and_then(|command| match command {
+                RenderPassCommand::Raster { draw_commands, .. } => draw_commands[0].depth_range,
+                _ => None,
+            })
+            .unwrap();
+
+        let viewport = raster_viewport(
+            vk::Extent2D {
+                width: 64,
+                height: 64,
+            },
+            depth_range,
+            false,
+        );
+        assert_eq!(viewport.min_depth, 0.25);
+        assert_eq!(viewport.max_depth, 0.5);
+    }
+
+    #[test]
+    fn depth_ranges_outside_zero_to_one_are_rejected() {
+        assert_eq!(
+            depth_range_graph([0.0, 1.5]).err(),
+            Some(
+                crate::render_graph_builder::RenderGraphError::InvalidDepthRange {
+                    pass: "decals".to_string(),
+                    draw_index: 0,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn binding_one_bind_point_leaves_the_other_unbound() {
+        use ash::vk::Handle;
+
+        let layout = vk::PipelineLayout::from_raw(1);
+        let mut bound_sets = BoundDescriptorSets::new(vk::DescriptorSet::null());
+
+        // A transfer-only command buffer never binds, so nothing is bound after it
+        bound_sets.reset();
+        assert_eq!(bound_sets.compute_layout, None);
+        assert_eq!(bound_sets.graphics_layout, None);
+
+        assert!(bound_sets.mark_bound(vk::PipelineBindPoint::COMPUTE, layout));
+        assert_eq!(bound_sets.compute_layout, Some(layout));
+        assert_eq!(bound_sets.graphics_layout, None);
+    }
+
+    #[test]
+    fn switching_pipeline_layouts_rebinds_the_set() {
+        use ash::vk::Handle;
+
+        let first_layout = vk::PipelineLayout::from_raw(1);
+        let second_layout = vk::PipelineLayout::from_raw(2);
+        let mut bound_sets = BoundDescriptorSets::new(vk::DescriptorSet::null());
+
+        let binds: Vec<bool> = [first_layout, first_layout, second_layout, first_layout]
+            .into_iter()
+            .map(|layout| bound_sets.mark_bound(vk::PipelineBindPoint::GRAPHICS, layout))
+            .collect();
+        assert_eq!(binds, [true, false, true, true]);
+
+        // Per-pass binding forgets the bound layout, so the next pass binds again
+        bound_sets.reset();
+        assert!(bound_sets.mark_bound(vk::PipelineBindPoint::GRAPHICS, first_layout));
+    }
+
+    #[test]
+    fn each_timed_pass_gets_one_timing() {
+        let pass_names = vec![
+            "shadows".to_string(),
+            "gbuffer".to_string(),
+            "tonemap".to_string(),
+        ];
+        // A 2ns timestamp period, each pass takes 5, 10 and 0 ticks
+        let timestamps = [100, 105, 105, 115, 120, 120];
+
+        let frame_timings =
+            FrameTimings::from_timestamps(7, pass_names, Vec::new(), &timestamps, 2.0);
+        assert_eq!(frame_timings.frame_id, 7);
+        let passes: Vec<(&str, u128)> = frame_timings
+            .passes
+            .iter()
+            .map(|pass| (pass.name.as_str(), pass.gpu_time.as_nanos()))
+            .collect();
+        assert_eq!(passes, [("shadows", 10), ("gbuffer", 20), ("tonemap", 0)]);
+        assert_eq!(frame_timings.total_gpu_time().as_nanos(), 30);
+    }
+
+    #[test]
+    fn pipelines_with_extra_color_targets_are_rejected() {
+        let single_target = [vk::Format::R8G8B8A8_UNORM];
+        let two_targets = [vk::Format::R8G8B8A8_UNORM, vk::Format::R16G16B16A16_SFLOAT];
+
+        assert!(check_color_targets("gbuffer", 0, &single_target, &single_target).is_ok());
+
+        let error = check_color_targets("gbuffer", 1, &two_targets, &single_target).unwrap_err();
+        assert!(matches!(
+            error,
+            VulkanError::ColorTargetMismatch {
+                ref pass,
+                draw_index: 1,
+                ref pipeline_formats,
+                ref framebuffer_formats,
+            } if pass == "gbuffer"
+                && pipeline_formats[..] == two_targets
+                && framebuffer_formats[..] == single_target
+        ));
+    }
+
+    #[test]
+    fn released_images_end_the_frame_in_their_release_layout() {
+        use ImageResourceAccess::{AttachmentWrite, SampledRead};
+
+        // A sampled image released as a color attachment for an external runtime
+        let (src, dst) = rest_transition(Some(SampledRead), Some(AttachmentWrite)).unwrap();
+        assert_eq!((src, dst), (SampledRead, AttachmentWrite));
+        assert_eq!(
+            dst.get_barrier_flags(true).layout,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        );
+
+        // Images already in their rest access, or unused this frame, aren't transitioned
+        assert_eq!(
+            rest_transition(Some(AttachmentWrite), Some(AttachmentWrite)),
+            None
+        );
+        assert_eq!(rest_transition(None, Some(AttachmentWrite)), None);
+    }
+
+    #[test]
+    fn flipped_viewports_start_at_the_bottom_with_a_negative_height() {
+        let extent = vk::Extent2D {
+            width: 1920,
+            height: 1080,
+        };
+
+        let flipped = raster_viewport(extent, [0.0, 1.0], true);
+        assert_eq!((flipped.x, flipped.y), (0.0, 1080.0));
+        assert_eq!((flipped.width, flipped.height), (1920.0, -1080.0));
+
+        let upright = raster_viewport(extent, [0.0, 1.0], false);
+        assert_eq!((upright.y, upright.height), (0.0, 1080.0));
+    }
+
+    #[test]
+    fn depth_only_pipelines_need_a_matching_depth_attachment() {
+        let depth_format = Some(vk::Format::D32_SFLOAT);
+        assert!(check_color_targets("shadow_map", 0, &[], &[]).is_ok());
+        assert!(check_depth_target("shadow_map", 0, depth_format, depth_format).is_ok());
+
+        assert!(matches!(
+            check_depth_target("shadow_map", 0, depth_format, None),
+            Err(VulkanError::DepthTargetMismatch {
+                pipeline_format: vk::Format::D32_SFLOAT,
+                framebuffer_format: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_depth_target("shadow_map", 0, depth_format, Some(vk::Format::D16_UNORM)),
+            Err(VulkanError::DepthTargetMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn sampled_only_images_rest_in_their_sampled_layout() {
+        use ImageResourceAccess::{AttachmentWrite, SampledRead, TransferWrite};
+
+        let texture_usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        let rest_access = ImageResourceAccess::steady_access(texture_usage);
+        assert_eq!(rest_access, Some(SampledRead));
+        let render_target_usage =
+            vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        assert_eq!(
+            ImageResourceAccess::steady_access(render_target_usage),
+            None
+        );
+
+        // The upload moves the texture into its sampled layout once
+        assert_eq!(
+            rest_transition(Some(TransferWrite), rest_access),
+            Some((TransferWrite, SampledRead))
+        );
+
+        // Frames that only sample it record neither a barrier nor an end of frame transition
+        assert!(!needs_image_barrier(SampledRead, SampledRead));
+        assert_eq!(rest_transition(Some(SampledRead), rest_access), None);
+
+        assert!(needs_image_barrier(AttachmentWrite, AttachmentWrite));
+        assert!(needs_image_barrier(TransferWrite, SampledRead));
+    }
+
+    #[test]
+    fn declared_blend_constants_can_be_set_per_draw() {
+        let mut builder = BasicRenderGraphBuilder::new();
+        let image = builder.create_transient_image(TransientImageDesc {
+            size: TransientImageSize::Exact(vk::Extent2D {
+                width: 64,
+                height: 64,
+            }),
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            mip_levels: 1,
+            memory_location: gpu_allocator::MemoryLocation::GpuOnly,
+        });
+        let mut raster_pass = RasterPassBuilder::new("blend_pass");
+        raster_pass.add_color_attachment(image, None);
+        let mut draw = RasterDrawCommandBuilder::new(RasterPipelineHandle(Default::default()));
+        draw.set_blend_constants([0.25, 0.5, 0.75, 1.0]);
+        draw.draw(0..3, 0..1);
+        draw.build(&mut raster_pass).unwrap();
+        raster_pass.build(&mut builder);
+        let render_graph = builder.build().unwrap();
+
+        let values = render_graph.command_buffers[0]
+            .render_pass_sets
+            .iter()
+            .flat_map(|render_pass_set| render_pass_set.render_passes.iter())
+            .find_map(|render_pass| match &render_pass.command {
+                Some(RenderPassCommand::Raster { draw_commands, .. }) => {
+                    Some(draw_commands[0].dynamic_state)
+                }
+                _ => None,
+            })
+            .expect("Expected a raster pass");
+        assert_eq!(values.blend_constants, Some([0.25, 0.5, 0.75, 1.0]));
+
+        // The executor then records cmd_set_blend_constants for the declared state
+        assert!(
+            check_dynamic_states("blend_pass", 0, &[DynamicState::BlendConstants], &values).is_ok()
+        );
+        assert!(matches!(
+            check_dynamic_states("blend_pass", 0, &[DynamicState::LineWidth], &values),
+            Err(VulkanError::UndeclaredDynamicState {
+                draw_index: 0,
+                state: DynamicState::BlendConstants,
+                ..
+            })
+        ));
+    }
+}
@@ -1,20 +1,27 @@
-use crate::buffer::{AshBuffer, Buffer};
-use crate::descriptor_set::{DescriptorCount, DescriptorSet};
-use crate::device::AshDevice;
+use crate::buffer::{AshBuffer, Buffer, BufferAllocationInfo};
+use crate::descriptor_set::{DescriptorCount, DescriptorSet, DynamicBufferSets};
+use crate::device::{AshDevice, StagingMemoryLocations};
 use crate::image::{AshImage, Image, TransientImageSize};
 use crate::render_graph::{
     BufferGraphResource, BufferOffset, BufferReads, BufferResourceDescription, ImageGraphResource,
     ImageResourceDescription,
 };
-use crate::render_graph_builder::BufferReadCallback;
+use crate::render_graph_builder::{AttachmentSubresource, BufferReadCallback};
 use crate::sampler::Sampler;
 use crate::swapchain::AcquiredSwapchainImage;
-use crate::{BufferKey, BufferUsage, ImageHandle, ImageKey, SamplerKey, VulkanError};
+use crate::{
+    AllocationScheme, BufferKey, BufferUsage, BufferWriteError, ImageHandle, ImageKey, SamplerKey,
+    UpdateAfterBindSupport, VulkanError,
+};
 use ash::vk;
 use gpu_allocator::vulkan::Allocation;
 use gpu_allocator::MemoryLocation;
+#[cfg(feature = "track-resources")]
+use log::info;
 use log::{error, warn};
 use slotmap::SlotMap;
+use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
 
 #[derive(Default, Debug, Eq, PartialEq, Copy, Clone)]
@@ -41,18 +48,54 @@ pub enum BufferResourceAccess {
     UniformRead,
     StorageRead,
     StorageWrite,
+    /// Geometry, index and instance data read by an acceleration structure build
+    AccelStructBuildRead,
+    /// Acceleration structure output and scratch memory written by a build
+    AccelStructBuildWrite,
 }
 
 impl BufferResourceAccess {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::TransferWrite | Self::StorageWrite | Self::AccelStructBuildWrite
+        )
+    }
+
+    /// The usage flags a buffer needs to be created with to support this access
+    pub fn required_usage(&self) -> BufferUsage {
+        match self {
+            Self::None => BufferUsage::empty(),
+            Self::TransferRead | Self::TransferWrite => BufferUsage::TRANSFER,
+            Self::VertexRead => BufferUsage::VERTEX,
+            Self::IndexRead => BufferUsage::INDEX,
+            Self::IndirectRead => BufferUsage::INDIRECT,
+            Self::UniformRead => BufferUsage::UNIFORM,
+            Self::StorageRead | Self::StorageWrite => BufferUsage::STORAGE,
+            Self::AccelStructBuildRead => BufferUsage::ACCELERATION_STRUCTURE_INPUT,
+            Self::AccelStructBuildWrite => BufferUsage::ACCELERATION_STRUCTURE_STORAGE,
+        }
+    }
+
+    /// Shader stages a buffer access is assumed to happen in when the pass type isn't known
+    pub const ALL_SHADER_STAGES: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::from_raw(
+        vk::PipelineStageFlags2::VERTEX_SHADER.as_raw()
+            | vk::PipelineStageFlags2::FRAGMENT_SHADER.as_raw()
+            | vk::PipelineStageFlags2::COMPUTE_SHADER.as_raw(),
+    );
+    // | vk::PipelineStageFlags2::TASK_SHADER_EXT
+    // | vk::PipelineStageFlags2::MESH_SHADER_EXT
+    // | vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR;
+
     pub fn get_barrier_flags(&self) -> BufferBarrierFlags {
-        //TODO: select shader flags based on pass type or pipeline?
-        let shader_all: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::VERTEX_SHADER
-            | vk::PipelineStageFlags2::FRAGMENT_SHADER
-            | vk::PipelineStageFlags2::COMPUTE_SHADER;
-        // | vk::PipelineStageFlags2::TASK_SHADER_EXT
-        // | vk::PipelineStageFlags2::MESH_SHADER_EXT
-        // | vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR;
+        self.get_stage_barrier_flags(Self::ALL_SHADER_STAGES)
+    }
 
+    /// Uniform and storage accesses are limited to `shader_stages`, e.g. only COMPUTE_SHADER for a compute pass
+    pub fn get_stage_barrier_flags(
+        &self,
+        shader_stages: vk::PipelineStageFlags2,
+    ) -> BufferBarrierFlags {
         match self {
             Self::None => BufferBarrierFlags {
                 stage_mask: vk::PipelineStageFlags2::NONE,
@@ -79,17 +122,27 @@ impl BufferResourceAccess {
                 access_mask: vk::AccessFlags2::INDIRECT_COMMAND_READ,
             },
             Self::UniformRead => BufferBarrierFlags {
-                stage_mask: shader_all,
+                stage_mask: shader_stages,
                 access_mask: vk::AccessFlags2::UNIFORM_READ,
             },
             Self::StorageRead => BufferBarrierFlags {
-                stage_mask: shader_all,
+                stage_mask: shader_stages,
                 access_mask: vk::AccessFlags2::SHADER_STORAGE_READ,
             },
             Self::StorageWrite => BufferBarrierFlags {
-                stage_mask: shader_all,
+                stage_mask: shader_stages,
                 access_mask: vk::AccessFlags2::SHADER_WRITE,
             },
+            Self::AccelStructBuildRead => BufferBarrierFlags {
+                stage_mask: vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+                access_mask: vk::AccessFlags2::SHADER_READ
+                    | vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR,
+            },
+            Self::AccelStructBuildWrite => BufferBarrierFlags {
+                stage_mask: vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+                access_mask: vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR
+                    | vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR,
+            },
         }
     }
 }
@@ -114,6 +167,41 @@ pub enum ImageResourceAccess {
 }
 
 impl ImageResourceAccess {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::TransferWrite | Self::AttachmentWrite | Self::StorageWrite
+        )
+    }
+
+    /// Access an image with this usage rests in between frames when none is set,
+    /// only images the gpu never writes outside of transfers stay sampled so reading them needs no barrier
+    pub fn steady_access(usage: vk::ImageUsageFlags) -> Option<Self> {
+        let gpu_written = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+            | vk::ImageUsageFlags::STORAGE;
+        (usage.contains(vk::ImageUsageFlags::SAMPLED) && !usage.intersects(gpu_written))
+            .then_some(Self::SampledRead)
+    }
+
+    /// Image usage flags an image needs for this access
+    pub fn required_usage(&self, is_color_image: bool) -> vk::ImageUsageFlags {
+        match self {
+            Self::None => vk::ImageUsageFlags::empty(),
+            Self::TransferRead => vk::ImageUsageFlags::TRANSFER_SRC,
+            Self::TransferWrite => vk::ImageUsageFlags::TRANSFER_DST,
+            Self::AttachmentWrite => {
+                if is_color_image {
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                } else {
+                    vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                }
+            }
+            Self::SampledRead => vk::ImageUsageFlags::SAMPLED,
+            Self::StorageRead | Self::StorageWrite => vk::ImageUsageFlags::STORAGE,
+        }
+    }
+
     pub fn get_barrier_flags(&self, is_color_image: bool) -> ImageBarrierFlags {
         //TODO: select shader flags based on pass type or pipeline?
         let shader_all: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::VERTEX_SHADER
@@ -174,6 +262,52 @@ impl ImageResourceAccess {
     }
 }
 
+/// Bytes used by movable buffers in each memory block while defragmenting. Buffers are moved out of the sparsest
+/// blocks first and a move is only kept when it lands in a block at least as full, so sparse blocks empty out
+/// and the allocator releases them
+pub struct BlockCompaction {
+    used_bytes: HashMap<vk::DeviceMemory, vk::DeviceSize>,
+}
+
+impl BlockCompaction {
+    /// Also returns the buffers in the order to move them, sparsest block first then largest buffer first
+    pub fn new<K: Copy>(buffers: &[(K, vk::DeviceMemory, vk::DeviceSize)]) -> (Self, Vec<K>) {
+        let mut used_bytes: HashMap<vk::DeviceMemory, vk::DeviceSize> = HashMap::new();
+        for &(_, memory, size) in buffers {
+            *used_bytes.entry(memory).or_default() += size;
+        }
+
+        let mut order = buffers.to_vec();
+        order.sort_by_key(|&(_, memory, size)| (used_bytes[&memory], std::cmp::Reverse(size)));
+        (
+            Self { used_bytes },
+            order.into_iter().map(|(key, _, _)| key).collect(),
+        )
+    }
+
+    /// Whether moving a buffer of size bytes between the blocks compacts memory, recording the move if it does.
+    /// Moves into a block no tracked buffer is in, e.g. one the allocator just created, are rejected
+    pub fn accept_move(
+        &mut self,
+        from: vk::DeviceMemory,
+        to: vk::DeviceMemory,
+        size: vk::DeviceSize,
+    ) -> bool {
+        let (Some(&from_used), Some(&to_used)) =
+            (self.used_bytes.get(&from), self.used_bytes.get(&to))
+        else {
+            return false;
+        };
+        if from == to || to_used < from_used {
+            return false;
+        }
+
+        self.used_bytes.insert(from, from_used.saturating_sub(size));
+        self.used_bytes.insert(to, to_used + size);
+        true
+    }
+}
+
 pub struct BufferResource {
     pub buffer: Buffer,
     pub queue_owner: Option<Queue>,
@@ -192,6 +326,9 @@ pub enum BufferTempDescription {
 pub struct MappedSlice {
     ptr: std::ptr::NonNull<std::ffi::c_void>,
     size: usize,
+
+    /// Memory and offset of an allocation without HOST_COHERENT, writes must be flushed and reads invalidated
+    non_coherent: Option<(vk::DeviceMemory, vk::DeviceSize)>,
 }
 
 impl MappedSlice {
@@ -199,9 +336,65 @@ impl MappedSlice {
         allocation.mapped_ptr().map(|ptr| Self {
             ptr,
             size: allocation.size() as usize,
+            non_coherent: (!allocation
+                .memory_properties()
+                .contains(vk::MemoryPropertyFlags::HOST_COHERENT))
+            .then(|| (unsafe { allocation.memory() }, allocation.offset())),
         })
     }
 
+    /// Makes host writes to `range` visible to the device, does nothing for coherent memory
+    pub fn flush(&self, device: &AshDevice, range: Range<usize>) -> ash::prelude::VkResult<()> {
+        match self.memory_range(device.non_coherent_atom_size, range) {
+            Some(memory_range) => unsafe {
+                device.core.flush_mapped_memory_ranges(&[memory_range])
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Makes device writes to `range` visible to the host, does nothing for coherent memory
+    pub fn invalidate(
+        &self,
+        device: &AshDevice,
+        range: Range<usize>,
+    ) -> ash::prelude::VkResult<()> {
+        match self.memory_range(device.non_coherent_atom_size, range) {
+            Some(memory_range) => unsafe {
+                device.core.invalidate_mapped_memory_ranges(&[memory_range])
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn memory_range(
+        &self,
+        atom_size: vk::DeviceSize,
+        range: Range<usize>,
+    ) -> Option<vk::MappedMemoryRange> {
+        let (memory, allocation_offset) = self.non_coherent?;
+        if range.is_empty() {
+            return None;
+        }
+
+        let start = (allocation_offset + range.start as vk::DeviceSize) / atom_size * atom_size;
+        let end = (allocation_offset + range.end as vk::DeviceSize).next_multiple_of(atom_size);
+
+        // Rounding up may pass the end of the memory object, which is only allowed as WHOLE_SIZE
+        let size = if end > allocation_offset + self.size as vk::DeviceSize {
+            vk::WHOLE_SIZE
+        } else {
+            end - start
+        };
+        Some(
+            vk::MappedMemoryRange::builder()
+                .memory(memory)
+                .offset(start)
+                .size(size)
+                .build(),
+        )
+    }
+
     pub fn slice(&self) -> &[u8] {
         unsafe { std::slice::from_raw_parts(self.ptr.cast().as_ptr(), self.size) }
     }
@@ -223,11 +416,36 @@ pub struct BufferTempResource {
 pub struct ImageResource {
     pub image: Image,
     pub last_access: ImageResourceAccess,
+
+    /// Access the image is transitioned to at the end of every frame it is used in
+    pub rest_access: Option<ImageResourceAccess>,
 }
 
 pub struct ImageTempResource {
     pub image: AshImage,
     pub last_access: ImageResourceAccess,
+    pub rest_access: Option<ImageResourceAccess>,
+    pub attachment_views: Vec<(AttachmentSubresource, vk::ImageView)>,
+}
+
+impl ImageTempResource {
+    pub fn get_attachment_view(&self, subresource: AttachmentSubresource) -> vk::ImageView {
+        if subresource.is_default() {
+            return self.image.view;
+        }
+
+        self.attachment_views
+            .iter()
+            .find(|(view_subresource, _)| *view_subresource == subresource)
+            .map(|(_, view)| *view)
+            .unwrap_or_else(|| {
+                error!(
+                    "No image view created for {:?}, using default view",
+                    subresource
+                );
+                self.image.view
+            })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -254,10 +472,88 @@ struct TempBufferRead {
     callback: BufferReadCallback,
 }
 
+/// Live resource counts, excluding resources already queued for deletion
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceCounts {
+    pub buffers: usize,
+    pub images: usize,
+    pub samplers: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LiveBuffer {
+    usage: vk::BufferUsageFlags,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LiveImage {
+    usage: vk::ImageUsageFlags,
+    mip_levels: u32,
+    array_layers: u32,
+}
+
+/// Snapshot of the persistent resources that haven't been destroyed and their usage,
+/// used to catch stale handles and resources bound in ways they weren't created for
+#[derive(Debug, Default, Clone)]
+pub struct LiveResources {
+    buffers: HashMap<BufferKey, LiveBuffer>,
+    images: HashMap<ImageKey, LiveImage>,
+}
+
+impl LiveResources {
+    pub fn contains_buffer(&self, key: BufferKey) -> bool {
+        self.buffers.contains_key(&key)
+    }
+
+    pub fn contains_image(&self, key: ImageKey) -> bool {
+        self.images.contains_key(&key)
+    }
+
+    pub fn buffer_usage(&self, key: BufferKey) -> Option<vk::BufferUsageFlags> {
+        self.buffers.get(&key).map(|buffer| buffer.usage)
+    }
+
+    pub fn buffer_size(&self, key: BufferKey) -> Option<u64> {
+        self.buffers.get(&key).map(|buffer| buffer.size)
+    }
+
+    pub fn image_usage(&self, key: ImageKey) -> Option<vk::ImageUsageFlags> {
+        self.images.get(&key).map(|image| image.usage)
+    }
+
+    /// Mip level and array layer counts of the image
+    pub fn image_subresource_counts(&self, key: ImageKey) -> Option<(u32, u32)> {
+        self.images
+            .get(&key)
+            .map(|image| (image.mip_levels, image.array_layers))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn insert_image(&mut self, key: ImageKey, usage: vk::ImageUsageFlags) {
+        self.insert_image_array(key, usage, 1);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn insert_image_array(
+        &mut self,
+        key: ImageKey,
+        usage: vk::ImageUsageFlags,
+        array_layers: u32,
+    ) {
+        self.images.insert(
+            key,
+            LiveImage {
+                usage,
+                mip_levels: 1,
+                array_layers,
+            },
+        );
+    }
+}
+
 #[derive(Default)]
 struct ResourceFrame {
-    freed_buffers: Vec<BufferKey>,
-    freed_images: Vec<ImageKey>,
     transient_buffers: Vec<Buffer>,
     transient_images: Vec<Image>,
 
@@ -271,32 +567,44 @@ pub struct ResourceManager {
     device: Arc<AshDevice>,
 
     pub(crate) descriptor_set: DescriptorSet,
+    pub(crate) dynamic_buffer_sets: DynamicBufferSets,
+    pub(crate) dynamic_storage_buffer_sets: DynamicBufferSets,
 
     pub(crate) buffers: SlotMap<BufferKey, BufferResource>,
-    freed_buffers: Vec<BufferKey>,
+    /// Removed keys along with the last frame that may still use them, see [`ResourceManager::last_use_frame`]
+    freed_buffers: Vec<(u64, BufferKey)>,
 
     images: SlotMap<ImageKey, ImageResource>,
-    freed_images: Vec<ImageKey>,
+    freed_images: Vec<(u64, ImageKey)>,
+
+    /// Number of flush_frame calls a removed resource waits before being destroyed,
+    /// must equal the executor's frames in flight
+    deletion_delay: u64,
+    flushed_frames: u64,
 
     samplers: SlotMap<SamplerKey, Arc<Sampler>>,
 
+    staging_memory: StagingMemoryLocations,
     frames_in_flight: Vec<ResourceFrame>,
     frame_index: usize,
 }
 
 impl ResourceManager {
-    pub fn new(device: Arc<AshDevice>, frame_in_flight_count: u32) -> Self {
-        let descriptor_set = DescriptorSet::new(
-            device.clone(),
-            DescriptorCount {
-                storage_buffers: 1024,
-                storage_images: 1024,
-                sampled_images: 1024,
-                samplers: 128,
-                ..Default::default()
-            },
-        )
-        .unwrap();
+    pub fn new(
+        device: Arc<AshDevice>,
+        frame_in_flight_count: u32,
+        descriptor_count: DescriptorCount,
+        update_after_bind: UpdateAfterBindSupport,
+        staging_memory: StagingMemoryLocations,
+    ) -> Self {
+        assert_ne!(
+            frame_in_flight_count, 0,
+            "Requires at least one frame in flight"
+        );
+        let descriptor_set =
+            DescriptorSet::new(device.clone(), descriptor_count, update_after_bind).unwrap();
+        let dynamic_buffer_sets = DynamicBufferSets::new_uniform(device.clone()).unwrap();
+        let dynamic_storage_buffer_sets = DynamicBufferSets::new_storage(device.clone()).unwrap();
 
         let frames_in_flight = (0..frame_in_flight_count as usize)
             .map(|_| ResourceFrame::default())
@@ -311,50 +619,178 @@ impl ResourceManager {
             images: SlotMap::with_key(),
             freed_images: Vec::new(),
 
+            deletion_delay: frame_in_flight_count as u64,
+            flushed_frames: 0,
+
             samplers: SlotMap::with_key(),
 
             descriptor_set,
+            dynamic_buffer_sets,
+            dynamic_storage_buffer_sets,
+            staging_memory,
             frames_in_flight,
             frame_index: 0,
         }
     }
 
+    /// Flushes called before the resource was removed don't count towards its deletion delay
+    pub fn deletion_delay(&self) -> u64 {
+        self.deletion_delay
+    }
+
+    /// A resource removed now can still be recorded into the next submitted frame, which flushes before recording
+    fn last_use_frame(&self) -> u64 {
+        self.flushed_frames + 1
+    }
+
+    /// Must be called once per frame after the frame submitted deletion_delay frames ago has finished
     pub fn flush_frame(&mut self) {
+        self.flushed_frames += 1;
         self.frame_index = (self.frame_index + 1) % self.frames_in_flight.len();
         let frame = &mut self.frames_in_flight[self.frame_index];
 
         //Read callbacks
         for buffer_read in frame.buffer_reads.drain(..) {
-            let slice = match &buffer_read.source {
-                ReadSource::StagingBuffer => frame
-                    .read_staging_buffer
-                    .as_ref()
-                    .expect("Tried to read staging buffer that doesn't exist")
-                    .allocation
-                    .mapped_slice()
-                    .unwrap(),
-                ReadSource::TempBuffer(buffer) => buffer.mapped_slice.as_ref().unwrap().slice(),
+            let mapped_slice = match &buffer_read.source {
+                ReadSource::StagingBuffer => MappedSlice::new(
+                    &frame
+                        .read_staging_buffer
+                        .as_ref()
+                        .expect("Tried to read staging buffer that doesn't exist")
+                        .allocation,
+                )
+                .unwrap(),
+                ReadSource::TempBuffer(buffer) => buffer.mapped_slice.clone().unwrap(),
             };
+            let read_range = buffer_read.offset..(buffer_read.offset + buffer_read.size);
+            if let Err(err) = mapped_slice.invalidate(&self.device, read_range) {
+                error!("Failed to invalidate buffer read memory: {}", err);
+            }
+            let slice = mapped_slice.slice();
             buffer_read
                 .callback
                 .call(&slice[buffer_read.offset..(buffer_read.offset + buffer_read.size)]);
         }
 
-        for key in frame.freed_buffers.drain(..) {
-            if self.buffers.remove(key).is_none() {
-                warn!("BufferKey({:?}) was invalid on deletion", key);
-            }
+        let (flushed_frames, deletion_delay) = (self.flushed_frames, self.deletion_delay);
+        let dynamic_buffer_sets = [&self.dynamic_buffer_sets, &self.dynamic_storage_buffer_sets];
+        destroy_due(
+            &mut self.freed_buffers,
+            &mut self.buffers,
+            flushed_frames,
+            deletion_delay,
+            |resource| {
+                dynamic_buffer_sets
+                    .iter()
+                    .for_each(|sets| sets.free_buffer(resource.buffer.handle))
+            },
+        );
+        destroy_due(
+            &mut self.freed_images,
+            &mut self.images,
+            flushed_frames,
+            deletion_delay,
+            drop,
+        );
+
+        for buffer in frame.transient_buffers.drain(..) {
+            self.dynamic_buffer_sets.free_buffer(buffer.handle);
+            self.dynamic_storage_buffer_sets.free_buffer(buffer.handle);
         }
-        for key in frame.freed_images.drain(..) {
-            if self.images.remove(key).is_none() {
-                warn!("ImageKey({:?}) was invalid on deletion", key);
-            }
+        frame.transient_images.clear();
+    }
+
+    pub fn resource_counts(&self) -> ResourceCounts {
+        ResourceCounts {
+            buffers: live_count(&self.buffers, &self.freed_buffers),
+            images: live_count(&self.images, &self.freed_images),
+            samplers: self.samplers.len(),
         }
+    }
 
-        frame.freed_buffers = std::mem::take(&mut self.freed_buffers);
-        frame.freed_images = std::mem::take(&mut self.freed_images);
-        frame.transient_buffers.clear();
-        frame.transient_images.clear();
+    /// Destroyed resources stay in the slotmaps until the gpu is done with them, so those are excluded
+    pub fn live_resources(&self) -> LiveResources {
+        let mut live_resources = LiveResources {
+            buffers: self
+                .buffers
+                .iter()
+                .map(|(key, resource)| {
+                    (
+                        key,
+                        LiveBuffer {
+                            usage: resource.buffer.usage,
+                            size: resource.buffer.size,
+                        },
+                    )
+                })
+                .collect(),
+            images: self
+                .images
+                .iter()
+                .map(|(key, resource)| {
+                    (
+                        key,
+                        LiveImage {
+                            usage: resource.image.usage,
+                            mip_levels: resource.image.mip_levels,
+                            array_layers: resource.image.array_layers,
+                        },
+                    )
+                })
+                .collect(),
+        };
+        for (_, key) in self.freed_buffers.iter() {
+            live_resources.buffers.remove(key);
+        }
+        for (_, key) in self.freed_images.iter() {
+            live_resources.images.remove(key);
+        }
+        live_resources
+    }
+
+    /// Logs every live resource, useful for finding which subsystem is leaking handles
+    #[cfg(feature = "track-resources")]
+    pub fn debug_dump_resources(&self) {
+        let pending_buffers: Vec<BufferKey> =
+            self.freed_buffers.iter().map(|&(_, key)| key).collect();
+        let pending_images: Vec<ImageKey> = self.freed_images.iter().map(|&(_, key)| key).collect();
+
+        let counts = self.resource_counts();
+        info!(
+            "Live resources: {} buffers, {} images, {} samplers",
+            counts.buffers, counts.images, counts.samplers
+        );
+        for (key, resource) in self
+            .buffers
+            .iter()
+            .filter(|(key, _)| !pending_buffers.contains(key))
+        {
+            info!(
+                "Buffer({:?}) {:?}: {} bytes {:?} {:?}",
+                key,
+                resource.buffer.name,
+                resource.buffer.size,
+                resource.buffer.usage,
+                resource.buffer.location
+            );
+        }
+        for (key, resource) in self
+            .images
+            .iter()
+            .filter(|(key, _)| !pending_images.contains(key))
+        {
+            info!(
+                "Image({:?}) {:?}: {:?} {:?} {:?}",
+                key,
+                resource.image.name,
+                resource.image.size,
+                resource.image.format,
+                resource.image.usage
+            );
+        }
+        for key in self.samplers.keys() {
+            info!("Sampler({:?})", key);
+        }
     }
 
     //Buffers
@@ -364,6 +800,7 @@ impl ResourceManager {
         size: usize,
         usage: BufferUsage,
         location: MemoryLocation,
+        allocation_scheme: AllocationScheme,
     ) -> Result<BufferKey, VulkanError> {
         let mut buffer = Buffer::new(
             self.device.clone(),
@@ -371,6 +808,7 @@ impl ResourceManager {
             size as vk::DeviceSize,
             usage.to_vk(),
             location,
+            allocation_scheme,
         )?;
 
         if buffer.usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
@@ -396,7 +834,102 @@ impl ResourceManager {
         })
     }
     pub fn remove_buffer(&mut self, key: BufferKey) {
-        self.freed_buffers.push(key);
+        let last_use_frame = self.last_use_frame();
+        queue_deletion(&mut self.freed_buffers, &self.buffers, last_use_frame, key);
+    }
+    /// Swaps a new buffer of the given size in under the same key, returning None for invalid keys.
+    /// The old buffer is moved to a new key that is already queued for deletion,
+    /// so it outlives both in flight frames and any copy out of it
+    pub fn resize_buffer(
+        &mut self,
+        key: BufferKey,
+        size: usize,
+    ) -> Result<Option<BufferKey>, VulkanError> {
+        let Some(old_resource) = self.replace_buffer(key, size)? else {
+            return Ok(None);
+        };
+        let old_key = self.buffers.insert(old_resource);
+        let last_use_frame = self.last_use_frame();
+        queue_deletion(
+            &mut self.freed_buffers,
+            &self.buffers,
+            last_use_frame,
+            old_key,
+        );
+        Ok(Some(old_key))
+    }
+    /// Swaps a new buffer of the given size in under the same key, returning the old buffer or None for invalid keys.
+    /// The old buffer must be put back with restore_buffer or destroyed
+    pub fn replace_buffer(
+        &mut self,
+        key: BufferKey,
+        size: usize,
+    ) -> Result<Option<BufferResource>, VulkanError> {
+        let Some(resource) = self.buffers.get(key) else {
+            return Ok(None);
+        };
+
+        #[cfg(feature = "track-resources")]
+        let name = resource.buffer.name.clone();
+        #[cfg(not(feature = "track-resources"))]
+        let name = String::from("Resized Buffer");
+
+        let mut buffer = Buffer::new(
+            self.device.clone(),
+            &name,
+            size as vk::DeviceSize,
+            resource.buffer.usage,
+            resource.buffer.location,
+            resource.buffer.allocation_scheme,
+        )?;
+        if buffer.usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+            buffer.storage_binding = Some(self.descriptor_set.bind_storage_buffer(&buffer));
+        }
+
+        Ok(Some(std::mem::replace(
+            &mut self.buffers[key],
+            BufferResource {
+                buffer,
+                queue_owner: None,
+                last_access: Default::default(),
+            },
+        )))
+    }
+    /// Undoes replace_buffer, the replacement is destroyed right away so the device must be idle
+    pub fn restore_buffer(&mut self, key: BufferKey, old_resource: BufferResource) {
+        let replacement = std::mem::replace(&mut self.buffers[key], old_resource);
+        self.destroy_idle_buffer(replacement);
+    }
+    /// Destroys a buffer right away instead of after the deletion delay, the device must be idle
+    pub fn destroy_idle_buffer(&self, resource: BufferResource) {
+        self.dynamic_buffer_sets.free_buffer(resource.buffer.handle);
+        self.dynamic_storage_buffer_sets
+            .free_buffer(resource.buffer.handle);
+    }
+    /// Buffers with suballocated memory that aren't queued for deletion, with the memory block each is in
+    pub fn movable_buffers(&self) -> Vec<(BufferKey, vk::DeviceMemory, vk::DeviceSize)> {
+        self.buffers
+            .iter()
+            .filter(|(key, resource)| {
+                resource.buffer.allocation_scheme == AllocationScheme::Managed
+                    && !self
+                        .freed_buffers
+                        .iter()
+                        .any(|(_, freed_key)| freed_key == key)
+            })
+            .map(|(key, resource)| {
+                (
+                    key,
+                    unsafe { resource.buffer.allocation.memory() },
+                    resource.buffer.size,
+                )
+            })
+            .collect()
+    }
+    pub fn buffer_allocation_info(&self, key: BufferKey) -> Option<BufferAllocationInfo> {
+        self.buffers
+            .get(key)
+            .map(|resource| resource.buffer.allocation_info())
     }
 
     //Images
@@ -407,18 +940,27 @@ impl ResourceManager {
 
         if image.usage.contains(vk::ImageUsageFlags::SAMPLED) {
             image.sampled_binding = Some(self.descriptor_set.bind_sampled_image(&image));
+            image.stencil_sampled_binding = self.descriptor_set.bind_sampled_stencil_image(&image);
         }
 
+        let rest_access = ImageResourceAccess::steady_access(image.usage);
         self.images.insert(ImageResource {
             image,
             last_access: ImageResourceAccess::None,
+            rest_access,
         })
     }
     pub fn get_image(&self, key: ImageKey) -> Option<&Image> {
         self.images.get(key).map(|resource| &resource.image)
     }
     pub fn remove_image(&mut self, key: ImageKey) {
-        self.freed_images.push(key);
+        let last_use_frame = self.last_use_frame();
+        queue_deletion(&mut self.freed_images, &self.images, last_use_frame, key);
+    }
+    pub fn set_image_rest_access(&mut self, key: ImageKey, access: Option<ImageResourceAccess>) {
+        if let Some(image) = self.images.get_mut(key) {
+            image.rest_access = access;
+        }
     }
 
     //Samplers
@@ -442,6 +984,7 @@ impl ResourceManager {
         buffer_size: usize,
         buffer_usage: vk::BufferUsageFlags,
         buffer_location: MemoryLocation,
+        preferred_memory_types: u32,
     ) -> Result<Option<BufferTempResource>, VulkanError> {
         if buffer_size == 0 {
             return Ok(None);
@@ -453,12 +996,14 @@ impl ResourceManager {
             .unwrap_or_default();
 
         if buffer_size > current_size {
-            *buffer = Some(Buffer::new(
+            *buffer = Some(Buffer::new_preferring_memory_types(
                 device.clone(),
                 buffer_name,
                 buffer_size as vk::DeviceSize,
                 buffer_usage,
                 buffer_location,
+                preferred_memory_types,
+                AllocationScheme::Managed,
             )?);
         }
 
@@ -482,7 +1027,8 @@ impl ResourceManager {
             "Write Staging Buffer",
             buffer_size,
             vk::BufferUsageFlags::TRANSFER_SRC,
-            MemoryLocation::CpuToGpu,
+            self.staging_memory.write,
+            0,
         )
     }
 
@@ -498,6 +1044,17 @@ impl ResourceManager {
         for buffer_read in buffer_reads.buffer_reads.iter() {
             let buffer = buffer_resources[buffer_read.buffer_offset.buffer].clone();
 
+            let offset = buffer_read.buffer_offset.offset as usize;
+            let buffer_size = buffer.buffer.size as usize;
+            if offset + buffer_read.read_size > buffer_size {
+                return Err(BufferWriteError::ReadOutOfBounds {
+                    offset,
+                    size: buffer_read.read_size,
+                    buffer_size,
+                }
+                .into());
+            }
+
             if buffer.supports_direct_upload {
                 frame_buffer_reads.push(TempBufferRead {
                     source: ReadSource::TempBuffer(buffer),
@@ -534,7 +1091,9 @@ impl ResourceManager {
             "Read Staging Buffer",
             staging_offset,
             vk::BufferUsageFlags::TRANSFER_DST,
-            MemoryLocation::GpuToCpu,
+            self.staging_memory.read,
+            self.staging_memory
+                .read_memory_types(self.device.host_cached_memory_types),
         )?;
 
         Ok(staging_buffer.map(|staging_buffer| StagingBufferTemp {
@@ -588,6 +1147,7 @@ impl ResourceManager {
                         *size as vk::DeviceSize,
                         usage.to_vk(),
                         *location,
+                        AllocationScheme::Managed,
                     )?;
                     if buffer.usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
                         buffer.storage_binding =
@@ -626,12 +1186,16 @@ impl ResourceManager {
                     let image = &mut self.images[*key];
                     //TODO: get usages with multiple frames in flight
                     //TODO: write last usages + queue + layout
+                    let rest_access =
+                        frame_rest_access(graph_image.release_access, image.rest_access);
+                    let frame_last_access = frame_end_access(graph_image.last_access, rest_access);
+                    let attachment_views =
+                        get_attachment_views(&mut image.image, &graph_image.attachment_views)?;
                     ImageTempResource {
                         image: image.image.get_copy(),
-                        last_access: std::mem::replace(
-                            &mut image.last_access,
-                            graph_image.last_access.unwrap_or_default(),
-                        ),
+                        last_access: std::mem::replace(&mut image.last_access, frame_last_access),
+                        rest_access,
+                        attachment_views,
                     }
                 }
                 ImageResourceDescription::Transient(transient_image_description) => {
@@ -643,8 +1207,19 @@ impl ResourceManager {
                     );
                     let image_description = transient_image_description
                         .to_image_description([image_size.width, image_size.height]);
-                    let mut image =
-                        Image::new_2d(self.device.clone(), "Transient Image", &image_description)?;
+                    let mut image = match transient_image_description.size {
+                        TransientImageSize::Volume(size) => Image::new_3d(
+                            self.device.clone(),
+                            "Transient Image",
+                            &image_description,
+                            size[2],
+                        )?,
+                        _ => Image::new_2d(
+                            self.device.clone(),
+                            "Transient Image",
+                            &image_description,
+                        )?,
+                    };
 
                     if image.usage.contains(vk::ImageUsageFlags::STORAGE) {
                         image.storage_binding =
@@ -654,11 +1229,17 @@ impl ResourceManager {
                     if image.usage.contains(vk::ImageUsageFlags::SAMPLED) {
                         image.sampled_binding =
                             Some(self.descriptor_set.bind_sampled_image(&image));
+                        image.stencil_sampled_binding =
+                            self.descriptor_set.bind_sampled_stencil_image(&image);
                     }
 
+                    let attachment_views =
+                        get_attachment_views(&mut image, &graph_image.attachment_views)?;
                     let resource = ImageTempResource {
                         image: image.get_copy(),
                         last_access: ImageResourceAccess::None, //Never used before
+                        rest_access: None,
+                        attachment_views,
                     };
                     let frame = &mut self.frames_in_flight[self.frame_index];
                     frame.transient_images.push(image);
@@ -669,6 +1250,8 @@ impl ResourceManager {
                     ImageTempResource {
                         image: swapchain_images[*index].image,
                         last_access: ImageResourceAccess::None,
+                        rest_access: None,
+                        attachment_views: Vec::new(),
                     }
                 }
             });
@@ -686,6 +1269,10 @@ fn get_transient_image_size(
 ) -> vk::Extent2D {
     match size {
         TransientImageSize::Exact(extent) => extent,
+        TransientImageSize::Volume(size) => vk::Extent2D {
+            width: size[0],
+            height: size[1],
+        },
         TransientImageSize::Relative(scale, target) => {
             let mut extent = match target {
                 ImageHandle::Persistent(image_key) => {
@@ -718,3 +1305,246 @@ fn get_transient_image_size(
         }
     }
 }
+
+fn get_attachment_views(
+    image: &mut Image,
+    subresources: &[AttachmentSubresource],
+) -> Result<Vec<(AttachmentSubresource, vk::ImageView)>, VulkanError> {
+    let mut attachment_views = Vec::with_capacity(subresources.len());
+    for subresource in subresources {
+        attachment_views.push((*subresource, image.get_subresource_view(*subresource)?));
+    }
+    Ok(attachment_views)
+}
+
+/// A removed resource is destroyed once the frame that last used it is `deletion_delay` flushes in the past,
+/// flushing then means the executor has waited on that frame's context
+fn deletion_due(flushed_frames: u64, last_use_frame: u64, deletion_delay: u64) -> bool {
+    flushed_frames >= last_use_frame + deletion_delay
+}
+
+/// Queues a live resource for deletion. Invalid keys and repeat removals are ignored,
+/// so each resource is only subtracted from the live count and destroyed once
+fn queue_deletion<K: slotmap::Key, V>(
+    freed: &mut Vec<(u64, K)>,
+    resources: &SlotMap<K, V>,
+    last_use_frame: u64,
+    key: K,
+) {
+    if !resources.contains_key(key) {
+        warn!("Tried to remove invalid {:?}", key);
+    } else if freed.iter().any(|&(_, freed_key)| freed_key == key) {
+        warn!("Tried to remove {:?} more than once", key);
+    } else {
+        freed.push((last_use_frame, key));
+    }
+}
+
+/// Removes the queued resources whose deletion is due, passing each to destroy
+fn destroy_due<K: slotmap::Key, V>(
+    freed: &mut Vec<(u64, K)>,
+    resources: &mut SlotMap<K, V>,
+    flushed_frames: u64,
+    deletion_delay: u64,
+    mut destroy: impl FnMut(V),
+) {
+    freed.retain(|&(last_use_frame, key)| {
+        if !deletion_due(flushed_frames, last_use_frame, deletion_delay) {
+            return true;
+        }
+        match resources.remove(key) {
+            Some(resource) => destroy(resource),
+            None => warn!("{:?} was invalid on deletion", key),
+        }
+        false
+    });
+}
+
+/// Resources still in the slotmap that aren't waiting to be deleted
+fn live_count<K: slotmap::Key, V>(resources: &SlotMap<K, V>, freed: &[(u64, K)]) -> usize {
+    resources.len().saturating_sub(freed.len())
+}
+
+/// A release access set for this frame overrides the image's rest access
+fn frame_rest_access(
+    release_access: Option<ImageResourceAccess>,
+    rest_access: Option<ImageResourceAccess>,
+) -> Option<ImageResourceAccess> {
+    release_access.or(rest_access)
+}
+
+/// Access a persistent image is left in after a frame that used it, its rest access if it has one
+fn frame_end_access(
+    last_access: Option<ImageResourceAccess>,
+    rest_access: Option<ImageResourceAccess>,
+) -> ImageResourceAccess {
+    last_access
+        .map(|access| rest_access.unwrap_or(access))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_with_a_rest_access_end_the_frame_in_it() {
+        use ImageResourceAccess::{AttachmentWrite, SampledRead};
+        assert_eq!(
+            frame_end_access(Some(AttachmentWrite), Some(SampledRead)),
+            SampledRead
+        );
+        assert_eq!(
+            frame_end_access(Some(SampledRead), Some(SampledRead)),
+            SampledRead
+        );
+        assert_eq!(
+            frame_end_access(Some(AttachmentWrite), None),
+            AttachmentWrite
+        );
+    }
+
+    #[test]
+    fn compaction_empties_the_sparsest_block_first() {
+        let [sparse, dense, fresh] = [1u64, 2, 3].map(<vk::DeviceMemory as vk::Handle>::from_raw);
+        let buffers = [
+            ("dense_small", dense, 64),
+            ("sparse_small", sparse, 16),
+            ("dense_large", dense, 256),
+            ("sparse_large", sparse, 32),
+        ];
+        let (mut compaction, order) = BlockCompaction::new(&buffers);
+        assert_eq!(
+            order,
+            vec!["sparse_large", "sparse_small", "dense_large", "dense_small"]
+        );
+
+        // Moving within a block, into a sparser block or into a block the allocator just created doesn't compact
+        assert!(!compaction.accept_move(sparse, sparse, 32));
+        assert!(!compaction.accept_move(dense, sparse, 64));
+        assert!(!compaction.accept_move(sparse, fresh, 32));
+
+        assert!(compaction.accept_move(sparse, dense, 32));
+        assert!(compaction.accept_move(sparse, dense, 16));
+
+        // Once moved the dense block doesn't move back into the emptied one
+        assert!(!compaction.accept_move(dense, sparse, 256));
+    }
+
+    #[test]
+    fn removed_resources_outlive_every_frame_that_may_use_them() {
+        const FRAMES_IN_FLIGHT: u64 = 2;
+
+        // Removed between the first frame's flush and the second frame's, so the second frame may still use it
+        let flushed_frames_at_removal = 1;
+        let last_use_frame = flushed_frames_at_removal + 1;
+        assert!(
+            !deletion_due(
+                flushed_frames_at_removal + FRAMES_IN_FLIGHT,
+                last_use_frame,
+                FRAMES_IN_FLIGHT
+            ),
+            "the frame submitted after the removal may still be in flight"
+        );
+        assert!(deletion_due(
+            flushed_frames_at_removal + FRAMES_IN_FLIGHT + 1,
+            last_use_frame,
+            FRAMES_IN_FLIGHT
+        ));
+    }
+
+    #[test]
+    fn release_access_overrides_the_rest_access_for_the_frame() {
+        use ImageResourceAccess::{AttachmentWrite, SampledRead};
+        let rest_access = frame_rest_access(Some(AttachmentWrite), Some(SampledRead));
+        assert_eq!(rest_access, Some(AttachmentWrite));
+        assert_eq!(
+            frame_end_access(Some(SampledRead), rest_access),
+            AttachmentWrite
+        );
+        assert_eq!(
+            frame_rest_access(None, Some(SampledRead)),
+            Some(SampledRead)
+        );
+    }
+
+    #[test]
+    fn writes_to_non_coherent_memory_flush_the_written_atoms() {
+        use ash::vk::Handle;
+
+        let mut backing = [0u8; 1024];
+        let ptr = std::ptr::NonNull::new(backing.as_mut_ptr().cast()).unwrap();
+        let mapped_slice = |non_coherent| MappedSlice {
+            ptr,
+            size: 1024,
+            non_coherent,
+        };
+        let memory = vk::DeviceMemory::from_raw(1);
+
+        // The allocation starts 256 bytes into its memory object, ranges are rounded out to 64 byte atoms
+        let non_coherent = mapped_slice(Some((memory, 256)));
+        let memory_range = non_coherent.memory_range(64, 10..100).unwrap();
+        assert_eq!(memory_range.memory, memory);
+        assert_eq!((memory_range.offset, memory_range.size), (256, 128));
+
+        let memory_range = non_coherent.memory_range(64, 1000..1024).unwrap();
+        assert_eq!((memory_range.offset, memory_range.size), (1216, 64));
+
+        // Rounding past the end of the allocation flushes the rest of the memory object instead
+        let memory_range = non_coherent.memory_range(48, 1000..1024).unwrap();
+        assert_eq!(
+            (memory_range.offset, memory_range.size),
+            (1248, vk::WHOLE_SIZE)
+        );
+
+        assert!(non_coherent.memory_range(64, 10..10).is_none());
+        assert!(mapped_slice(None).memory_range(64, 10..100).is_none());
+    }
+
+    #[test]
+    fn live_counts_reach_zero_after_the_deletion_delay() {
+        const DELETION_DELAY: u64 = 3;
+        let mut buffers = SlotMap::<crate::BufferKey, ()>::with_key();
+        let mut freed = Vec::new();
+        let keys: Vec<_> = (0..3).map(|_| buffers.insert(())).collect();
+        assert_eq!(live_count(&buffers, &freed), 3);
+
+        // Removed like ResourceManager::remove_buffer just before the frame flushed next
+        let mut flushed_frames = 0;
+        for &key in keys.iter() {
+            queue_deletion(&mut freed, &buffers, flushed_frames + 1, key);
+        }
+        queue_deletion(&mut freed, &buffers, flushed_frames + 1, keys[0]);
+        assert_eq!(freed.len(), 3);
+        assert_eq!(live_count(&buffers, &freed), 0);
+
+        let mut destroyed = 0;
+        for _ in 0..DELETION_DELAY {
+            assert_eq!(buffers.len(), 3);
+            flushed_frames += 1;
+            destroy_due(
+                &mut freed,
+                &mut buffers,
+                flushed_frames,
+                DELETION_DELAY,
+                |_| destroyed += 1,
+            );
+            assert_eq!(live_count(&buffers, &freed), 0);
+        }
+        flushed_frames += 1;
+        destroy_due(
+            &mut freed,
+            &mut buffers,
+            flushed_frames,
+            DELETION_DELAY,
+            |_| destroyed += 1,
+        );
+        assert_eq!(destroyed, 3);
+        assert!(buffers.is_empty() && freed.is_empty());
+
+        // Keys that were already destroyed aren't queued again
+        queue_deletion(&mut freed, &buffers, flushed_frames + 1, keys[1]);
+        assert!(freed.is_empty());
+        assert_eq!(live_count(&buffers, &freed), 0);
+    }
+}
@@ -74,12 +74,57 @@ pub struct SamplerDescription {
     pub min_filter: FilterMode,
     pub mip_filter: FilterMode,
     pub lod_clamp_range: Option<std::ops::Range<f32>>,
+    /// Added to the computed mip level, positive values pick blurrier mips
+    pub mip_lod_bias: f32,
     pub anisotropy_clamp: Option<f32>,
     pub border_color: BorderColor,
     pub unnormalized_coordinates: bool,
+    /// Makes this a comparison sampler, used for shadow map lookups
+    pub compare_op: Option<vk::CompareOp>,
 }
 
 impl SamplerDescription {
+    pub fn linear_repeat() -> Self {
+        Self {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mip_filter: FilterMode::Linear,
+            ..Default::default()
+        }
+    }
+
+    pub fn nearest_clamp() -> Self {
+        Self {
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            ..Default::default()
+        }
+    }
+
+    /// Linear repeat sampler with anisotropic filtering up to max samples
+    pub fn anisotropic(max: f32) -> Self {
+        Self {
+            anisotropy_clamp: Some(max),
+            ..Self::linear_repeat()
+        }
+    }
+
+    /// Comparison sampler for hardware PCF, areas outside the shadow map are treated as lit
+    pub fn shadow_pcf() -> Self {
+        Self {
+            address_mode_u: AddressMode::ClampToBorder,
+            address_mode_v: AddressMode::ClampToBorder,
+            address_mode_w: AddressMode::ClampToBorder,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mip_filter: FilterMode::Nearest,
+            border_color: BorderColor::OpaqueWhite,
+            compare_op: Some(vk::CompareOp::LESS_OR_EQUAL),
+            ..Default::default()
+        }
+    }
+
     fn to_vk(&self) -> vk::SamplerCreateInfo {
         let lod_clamp_range = self
             .lod_clamp_range
@@ -92,12 +137,15 @@ impl SamplerDescription {
             .mag_filter(self.mag_filter.to_vk())
             .min_filter(self.min_filter.to_vk())
             .mipmap_mode(self.mip_filter.to_mip_vk())
+            .mip_lod_bias(self.mip_lod_bias)
             .min_lod(lod_clamp_range.start)
             .max_lod(lod_clamp_range.end)
             .anisotropy_enable(self.anisotropy_clamp.is_some())
             .max_anisotropy(self.anisotropy_clamp.unwrap_or_default())
             .border_color(self.border_color.to_vk())
             .unnormalized_coordinates(self.unnormalized_coordinates)
+            .compare_enable(self.compare_op.is_some())
+            .compare_op(self.compare_op.unwrap_or(vk::CompareOp::NEVER))
             .build()
     }
 }
@@ -137,3 +185,68 @@ impl Drop for Sampler {
         unsafe { self.device.core.destroy_sampler(self.handle, None) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address_modes(info: &vk::SamplerCreateInfo) -> [vk::SamplerAddressMode; 3] {
+        [
+            info.address_mode_u,
+            info.address_mode_v,
+            info.address_mode_w,
+        ]
+    }
+
+    #[test]
+    fn linear_and_nearest_presets() {
+        let linear = SamplerDescription::linear_repeat().to_vk();
+        assert_eq!(
+            (linear.mag_filter, linear.min_filter),
+            (vk::Filter::LINEAR, vk::Filter::LINEAR)
+        );
+        assert_eq!(linear.mipmap_mode, vk::SamplerMipmapMode::LINEAR);
+        assert_eq!(address_modes(&linear), [vk::SamplerAddressMode::REPEAT; 3]);
+        assert_eq!(linear.anisotropy_enable, vk::FALSE);
+        assert_eq!(linear.compare_enable, vk::FALSE);
+
+        let nearest = SamplerDescription::nearest_clamp().to_vk();
+        assert_eq!(
+            (nearest.mag_filter, nearest.min_filter),
+            (vk::Filter::NEAREST, vk::Filter::NEAREST)
+        );
+        assert_eq!(
+            address_modes(&nearest),
+            [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3]
+        );
+        assert_eq!(nearest.compare_enable, vk::FALSE);
+    }
+
+    #[test]
+    fn anisotropic_preset_filters_linearly_with_the_clamp() {
+        let anisotropic = SamplerDescription::anisotropic(8.0).to_vk();
+        assert_eq!(anisotropic.mag_filter, vk::Filter::LINEAR);
+        assert_eq!(
+            address_modes(&anisotropic),
+            [vk::SamplerAddressMode::REPEAT; 3]
+        );
+        assert_eq!(anisotropic.anisotropy_enable, vk::TRUE);
+        assert_eq!(anisotropic.max_anisotropy, 8.0);
+    }
+
+    #[test]
+    fn shadow_preset_compares_and_clamps_to_a_lit_border() {
+        let shadow = SamplerDescription::shadow_pcf().to_vk();
+        assert_eq!(
+            (shadow.mag_filter, shadow.min_filter),
+            (vk::Filter::LINEAR, vk::Filter::LINEAR)
+        );
+        assert_eq!(
+            address_modes(&shadow),
+            [vk::SamplerAddressMode::CLAMP_TO_BORDER; 3]
+        );
+        assert_eq!(shadow.border_color, vk::BorderColor::FLOAT_OPAQUE_WHITE);
+        assert_eq!(shadow.compare_enable, vk::TRUE);
+        assert_eq!(shadow.compare_op, vk::CompareOp::LESS_OR_EQUAL);
+    }
+}
@@ -0,0 +1,845 @@
+use crate::descriptor_set::DescriptorSetInner;
+use ash::vk;
+use std::collections::HashMap;
+
+const SPIRV_MAGIC: u32 = 0x07230203;
+const SPIRV_HEADER_LEN: usize = 5;
+
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_CONSTANT_COMPOSITE: u32 = 44;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_SPEC_CONSTANT_COMPOSITE: u32 = 51;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+const EXECUTION_MODE_LOCAL_SIZE_ID: u32 = 38;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const BUILT_IN_WORKGROUP_SIZE: u32 = 25;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_WORKGROUP: u32 = 4;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ShaderReflectionError {
+    #[error("Invalid SPIR-V module: {0}")]
+    InvalidModule(&'static str),
+    #[error(
+        "Binding (set: {set}, binding: {binding}) of type {ty:?} doesn't match the bindless layout"
+    )]
+    IncompatibleBinding {
+        set: u32,
+        binding: u32,
+        ty: ShaderResourceType,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderResourceType {
+    UniformBuffer,
+    StorageBuffer,
+    StorageImage,
+    SampledImage,
+    Sampler,
+    CombinedImageSampler,
+    AccelerationStructure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderEntryPoint {
+    pub name: String,
+    pub stage: vk::ShaderStageFlags,
+    /// Only set for compute shaders that declare a LocalSize or LocalSizeId execution mode or a WorkgroupSize
+    /// built-in, which overrides both. Specialization constants are resolved to their default values
+    pub workgroup_size: Option<[u32; 3]>,
+    /// Bytes of Workgroup storage the entry point may use. Workgroup variables aren't tied to an entry point
+    /// before SPIR-V 1.4, so this is the module's total for every compute entry point
+    pub shared_memory_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderResourceBinding {
+    pub name: Option<String>,
+    pub set: u32,
+    pub binding: u32,
+    pub ty: ShaderResourceType,
+    /// None for runtime sized (bindless) arrays
+    pub count: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub entry_points: Vec<ShaderEntryPoint>,
+    pub bindings: Vec<ShaderResourceBinding>,
+    /// Size in bytes of the push constant block, if the shader uses one
+    pub push_constant_size: Option<u32>,
+}
+
+impl ShaderReflection {
+    pub fn get_entry_point(&self, name: &str) -> Option<&ShaderEntryPoint> {
+        self.entry_points.iter().find(|entry| entry.name == name)
+    }
+
+    /// Checks that every binding lines up with the bindless descriptor set the renderer binds
+    pub fn validate_bindless_layout(&self) -> Result<(), ShaderReflectionError> {
+        for binding in self.bindings.iter() {
+            let expected_binding = match binding.ty {
+                ShaderResourceType::StorageBuffer => DescriptorSetInner::STORAGE_BUFFER_BINDING,
+                ShaderResourceType::StorageImage => DescriptorSetInner::STORAGE_IMAGE_BINDING,
+                ShaderResourceType::SampledImage => DescriptorSetInner::SAMPLED_IMAGE_BINDING,
+                ShaderResourceType::Sampler => DescriptorSetInner::SAMPLER_BINDING,
+                ShaderResourceType::AccelerationStructure => {
+                    DescriptorSetInner::ACCELERATION_STRUCTURE_BINDING
+                }
+                ShaderResourceType::UniformBuffer | ShaderResourceType::CombinedImageSampler => {
+                    u16::MAX
+                }
+            };
+
+            if binding.set != 0 || binding.binding != expected_binding as u32 {
+                return Err(ShaderReflectionError::IncompatibleBinding {
+                    set: binding.set,
+                    binding: binding.binding,
+                    ty: binding.ty,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SpirvType {
+    Scalar { size: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Default)]
+struct SpirvModule {
+    names: HashMap<u32, String>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    composites: HashMap<u32, Vec<u32>>,
+    variables: Vec<(u32, u32, u32)>,
+    decorations: HashMap<(u32, u32), u32>,
+    flags: HashMap<u32, Vec<u32>>,
+    member_offsets: HashMap<(u32, u32), u32>,
+}
+
+impl SpirvModule {
+    fn has_decoration(&self, id: u32, decoration: u32) -> bool {
+        self.flags
+            .get(&id)
+            .map(|flags| flags.contains(&decoration))
+            .unwrap_or(false)
+    }
+
+    /// Sizes saturate at u32::MAX so oversized declarations still fail limit checks
+    fn type_size(&self, id: u32) -> u32 {
+        match self.types.get(&id) {
+            Some(SpirvType::Scalar { size }) => *size,
+            Some(SpirvType::Vector { component, count }) => {
+                self.type_size(*component).saturating_mul(*count)
+            }
+            Some(SpirvType::Matrix { column, count }) => {
+                align_up(self.type_size(*column), self.type_alignment(*column))
+                    .saturating_mul(*count)
+            }
+            Some(SpirvType::Array { element, length }) => {
+                let stride = self
+                    .decorations
+                    .get(&(id, DECORATION_ARRAY_STRIDE))
+                    .copied()
+                    .unwrap_or_else(|| {
+                        align_up(self.type_size(*element), self.type_alignment(*element))
+                    });
+                stride.saturating_mul(*length)
+            }
+            Some(SpirvType::Struct { members }) => {
+                // Workgroup structs have no explicit layout, so undecorated members are placed at their natural alignment
+                let mut size = 0;
+                let mut end = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let offset = self
+                        .member_offsets
+                        .get(&(id, index as u32))
+                        .copied()
+                        .unwrap_or_else(|| align_up(end, self.type_alignment(*member)));
+                    end = offset.saturating_add(self.type_size(*member));
+                    size = size.max(end);
+                }
+                align_up(size, self.type_alignment(id))
+            }
+            _ => 0,
+        }
+    }
+
+    /// Natural alignment of a type, three component vectors align like four component ones
+    fn type_alignment(&self, id: u32) -> u32 {
+        match self.types.get(&id) {
+            Some(SpirvType::Scalar { size }) => (*size).max(1),
+            Some(SpirvType::Vector { component, count }) => {
+                let component_alignment = self.type_alignment(*component);
+                if *count == 2 {
+                    component_alignment * 2
+                } else {
+                    component_alignment * 4
+                }
+            }
+            Some(SpirvType::Matrix { column, .. }) => self.type_alignment(*column),
+            Some(SpirvType::Array { element, .. }) => self.type_alignment(*element),
+            Some(SpirvType::Struct { members }) => members
+                .iter()
+                .map(|member| self.type_alignment(*member))
+                .max()
+                .unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    fn resource_type(&self, storage_class: u32, type_id: u32) -> Option<ShaderResourceType> {
+        match self.types.get(&type_id)? {
+            SpirvType::Image { sampled: 2 } => Some(ShaderResourceType::StorageImage),
+            SpirvType::Image { .. } => Some(ShaderResourceType::SampledImage),
+            SpirvType::Sampler => Some(ShaderResourceType::Sampler),
+            SpirvType::SampledImage => Some(ShaderResourceType::CombinedImageSampler),
+            SpirvType::AccelerationStructure => Some(ShaderResourceType::AccelerationStructure),
+            SpirvType::Struct { .. } => match storage_class {
+                STORAGE_CLASS_STORAGE_BUFFER => Some(ShaderResourceType::StorageBuffer),
+                STORAGE_CLASS_UNIFORM if self.has_decoration(type_id, DECORATION_BUFFER_BLOCK) => {
+                    Some(ShaderResourceType::StorageBuffer)
+                }
+                STORAGE_CLASS_UNIFORM if self.has_decoration(type_id, DECORATION_BLOCK) => {
+                    Some(ShaderResourceType::UniformBuffer)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    value
+        .checked_next_multiple_of(alignment.max(1))
+        .unwrap_or(u32::MAX)
+}
+
+fn read_string(words: &[u32]) -> String {
+    let mut bytes = Vec::new();
+    for word in words.iter() {
+        for byte in word.to_le_bytes() {
+            if byte == 0 {
+                return String::from_utf8_lossy(&bytes).into_owned();
+            }
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn execution_model_to_stage(execution_model: u32) -> vk::ShaderStageFlags {
+    match execution_model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        5267 => vk::ShaderStageFlags::TASK_NV,
+        5268 => vk::ShaderStageFlags::MESH_NV,
+        5313 => vk::ShaderStageFlags::RAYGEN_KHR,
+        5314 => vk::ShaderStageFlags::INTERSECTION_KHR,
+        5315 => vk::ShaderStageFlags::ANY_HIT_KHR,
+        5316 => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        5317 => vk::ShaderStageFlags::MISS_KHR,
+        5318 => vk::ShaderStageFlags::CALLABLE_KHR,
+        5364 => vk::ShaderStageFlags::TASK_EXT,
+        5365 => vk::ShaderStageFlags::MESH_EXT,
+        _ => vk::ShaderStageFlags::empty(),
+    }
+}
+
+/// Reads the entry points, descriptor bindings and push constant block out of a SPIR-V module
+pub fn reflect_shader(code: &[u32]) -> Result<ShaderReflection, ShaderReflectionError> {
+    if code.len() < SPIRV_HEADER_LEN {
+        return Err(ShaderReflectionError::InvalidModule("Missing header"));
+    }
+
+    if code[0] != SPIRV_MAGIC {
+        return Err(ShaderReflectionError::InvalidModule("Invalid magic number"));
+    }
+
+    let mut module = SpirvModule::default();
+    let mut entry_points: Vec<(u32, ShaderEntryPoint)> = Vec::new();
+    let mut local_size_ids: Vec<(u32, [u32; 3])> = Vec::new();
+
+    let mut index = SPIRV_HEADER_LEN;
+    while index < code.len() {
+        let word_count = (code[index] >> 16) as usize;
+        let opcode = code[index] & 0xFFFF;
+
+        if word_count == 0 || index + word_count > code.len() {
+            return Err(ShaderReflectionError::InvalidModule(
+                "Instruction out of bounds",
+            ));
+        }
+
+        let operands = &code[index + 1..index + word_count];
+        let operand = |i: usize| {
+            operands
+                .get(i)
+                .copied()
+                .ok_or(ShaderReflectionError::InvalidModule("Missing operand"))
+        };
+        let operands_from = |i: usize| {
+            operands
+                .get(i..)
+                .ok_or(ShaderReflectionError::InvalidModule("Missing operand"))
+        };
+
+        match opcode {
+            OP_NAME => {
+                module
+                    .names
+                    .insert(operand(0)?, read_string(operands_from(1)?));
+            }
+            OP_ENTRY_POINT => {
+                let name = read_string(operands_from(2)?);
+                entry_points.push((
+                    operand(1)?,
+                    ShaderEntryPoint {
+                        name,
+                        stage: execution_model_to_stage(operand(0)?),
+                        workgroup_size: None,
+                        shared_memory_size: 0,
+                    },
+                ));
+            }
+            OP_EXECUTION_MODE if operands.get(1) == Some(&EXECUTION_MODE_LOCAL_SIZE) => {
+                let function_id = operand(0)?;
+                let workgroup_size = [operand(2)?, operand(3)?, operand(4)?];
+                for (_, entry_point) in entry_points.iter_mut().filter(|(id, _)| *id == function_id)
+                {
+                    entry_point.workgroup_size = Some(workgroup_size);
+                }
+            }
+            OP_EXECUTION_MODE if operands.get(1) == Some(&EXECUTION_MODE_LOCAL_SIZE_ID) => {
+                local_size_ids.push((operand(0)?, [operand(2)?, operand(3)?, operand(4)?]));
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Scalar {
+                        size: operand(1)? / 8,
+                    },
+                );
+            }
+            OP_TYPE_VECTOR => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Vector {
+                        component: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            OP_TYPE_MATRIX => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Matrix {
+                        column: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            OP_TYPE_IMAGE => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Image {
+                        sampled: operand(6)?,
+                    },
+                );
+            }
+            OP_TYPE_SAMPLER => {
+                module.types.insert(operand(0)?, SpirvType::Sampler);
+            }
+            OP_TYPE_SAMPLED_IMAGE => {
+                module.types.insert(operand(0)?, SpirvType::SampledImage);
+            }
+            OP_TYPE_ACCELERATION_STRUCTURE => {
+                module
+                    .types
+                    .insert(operand(0)?, SpirvType::AccelerationStructure);
+            }
+            OP_TYPE_ARRAY => {
+                let length = module.constants.get(&operand(2)?).copied().unwrap_or(1);
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Array {
+                        element: operand(1)?,
+                        length,
+                    },
+                );
+            }
+            OP_TYPE_RUNTIME_ARRAY => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::RuntimeArray {
+                        element: operand(1)?,
+                    },
+                );
+            }
+            OP_TYPE_STRUCT => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Struct {
+                        members: operands_from(1)?.to_vec(),
+                    },
+                );
+            }
+            OP_TYPE_POINTER => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Pointer {
+                        pointee: operand(2)?,
+                    },
+                );
+            }
+            OP_CONSTANT | OP_SPEC_CONSTANT => {
+                module.constants.insert(operand(1)?, operand(2)?);
+            }
+            OP_CONSTANT_COMPOSITE | OP_SPEC_CONSTANT_COMPOSITE => {
+                module
+                    .composites
+                    .insert(operand(1)?, operands_from(2)?.to_vec());
+            }
+            OP_VARIABLE => {
+                module
+                    .variables
+                    .push((operand(0)?, operand(1)?, operand(2)?));
+            }
+            OP_DECORATE => {
+                let target = operand(0)?;
+                let decoration = operand(1)?;
+                match operands.get(2) {
+                    Some(value) => {
+                        module.decorations.insert((target, decoration), *value);
+                    }
+                    None => module.flags.entry(target).or_default().push(decoration),
+                }
+            }
+            OP_MEMBER_DECORATE if operands.get(2) == Some(&DECORATION_OFFSET) => {
+                module
+                    .member_offsets
+                    .insert((operand(0)?, operand(1)?), operand(3)?);
+            }
+            _ => {}
+        }
+
+        index += word_count;
+    }
+
+    // LocalSizeId may reference constants declared after the execution mode
+    for (function_id, size_ids) in local_size_ids {
+        let workgroup_size = size_ids.map(|id| module.constants.get(&id).copied().unwrap_or(1));
+        for (_, entry_point) in entry_points.iter_mut().filter(|(id, _)| *id == function_id) {
+            entry_point.workgroup_size = Some(workgroup_size);
+        }
+    }
+
+    // A WorkgroupSize built-in overrides the execution modes of every entry point in the module
+    let workgroup_size_built_in = module
+        .composites
+        .iter()
+        .find(|(id, _)| {
+            module.decorations.get(&(**id, DECORATION_BUILT_IN)) == Some(&BUILT_IN_WORKGROUP_SIZE)
+        })
+        .map(|(_, constituents)| {
+            let size = |axis: usize| {
+                constituents
+                    .get(axis)
+                    .and_then(|id| module.constants.get(id))
+                    .copied()
+                    .unwrap_or(1)
+            };
+            [size(0), size(1), size(2)]
+        });
+    if let Some(workgroup_size) = workgroup_size_built_in {
+        for (_, entry_point) in entry_points
+            .iter_mut()
+            .filter(|(_, entry_point)| entry_point.stage == vk::ShaderStageFlags::COMPUTE)
+        {
+            entry_point.workgroup_size = Some(workgroup_size);
+        }
+    }
+
+    // Each variable starts at its own alignment, overflowing u32 saturates so the limit check still fails
+    let shared_memory_size = module
+        .variables
+        .iter()
+        .filter(|(_, _, storage_class)| *storage_class == STORAGE_CLASS_WORKGROUP)
+        .filter_map(
+            |(pointer_type, _, _)| match module.types.get(pointer_type) {
+                Some(SpirvType::Pointer { pointee }) => Some(*pointee),
+                _ => None,
+            },
+        )
+        .try_fold(0u32, |total, pointee| {
+            align_up(total, module.type_alignment(pointee)).checked_add(module.type_size(pointee))
+        })
+        .unwrap_or(u32::MAX);
+    for (_, entry_point) in entry_points
+        .iter_mut()
+        .filter(|(_, entry_point)| entry_point.stage == vk::ShaderStageFlags::COMPUTE)
+    {
+        entry_point.shared_memory_size = shared_memory_size;
+    }
+
+    let mut reflection = ShaderReflection {
+        entry_points: entry_points
+            .into_iter()
+            .map(|(_, entry_point)| entry_point)
+            .collect(),
+        ..Default::default()
+    };
+
+    for (pointer_type, variable_id, storage_class) in module.variables.iter().copied() {
+        let pointee = match module.types.get(&pointer_type) {
+            Some(SpirvType::Pointer { pointee }) => *pointee,
+            _ => continue,
+        };
+
+        match storage_class {
+            STORAGE_CLASS_PUSH_CONSTANT => {
+                reflection.push_constant_size = Some(module.type_size(pointee));
+            }
+            STORAGE_CLASS_UNIFORM_CONSTANT
+            | STORAGE_CLASS_UNIFORM
+            | STORAGE_CLASS_STORAGE_BUFFER => {
+                let (element_type, count) = match module.types.get(&pointee) {
+                    Some(SpirvType::Array { element, length }) => (*element, Some(*length)),
+                    Some(SpirvType::RuntimeArray { element }) => (*element, None),
+                    _ => (pointee, Some(1)),
+                };
+
+                let (Some(set), Some(binding)) = (
+                    module
+                        .decorations
+                        .get(&(variable_id, DECORATION_DESCRIPTOR_SET))
+                        .copied(),
+                    module
+                        .decorations
+                        .get(&(variable_id, DECORATION_BINDING))
+                        .copied(),
+                ) else {
+                    continue;
+                };
+
+                if let Some(ty) = module.resource_type(storage_class, element_type) {
+                    reflection.bindings.push(ShaderResourceBinding {
+                        name: module.names.get(&variable_id).cloned(),
+                        set,
+                        binding,
+                        ty,
+                        count,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(reflection)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const OP_EXECUTION_MODEL_VERTEX: u32 = 0;
+    const OP_EXECUTION_MODEL_FRAGMENT: u32 = 4;
+    const OP_EXECUTION_MODEL_GL_COMPUTE: u32 = 5;
+
+    fn instruction(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![((operands.len() as u32 + 1) << 16) | opcode];
+        words.extend_from_slice(operands);
+        words
+    }
+
+    fn string(value: &str) -> Vec<u32> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize((bytes.len() / 4 + 1) * 4, 0);
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect()
+    }
+
+    fn module(instructions: &[Vec<u32>]) -> Vec<u32> {
+        let mut code = vec![SPIRV_MAGIC, 0x0001_0300, 0, 100, 0];
+        for instruction in instructions {
+            code.extend_from_slice(instruction);
+        }
+        code
+    }
+
+    /// A vertex entry point vs_main and a fragment entry point fs_main in one module
+    pub(crate) fn vertex_fragment_shader() -> Vec<u32> {
+        let (vertex_function, fragment_function) = (1, 2);
+        module(&[
+            instruction(
+                OP_ENTRY_POINT,
+                &[
+                    [OP_EXECUTION_MODEL_VERTEX, vertex_function].as_slice(),
+                    &string("vs_main"),
+                ]
+                .concat(),
+            ),
+            instruction(
+                OP_ENTRY_POINT,
+                &[
+                    [OP_EXECUTION_MODEL_FRAGMENT, fragment_function].as_slice(),
+                    &string("fs_main"),
+                ]
+                .concat(),
+            ),
+        ])
+    }
+
+    /// layout(local_size_x = 8, local_size_y = 8) in;
+    /// layout(set = 0, binding = 0) buffer Buffer { uint values[]; };
+    fn compute_shader() -> Vec<u32> {
+        let (function, uint, runtime_array, block, pointer, variable) = (1, 2, 3, 4, 5, 10);
+        module(&[
+            instruction(
+                OP_ENTRY_POINT,
+                &[
+                    [OP_EXECUTION_MODEL_GL_COMPUTE, function].as_slice(),
+                    &string("main"),
+                ]
+                .concat(),
+            ),
+            instruction(
+                OP_EXECUTION_MODE,
+                &[function, EXECUTION_MODE_LOCAL_SIZE, 8, 8, 1],
+            ),
+            instruction(
+                OP_NAME,
+                &[[variable].as_slice(), &string("Buffer")].concat(),
+            ),
+            instruction(OP_DECORATE, &[variable, DECORATION_DESCRIPTOR_SET, 0]),
+            instruction(OP_DECORATE, &[variable, DECORATION_BINDING, 0]),
+            instruction(OP_DECORATE, &[block, DECORATION_BLOCK]),
+            instruction(OP_TYPE_INT, &[uint, 32, 0]),
+            instruction(OP_TYPE_RUNTIME_ARRAY, &[runtime_array, uint]),
+            instruction(OP_TYPE_STRUCT, &[block, runtime_array]),
+            instruction(
+                OP_TYPE_POINTER,
+                &[pointer, STORAGE_CLASS_STORAGE_BUFFER, block],
+            ),
+            instruction(
+                OP_VARIABLE,
+                &[pointer, variable, STORAGE_CLASS_STORAGE_BUFFER],
+            ),
+        ])
+    }
+
+    /// A compute entry point with local size 8x8x1 and a Workgroup variable of each type in shared_types.
+    /// Type ids 20 to 22 are float, vec4 and a struct { float; vec4; }
+    fn shared_memory_shader(shared_types: &[u32], extra: &[Vec<u32>]) -> Vec<u32> {
+        let (function, float, vec4, shared_struct) = (1, 20, 21, 22);
+        let mut instructions = vec![
+            instruction(
+                OP_ENTRY_POINT,
+                &[
+                    [OP_EXECUTION_MODEL_GL_COMPUTE, function].as_slice(),
+                    &string("main"),
+                ]
+                .concat(),
+            ),
+            instruction(
+                OP_EXECUTION_MODE,
+                &[function, EXECUTION_MODE_LOCAL_SIZE, 8, 8, 1],
+            ),
+            instruction(OP_TYPE_FLOAT, &[float, 32]),
+            instruction(OP_TYPE_VECTOR, &[vec4, float, 4]),
+            instruction(OP_TYPE_STRUCT, &[shared_struct, float, vec4]),
+        ];
+        for (index, shared_type) in shared_types.iter().enumerate() {
+            let (pointer, variable) = (100 + index as u32 * 2, 101 + index as u32 * 2);
+            instructions.push(instruction(
+                OP_TYPE_POINTER,
+                &[pointer, STORAGE_CLASS_WORKGROUP, *shared_type],
+            ));
+            instructions.push(instruction(
+                OP_VARIABLE,
+                &[pointer, variable, STORAGE_CLASS_WORKGROUP],
+            ));
+        }
+        instructions.extend_from_slice(extra);
+        module(&instructions)
+    }
+
+    /// Compute entry point using a shared float array of length floats
+    pub(crate) fn shared_array_shader(floats: u32) -> Vec<u32> {
+        let (uint, length, array) = (30, 31, 32);
+        shared_memory_shader(
+            &[array],
+            &[
+                instruction(OP_TYPE_INT, &[uint, 32, 0]),
+                instruction(OP_CONSTANT, &[uint, length, floats]),
+                instruction(OP_TYPE_ARRAY, &[array, 20, length]),
+            ],
+        )
+    }
+
+    fn main_entry_point(code: &[u32]) -> ShaderEntryPoint {
+        reflect_shader(code)
+            .unwrap()
+            .get_entry_point("main")
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn shared_memory_respects_alignment() {
+        // The vec4 member starts at 16, not right after the float
+        assert_eq!(
+            main_entry_point(&shared_memory_shader(&[22], &[])).shared_memory_size,
+            32
+        );
+        // Separate variables are aligned the same way
+        assert_eq!(
+            main_entry_point(&shared_memory_shader(&[20, 21], &[])).shared_memory_size,
+            32
+        );
+        assert_eq!(
+            main_entry_point(&shared_array_shader(512)).shared_memory_size,
+            2048
+        );
+    }
+
+    #[test]
+    fn shared_memory_size_saturates() {
+        let entry_point = main_entry_point(&shared_memory_shader(
+            &[32, 32],
+            &[
+                instruction(OP_TYPE_INT, &[30, 32, 0]),
+                instruction(OP_CONSTANT, &[30, 31, u32::MAX / 4]),
+                instruction(OP_TYPE_ARRAY, &[32, 20, 31]),
+            ],
+        ));
+        assert_eq!(entry_point.shared_memory_size, u32::MAX);
+    }
+
+    #[test]
+    fn workgroup_size_built_in_overrides_local_size() {
+        let (uint, x, y, z, uvec3, workgroup_size) = (30, 31, 32, 33, 34, 35);
+        let entry_point = main_entry_point(&shared_memory_shader(
+            &[],
+            &[
+                instruction(
+                    OP_DECORATE,
+                    &[workgroup_size, DECORATION_BUILT_IN, BUILT_IN_WORKGROUP_SIZE],
+                ),
+                instruction(OP_TYPE_INT, &[uint, 32, 0]),
+                instruction(OP_TYPE_VECTOR, &[uvec3, uint, 3]),
+                instruction(OP_SPEC_CONSTANT, &[uint, x, 64]),
+                instruction(OP_CONSTANT, &[uint, y, 2]),
+                instruction(OP_CONSTANT, &[uint, z, 1]),
+                instruction(
+                    OP_SPEC_CONSTANT_COMPOSITE,
+                    &[uvec3, workgroup_size, x, y, z],
+                ),
+            ],
+        ));
+        assert_eq!(entry_point.workgroup_size, Some([64, 2, 1]));
+    }
+
+    #[test]
+    fn reflects_compute_shader() {
+        let reflection = reflect_shader(&compute_shader()).unwrap();
+
+        let entry_point = reflection.get_entry_point("main").unwrap();
+        assert_eq!(entry_point.stage, vk::ShaderStageFlags::COMPUTE);
+        assert_eq!(entry_point.workgroup_size, Some([8, 8, 1]));
+
+        assert_eq!(
+            reflection.bindings,
+            vec![ShaderResourceBinding {
+                name: Some(String::from("Buffer")),
+                set: 0,
+                binding: 0,
+                ty: ShaderResourceType::StorageBuffer,
+                count: Some(1),
+            }]
+        );
+        assert_eq!(reflection.push_constant_size, None);
+    }
+
+    #[test]
+    fn truncated_instructions_are_invalid() {
+        for truncated in [
+            instruction(OP_ENTRY_POINT, &[OP_EXECUTION_MODEL_GL_COMPUTE]),
+            instruction(OP_ENTRY_POINT, &[]),
+            instruction(OP_NAME, &[]),
+            instruction(OP_TYPE_STRUCT, &[]),
+        ] {
+            assert!(matches!(
+                reflect_shader(&module(&[truncated])),
+                Err(ShaderReflectionError::InvalidModule(_))
+            ));
+        }
+
+        let mut overrun = compute_shader();
+        overrun.push(instruction(OP_DECORATE, &[1, 2, 3])[0]);
+        assert!(matches!(
+            reflect_shader(&overrun),
+            Err(ShaderReflectionError::InvalidModule(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_header() {
+        assert!(reflect_shader(&[SPIRV_MAGIC]).is_err());
+        assert!(reflect_shader(&[0, 0, 0, 0, 0]).is_err());
+    }
+}
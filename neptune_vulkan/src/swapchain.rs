@@ -1,7 +1,7 @@
 use crate::device::AshDevice;
-use crate::image::AshImage;
+use crate::image::{vk_format_bytes_per_pixel, AshImage};
 use crate::instance::AshInstance;
-use crate::SurfaceHandle;
+use crate::{SurfaceHandle, VulkanError};
 use ash::vk;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -12,10 +12,14 @@ struct SwapchainInstance {
 
     images: Vec<AshImage>,
 
+    /// Semaphore last used to acquire each image, it is only recycled once that image is acquired again
+    image_ready_semaphores: Vec<vk::Semaphore>,
+    present_ready_semaphores: Vec<vk::Semaphore>,
+    free_semaphores: Vec<vk::Semaphore>,
+
     #[allow(unused)]
     image_color_space: vk::ColorSpaceKHR,
 
-    #[allow(unused)]
     pre_transform: vk::SurfaceTransformFlagsKHR,
 
     #[allow(unused)]
@@ -70,10 +74,23 @@ impl SwapchainInstance {
             });
         }
 
+        let image_ready_semaphores = vec![vk::Semaphore::null(); images.len()];
+        let mut present_ready_semaphores = Vec::with_capacity(images.len());
+        for _ in 0..images.len() {
+            present_ready_semaphores.push(unsafe {
+                device
+                    .core
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+            }?);
+        }
+
         Ok(Self {
             device,
             handle,
             images,
+            image_ready_semaphores,
+            present_ready_semaphores,
+            free_semaphores: Vec::new(),
             image_color_space: create_info.image_color_space,
             pre_transform: create_info.pre_transform,
             composite_alpha: create_info.composite_alpha,
@@ -82,9 +99,30 @@ impl SwapchainInstance {
     }
 }
 
+impl SwapchainInstance {
+    fn get_free_semaphore(&mut self) -> ash::prelude::VkResult<vk::Semaphore> {
+        match self.free_semaphores.pop() {
+            Some(semaphore) => Ok(semaphore),
+            None => unsafe {
+                self.device
+                    .core
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+            },
+        }
+    }
+}
+
 impl Drop for SwapchainInstance {
     fn drop(&mut self) {
         unsafe {
+            // Semaphores may still be pending on an acquire or present
+            let _ = self.device.core.device_wait_idle();
+            self.image_ready_semaphores
+                .iter()
+                .chain(self.present_ready_semaphores.iter())
+                .chain(self.free_semaphores.iter())
+                .filter(|semaphore| **semaphore != vk::Semaphore::null())
+                .for_each(|semaphore| self.device.core.destroy_semaphore(*semaphore, None));
             self.images
                 .iter()
                 .for_each(|image| self.device.core.destroy_image_view(image.view, None));
@@ -99,8 +137,15 @@ pub struct SurfaceSettings {
     pub image_count: u32,
     pub format: vk::SurfaceFormatKHR,
     pub size: [u32; 2],
+    /// Must be a subset of [`crate::Device::get_surface_supported_usage`],
+    /// add STORAGE or TRANSFER_DST to write to swapchain images from compute or blits
     pub usage: vk::ImageUsageFlags,
     pub present_mode: vk::PresentModeKHR,
+    /// How long acquiring an image may block before the frame fails with TIMEOUT, u64::MAX waits forever
+    pub acquire_timeout_ns: u64,
+    /// None or an unsupported transform uses the surface's current transform, rotated surfaces then
+    /// need rendering compensated with [`pre_transform_matrix`] to present upright
+    pub pre_transform: Option<vk::SurfaceTransformFlagsKHR>,
 }
 
 pub struct Swapchain {
@@ -132,11 +177,34 @@ impl Swapchain {
         Ok(new_self)
     }
 
+    pub fn settings(&self) -> &SurfaceSettings {
+        &self.settings
+    }
+
+    /// Transform the current swapchain was created with, None while the surface has no swapchain
+    pub fn pre_transform(&self) -> Option<vk::SurfaceTransformFlagsKHR> {
+        self.current_swapchain
+            .as_ref()
+            .map(|swapchain| swapchain.pre_transform)
+    }
+
     pub fn update_settings(&mut self, settings: &SurfaceSettings) -> ash::prelude::VkResult<()> {
         self.settings = settings.clone();
         self.rebuild()
     }
 
+    /// A minimized window's surface has a zero max extent, so no swapchain can be created for it
+    pub fn is_minimized(&self) -> ash::prelude::VkResult<bool> {
+        let capabilities = unsafe {
+            self.device
+                .instance
+                .surface
+                .get_physical_device_surface_capabilities(self.device.physical, self.surface)
+        }?;
+        Ok(is_zero_extent(capabilities.max_image_extent))
+    }
+
+    /// Does nothing while the surface has a zero extent, the swapchain is rebuilt once the surface is usable again
     pub fn rebuild(&mut self) -> ash::prelude::VkResult<()> {
         let (extent, transform, image_count) = get_swapchain_extent_transform_count(
             &self.device.instance.surface,
@@ -145,6 +213,17 @@ impl Swapchain {
             &self.settings,
         )?;
 
+        if is_zero_extent(extent) {
+            return Ok(());
+        }
+
+        // Sharing the images between the graphics and present families avoids ownership transfers,
+        // the present still waits on the semaphore signaled by the graphics submit
+        let (sharing_mode, queue_family_indices) = image_sharing(
+            self.device.graphics_queue.map(|queue| queue.family_index),
+            self.device.present_queue.map(|queue| queue.family_index),
+        );
+
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
             .surface(self.surface)
             .min_image_count(image_count)
@@ -153,7 +232,8 @@ impl Swapchain {
             .image_extent(extent)
             .image_array_layers(1)
             .image_usage(self.settings.usage)
-            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .image_sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices)
             .pre_transform(transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(self.settings.present_mode)
@@ -173,30 +253,51 @@ impl Swapchain {
         Ok(())
     }
 
+    /// Acquires the next image along with semaphores owned by that image index,
+    /// so a semaphore is never reused while its previous acquire or present is still pending
     pub(crate) fn acquire_next_image(
-        &self,
-        image_ready_semaphore: vk::Semaphore,
+        &mut self,
+        timeout_ns: u64,
     ) -> ash::prelude::VkResult<(AcquiredSwapchainImage, bool)> {
-        let swapchain = self.current_swapchain.as_ref().unwrap();
+        // Surfaces created while minimized don't have a swapchain yet
+        let swapchain = match self.current_swapchain.as_mut() {
+            Some(swapchain) => swapchain,
+            None => return Err(vk::Result::ERROR_OUT_OF_DATE_KHR),
+        };
+        let image_ready_semaphore = swapchain.get_free_semaphore()?;
 
-        unsafe {
+        let (index, suboptimal) = match unsafe {
             self.device.swapchain.acquire_next_image(
                 swapchain.handle,
-                u64::MAX,
+                timeout_ns,
                 image_ready_semaphore,
                 vk::Fence::null(),
             )
-        }
-        .map(|(index, suboptimal)| {
-            (
-                AcquiredSwapchainImage {
-                    swapchain_handle: swapchain.handle,
-                    image_index: index,
-                    image: swapchain.images[index as usize],
-                },
-                suboptimal,
-            )
-        })
+        } {
+            Ok(result) => result,
+            Err(err) => {
+                swapchain.free_semaphores.push(image_ready_semaphore);
+                return Err(err);
+            }
+        };
+
+        recycle_image_ready_semaphore(
+            &mut swapchain.image_ready_semaphores,
+            &mut swapchain.free_semaphores,
+            index as usize,
+            image_ready_semaphore,
+        );
+
+        Ok((
+            AcquiredSwapchainImage {
+                swapchain_handle: swapchain.handle,
+                image_index: index,
+                image: swapchain.images[index as usize],
+                image_ready_semaphore,
+                present_ready_semaphore: swapchain.present_ready_semaphores[index as usize],
+            },
+            suboptimal,
+        ))
     }
 }
 
@@ -205,6 +306,133 @@ pub struct AcquiredSwapchainImage {
     pub swapchain_handle: vk::SwapchainKHR,
     pub image_index: u32,
     pub image: AshImage,
+    pub image_ready_semaphore: vk::Semaphore,
+    pub present_ready_semaphore: vk::Semaphore,
+}
+
+/// Usage flags the surface allows swapchain images to be created with
+pub(crate) fn get_supported_usage(
+    device: &AshDevice,
+    surface_handle: SurfaceHandle,
+) -> ash::prelude::VkResult<vk::ImageUsageFlags> {
+    let surface = match device.instance.surface_list.get(surface_handle.0) {
+        None => return Err(vk::Result::ERROR_SURFACE_LOST_KHR),
+        Some(surface) => surface,
+    };
+
+    unsafe {
+        device
+            .instance
+            .surface
+            .get_physical_device_surface_capabilities(device.physical, surface)
+            .map(|capabilities| capabilities.supported_usage_flags)
+    }
+}
+
+/// Present modes the surface supports, FIFO is always supported
+pub(crate) fn get_supported_present_modes(
+    device: &AshDevice,
+    surface_handle: SurfaceHandle,
+) -> ash::prelude::VkResult<Vec<vk::PresentModeKHR>> {
+    let surface = match device.instance.surface_list.get(surface_handle.0) {
+        None => return Err(vk::Result::ERROR_SURFACE_LOST_KHR),
+        Some(surface) => surface,
+    };
+
+    unsafe {
+        device
+            .instance
+            .surface
+            .get_physical_device_surface_present_modes(device.physical, surface)
+    }
+}
+
+/// Surface formats the surface supports, excluding any format whose texel size isn't known
+pub(crate) fn get_supported_formats(
+    device: &AshDevice,
+    surface_handle: SurfaceHandle,
+) -> ash::prelude::VkResult<Vec<vk::SurfaceFormatKHR>> {
+    let surface = match device.instance.surface_list.get(surface_handle.0) {
+        None => return Err(vk::Result::ERROR_SURFACE_LOST_KHR),
+        Some(surface) => surface,
+    };
+
+    let formats = unsafe {
+        device
+            .instance
+            .surface
+            .get_physical_device_surface_formats(device.physical, surface)
+    }?;
+    Ok(formats
+        .into_iter()
+        .filter(|surface_format| vk_format_bytes_per_pixel(surface_format.format).is_some())
+        .collect())
+}
+
+/// First preferred format that is available, falling back to the first available format
+pub fn pick_surface_format(
+    available: &[vk::SurfaceFormatKHR],
+    preferred: &[vk::SurfaceFormatKHR],
+) -> Option<vk::SurfaceFormatKHR> {
+    preferred
+        .iter()
+        .find(|preferred| available.contains(preferred))
+        .or(available.first())
+        .copied()
+}
+
+/// Column major clip space matrix that counter rotates rendering for a swapchain pre-transform,
+/// applied after the projection so the presented image is upright
+pub fn pre_transform_matrix(transform: vk::SurfaceTransformFlagsKHR) -> [[f32; 4]; 4] {
+    type T = vk::SurfaceTransformFlagsKHR;
+    let (cos, sin) = match transform {
+        T::ROTATE_90 | T::HORIZONTAL_MIRROR_ROTATE_90 => (0.0, 1.0),
+        T::ROTATE_180 | T::HORIZONTAL_MIRROR_ROTATE_180 => (-1.0, 0.0),
+        T::ROTATE_270 | T::HORIZONTAL_MIRROR_ROTATE_270 => (0.0, -1.0),
+        _ => (1.0, 0.0),
+    };
+    let mirror = match transform {
+        T::HORIZONTAL_MIRROR
+        | T::HORIZONTAL_MIRROR_ROTATE_90
+        | T::HORIZONTAL_MIRROR_ROTATE_180
+        | T::HORIZONTAL_MIRROR_ROTATE_270 => -1.0,
+        _ => 1.0,
+    };
+
+    [
+        [cos * mirror, sin * mirror, 0.0, 0.0],
+        [-sin, cos, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ]
+}
+
+/// Width and height as seen by the viewer, swapped when the pre-transform rotates by 90 or 270 degrees,
+/// use this for the projection's aspect ratio
+pub fn pre_transform_extent(transform: vk::SurfaceTransformFlagsKHR, size: [u32; 2]) -> [u32; 2] {
+    type T = vk::SurfaceTransformFlagsKHR;
+    match transform {
+        T::ROTATE_90
+        | T::ROTATE_270
+        | T::HORIZONTAL_MIRROR_ROTATE_90
+        | T::HORIZONTAL_MIRROR_ROTATE_270 => [size[1], size[0]],
+        _ => size,
+    }
+}
+
+/// Errors if any of the requested swapchain usage flags are not supported by the surface
+pub(crate) fn validate_usage(
+    requested: vk::ImageUsageFlags,
+    supported: vk::ImageUsageFlags,
+) -> Result<(), VulkanError> {
+    if supported.contains(requested) {
+        Ok(())
+    } else {
+        Err(VulkanError::UnsupportedSwapchainUsage {
+            requested,
+            supported,
+        })
+    }
 }
 
 fn get_swapchain_extent_transform_count(
@@ -236,7 +464,10 @@ fn get_swapchain_extent_transform_count(
                     capabilities.max_image_extent.height,
                 ),
             },
-            capabilities.current_transform,
+            settings
+                .pre_transform
+                .filter(|transform| capabilities.supported_transforms.contains(*transform))
+                .unwrap_or(capabilities.current_transform),
             image_count,
         ))
     }
@@ -279,3 +510,192 @@ impl SwapchainManager {
             .map(|surface| self.swapchains.remove(&surface));
     }
 }
+
+/// Swapchain images are shared concurrently only when presenting from a separate family
+fn image_sharing(
+    graphics_family: Option<u32>,
+    present_family: Option<u32>,
+) -> (vk::SharingMode, Vec<u32>) {
+    let queue_family_indices: Vec<u32> =
+        graphics_family.into_iter().chain(present_family).collect();
+    let sharing_mode = if present_family.is_some() {
+        vk::SharingMode::CONCURRENT
+    } else {
+        vk::SharingMode::EXCLUSIVE
+    };
+    (sharing_mode, queue_family_indices)
+}
+
+fn is_zero_extent(extent: vk::Extent2D) -> bool {
+    extent.width == 0 || extent.height == 0
+}
+
+/// Hands the image index the semaphore its acquire signals, freeing the one from that image's previous acquire.
+/// The old semaphore is only safe to reuse now that the same image has been acquired again
+fn recycle_image_ready_semaphore(
+    image_ready_semaphores: &mut [vk::Semaphore],
+    free_semaphores: &mut Vec<vk::Semaphore>,
+    index: usize,
+    semaphore: vk::Semaphore,
+) {
+    let old_semaphore = std::mem::replace(&mut image_ready_semaphores[index], semaphore);
+    if old_semaphore != vk::Semaphore::null() {
+        free_semaphores.push(old_semaphore);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform_point(matrix: [[f32; 4]; 4], point: [f32; 2]) -> [f32; 2] {
+        [
+            matrix[0][0] * point[0] + matrix[1][0] * point[1],
+            matrix[0][1] * point[0] + matrix[1][1] * point[1],
+        ]
+    }
+
+    #[test]
+    fn rotate_90_matrix_rotates_clip_space() {
+        let matrix = pre_transform_matrix(vk::SurfaceTransformFlagsKHR::ROTATE_90);
+        assert_eq!(transform_point(matrix, [1.0, 0.0]), [0.0, 1.0]);
+        assert_eq!(transform_point(matrix, [0.0, 1.0]), [-1.0, 0.0]);
+
+        let identity = pre_transform_matrix(vk::SurfaceTransformFlagsKHR::IDENTITY);
+        assert_eq!(transform_point(identity, [0.5, -0.25]), [0.5, -0.25]);
+    }
+
+    #[test]
+    fn rotated_transforms_swap_the_extent() {
+        type T = vk::SurfaceTransformFlagsKHR;
+        assert_eq!(
+            pre_transform_extent(T::ROTATE_90, [1920, 1080]),
+            [1080, 1920]
+        );
+        assert_eq!(
+            pre_transform_extent(T::ROTATE_270, [1920, 1080]),
+            [1080, 1920]
+        );
+        assert_eq!(
+            pre_transform_extent(T::ROTATE_180, [1920, 1080]),
+            [1920, 1080]
+        );
+        assert_eq!(
+            pre_transform_extent(T::IDENTITY, [1920, 1080]),
+            [1920, 1080]
+        );
+    }
+
+    #[test]
+    fn swapchain_usage_must_be_supported_by_the_surface() {
+        let supported = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE;
+        assert!(validate_usage(
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE,
+            supported
+        )
+        .is_ok());
+
+        let requested = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST;
+        assert!(matches!(
+            validate_usage(requested, supported),
+            Err(VulkanError::UnsupportedSwapchainUsage {
+                requested: error_requested,
+                supported: error_supported,
+            }) if error_requested == requested && error_supported == supported
+        ));
+    }
+
+    #[test]
+    fn minimized_surfaces_have_a_zero_extent() {
+        for (width, height, zero) in [
+            (0, 0, true),
+            (0, 600, true),
+            (800, 0, true),
+            (800, 600, false),
+        ] {
+            assert_eq!(is_zero_extent(vk::Extent2D { width, height }), zero);
+        }
+    }
+
+    #[test]
+    fn separate_present_families_share_swapchain_images() {
+        assert_eq!(
+            image_sharing(Some(0), Some(2)),
+            (vk::SharingMode::CONCURRENT, vec![0, 2])
+        );
+        assert_eq!(
+            image_sharing(Some(0), None),
+            (vk::SharingMode::EXCLUSIVE, vec![0])
+        );
+    }
+
+    #[test]
+    fn surface_format_preferences_are_tried_in_order() {
+        let format = |format| vk::SurfaceFormatKHR {
+            format,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        let available = [
+            format(vk::Format::B8G8R8A8_UNORM),
+            format(vk::Format::R8G8B8A8_SRGB),
+            format(vk::Format::B8G8R8A8_SRGB),
+        ];
+
+        let preferred = [
+            format(vk::Format::A2B10G10R10_UNORM_PACK32),
+            format(vk::Format::B8G8R8A8_SRGB),
+            format(vk::Format::R8G8B8A8_SRGB),
+        ];
+        assert_eq!(
+            pick_surface_format(&available, &preferred),
+            Some(format(vk::Format::B8G8R8A8_SRGB))
+        );
+
+        // Without an available preference the first available format is used
+        assert_eq!(
+            pick_surface_format(&available, &preferred[..1]),
+            Some(available[0])
+        );
+        assert_eq!(pick_surface_format(&[], &preferred), None);
+    }
+
+    #[test]
+    fn image_ready_semaphores_are_recycled_after_their_image_is_acquired_again() {
+        use ash::vk::Handle;
+        let semaphore = |raw| vk::Semaphore::from_raw(raw);
+        let mut image_ready_semaphores = vec![vk::Semaphore::null(); 2];
+        let mut free_semaphores = Vec::new();
+
+        // First acquire of each image has nothing to recycle
+        recycle_image_ready_semaphore(
+            &mut image_ready_semaphores,
+            &mut free_semaphores,
+            0,
+            semaphore(1),
+        );
+        recycle_image_ready_semaphore(
+            &mut image_ready_semaphores,
+            &mut free_semaphores,
+            1,
+            semaphore(2),
+        );
+        assert_eq!(image_ready_semaphores, [semaphore(1), semaphore(2)]);
+        assert!(free_semaphores.is_empty());
+
+        // Acquiring image 1 again only frees image 1's old semaphore, image 0's may still be pending
+        recycle_image_ready_semaphore(
+            &mut image_ready_semaphores,
+            &mut free_semaphores,
+            1,
+            semaphore(3),
+        );
+        assert_eq!(image_ready_semaphores, [semaphore(1), semaphore(3)]);
+        assert_eq!(free_semaphores, [semaphore(2)]);
+
+        // The recycled semaphore is reused by the next acquire
+        let reused = free_semaphores.pop().unwrap();
+        recycle_image_ready_semaphore(&mut image_ready_semaphores, &mut free_semaphores, 0, reused);
+        assert_eq!(image_ready_semaphores, [semaphore(2), semaphore(3)]);
+        assert_eq!(free_semaphores, [semaphore(1)]);
+    }
+}
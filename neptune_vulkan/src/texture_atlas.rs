@@ -0,0 +1,313 @@
+use crate::{AllocationScheme, Device, ImageDescription2D, ImageHandle, VulkanError};
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+
+/// Texels left empty around each sprite to stop linear filtering bleeding into neighbours
+const SPRITE_PADDING: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvRect {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpriteId(usize);
+
+#[derive(Clone)]
+struct Shelf {
+    y: u32,
+    height: u32,
+    cursor_x: u32,
+}
+
+/// Simple shelf packer, rects are placed left to right on the first shelf they fit on
+#[derive(Clone)]
+struct ShelfPacker {
+    size: [u32; 2],
+    shelves: Vec<Shelf>,
+}
+
+impl ShelfPacker {
+    fn new(size: [u32; 2]) -> Self {
+        Self {
+            size,
+            shelves: Vec::new(),
+        }
+    }
+
+    fn pack(&mut self, size: [u32; 2]) -> Option<[u32; 2]> {
+        let padded = [
+            size[0].checked_add(SPRITE_PADDING)?,
+            size[1].checked_add(SPRITE_PADDING)?,
+        ];
+        if padded[0] > self.size[0] || padded[1] > self.size[1] {
+            return None;
+        }
+
+        for shelf in self.shelves.iter_mut() {
+            if padded[1] <= shelf.height && shelf.cursor_x + padded[0] <= self.size[0] {
+                let position = [shelf.cursor_x, shelf.y];
+                shelf.cursor_x += padded[0];
+                return Some(position);
+            }
+        }
+
+        let next_y = self
+            .shelves
+            .last()
+            .map(|shelf| shelf.y + shelf.height)
+            .unwrap_or(0);
+        if next_y + padded[1] > self.size[1] {
+            return None;
+        }
+
+        self.shelves.push(Shelf {
+            y: next_y,
+            height: padded[1],
+            cursor_x: padded[0],
+        });
+        Some([0, next_y])
+    }
+}
+
+/// Doubles `size` until every sprite and then the pending one fit, returning the packer, each sprite's new position
+/// and the pending sprite's position. Sprites are placed tallest first so shelves waste less space
+fn grow_packer(
+    size: [u32; 2],
+    max_size: u32,
+    sprite_sizes: &[[u32; 2]],
+    pending_size: [u32; 2],
+) -> Option<(ShelfPacker, Vec<[u32; 2]>, [u32; 2])> {
+    let mut order: Vec<usize> = (0..sprite_sizes.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(sprite_sizes[index][1]));
+
+    let mut size = [size[0].max(1), size[1].max(1)];
+    loop {
+        size = [size[0].checked_mul(2)?, size[1].checked_mul(2)?];
+        if size[0] > max_size || size[1] > max_size {
+            return None;
+        }
+
+        let mut packer = ShelfPacker::new(size);
+        let mut positions = vec![[0; 2]; sprite_sizes.len()];
+        let all_fit = order.iter().all(|&index| {
+            packer
+                .pack(sprite_sizes[index])
+                .map(|position| positions[index] = position)
+                .is_some()
+        });
+
+        // Make sure the pending sprite also fits before settling on this size
+        if all_fit {
+            if let Some(pending_position) = packer.pack(pending_size) {
+                return Some((packer, positions, pending_position));
+            }
+        }
+    }
+}
+
+struct Sprite {
+    size: [u32; 2],
+    position: [u32; 2],
+    data: Vec<u8>,
+}
+
+/// Packs many small images into a single sampled image.
+/// Sprite data is kept on the cpu so the atlas can be re-packed when it needs to grow.
+pub struct TextureAtlas {
+    name: String,
+    format: vk::Format,
+    bytes_per_texel: usize,
+    max_size: u32,
+
+    image: ImageHandle,
+    packer: ShelfPacker,
+    sprites: Vec<Sprite>,
+}
+
+impl TextureAtlas {
+    /// `initial_size` is clamped to at least 1 texel
+    pub fn new(
+        device: &mut Device,
+        name: &str,
+        format: vk::Format,
+        bytes_per_texel: usize,
+        initial_size: u32,
+        max_size: u32,
+    ) -> Result<Self, VulkanError> {
+        let size = [initial_size.max(1); 2];
+        let image = Self::create_atlas_image(device, name, format, size)?;
+        Ok(Self {
+            name: name.to_string(),
+            format,
+            bytes_per_texel,
+            max_size,
+            image,
+            packer: ShelfPacker::new(size),
+            sprites: Vec::new(),
+        })
+    }
+
+    pub fn image(&self) -> ImageHandle {
+        self.image
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.packer.size
+    }
+
+    pub fn add_sprite(
+        &mut self,
+        device: &mut Device,
+        size: [u32; 2],
+        data: &[u8],
+    ) -> Result<SpriteId, VulkanError> {
+        let expected_len = size[0] as usize * size[1] as usize * self.bytes_per_texel;
+        if data.len() != expected_len {
+            return Err(VulkanError::SpriteDataSize {
+                size,
+                expected: expected_len,
+                actual: data.len(),
+            });
+        }
+
+        let position = match self.packer.pack(size) {
+            Some(position) => position,
+            None => self.grow(device, size)?,
+        };
+
+        device.upload_image_region(self.image, position, size, data)?;
+        self.sprites.push(Sprite {
+            size,
+            position,
+            data: data.to_vec(),
+        });
+        Ok(SpriteId(self.sprites.len() - 1))
+    }
+
+    pub fn uv_rect(&self, id: SpriteId) -> UvRect {
+        let sprite = &self.sprites[id.0];
+        let uv = |position: [u32; 2]| {
+            [
+                position[0] as f32 / self.packer.size[0] as f32,
+                position[1] as f32 / self.packer.size[1] as f32,
+            ]
+        };
+        UvRect {
+            min: uv(sprite.position),
+            max: uv([
+                sprite.position[0] + sprite.size[0],
+                sprite.position[1] + sprite.size[1],
+            ]),
+        }
+    }
+
+    pub fn destroy(self, device: &mut Device) {
+        device.destroy_image(self.image);
+    }
+
+    /// Re-packs every sprite into a larger image and re-uploads them, returning the pending sprite's position
+    fn grow(
+        &mut self,
+        device: &mut Device,
+        pending_size: [u32; 2],
+    ) -> Result<[u32; 2], VulkanError> {
+        let sprite_sizes: Vec<[u32; 2]> = self.sprites.iter().map(|sprite| sprite.size).collect();
+        let (packer, positions, pending_position) =
+            grow_packer(self.packer.size, self.max_size, &sprite_sizes, pending_size).ok_or_else(
+                || VulkanError::TextureAtlasFull {
+                    name: self.name.clone(),
+                    max_size: self.max_size,
+                },
+            )?;
+
+        let image = Self::create_atlas_image(device, &self.name, self.format, packer.size)?;
+        for (sprite, position) in self.sprites.iter_mut().zip(positions) {
+            sprite.position = position;
+            device.upload_image_region(image, position, sprite.size, &sprite.data)?;
+        }
+
+        device.destroy_image(std::mem::replace(&mut self.image, image));
+        self.packer = packer;
+        Ok(pending_position)
+    }
+
+    fn create_atlas_image(
+        device: &mut Device,
+        name: &str,
+        format: vk::Format,
+        size: [u32; 2],
+    ) -> Result<ImageHandle, VulkanError> {
+        device.create_image(
+            name,
+            &ImageDescription2D {
+                size,
+                format,
+                usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+                mip_levels: 1,
+                location: MemoryLocation::GpuOnly,
+                allocation_scheme: AllocationScheme::Managed,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps(a: ([u32; 2], [u32; 2]), b: ([u32; 2], [u32; 2])) -> bool {
+        (0..2).all(|axis| a.0[axis] < b.0[axis] + b.1[axis] && b.0[axis] < a.0[axis] + a.1[axis])
+    }
+
+    #[test]
+    fn packed_rects_stay_in_bounds_without_overlapping() {
+        let mut packer = ShelfPacker::new([64, 64]);
+        let sizes = [[16, 16], [8, 24], [30, 4], [16, 16], [40, 10], [5, 5]];
+        let rects: Vec<([u32; 2], [u32; 2])> = sizes
+            .iter()
+            .map(|&size| (packer.pack(size).expect("rect should fit"), size))
+            .collect();
+
+        for (index, &(position, size)) in rects.iter().enumerate() {
+            assert!(position[0] + size[0] <= 64 && position[1] + size[1] <= 64);
+            for &other in &rects[index + 1..] {
+                assert!(
+                    !overlaps((position, size), other),
+                    "{:?} overlaps {:?}",
+                    (position, size),
+                    other
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn packer_rejects_rects_larger_than_the_atlas() {
+        let mut packer = ShelfPacker::new([16, 16]);
+        assert_eq!(packer.pack([16, 4]), None, "padding doesn't fit");
+        assert_eq!(packer.pack([u32::MAX, 1]), None);
+        assert_eq!(packer.pack([15, 15]), Some([0, 0]));
+        assert_eq!(packer.pack([1, 1]), None);
+    }
+
+    #[test]
+    fn growing_repacks_existing_sprites_and_the_pending_one() {
+        let (packer, positions, pending_position) =
+            grow_packer([16, 16], 256, &[[12, 12], [12, 12]], [12, 12])
+                .expect("should fit within the max size");
+        assert_eq!(packer.size, [32, 32]);
+        assert_eq!(positions, vec![[0, 0], [13, 0]]);
+        assert_eq!(pending_position, [0, 13]);
+    }
+
+    #[test]
+    fn growing_from_zero_or_past_the_max_size_terminates() {
+        let (packer, _, _) =
+            grow_packer([0, 0], 256, &[], [3, 3]).expect("zero size still doubles");
+        assert_eq!(packer.size, [4, 4]);
+        assert!(grow_packer([128, 128], 256, &[], [300, 300]).is_none());
+        assert!(grow_packer([1 << 31, 1 << 31], u32::MAX, &[], [1, 1]).is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::BufferHandle;
+
+pub(crate) fn align_up(value: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {
+        value
+    } else {
+        value.div_ceil(alignment) * alignment
+    }
+}
+
+/// Sub-allocates aligned slices of per-frame data from a single persistent CpuToGpu buffer.
+/// The buffer is split into one region per frame in flight plus one, so a region is only reused
+/// once the executor has waited on the fence of the frame that last wrote to it.
+pub struct DynamicUniformRing {
+    pub(crate) buffer: BufferHandle,
+    pub(crate) alignment: u64,
+    pub(crate) region_size: u64,
+    pub(crate) region_count: u64,
+
+    pub(crate) current_frame: u64,
+    pub(crate) region_offset: u64,
+}
+
+impl DynamicUniformRing {
+    pub(crate) fn new(
+        buffer: BufferHandle,
+        alignment: u64,
+        region_size: u64,
+        region_count: u64,
+    ) -> Self {
+        Self {
+            buffer,
+            alignment,
+            region_size,
+            region_count,
+            current_frame: 0,
+            region_offset: 0,
+        }
+    }
+
+    pub(crate) fn buffer_size(alignment: u64, frame_size: u64, region_count: u64) -> u64 {
+        align_up(frame_size, alignment) * region_count
+    }
+
+    pub fn buffer(&self) -> BufferHandle {
+        self.buffer
+    }
+
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// Returns the offset into the buffer of a new allocation, or None if the current frame's region is full
+    pub(crate) fn allocate(&mut self, frame: u64, size: u64) -> Option<u64> {
+        if frame != self.current_frame {
+            self.current_frame = frame;
+            self.region_offset = 0;
+        }
+
+        let offset = align_up(self.region_offset, self.alignment);
+        if offset + size > self.region_size {
+            return None;
+        }
+        self.region_offset = offset + size;
+
+        Some((frame % self.region_count) * self.region_size + offset)
+    }
+}
+
+/// Packs many small uniform blocks, e.g. per-object or per-material data, into one buffer.
+/// Each block is bound through ShaderResourceUsage::DynamicBuffer with the offset returned by push,
+/// the whole batch is uploaded with Device::create_uniform_batch_buffer
+pub struct UniformBatch {
+    alignment: u64,
+    data: Vec<u8>,
+    /// End of the last block, data is zero padded past it so the last block can be bound with max_block_size
+    end: u64,
+    max_block_size: u64,
+}
+
+impl UniformBatch {
+    pub fn new(alignment: u64) -> Self {
+        Self {
+            alignment,
+            data: Vec::new(),
+            end: 0,
+            max_block_size: 0,
+        }
+    }
+
+    /// Copies the block into the batch, returning its dynamic offset
+    pub fn push<T: bytemuck::Pod>(&mut self, block: &T) -> u32 {
+        self.push_bytes(bytemuck::bytes_of(block))
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> u32 {
+        let offset = align_up(self.end, self.alignment);
+        self.data.resize(offset as usize, 0);
+        self.data.extend_from_slice(bytes);
+        self.end = offset + bytes.len() as u64;
+        self.max_block_size = self.max_block_size.max(bytes.len() as u64);
+        self.data.resize((offset + self.max_block_size) as usize, 0);
+        offset as u32
+    }
+
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// Size of the largest block, use as the DynamicBuffer range so every block is in bounds
+    pub fn max_block_size(&self) -> u64 {
+        self.max_block_size
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.end = 0;
+        self.max_block_size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_allocations_are_aligned_and_wrap_per_frame() {
+        let mut ring = DynamicUniformRing::new(BufferHandle::Transient(0), 256, 1024, 3);
+
+        let offsets: Vec<u64> = (0..4).map(|_| ring.allocate(0, 100).unwrap()).collect();
+        assert_eq!(offsets, vec![0, 256, 512, 768]);
+        assert_eq!(ring.allocate(0, 100), None, "frame 0's region is full");
+
+        assert_eq!(ring.allocate(1, 64), Some(1024));
+        assert_eq!(ring.allocate(1, 64), Some(1024 + 256));
+        assert_eq!(ring.allocate(2, 64), Some(2048));
+
+        // Frame 3 reuses frame 0's region, once its fence has been waited on
+        assert_eq!(ring.allocate(3, 64), Some(0));
+        assert_eq!(ring.allocate(4, 1024), Some(1024));
+        assert_eq!(ring.allocate(4, 1), None);
+
+        for frame in 5..20 {
+            let offset = ring.allocate(frame, 17).unwrap();
+            assert_eq!(offset % 256, 0);
+            assert_eq!(offset, (frame % 3) * 1024);
+        }
+    }
+
+    #[test]
+    fn align_up_rounds_to_the_next_multiple() {
+        assert_eq!(align_up(0, 64), 0);
+        assert_eq!(align_up(1, 64), 64);
+        assert_eq!(align_up(64, 64), 64);
+        assert_eq!(align_up(65, 64), 128);
+        assert_eq!(align_up(7, 0), 7);
+    }
+
+    #[test]
+    fn batched_blocks_are_aligned_within_one_buffer() {
+        let mut batch = UniformBatch::new(256);
+        let offsets: Vec<u32> = (0..3u8).map(|i| batch.push(&[i; 64])).collect();
+
+        assert_eq!(offsets, vec![0, 256, 512]);
+        assert_eq!(batch.max_block_size(), 64);
+        for (i, offset) in offsets.iter().enumerate() {
+            let offset = *offset as usize;
+            assert_eq!(offset % 256, 0);
+            assert_eq!(&batch.data()[offset..offset + 64], &[i as u8; 64]);
+        }
+    }
+
+    #[test]
+    fn last_block_is_padded_to_the_largest_block() {
+        let mut batch = UniformBatch::new(64);
+        batch.push(&[1u8; 128]);
+        let last = batch.push(&[2u8; 16]);
+
+        assert_eq!(last, 128);
+        assert!(last as u64 + batch.max_block_size() <= batch.data().len() as u64);
+
+        // Padding is not counted as part of the last block
+        assert_eq!(batch.push(&[3u8; 16]), 192);
+    }
+}
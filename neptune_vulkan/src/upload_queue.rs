@@ -35,12 +35,22 @@ impl UploadQueue {
         index
     }
 
+    /// Images are only added once, so every upload to one image shares a single barrier and rest transition
     fn add_image(&mut self, image: ImageHandle, access: ImageResourceAccess) -> ImageIndex {
+        let key = image.as_key();
+        if let Some(index) = self.image_resources.iter().position(|resource| {
+            matches!(resource.description, ImageResourceDescription::Persistent(other) if other == key)
+        }) {
+            return index;
+        }
+
         let index = self.image_resources.len();
         self.image_resources.push(ImageGraphResource {
             description: ImageResourceDescription::Persistent(image.as_key()),
             first_access: None,
             last_access: Some(access),
+            attachment_views: Vec::new(),
+            release_access: None,
         });
         self.image_access.push((index, access));
         index
@@ -73,7 +83,7 @@ impl UploadQueue {
         &mut self,
         src: crate::render_graph_builder::ImageCopyBuffer,
         dst: crate::render_graph_builder::ImageCopyImage,
-        copy_size: [u32; 2],
+        copy_size: [u32; 3],
     ) {
         let src = ImageCopyBuffer {
             buffer: self.add_buffer(src.buffer, BufferResourceAccess::TransferRead),
@@ -85,6 +95,8 @@ impl UploadQueue {
         let dst = ImageCopyImage {
             image: self.add_image(dst.image, ImageResourceAccess::TransferWrite),
             offset: dst.offset,
+            mip_level: dst.mip_level,
+            base_array_layer: dst.base_array_layer,
         };
 
         self.transfers.push(Transfer::BufferToImage {
@@ -106,6 +118,8 @@ impl UploadQueue {
                     index,
                     src: BufferBarrierSource::FirstUsage,
                     dst: BufferResourceAccess::TransferWrite,
+                    src_shader_stages: BufferResourceAccess::ALL_SHADER_STAGES,
+                    dst_shader_stages: BufferResourceAccess::ALL_SHADER_STAGES,
                 })
                 .collect();
 
@@ -133,6 +147,8 @@ impl UploadQueue {
                         render_passes: vec![RenderPass {
                             label_name: "Device Upload Pass".to_string(),
                             label_color: [0.5, 0.0, 0.5, 1.0],
+                            buffer_usages: std::mem::take(&mut self.buffer_access),
+                            image_usages: std::mem::take(&mut self.image_access),
                             command: Some(RenderPassCommand::Transfer {
                                 transfers: std::mem::take(&mut self.transfers),
                             }),